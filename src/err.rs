@@ -1,27 +1,40 @@
-use winapi::shared::minwindef::DWORD;
-
-use std::{ffi::NulError, fmt};
+use std::{ffi::NulError, fmt, io};
 
 #[derive(Debug)]
 pub enum Error {
     CStringNulError(NulError),
-    LookupPrivilegeValueFailed(DWORD),
-    GetSelfProcessTokenFailed(DWORD),
-    AdjustTokenPrivilegesFailed(DWORD),
-    FindFirstVolumeFailed(DWORD),
-    FindNextVolumeFailed(DWORD),
-    GetVolumePathNamesFailed(DWORD),
-    OpenVolumeHandleFailed(DWORD),
+    IoError(io::Error),
+    LookupPrivilegeValueFailed(u32),
+    GetSelfProcessTokenFailed(u32),
+    AdjustTokenPrivilegesFailed(u32),
+    FindFirstVolumeFailed(u32),
+    FindNextVolumeFailed(u32),
+    GetVolumePathNamesFailed(u32),
+    OpenVolumeHandleFailed(u32),
     MissingNullTerminator,
-    GetNtfsVolumeDataFailed(DWORD),
+    GetNtfsVolumeDataFailed(u32),
     UnknownNtfsVersion,
     GetNtfsVolumeDataBadSize,
-    OpenMftFailed(DWORD),
-    GetRetrievalPointersFailed(DWORD),
-    ReadVolumeFailed(DWORD),
+    ReadVolumeFailed(u32),
     ReadVolumeTooShort,
     MftHasNoExtents,
-    MftStartLcnNotFirstExtent,
+    MftRecordNotInUse,
+    SegmentOutsideMftExtents(u64),
+    BadBootSectorSignature,
+    BadBootSectorGeometry,
+    BadRecordSize(u64),
+    BadGptHeader,
+    BadVhdFooter,
+    BadVhdDynamicHeader,
+    UnsupportedVhdType(u32),
+    BadVhdxHeader,
+    BadVhdxRegionTable,
+    BadVhdxMetadata,
+    BadVhdxBlockState(u64),
+    VhdxNeedsLogReplay,
+    MissingParentDisk,
+    ParentChainTooDeep,
+    BadAttribute,
     UnknownFormCode(u8),
     UnknownAttributeTypeCode(u32),
     UnknownFilenameType(u8),
@@ -30,6 +43,18 @@ pub enum Error {
     UnknownStandardInformationSize(usize),
     UnknownFilenameSize(usize),
     UnknownAttributeListEntrySize(usize),
+    BadReparsePoint(u32),
+    MissingIndexRoot,
+    BadIndexNode,
+    UnmappedVcn(u64),
+    DiscontiguousAttribute(u64),
+    MissingVolume,
+    BadCompressedData,
+    UnsupportedWofAlgorithm(u32),
+    BadSecurityDescriptor,
+    BadExtendedAttribute,
+    BadObjectId,
+    BadVolumeInformation,
     BadMultiSectorHeaderSignature,
     UpdateSequenceValidationFailed,
     AttributeListPointedToUnusedFileRecord,
@@ -39,6 +64,20 @@ impl From<NulError> for Error {
         Error::CStringNulError(err)
     }
 }
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+// So errors can be returned from `Read` and `Seek` implementations.
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::IoError(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
 impl std::error::Error for Error {}
 impl std::fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+use std::{convert::TryInto as _, fmt};
+
+// A GUID in the mixed-endian layout Windows uses on disk: the first three
+// fields are little-endian, the last eight bytes are stored as-is.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Guid {
+    pub data1: u32,
+    pub data2: u16,
+    pub data3: u16,
+    pub data4: [u8; 8],
+}
+impl Guid {
+    pub const fn from_fields(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        Guid {
+            data1,
+            data2,
+            data3,
+            data4,
+        }
+    }
+
+    pub fn load(buf: &[u8]) -> Self {
+        Guid {
+            data1: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            data2: u16::from_le_bytes([buf[4], buf[5]]),
+            data3: u16::from_le_bytes([buf[6], buf[7]]),
+            data4: buf[8..16].try_into().unwrap(),
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == Guid::default()
+    }
+}
+impl fmt::Display for Guid {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            self.data1, self.data2, self.data3, self.data4[0], self.data4[1]
+        )?;
+        for byte in &self.data4[2..] {
+            write!(fmt, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Debug for Guid {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{{{}}}", self)
+    }
+}
@@ -1,31 +1,46 @@
-mod err;
-mod mft;
+pub mod err;
+pub mod guid;
+#[cfg(windows)]
+pub mod live;
+pub mod mft;
+pub mod partition;
+#[cfg(windows)]
 mod privileges;
+pub mod reader;
+#[cfg(test)]
+mod test_support;
+pub mod vhd;
+pub mod vhdx;
+#[cfg(windows)]
 mod volumes;
 
 #[cfg(feature = "progress")]
 use indicatif::{HumanDuration, ProgressBar};
 
-use std::{collections::HashMap, convert::TryInto as _, ffi::{OsString, OsStr}, ops::Deref, path::PathBuf};
-use winapi::um::{handleapi::CloseHandle, winnt::HANDLE};
+#[cfg(windows)]
+pub use live::SafeHandle;
 
-use serde::{Serialize, Deserialize};
+use std::{collections::HashMap, convert::TryInto as _, ffi::{OsString, OsStr}, path::{Path, PathBuf}};
 
-#[derive(Debug)]
-pub struct SafeHandle {
-  handle: HANDLE,
-}
-impl Deref for SafeHandle {
-  type Target = HANDLE;
+use serde::{Serialize, Deserialize};
 
-  fn deref(&self) -> &Self::Target {
-    &self.handle
-  }
-}
-impl Drop for SafeHandle {
-  fn drop(&mut self) {
-    unsafe { CloseHandle(self.handle) };
-  }
+// Where entries go when the directory they were in can't be found, or its
+// record has since been reused for something else.
+pub const ORPHAN_DIRECTORY: &str = "$OrphanFiles";
+
+// Whether `parent` is still the record that `reference` (from one of the
+// child's names) pointed at. Freeing a record bumps its sequence number, so
+// the parent of a deleted child may be one ahead if it was deleted too.
+fn is_parent_of(
+  parent: &mft::MftEntry,
+  reference: mft::sys::FileReference,
+  child: &mft::MftEntry,
+) -> bool {
+  let expected = reference.sequence_number;
+  // Old versions of NTFS didn't use sequence numbers.
+  expected == 0
+    || parent.sequence_number == expected
+    || (child.is_deleted && parent.is_deleted && parent.sequence_number == expected.wrapping_add(1))
 }
 
 pub struct Contructor {
@@ -53,24 +68,45 @@ impl Contructor {
     let mut result = self.drive_letter.clone();
 
     let mut parts = Vec::new();
+    let mut is_orphan = false;
 
     loop {
       let parents = entry.parents();
 
-      if parents.len() == 0 {
+      if parents.is_empty() {
         break;
       }
-      if parents[0] != entry.base_record_segment_idx {
+      if parents[0].segment() != entry.base_record_segment_idx {
         parts.push(entry.get_best_filename()?);
-        entry = self.entries.get(&parents[0])?;
+        // The parent may have been deleted, and its record reused for
+        // something else since. Only the sequence numbers can tell, and a
+        // loop means we've been led astray the same way.
+        let parent = self
+          .entries
+          .get(&parents[0].segment())
+          .filter(|parent| is_parent_of(parent, parents[0], entry));
+        match parent {
+          Some(parent) if parts.len() <= self.entries.len() => entry = parent,
+          _ => {
+            is_orphan = true;
+            break;
+          }
+        }
       } else {
         break;
       }
     }
 
+    if is_orphan {
+      result.push('\\');
+      result.push_str(ORPHAN_DIRECTORY);
+    }
     for part in parts.iter().rev() {
+      // Display escapes unpaired surrogates, so distinct names only
+      // collapse onto the same path if one has a backslash in it, which
+      // Windows doesn't allow.
       result.push('\\');
-      result.push_str(&part.to_string_lossy());
+      result.push_str(&part.to_string());
     }
 
     Some(result)
@@ -80,13 +116,61 @@ impl Contructor {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Entry {
   pub name: OsString,
   pub path: String,
   pub real_size: u64,
   pub alloc_size: u64,
   pub is_dir: bool,
+  // From $STANDARD_INFORMATION; None for directories we only know about
+  // because something inside them was found.
+  pub created: Option<mft::FileTime>,
+  pub modified: Option<mft::FileTime>,
+  pub mft_changed: Option<mft::FileTime>,
+  pub accessed: Option<mft::FileTime>,
+  pub usn: Option<u64>,
+  pub reparse_tag: Option<u32>,
+  // Where a symlink, junction or similar points. These aren't followed, so
+  // the target's contents aren't counted here.
+  pub link_target: Option<String>,
+  // The Linux owner and mode WSL keeps in a file's extended attributes, for
+  // files created through WSL.
+  pub unix_uid: Option<u32>,
+  pub unix_gid: Option<u32>,
+  pub unix_mode: Option<u32>,
+  // Major and minor numbers, for device files
+  pub unix_device: Option<(u32, u32)>,
+  // The IDs the link tracking service gave the file, which shortcuts use
+  // to find it again after it's moved.
+  pub object_id: Option<mft::ObjectId>,
+  // Recovered from a record that's no longer in use. Deleted entries don't
+  // count towards the sizes of the directories they're in.
+  pub is_deleted: bool,
+}
+
+impl Entry {
+  // Fills in everything that comes from the file's own record.
+  fn set_record_details(&mut self, entry: &mft::MftEntry) {
+    if let Some(info) = entry.get_standard_information() {
+      self.created = Some(info.created);
+      self.modified = Some(info.modified);
+      self.mft_changed = Some(info.mft_changed);
+      self.accessed = Some(info.accessed);
+      self.usn = info.usn;
+    }
+    if let Some(ref reparse_point) = entry.reparse_point {
+      self.reparse_tag = Some(reparse_point.tag());
+      self.link_target = reparse_point.target();
+    }
+    if let Some(lx) = entry.lx_metadata() {
+      self.unix_uid = lx.uid;
+      self.unix_gid = lx.gid;
+      self.unix_mode = lx.mode;
+      self.unix_device = lx.device;
+    }
+    self.object_id = entry.object_id;
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -103,6 +187,19 @@ pub struct TestEntry {
 pub struct Filesystem {
   pub files: HashMap<String, Entry>,
   pub dirs: HashMap<String, TestEntry>,
+  // Each volume's label, version and state, by the root its paths are under.
+  // Volumes whose $Volume couldn't be read are left out.
+  pub volumes: HashMap<String, mft::VolumeMetadata>,
+  // The path of the live file with each object ID, for find_by_object_id.
+  // Built as volumes are loaded, and not serialized.
+  #[serde(skip)]
+  object_ids: HashMap<guid::Guid, String>,
+}
+
+impl Default for Filesystem {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Filesystem {
@@ -110,7 +207,24 @@ impl Filesystem {
     Filesystem {
       files: HashMap::new(),
       dirs: HashMap::new(),
+      volumes: HashMap::new(),
+      object_ids: HashMap::new(),
+    }
+  }
+
+  // Finds the file with the given object ID, as found in a shortcut's link
+  // tracking data. Files which have moved between volumes are found by
+  // their birth object ID instead, which doesn't change.
+  pub fn find_by_object_id(&self, object_id: &guid::Guid) -> Option<&Entry> {
+    self.object_ids.get(object_id).and_then(|path| self.files.get(path))
+  }
+
+  // A file's current object ID takes precedence over another's birth one.
+  fn index_object_id(&mut self, ids: mft::ObjectId, path: &str) {
+    if !ids.birth_object_id.is_nil() {
+      self.object_ids.entry(ids.birth_object_id).or_insert_with(|| path.to_string());
     }
+    self.object_ids.insert(ids.object_id, path.to_string());
   }
 
   fn add_fs_entry(&mut self, entry: u64, constructor: &Contructor) {
@@ -118,36 +232,59 @@ impl Filesystem {
     let mut real_size = 0;
     let alloc_size = entry.get_allocated_size(constructor.bytes_per_cluster);
     let id = entry.base_record_segment_idx;
-    let name = entry.get_best_filename().unwrap_or(OsString::from(""));
+    let name = entry
+      .get_best_filename()
+      .map(|name| name.to_os_string())
+      .unwrap_or_default();
 
     for i in 0..entry.data.len() {
       real_size += entry.data[i].logical_size;
     }
 
-    let mut path = constructor.get_full_path(id).unwrap();
+    let is_deleted = entry.is_deleted;
+    let mut path = match constructor.get_full_path(id) {
+      Some(path) => path,
+      // Nameless leftovers aren't worth reporting.
+      None if is_deleted => return,
+      None => panic!("no path for record {}", id),
+    };
+    // Live files take precedence over deleted ones with the same path.
+    let is_taken = self.files.get(&path).is_some_and(|file| !file.is_deleted);
+    if is_deleted && (entry.filename.is_empty() || is_taken) {
+      return;
+    }
+    if let Some(ids) = entry.object_id.filter(|_| !is_deleted) {
+      self.index_object_id(ids, &path);
+    }
 
     let file = self
       .files
       .entry(path.clone())
       .and_modify(|file| {
-        file.real_size = file.real_size;
         file.path = path.clone();
         file.name = name.clone();
-        file.is_dir = file.is_dir;
+        file.set_record_details(entry);
       })
-      .or_insert(Entry {
-        name: name.clone(),
-        path: path.clone(),
-        alloc_size,
-        real_size,
-        is_dir: false,
+      .or_insert_with(|| {
+        let mut file = Entry {
+          name: name.clone(),
+          path: path.clone(),
+          alloc_size,
+          real_size,
+          is_deleted,
+          ..Default::default()
+        };
+        file.set_record_details(entry);
+        file
       });
 
     if file.is_dir {
       return;
     };
+    let (real_size, alloc_size) = if is_deleted { (0, 0) } else { (real_size, alloc_size) };
+    let orphan_directory = format!("{}\\{}", constructor.drive_letter, ORPHAN_DIRECTORY);
     loop {
-      let to_split = String::from(path.clone());
+      let to_split = path.clone();
       let mut split = to_split.rsplitn(2, '\\');
       let name = split.next();
       let possible_path = split.next();
@@ -175,16 +312,18 @@ impl Filesystem {
             real_size,
             alloc_size,
             is_dir: true,
+            // $OrphanFiles isn't a real directory, deleted or otherwise.
+            is_deleted: is_deleted && path != orphan_directory,
+            ..Default::default()
           });
       }
     }
   }
 
+  #[allow(dead_code)]
   fn add_dir_entry(&mut self, original_path: String, entry: Entry) {
     let path = PathBuf::from(original_path.clone());
-    let parent = path.parent();
-    if parent.is_some() {
-      let parent = parent.unwrap();
+    if let Some(parent) = path.parent() {
       self
         .dirs
         .entry(parent.to_string_lossy().into_owned())
@@ -203,7 +342,7 @@ impl Filesystem {
         })
         .or_insert(TestEntry {
           name: PathBuf::from(parent.file_name().unwrap_or(OsStr::new(""))),
-          path: PathBuf::from(parent.clone()),
+          path: PathBuf::from(parent),
           is_dir: true,
           real_size: entry.real_size,
           alloc_size: entry.alloc_size,
@@ -219,21 +358,35 @@ impl Filesystem {
     }
   }
 
+  #[cfg(windows)]
   fn handle_volume(&mut self, volume: volumes::VolumeInfo) {
     #[cfg(feature = "progress")]
     println!("Reading {}...", volume.paths[0].to_string_lossy());
-    #[cfg(feature = "progress")]
-    let begin = std::time::Instant::now();
 
     let handle = volume.get_handle().unwrap();
-    let (mft, bytes_per_cluster) = mft::MasterFileTable::load(handle, &volume.paths[0]).unwrap();
+    let (mft, _) = mft::MasterFileTable::load(handle).unwrap();
+
+    self.handle_mft(mft, volume.paths[0].clone()).unwrap();
+  }
+
+  fn handle_mft<R: reader::VolumeReader>(
+    &mut self,
+    mut mft: mft::MasterFileTable<R>,
+    root: OsString,
+  ) -> Result<(), err::Error> {
+    #[cfg(feature = "progress")]
+    let begin = std::time::Instant::now();
 
     #[cfg(feature = "progress")]
     let entry_count = mft.entry_count();
 
+    if let Ok(Some(volume)) = mft.read_volume_metadata() {
+      self.volumes.insert(root.to_string_lossy().into_owned(), volume);
+    }
+
     let mut constructor = Contructor::new(
-      volume.paths[0].clone(),
-      bytes_per_cluster,
+      root,
+      mft.bytes_per_cluster(),
       mft.entry_count().try_into().unwrap(),
     );
 
@@ -243,7 +396,7 @@ impl Filesystem {
     progress.set_draw_delta(entry_count / 20);
 
     for entry in mft {
-      constructor.add_entry(entry.unwrap());
+      constructor.add_entry(entry?);
       #[cfg(feature = "progress")]
       progress.inc(1);
     }
@@ -255,7 +408,10 @@ impl Filesystem {
     #[cfg(feature = "progress")]
     progress.set_draw_delta(entry_count / 20);
 
-    for (id, _entry) in constructor.entries.clone() {
+    // Live entries go first, so deleted ones can't take their paths.
+    let mut ids = constructor.entries.keys().copied().collect::<Vec<_>>();
+    ids.sort_by_key(|id| constructor.entries[id].is_deleted);
+    for id in ids {
       self.add_fs_entry(id, &constructor);
 
       #[cfg(feature = "progress")]
@@ -275,9 +431,12 @@ impl Filesystem {
       HumanDuration(time_taken),
       1000f64 * (entry_count as f64) / (time_taken.as_millis() as f64)
     );
+
+    Ok(())
   }
 }
 
+#[cfg(windows)]
 pub fn get_drive_list() -> Vec<OsString> {
   let volumes = volumes::VolumeIterator::new().unwrap();
   let mut output = Vec::new();
@@ -299,6 +458,7 @@ pub fn get_drive_list() -> Vec<OsString> {
   output
 }
 
+#[cfg(windows)]
 pub fn main(drive_letters: Option<Vec<char>>) -> Result<Filesystem, err::Error> {
   match privileges::has_sufficient_privileges() {
     Ok(true) => {}
@@ -339,3 +499,470 @@ pub fn main(drive_letters: Option<Vec<char>>) -> Result<Filesystem, err::Error>
   }
   Ok(filesystem)
 }
+
+// Builds a filesystem from a raw image, either of a whole disk (in which case
+// every NTFS partition on it is loaded) or of a single NTFS volume. Paths are
+// rooted at "PartitionN" for disks, and at "\\" for single volumes. As with
+// the volumes of the running system, one that can't be read is skipped.
+pub fn load_image<P: AsRef<Path>>(path: P) -> Result<Filesystem, err::Error> {
+  let mut filesystem = Filesystem::new();
+  for volume in partition::ImageVolumeIterator::new(path)? {
+    let result = volume.and_then(|volume| {
+      let mft = mft::MasterFileTable::open(volume.get_reader()?)?;
+      filesystem.handle_mft(mft, volume.paths[0].clone())
+    });
+    if let Err(err) = result {
+      eprintln!("Failed to read a volume of the image: {:?}", err);
+    }
+  }
+  Ok(filesystem)
+}
+
+// Builds a filesystem from an already opened MFT, for when it needs setting
+// up first; for instance, to include deleted files with `with_deleted_records`.
+pub fn load_mft<R: reader::VolumeReader>(
+  mft: mft::MasterFileTable<R>,
+  root: OsString,
+) -> Result<Filesystem, err::Error> {
+  let mut filesystem = Filesystem::new();
+  filesystem.handle_mft(mft, root)?;
+  Ok(filesystem)
+}
+
+// Builds a filesystem from a standalone copy of a volume's $MFT, such as one
+// extracted by another tool. `record_size` is the size of one file record
+// segment (almost always 1024 bytes).
+pub fn load_mft_file<P: AsRef<Path>>(path: P, record_size: u64) -> Result<Filesystem, err::Error> {
+  let mft = mft::MasterFileTable::from_mft_file(path, record_size)?;
+
+  let mut filesystem = Filesystem::new();
+  filesystem.handle_mft(mft, OsString::new())?;
+  Ok(filesystem)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_support::{namespaces, Attribute, ImageBuilder, Record, StandardInfo, ROOT_RECORD};
+
+  #[test]
+  fn builds_paths_from_parents() {
+    let mut builder = ImageBuilder::new();
+    let dir = builder.add_directory(ROOT_RECORD, "dir");
+    let sub = builder.add_directory(dir, "sub");
+    builder.add_file(sub, "a.txt", b"12345");
+    builder.add_file(dir, "b.txt", b"123");
+    builder.add_file(ROOT_RECORD, "c.txt", b"1");
+
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+
+    let mut paths = filesystem.files.keys().cloned().collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(
+      paths,
+      vec![
+        "C:",
+        "C:\\$MFT",
+        "C:\\c.txt",
+        "C:\\dir",
+        "C:\\dir\\b.txt",
+        "C:\\dir\\sub",
+        "C:\\dir\\sub\\a.txt",
+      ]
+    );
+
+    let file = &filesystem.files["C:\\dir\\sub\\a.txt"];
+    assert_eq!(file.name, OsString::from("a.txt"));
+    assert!(!file.is_dir);
+    // Directories add up the sizes of everything below them.
+    let dir = &filesystem.files["C:\\dir"];
+    assert!(dir.is_dir);
+    assert_eq!(dir.real_size, 8);
+  }
+
+  #[test]
+  fn loads_extracted_mft_file() {
+    let mut builder = ImageBuilder::new();
+    let dir = builder.add_directory(ROOT_RECORD, "dir");
+    builder.add_file(dir, "file.txt", b"content");
+
+    let path = std::env::temp_dir().join(format!("mft_ntfs_test_{}.mft", std::process::id()));
+    std::fs::write(&path, builder.mft_bytes()).unwrap();
+    let filesystem = load_mft_file(&path, 1024);
+    // Record sizes which can't be right are rejected up front.
+    let bad_sizes: Vec<_> = [0, 128, 1000]
+      .into_iter()
+      .map(|record_size| match load_mft_file(&path, record_size) {
+        Err(err::Error::BadRecordSize(size)) => size,
+        _ => panic!("record size {} was accepted", record_size),
+      })
+      .collect();
+    std::fs::remove_file(&path).unwrap();
+
+    let filesystem = filesystem.unwrap();
+    assert!(filesystem.files.contains_key("\\dir\\file.txt"));
+    assert_eq!(filesystem.files["\\dir\\file.txt"].real_size, 7);
+    assert_eq!(bad_sizes, [0, 128, 1000]);
+  }
+
+  #[test]
+  fn skips_unreadable_partitions_of_an_image() {
+    let mut builder = ImageBuilder::new();
+    builder.add_file(ROOT_RECORD, "file.txt", b"content");
+    let volume = builder.build();
+    let volume = volume.bytes();
+    let sectors = (volume.len() as u64 / test_support::SECTOR_SIZE) as u32;
+
+    // The second partition has a boot sector, but nothing where its MFT
+    // should be.
+    let first = 1;
+    let second = first + sectors;
+    let mut disk = vec![0; ((second + sectors) as u64 * test_support::SECTOR_SIZE) as usize];
+    disk[..512].copy_from_slice(&test_support::mbr_sector(&[
+      test_support::mbr_entry(0x07, first, sectors),
+      test_support::mbr_entry(0x07, second, sectors),
+    ]));
+    let offset = |lba: u32| (u64::from(lba) * test_support::SECTOR_SIZE) as usize;
+    disk[offset(first)..offset(second)].copy_from_slice(volume);
+    disk[offset(second)..offset(second) + 512]
+      .copy_from_slice(&test_support::boot_sector(u64::from(sectors)));
+
+    let path = std::env::temp_dir().join(format!("mft_ntfs_test_{}.img", std::process::id()));
+    std::fs::write(&path, disk).unwrap();
+    let filesystem = load_image(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    let filesystem = filesystem.unwrap();
+    assert_eq!(filesystem.files["Partition1\\file.txt"].real_size, 7);
+    assert!(!filesystem.files.keys().any(|path| path.starts_with("Partition2")));
+  }
+
+  #[test]
+  fn entries_carry_standard_information() {
+    let mut builder = ImageBuilder::new();
+    let dir = builder.add_directory(ROOT_RECORD, "dir");
+    builder.add_record(
+      Record::new()
+        .with(
+          StandardInfo {
+            created: 100,
+            modified: 200,
+            mft_changed: 300,
+            accessed: 400,
+            usn: 500,
+            ..Default::default()
+          }
+          .attribute(),
+        )
+        .with(Attribute::file_name(dir, "file", namespaces::WIN32))
+        .with(Attribute::resident_data(None, b"")),
+    );
+
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+
+    let file = &filesystem.files["C:\\dir\\file"];
+    assert_eq!(file.created, Some(mft::FileTime::from_raw(100)));
+    assert_eq!(file.modified, Some(mft::FileTime::from_raw(200)));
+    assert_eq!(file.mft_changed, Some(mft::FileTime::from_raw(300)));
+    assert_eq!(file.accessed, Some(mft::FileTime::from_raw(400)));
+    assert_eq!(file.usn, Some(500));
+    // Directories have their own record's times, even when one of their
+    // children was seen first.
+    assert!(filesystem.files["C:\\dir"].created.is_some());
+  }
+
+  #[test]
+  fn entries_report_link_targets() {
+    let mut builder = ImageBuilder::new();
+    builder.add_record(
+      Record::directory()
+        .with(Attribute::file_name(ROOT_RECORD, "junction", namespaces::WIN32))
+        .with(Attribute::reparse_point(test_support::link_reparse_buffer(
+          "\\??\\C:\\target",
+          "C:\\target",
+          None,
+        ))),
+    );
+
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+
+    let junction = &filesystem.files["C:\\junction"];
+    assert_eq!(junction.reparse_tag, Some(mft::reparse_tags::MOUNT_POINT));
+    assert_eq!(junction.link_target.as_deref(), Some("C:\\target"));
+    assert_eq!(filesystem.files["C:\\$MFT"].link_target, None);
+  }
+
+  #[test]
+  fn damaged_reparse_points_are_skipped() {
+    let mut builder = ImageBuilder::new();
+    let broken = builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(ROOT_RECORD, "broken", namespaces::WIN32))
+        .with(Attribute::resident_data(None, b"data"))
+        // Too short to hold even the reparse header
+        .with(Attribute::reparse_point(vec![0x0C, 0x00, 0x00])),
+    );
+    builder.add_file(ROOT_RECORD, "after.txt", b"123");
+    let image = builder.build();
+
+    let mut mft = mft::MasterFileTable::open(test_support::MemoryReader::new(image.bytes().to_vec())).unwrap();
+    let entry = mft.read_entry(broken).unwrap().unwrap();
+    assert!(entry.reparse_point.is_none());
+    assert_eq!(entry.bad_attributes, vec![mft::sys::AttributeType::ReparsePoint]);
+
+    let mft = mft::MasterFileTable::open(image).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+    let broken = &filesystem.files["C:\\broken"];
+    assert_eq!(broken.reparse_tag, None);
+    assert_eq!(broken.real_size, 4);
+    assert!(filesystem.files.contains_key("C:\\after.txt"));
+  }
+
+  #[test]
+  fn entries_carry_wsl_metadata() {
+    let mut builder = ImageBuilder::new();
+    let list = test_support::ea_list(&[
+      ("$LXUID", &1000u32.to_le_bytes()),
+      ("$LXGID", &100u32.to_le_bytes()),
+      ("$LXMOD", &0o100755u32.to_le_bytes()),
+    ]);
+    let mut information = vec![0; 8];
+    information[4..8].copy_from_slice(&(list.len() as u32).to_le_bytes());
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(ROOT_RECORD, "script.sh", namespaces::WIN32))
+        .with(Attribute::resident_data(None, b"#!/bin/sh\n"))
+        .with(Attribute::resident(test_support::type_codes::EA_INFORMATION, None, information))
+        .with(Attribute::resident(test_support::type_codes::EA, None, list)),
+    );
+
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+
+    let script = &filesystem.files["C:\\script.sh"];
+    assert_eq!(script.unix_uid, Some(1000));
+    assert_eq!(script.unix_gid, Some(100));
+    assert_eq!(script.unix_mode, Some(0o100755));
+    assert_eq!(script.unix_device, None);
+    assert_eq!(filesystem.files["C:\\$MFT"].unix_uid, None);
+  }
+
+  #[test]
+  fn damaged_extended_attributes_are_skipped() {
+    let mut builder = ImageBuilder::new();
+    let mut list = test_support::ea_list(&[("$LXUID", &1000u32.to_le_bytes()), ("$LXGID", &100u32.to_le_bytes())]);
+    // The last entry's value runs off the end.
+    list.truncate(list.len() - 2);
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(ROOT_RECORD, "script.sh", namespaces::WIN32))
+        .with(Attribute::resident_data(None, b"#!/bin/sh\n"))
+        .with(Attribute::resident(test_support::type_codes::EA_INFORMATION, None, vec![0; 4]))
+        .with(Attribute::resident(test_support::type_codes::EA, None, list)),
+    );
+    builder.add_file(ROOT_RECORD, "after.txt", b"123");
+
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+
+    let script = &filesystem.files["C:\\script.sh"];
+    assert_eq!(script.unix_uid, None);
+    assert_eq!(script.real_size, 10);
+    assert!(filesystem.files.contains_key("C:\\after.txt"));
+  }
+
+  #[test]
+  fn finds_files_by_object_id() {
+    let mut builder = ImageBuilder::new();
+    let dir = builder.add_directory(ROOT_RECORD, "docs");
+    let object_id = |current: u8, birth: u8| [[current; 16], [0x22; 16], [birth; 16], [0; 16]].concat();
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(dir, "report.docx", namespaces::WIN32))
+        .with(Attribute::resident(test_support::type_codes::OBJECT_ID, None, object_id(0x11, 0x11))),
+    );
+    // Moved here from another volume, so it was given a new object ID.
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(dir, "moved.docx", namespaces::WIN32))
+        .with(Attribute::resident(test_support::type_codes::OBJECT_ID, None, object_id(0x44, 0x33))),
+    );
+    // Born with the same ID as report.docx, which still has it.
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(dir, "copy.docx", namespaces::WIN32))
+        .with(Attribute::resident(test_support::type_codes::OBJECT_ID, None, object_id(0x66, 0x11))),
+    );
+
+    let mut mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    // There's no $Extend on this volume.
+    assert!(mft.read_object_ids().unwrap().is_empty());
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+
+    let find = |byte: u8| {
+      let id = guid::Guid::load(&[byte; 16]);
+      filesystem.find_by_object_id(&id).map(|file| file.path.as_str())
+    };
+    assert_eq!(find(0x11), Some("C:\\docs\\report.docx"));
+    assert_eq!(find(0x33), Some("C:\\docs\\moved.docx"));
+    assert_eq!(find(0x44), Some("C:\\docs\\moved.docx"));
+    assert_eq!(find(0x66), Some("C:\\docs\\copy.docx"));
+    assert_eq!(find(0x55), None);
+  }
+
+  #[test]
+  fn records_volume_metadata() {
+    let mut builder = ImageBuilder::new();
+    let mut information = vec![0; 12];
+    information[8] = 3;
+    information[9] = 1;
+    information[10..12].copy_from_slice(&mft::volume_flags::DIRTY.to_le_bytes());
+    builder.set_record(
+      3,
+      Record::new()
+        .with(Attribute::file_name(ROOT_RECORD, "$Volume", namespaces::WIN32_DOS))
+        .with(Attribute::resident(test_support::type_codes::VOLUME_NAME, None, test_support::utf16_bytes("Evidence")))
+        .with(Attribute::resident(test_support::type_codes::VOLUME_INFORMATION, None, information)),
+    );
+
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("Partition1")).unwrap();
+
+    let volume = &filesystem.volumes["Partition1"];
+    assert_eq!(volume.label, "Evidence");
+    assert_eq!((volume.major_version, volume.minor_version), (3, 1));
+    assert!(volume.is_dirty());
+    assert!(!volume.upgrade_on_mount());
+
+    // Without $Volume there's nothing to record.
+    let mft = mft::MasterFileTable::open(ImageBuilder::new().build()).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+    assert!(filesystem.volumes.is_empty());
+
+    // Nor with a damaged one, though the rest of the volume still loads.
+    let mut builder = ImageBuilder::new();
+    builder.set_record(
+      3,
+      Record::new()
+        .with(Attribute::file_name(ROOT_RECORD, "$Volume", namespaces::WIN32_DOS))
+        .with(Attribute::resident(test_support::type_codes::VOLUME_INFORMATION, None, vec![0; 8])),
+    );
+    builder.add_file(ROOT_RECORD, "file.txt", b"");
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let mut filesystem = Filesystem::new();
+    filesystem.handle_mft(mft, OsString::from("C:\\")).unwrap();
+    assert!(filesystem.volumes.is_empty());
+    assert!(filesystem.files.contains_key("C:\\$Volume"));
+    assert!(filesystem.files.contains_key("C:\\file.txt"));
+  }
+
+  #[test]
+  fn places_deleted_entries() {
+    let mut builder = ImageBuilder::new();
+    let dir = builder.add_directory(ROOT_RECORD, "dir");
+    builder.add_file(dir, "live.txt", b"123");
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(dir, "old.txt", namespaces::WIN32))
+        .with(Attribute::resident_data(None, b"12345"))
+        .not_in_use(),
+    );
+    // Freeing a record bumps its sequence number.
+    let gone = builder.add_record(
+      Record::directory()
+        .with_sequence_number(2)
+        .with(Attribute::file_name(ROOT_RECORD, "gone", namespaces::WIN32))
+        .not_in_use(),
+    );
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name_with_parent_reference(
+          test_support::file_reference(gone, 1),
+          "inner.txt",
+          namespaces::WIN32,
+        ))
+        .not_in_use(),
+    );
+    // This directory's record has been reused since the file was deleted.
+    let reused = builder.add_record(
+      Record::directory()
+        .with_sequence_number(3)
+        .with(Attribute::file_name(ROOT_RECORD, "new", namespaces::WIN32)),
+    );
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name_with_parent_reference(
+          test_support::file_reference(reused, 1),
+          "stale.txt",
+          namespaces::WIN32,
+        ))
+        .not_in_use(),
+    );
+    // Deleted, but a live file has since taken its name.
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(dir, "live.txt", namespaces::WIN32))
+        .not_in_use(),
+    );
+
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let filesystem = load_mft(mft.with_deleted_records(true), OsString::from("C:\\")).unwrap();
+
+    let old = &filesystem.files["C:\\dir\\old.txt"];
+    assert!(old.is_deleted);
+    assert_eq!(old.real_size, 5);
+    assert!(filesystem.files["C:\\gone"].is_deleted);
+    assert!(filesystem.files["C:\\gone\\inner.txt"].is_deleted);
+    assert!(filesystem.files["C:\\$OrphanFiles\\stale.txt"].is_deleted);
+    assert!(!filesystem.files["C:\\$OrphanFiles"].is_deleted);
+    assert!(!filesystem.files.contains_key("C:\\new\\stale.txt"));
+    assert!(!filesystem.files["C:\\dir\\live.txt"].is_deleted);
+    // Deleted files don't count towards their directory's size.
+    assert_eq!(filesystem.files["C:\\dir"].real_size, 3);
+  }
+
+  #[test]
+  fn orphans_entries_with_stale_parents() {
+    let mut builder = ImageBuilder::new();
+    let dir = builder.add_directory(ROOT_RECORD, "dir");
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name_with_parent_reference(
+          test_support::file_reference(dir, 7),
+          "stale.txt",
+          namespaces::WIN32,
+        ))
+        .with(Attribute::resident_data(None, b"1234")),
+    );
+    // Points at a record that isn't in use.
+    builder.add_record(
+      Record::new()
+        .with(Attribute::file_name(40, "lost.txt", namespaces::WIN32))
+        .with(Attribute::resident_data(None, b"12")),
+    );
+
+    let mft = mft::MasterFileTable::open(builder.build()).unwrap();
+    let filesystem = load_mft(mft, OsString::from("C:\\")).unwrap();
+
+    assert!(filesystem.files.contains_key("C:\\$OrphanFiles\\stale.txt"));
+    assert!(filesystem.files.contains_key("C:\\$OrphanFiles\\lost.txt"));
+    assert!(!filesystem.files.contains_key("C:\\dir\\stale.txt"));
+    let orphans = &filesystem.files["C:\\$OrphanFiles"];
+    assert!(orphans.is_dir);
+    assert!(!orphans.is_deleted);
+    assert_eq!(orphans.real_size, 6);
+    assert_eq!(filesystem.files["C:\\dir"].real_size, 0);
+  }
+}
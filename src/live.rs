@@ -0,0 +1,168 @@
+// The Windows live-volume backend: reading the volumes of the running system
+// through raw volume handles.
+
+use crate::{err::Error, mft::MasterFileTable, reader::VolumeReader};
+
+use winapi::{
+    ctypes::c_void,
+    um::{
+        errhandlingapi as ehapi,
+        fileapi::ReadFile,
+        handleapi::CloseHandle,
+        ioapiset::DeviceIoControl,
+        minwinbase::OVERLAPPED,
+        winioctl::{
+            FSCTL_GET_NTFS_VOLUME_DATA, NTFS_EXTENDED_VOLUME_DATA, NTFS_VOLUME_DATA_BUFFER,
+        },
+        winnt::HANDLE,
+    },
+};
+
+use std::{convert::TryInto as _, mem, ops::Deref, ptr};
+
+const NTFS_VOLUME_DATA_BUFFER_SIZE: usize =
+    mem::size_of::<NTFS_VOLUME_DATA_BUFFER>() + mem::size_of::<NTFS_EXTENDED_VOLUME_DATA>();
+
+#[derive(Debug)]
+pub struct SafeHandle {
+    pub(crate) handle: HANDLE,
+}
+impl Deref for SafeHandle {
+    type Target = HANDLE;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+impl Drop for SafeHandle {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.handle) };
+    }
+}
+
+// A live volume opened through the Windows API.
+pub struct VolumeHandle {
+    handle: SafeHandle,
+    bytes_per_sector: u64,
+    len: u64,
+}
+impl VolumeHandle {
+    pub fn new(handle: SafeHandle, bytes_per_sector: u64, len: u64) -> Self {
+        VolumeHandle {
+            handle,
+            bytes_per_sector,
+            len,
+        }
+    }
+}
+impl VolumeReader for VolumeHandle {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let mut overlapped = {
+            let mut ov = OVERLAPPED::default();
+            unsafe { ov.u.s_mut() }.Offset =
+                (offset & 0x0000_0000_FFFF_FFFFu64).try_into().unwrap();
+            unsafe { ov.u.s_mut() }.OffsetHigh = ((offset & 0xFFFF_FFFF_0000_0000u64) >> 32)
+                .try_into()
+                .unwrap();
+            ov
+        };
+
+        let mut num_bytes_read = 0;
+        let success = unsafe {
+            ReadFile(
+                *self.handle,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len().try_into().unwrap(),
+                &mut num_bytes_read,
+                &mut overlapped,
+            )
+        };
+        if success == 0 {
+            let err = unsafe { ehapi::GetLastError() };
+            return Err(Error::ReadVolumeFailed(err));
+        }
+        let num_bytes_read: usize = num_bytes_read.try_into().unwrap();
+        if num_bytes_read != buf.len() {
+            return Err(Error::ReadVolumeTooShort);
+        }
+
+        Ok(())
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.bytes_per_sector
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl MasterFileTable<VolumeHandle> {
+    pub fn load(volume_handle: SafeHandle) -> Result<(Self, u64), Error> {
+        let (volume_data, extended_data) = get_ntfs_volume_data(&volume_handle)?;
+
+        // We only know how to deal with NTFS 3.0 or 3.1 data. Make sure the volume
+        // is the correct NTFS version.
+        if !(extended_data.MajorVersion == 3
+            && (extended_data.MinorVersion == 0 || extended_data.MinorVersion == 1))
+        {
+            return Err(Error::UnknownNtfsVersion);
+        }
+
+        let volume = VolumeHandle::new(
+            volume_handle,
+            volume_data.BytesPerSector.into(),
+            volume_data.BytesPerSector as u64
+                * *unsafe { volume_data.NumberSectors.QuadPart() } as u64,
+        );
+
+        let mft = MasterFileTable::open(volume)?;
+        let bytes_per_cluster = mft.bytes_per_cluster();
+        Ok((mft, bytes_per_cluster))
+    }
+}
+fn get_ntfs_volume_data(
+    handle: &SafeHandle,
+) -> Result<(NTFS_VOLUME_DATA_BUFFER, NTFS_EXTENDED_VOLUME_DATA), Error> {
+    let mut result_size = 0;
+    // Build the buffer out of u64s to guarantee 8-byte alignment.
+    let mut buf: Vec<u64> = vec![0; NTFS_VOLUME_DATA_BUFFER_SIZE / 8];
+    let success = unsafe {
+        DeviceIoControl(
+            **handle,
+            FSCTL_GET_NTFS_VOLUME_DATA,
+            ptr::null_mut(),
+            0,
+            buf.as_mut_ptr() as *mut c_void,
+            (buf.len() * 8).try_into().unwrap(),
+            &mut result_size,
+            ptr::null_mut(),
+        )
+    };
+    if success == 0 {
+        let err = unsafe { ehapi::GetLastError() };
+        return Err(Error::GetNtfsVolumeDataFailed(err));
+    }
+
+    if result_size != (buf.len() as u32) * 8 {
+        return Err(Error::GetNtfsVolumeDataBadSize);
+    }
+
+    // Parse the results into the two structs. NTFS_VOLUME_DATA_BUFFER always
+    // comes first.
+    let (volume_data_buffer_bytes, extended_volume_data_bytes) =
+        buf.split_at(mem::size_of::<NTFS_VOLUME_DATA_BUFFER>() / 8);
+
+    let volume_data_buffer = volume_data_buffer_bytes.as_ptr() as *const NTFS_VOLUME_DATA_BUFFER;
+    let extended_volume_data =
+        extended_volume_data_bytes.as_ptr() as *const NTFS_EXTENDED_VOLUME_DATA;
+
+    // We want to be very explicit about this clone - these pointers don't currently
+    // have lifetimes known to rustc, so we have to copy the data on return.
+    #[allow(clippy::clone_on_copy)]
+    Ok((
+        unsafe { *volume_data_buffer }.clone(),
+        unsafe { *extended_volume_data }.clone(),
+    ))
+}
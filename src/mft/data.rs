@@ -0,0 +1,578 @@
+use crate::{
+    err::Error,
+    mft::{lznt1, stream::MftStream, sys, wof::ChunkTable, WofAlgorithm},
+    reader::VolumeReader,
+};
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+// How many clusters to read from the volume at a time.
+const READ_AHEAD_CLUSTERS: u64 = 16;
+// NTFS only ever compresses in units of 16 clusters.
+const COMPRESSION_UNIT_SHIFT: u8 = 4;
+
+enum Content {
+    Resident(Vec<u8>),
+    // Each run, along with the VCN it starts at
+    NonResident(Vec<(u64, sys::DataRun)>),
+}
+
+// The contents of one $DATA attribute, read straight off the volume. Sparse
+// runs, and anything past the valid data length, read as zeros. Compressed
+// attributes are decompressed as they're read.
+struct Attribute<'a, R: VolumeReader> {
+    mft_stream: &'a mut MftStream<R>,
+    bytes_per_cluster: u64,
+    content: Content,
+    // 0 if the attribute isn't compressed
+    compression_unit_clusters: u64,
+    len: u64,
+    valid_data_length: u64,
+
+    buffer: Vec<u8>,
+    buffer_vcn: u64, // the VCN at the start of `buffer`
+}
+impl<'a, R: VolumeReader> Attribute<'a, R> {
+    fn new(
+        mft_stream: &'a mut MftStream<R>,
+        bytes_per_cluster: u64,
+        data: &sys::Data,
+    ) -> Result<Self, Error> {
+        let content = match (&data.value, &data.runs) {
+            (Some(value), _) => Content::Resident(value.clone()),
+            (None, runs) => {
+                let mut vcn = data.lowest_vcn;
+                let mut mapped = Vec::new();
+                for run in runs.iter().flatten() {
+                    mapped.push((vcn, run.clone()));
+                    vcn += run.cluster_count;
+                }
+                Content::NonResident(mapped)
+            }
+        };
+        Ok(Attribute {
+            mft_stream,
+            bytes_per_cluster,
+            content,
+            compression_unit_clusters: match data.compression_unit {
+                0 => 0,
+                COMPRESSION_UNIT_SHIFT => 1 << COMPRESSION_UNIT_SHIFT,
+                _ => return Err(Error::BadCompressedData),
+            },
+            len: data.logical_size,
+            valid_data_length: data.valid_data_length.min(data.logical_size),
+            buffer: Vec::new(),
+            buffer_vcn: 0,
+        })
+    }
+
+    // Fills `buf` from `position`. Returns how much was read, which is only
+    // less than asked for at the end of the attribute.
+    fn read_at(&mut self, mut position: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = buf.len().min(self.len.saturating_sub(position) as usize);
+        let mut filled = 0;
+        while filled < len {
+            let read = if position < self.valid_data_length {
+                self.read_valid(position, &mut buf[filled..len])?
+            } else {
+                buf[filled..len].fill(0);
+                len - filled
+            };
+            filled += read;
+            position += read as u64;
+        }
+        Ok(len)
+    }
+
+    // Fills `buf` from `position`, which must be below the valid data
+    // length. Returns how much was read; this may be less than asked for,
+    // but is never zero.
+    fn read_valid(&mut self, position: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let wanted = buf.len().min((self.valid_data_length - position) as usize);
+        let runs = match self.content {
+            Content::Resident(ref value) => {
+                // A value shorter than it claims to be reads as zeros.
+                let available = value.get(position as usize..).unwrap_or(&[]);
+                let copied = wanted.min(available.len());
+                buf[..copied].copy_from_slice(&available[..copied]);
+                buf[copied..wanted].fill(0);
+                return Ok(wanted);
+            }
+            Content::NonResident(ref runs) => runs,
+        };
+
+        let vcn = position / self.bytes_per_cluster;
+        let buffer_clusters = self.buffer.len() as u64 / self.bytes_per_cluster;
+        if vcn < self.buffer_vcn || vcn >= self.buffer_vcn + buffer_clusters {
+            if !runs
+                .iter()
+                .any(|(run_vcn, run)| (*run_vcn..run_vcn + run.cluster_count).contains(&vcn))
+            {
+                return Err(Error::UnmappedVcn(vcn));
+            }
+            self.buffer_vcn = if self.compression_unit_clusters > 0 {
+                self.load_compression_unit(vcn)?
+            } else {
+                self.load_clusters(vcn)?
+            };
+        }
+
+        let offset = (position - self.buffer_vcn * self.bytes_per_cluster) as usize;
+        let len = wanted.min(self.buffer.len() - offset);
+        buf[..len].copy_from_slice(&self.buffer[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn runs(&self) -> &[(u64, sys::DataRun)] {
+        match self.content {
+            Content::NonResident(ref runs) => runs,
+            Content::Resident(_) => &[],
+        }
+    }
+
+    // Reads a few clusters, starting at `vcn`, into the buffer. Returns the
+    // VCN at the start of the buffer.
+    fn load_clusters(&mut self, vcn: u64) -> Result<u64, Error> {
+        let (run_vcn, run) = self
+            .runs()
+            .iter()
+            .find(|(run_vcn, run)| (*run_vcn..run_vcn + run.cluster_count).contains(&vcn))
+            .cloned()
+            .ok_or(Error::UnmappedVcn(vcn))?;
+        let clusters = (run_vcn + run.cluster_count - vcn).min(READ_AHEAD_CLUSTERS);
+        self.buffer
+            .resize((clusters * self.bytes_per_cluster) as usize, 0);
+        if run.is_sparse {
+            self.buffer.fill(0);
+        } else {
+            let lcn = run.starting_lcn as u64 + (vcn - run_vcn);
+            self.mft_stream
+                .read_clusters(lcn, clusters, &mut self.buffer[..], false)?;
+        }
+        Ok(vcn)
+    }
+
+    // Reads and decompresses the compression unit holding `vcn` into the
+    // buffer. A unit which uses all its clusters is stored as-is, one which
+    // is partly sparse holds LZNT1 data, and one which is all sparse is
+    // zeros. Returns the VCN at the start of the unit.
+    fn load_compression_unit(&mut self, vcn: u64) -> Result<u64, Error> {
+        let unit_clusters = self.compression_unit_clusters;
+        let unit_vcn = vcn - vcn % unit_clusters;
+        let unit_len = (unit_clusters * self.bytes_per_cluster) as usize;
+
+        let mut stored = Vec::new();
+        let mut has_sparse_clusters = false;
+        for (run_vcn, run) in self.runs().to_vec() {
+            let start = run_vcn.max(unit_vcn);
+            let end = (run_vcn + run.cluster_count).min(unit_vcn + unit_clusters);
+            if start >= end {
+                continue;
+            }
+            if run.is_sparse {
+                has_sparse_clusters = true;
+                continue;
+            }
+            let offset = stored.len();
+            stored.resize(
+                offset + ((end - start) * self.bytes_per_cluster) as usize,
+                0,
+            );
+            self.mft_stream.read_clusters(
+                run.starting_lcn as u64 + (start - run_vcn),
+                end - start,
+                &mut stored[offset..],
+                false,
+            )?;
+        }
+
+        if has_sparse_clusters && !stored.is_empty() {
+            self.buffer.clear();
+            lznt1::decompress(&stored, &mut self.buffer)?;
+        } else {
+            self.buffer = stored;
+        }
+        self.buffer.resize(unit_len, 0);
+        Ok(unit_vcn)
+    }
+}
+
+// A compressed file's chunk table, along with the last chunk read.
+struct Wof {
+    chunks: ChunkTable,
+    chunk: Vec<u8>,
+    chunk_index: Option<usize>,
+}
+
+// A readable, seekable view of a file's contents, or of one of its streams.
+// Files compressed by WOF (compact.exe) are decompressed as they're read.
+pub struct DataStream<'a, R: VolumeReader> {
+    attribute: Attribute<'a, R>,
+    wof: Option<Wof>,
+    len: u64,
+    position: u64,
+}
+impl<'a, R: VolumeReader> DataStream<'a, R> {
+    pub(crate) fn new(
+        mft_stream: &'a mut MftStream<R>,
+        bytes_per_cluster: u64,
+        data: &sys::Data,
+    ) -> Result<Self, Error> {
+        Ok(DataStream {
+            len: data.logical_size,
+            attribute: Attribute::new(mft_stream, bytes_per_cluster, data)?,
+            wof: None,
+            position: 0,
+        })
+    }
+
+    // Reads a file of `len` bytes, compressed with `algorithm` into the
+    // `compressed` stream.
+    pub(crate) fn wof(
+        mft_stream: &'a mut MftStream<R>,
+        bytes_per_cluster: u64,
+        compressed: &sys::Data,
+        algorithm: WofAlgorithm,
+        len: u64,
+    ) -> Result<Self, Error> {
+        // The table is at the start of the compressed stream, so a length
+        // which doesn't fit in it is checked before allocating the table.
+        let table_len = ChunkTable::table_len(algorithm, len)?;
+        if table_len > compressed.logical_size {
+            return Err(Error::BadCompressedData);
+        }
+        let mut attribute = Attribute::new(mft_stream, bytes_per_cluster, compressed)?;
+        let mut table = vec![0; table_len as usize];
+        if attribute.read_at(0, &mut table)? < table.len() {
+            return Err(Error::BadCompressedData);
+        }
+        let chunks = ChunkTable::load(algorithm, len, &table, attribute.len)?;
+        Ok(DataStream {
+            attribute,
+            wof: Some(Wof {
+                chunks,
+                chunk: Vec::new(),
+                chunk_index: None,
+            }),
+            len,
+            position: 0,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Fills `buf` from the current position in a WOF compressed file, which
+    // must be below its length.
+    fn read_compressed(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let wof = self.wof.as_mut().unwrap();
+        let chunk_size = wof.chunks.chunk_size();
+        let index = (self.position / chunk_size) as usize;
+        if wof.chunk_index != Some(index) {
+            let range = wof.chunks.chunk(index).ok_or(Error::BadCompressedData)?;
+            let mut input = vec![0; (range.end - range.start) as usize];
+            if self.attribute.read_at(range.start, &mut input)? < input.len() {
+                return Err(Error::BadCompressedData);
+            }
+            wof.chunk = wof.chunks.decompress(index, &input)?;
+            wof.chunk_index = Some(index);
+        }
+
+        let offset = (self.position % chunk_size) as usize;
+        let len = buf.len().min(wof.chunk.len() - offset);
+        buf[..len].copy_from_slice(&wof.chunk[offset..offset + len]);
+        Ok(len)
+    }
+}
+impl<'a, R: VolumeReader> Read for DataStream<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min((self.len - self.position) as usize);
+        let len = if self.wof.is_some() {
+            self.read_compressed(&mut buf[..len])?
+        } else {
+            self.attribute.read_at(self.position, &mut buf[..len])?
+        };
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+impl<'a, R: VolumeReader> Seek for DataStream<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mft::{MasterFileTable, NtfsName},
+        test_support::{
+            lznt1_compress, namespaces, wof_compressed_stream, wof_reparse_buffer, Attribute,
+            ImageBuilder, Record, Run, ROOT_RECORD,
+        },
+    };
+
+    fn read_all<R: VolumeReader>(
+        mft: &mut MasterFileTable<R>,
+        file: u64,
+        name: Option<&str>,
+    ) -> Vec<u8> {
+        let entry = mft.read_entry(file).unwrap().unwrap();
+        let name = name.map(NtfsName::from);
+        let data = entry.get_data(name.as_ref()).unwrap();
+        let mut content = Vec::new();
+        mft.open_data(data)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    #[test]
+    fn reads_resident_and_alternate_streams() {
+        let mut builder = ImageBuilder::new();
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "file", namespaces::WIN32))
+                .with(Attribute::resident_data(None, b"main"))
+                .with(Attribute::resident_data(
+                    Some("Zone.Identifier"),
+                    b"[ZoneTransfer]",
+                )),
+        );
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+        assert_eq!(read_all(&mut mft, file, None), b"main");
+        assert_eq!(
+            read_all(&mut mft, file, Some("Zone.Identifier")),
+            b"[ZoneTransfer]"
+        );
+    }
+
+    #[test]
+    fn reads_through_runs() {
+        let mut builder = ImageBuilder::new();
+        let mut first = vec![b'a'; 4096];
+        first.extend_from_slice(&[b'b'; 4096]);
+        let first = builder.write_clusters(&first);
+        let last = builder.write_clusters(&[b'c'; 4096]);
+        let size = 3 * 4096 + 100;
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "file", namespaces::WIN32))
+                .with(Attribute::non_resident_data(
+                    None,
+                    vec![Run::at(first, 2), Run::sparse(1), Run::at(last, 1)],
+                    size,
+                )),
+        );
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+
+        let content = read_all(&mut mft, file, None);
+        assert_eq!(content.len() as u64, size);
+        assert!(content[..4096].iter().all(|&b| b == b'a'));
+        assert!(content[4096..8192].iter().all(|&b| b == b'b'));
+        assert!(content[8192..12288].iter().all(|&b| b == 0));
+        assert!(content[12288..].iter().all(|&b| b == b'c'));
+
+        let entry = mft.read_entry(file).unwrap().unwrap();
+        let data = entry.get_data(None).unwrap();
+        let mut stream = mft.open_data(data).unwrap();
+        let mut buf = [0; 8];
+        stream.seek(SeekFrom::Start(4092)).unwrap();
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"aaaabbbb");
+        assert_eq!(stream.seek(SeekFrom::End(-2)).unwrap(), size - 2);
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(stream.seek(SeekFrom::Current(-(size as i64) - 1)).is_err());
+    }
+
+    #[test]
+    fn zeros_past_valid_data_length() {
+        let mut builder = ImageBuilder::new();
+        let lcn = builder.write_clusters(&[b'x'; 4096]);
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "file", namespaces::WIN32))
+                .with(
+                    Attribute::non_resident_data(None, vec![Run::at(lcn, 1)], 4096)
+                        .with_valid_data_length(1000),
+                ),
+        );
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+        let content = read_all(&mut mft, file, None);
+        assert_eq!(content.len(), 4096);
+        assert!(content[..1000].iter().all(|&b| b == b'x'));
+        assert!(content[1000..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn decompresses_compression_units() {
+        const UNIT: usize = 16 * 4096;
+        let mut text = Vec::new();
+        while text.len() < UNIT {
+            text.extend_from_slice(format!("entry {}\n", text.len() % 1000).as_bytes());
+        }
+        text.truncate(UNIT);
+        let noise = (0..UNIT as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect::<Vec<_>>();
+        let tail = &text[..5000];
+
+        let mut builder = ImageBuilder::new();
+        let compressed = lznt1_compress(&text);
+        let compressed_clusters = compressed.len().div_ceil(4096) as u64;
+        let first = builder.write_clusters(&compressed);
+        let stored = builder.write_clusters(&noise);
+        let compressed_tail = lznt1_compress(tail);
+        let last = builder.write_clusters(&compressed_tail);
+
+        // A compressed unit, a unit of zeros, one stored as-is because it
+        // didn't compress, and a short compressed unit at the end.
+        let size = 3 * UNIT as u64 + tail.len() as u64;
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "file", namespaces::WIN32))
+                .with(Attribute::compressed_data(
+                    None,
+                    vec![
+                        Run::at(first, compressed_clusters),
+                        Run::sparse(16 - compressed_clusters + 16),
+                        Run::at(stored, 16),
+                        Run::at(last, 1),
+                        Run::sparse(15),
+                    ],
+                    size,
+                )),
+        );
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+
+        let content = read_all(&mut mft, file, None);
+        assert_eq!(content.len() as u64, size);
+        assert!(content[..UNIT] == text[..]);
+        assert!(content[UNIT..2 * UNIT].iter().all(|&b| b == 0));
+        assert!(content[2 * UNIT..3 * UNIT] == noise[..]);
+        assert!(content[3 * UNIT..] == *tail);
+
+        // Only the clusters actually used count as allocated.
+        let entry = mft.read_entry(file).unwrap().unwrap();
+        let data = entry.get_data(None).unwrap();
+        assert_eq!(data.compression_unit, 4);
+        assert_eq!(
+            data.compute_allocated_size(4096),
+            (compressed_clusters + 17) * 4096
+        );
+    }
+
+    #[test]
+    fn rejects_other_compression_units() {
+        // Anything but 16 clusters is either damage, or would overflow.
+        for shift in [3, 5, 64] {
+            let mut builder = ImageBuilder::new();
+            let file = builder.add_record(
+                Record::new()
+                    .with(Attribute::file_name(ROOT_RECORD, "file", namespaces::WIN32))
+                    .with(
+                        Attribute::compressed_data(None, vec![Run::sparse(16)], 4096)
+                            .with_compression_unit(shift),
+                    ),
+            );
+            let mut mft = MasterFileTable::open(builder.build()).unwrap();
+            let entry = mft.read_entry(file).unwrap().unwrap();
+            assert!(matches!(
+                mft.open_data(entry.get_data(None).unwrap()),
+                Err(Error::BadCompressedData)
+            ));
+        }
+    }
+
+    #[test]
+    fn decompresses_wof_files() {
+        let mut content = Vec::new();
+        for i in 0..6000u32 {
+            content.extend_from_slice(format!("{} ", i % 1200).as_bytes());
+        }
+        // Noise, so that some chunks are stored as-is
+        content.extend((0..40000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8));
+
+        // XPRESS4K, and LZX
+        for algorithm in [0, 1] {
+            let mut builder = ImageBuilder::new();
+            let compressed = wof_compressed_stream(algorithm, &content);
+            let clusters = compressed.len().div_ceil(4096) as u64;
+            let lcn = builder.write_clusters(&compressed);
+            let file = builder.add_record(
+                Record::new()
+                    .with(Attribute::file_name(ROOT_RECORD, "file", namespaces::WIN32))
+                    .with(Attribute::reparse_point(wof_reparse_buffer(algorithm)))
+                    .with(Attribute::non_resident_data(
+                        None,
+                        vec![Run::sparse(content.len().div_ceil(4096) as u64)],
+                        content.len() as u64,
+                    ))
+                    .with(Attribute::non_resident_data(
+                        Some("WofCompressedData"),
+                        vec![Run::at(lcn, clusters)],
+                        compressed.len() as u64,
+                    )),
+            );
+            let mut mft = MasterFileTable::open(builder.build()).unwrap();
+            let entry = mft.read_entry(file).unwrap().unwrap();
+            assert_eq!(entry.get_allocated_size(4096), clusters * 4096);
+
+            let mut stream = mft.open_file(&entry).unwrap().unwrap();
+            assert_eq!(stream.len(), content.len() as u64);
+            let mut read = Vec::new();
+            stream.read_to_end(&mut read).unwrap();
+            assert!(read == content, "algorithm {}", algorithm);
+
+            let mut buf = [0; 16];
+            stream.seek(SeekFrom::Start(32760)).unwrap();
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, content[32760..32776]);
+        }
+    }
+
+    #[test]
+    fn rejects_wof_chunk_table_longer_than_stream() {
+        // 64TB of 4KB chunks would need a 128GB chunk table.
+        let mut builder = ImageBuilder::new();
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "file", namespaces::WIN32))
+                .with(Attribute::reparse_point(wof_reparse_buffer(0)))
+                .with(Attribute::non_resident_data(
+                    None,
+                    vec![Run::sparse(1)],
+                    1 << 46,
+                ))
+                .with(Attribute::resident_data(
+                    Some("WofCompressedData"),
+                    &[0; 64],
+                )),
+        );
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+        let entry = mft.read_entry(file).unwrap().unwrap();
+        assert!(matches!(
+            mft.open_file(&entry),
+            Err(Error::BadCompressedData)
+        ));
+    }
+}
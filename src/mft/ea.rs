@@ -0,0 +1,154 @@
+use crate::err::Error;
+
+use std::convert::TryInto as _;
+
+// The names WSL stores Linux file metadata under, on files in directories
+// with metadata enabled.
+const LX_UID: &[u8] = b"$LXUID";
+const LX_GID: &[u8] = b"$LXGID";
+const LX_MODE: &[u8] = b"$LXMOD";
+const LX_DEVICE: &[u8] = b"$LXDEV";
+
+mod ea_flags {
+    // Programs which don't understand EAs shouldn't open the file.
+    pub const NEED_EA: u8 = 0x80;
+}
+
+// One extended attribute, from a $EA attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedAttribute {
+    pub flags: u8,
+    // EA names are single-byte characters, and case-insensitive; NTFS
+    // stores them in upper case.
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+impl ExtendedAttribute {
+    // Parses the list of FILE_FULL_EA_INFORMATION structures in a $EA
+    // attribute.
+    pub fn load_list(mut buf: &[u8]) -> Result<Vec<Self>, Error> {
+        let mut attributes = Vec::new();
+        while buf.len() >= 8 {
+            let next_entry_offset = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+            let name_length = usize::from(buf[5]);
+            let value_length = usize::from(u16::from_le_bytes([buf[6], buf[7]]));
+            // The name is NUL-terminated, with the value straight after.
+            let value_start = 8 + name_length + 1;
+            let value = buf
+                .get(value_start..value_start + value_length)
+                .ok_or(Error::BadExtendedAttribute)?;
+            attributes.push(ExtendedAttribute {
+                flags: buf[4],
+                name: buf[8..8 + name_length].to_vec(),
+                value: value.to_vec(),
+            });
+
+            if next_entry_offset == 0 {
+                break;
+            }
+            buf = buf
+                .get(next_entry_offset..)
+                .ok_or(Error::BadExtendedAttribute)?;
+        }
+        Ok(attributes)
+    }
+
+    pub fn is_needed(&self) -> bool {
+        self.flags & ea_flags::NEED_EA != 0
+    }
+}
+
+// A $EA_INFORMATION attribute, which sums up the file's EAs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EaInformation {
+    // The size of the EAs in the packed form used by OS/2
+    pub packed_size: u16,
+    // How many EAs have the NEED_EA flag
+    pub need_ea_count: u16,
+    // The size of the EAs as stored in $EA
+    pub unpacked_size: u32,
+}
+impl EaInformation {
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < 8 {
+            return Err(Error::BadExtendedAttribute);
+        }
+        Ok(EaInformation {
+            packed_size: u16::from_le_bytes([buf[0], buf[1]]),
+            need_ea_count: u16::from_le_bytes([buf[2], buf[3]]),
+            unpacked_size: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+        })
+    }
+}
+
+// The Linux metadata WSL keeps in a file's EAs. Any of it can be missing,
+// in which case WSL falls back to the mount's defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LxMetadata {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // The st_mode, including the file type bits
+    pub mode: Option<u32>,
+    // The major and minor numbers of character and block devices
+    pub device: Option<(u32, u32)>,
+}
+impl LxMetadata {
+    // Returns None if none of WSL's EAs are there.
+    pub fn from_extended_attributes(attributes: &[ExtendedAttribute]) -> Option<Self> {
+        let find = |name: &[u8]| {
+            attributes
+                .iter()
+                .find(|attribute| attribute.name.eq_ignore_ascii_case(name))
+                .map(|attribute| &attribute.value[..])
+        };
+        let u32_value = |name| {
+            find(name)
+                .and_then(|value| Some(u32::from_le_bytes(value.get(0..4)?.try_into().unwrap())))
+        };
+        let metadata = LxMetadata {
+            uid: u32_value(LX_UID),
+            gid: u32_value(LX_GID),
+            mode: u32_value(LX_MODE),
+            device: find(LX_DEVICE).and_then(|value| {
+                let major = u32::from_le_bytes(value.get(0..4)?.try_into().unwrap());
+                let minor = u32::from_le_bytes(value.get(4..8)?.try_into().unwrap());
+                Some((major, minor))
+            }),
+        };
+        (metadata != LxMetadata::default()).then_some(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ea_list;
+
+    #[test]
+    fn reads_wsl_metadata() {
+        let list = ea_list(&[
+            ("$LXUID", &1000u32.to_le_bytes()),
+            ("$LXGID", &1000u32.to_le_bytes()),
+            ("$LXMOD", &0o100644u32.to_le_bytes()),
+            ("USER.COMMENT", b"hello"),
+        ]);
+        let attributes = ExtendedAttribute::load_list(&list).unwrap();
+        assert_eq!(attributes.len(), 4);
+        assert_eq!(attributes[3].name, b"USER.COMMENT");
+        assert_eq!(attributes[3].value, b"hello");
+        assert!(!attributes[3].is_needed());
+
+        let metadata = LxMetadata::from_extended_attributes(&attributes).unwrap();
+        assert_eq!(metadata.uid, Some(1000));
+        assert_eq!(metadata.mode, Some(0o100644));
+        assert_eq!(metadata.device, None);
+        assert_eq!(LxMetadata::from_extended_attributes(&attributes[3..]), None);
+
+        let device = ea_list(&[("$LXDEV", &[4, 0, 0, 0, 64, 0, 0, 0])]);
+        let attributes = ExtendedAttribute::load_list(&device).unwrap();
+        let metadata = LxMetadata::from_extended_attributes(&attributes).unwrap();
+        assert_eq!(metadata.device, Some((4, 64)));
+
+        assert!(ExtendedAttribute::load_list(&list[..list.len() - 4]).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::err::Error;
+
+// A canonical Huffman code, as used by XPRESS and LZX, decoded through a
+// table indexed by the next `max_bits` bits of input.
+pub struct HuffmanTable {
+    max_bits: u32,
+    // The symbol and code length for every possible `max_bits`-bit prefix.
+    // A length of 0 marks a prefix no code starts with.
+    entries: Vec<(u16, u8)>,
+}
+impl HuffmanTable {
+    // Codes are handed out in order of length, then symbol. Symbols with a
+    // length of 0 aren't used.
+    pub fn new(lengths: &[u8], max_bits: u32) -> Result<Self, Error> {
+        let mut entries = vec![(0, 0); 1 << max_bits];
+        let mut code: usize = 0;
+        for length in 1..=max_bits {
+            for (symbol, _) in lengths
+                .iter()
+                .enumerate()
+                .filter(|(_, &l)| u32::from(l) == length)
+            {
+                let span = 1 << (max_bits - length);
+                let start = code << (max_bits - length);
+                // Too many codes of this length to fit
+                if start + span > entries.len() {
+                    return Err(Error::BadCompressedData);
+                }
+                entries[start..start + span].fill((symbol as u16, length as u8));
+                code += 1;
+            }
+            code <<= 1;
+        }
+        if lengths.iter().any(|&l| u32::from(l) > max_bits) {
+            return Err(Error::BadCompressedData);
+        }
+        Ok(HuffmanTable { max_bits, entries })
+    }
+
+    pub fn max_bits(&self) -> u32 {
+        self.max_bits
+    }
+
+    // Looks up the code at the start of `bits` (the next `max_bits` bits of
+    // input), returning its symbol and length.
+    pub fn decode(&self, bits: u32) -> Result<(u16, u32), Error> {
+        match self.entries[bits as usize] {
+            (_, 0) => Err(Error::BadCompressedData),
+            (symbol, length) => Ok((symbol, length.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assigns_canonical_codes() {
+        // A: 0, B: 10, C: 110, D: 111
+        let table = HuffmanTable::new(&[1, 2, 3, 3], 3).unwrap();
+        assert_eq!(table.decode(0b011).unwrap(), (0, 1));
+        assert_eq!(table.decode(0b101).unwrap(), (1, 2));
+        assert_eq!(table.decode(0b110).unwrap(), (2, 3));
+        assert_eq!(table.decode(0b111).unwrap(), (3, 3));
+
+        // Incomplete codes are fine until an unused code turns up.
+        let table = HuffmanTable::new(&[0, 2, 2], 2).unwrap();
+        assert_eq!(table.decode(0b01).unwrap(), (2, 2));
+        assert!(table.decode(0b10).is_err());
+
+        assert!(HuffmanTable::new(&[1, 1, 1], 2).is_err());
+    }
+}
@@ -0,0 +1,602 @@
+use crate::{
+    err::Error,
+    mft::{fix_record_with_update_sequence, sys, NtfsName},
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto as _,
+};
+
+pub const INDEX_ROOT_HEADER_LENGTH: usize = 16;
+pub const INDEX_BLOCK_HEADER_LENGTH: usize = 24;
+const INDEX_NODE_HEADER_LENGTH: usize = 16;
+const INDEX_ENTRY_HEADER_LENGTH: usize = 16;
+
+mod index_node_flags {
+    // The node's entries point to subnodes in $INDEX_ALLOCATION
+    pub const HAS_CHILDREN: u8 = 0x01;
+}
+
+mod index_entry_flags {
+    pub const HAS_SUBNODE: u16 = 0x0001;
+    // Marks the final entry in a node, which has no key of its own
+    pub const LAST: u16 = 0x0002;
+}
+
+// One entry in an index node. In directory ($I30) indexes the key is a
+// copy of the child's $FILE_NAME attribute; view indexes such as $SII and
+// $O use their own keys, and carry data instead of a file reference.
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub file_reference: sys::FileReference,
+    pub key: Vec<u8>,
+    // The whole entry, as stored
+    pub raw: Vec<u8>,
+    pub subnode_vcn: Option<u64>,
+    pub is_last: bool,
+}
+impl IndexEntry {
+    // Parses the entry at the start of `buf`, returning it and its length.
+    pub fn load(buf: &[u8]) -> Result<(Self, usize), Error> {
+        if buf.len() < INDEX_ENTRY_HEADER_LENGTH {
+            return Err(Error::BadIndexNode);
+        }
+        let length: usize = u16::from_le_bytes([buf[8], buf[9]]).into();
+        let key_length: usize = u16::from_le_bytes([buf[10], buf[11]]).into();
+        let flags = u16::from_le_bytes([buf[12], buf[13]]);
+        if length < INDEX_ENTRY_HEADER_LENGTH
+            || length > buf.len()
+            || INDEX_ENTRY_HEADER_LENGTH + key_length > length
+        {
+            return Err(Error::BadIndexNode);
+        }
+
+        let subnode_vcn = if flags & index_entry_flags::HAS_SUBNODE != 0 {
+            if length < INDEX_ENTRY_HEADER_LENGTH + 8 {
+                return Err(Error::BadIndexNode);
+            }
+            Some(u64::from_le_bytes(
+                buf[length - 8..length].try_into().unwrap(),
+            ))
+        } else {
+            None
+        };
+
+        Ok((
+            IndexEntry {
+                file_reference: sys::FileReference::load(&buf[0..8]),
+                key: buf[INDEX_ENTRY_HEADER_LENGTH..INDEX_ENTRY_HEADER_LENGTH + key_length]
+                    .to_vec(),
+                raw: buf[..length].to_vec(),
+                subnode_vcn,
+                is_last: flags & index_entry_flags::LAST != 0,
+            },
+            length,
+        ))
+    }
+
+    // Parses the key as a $FILE_NAME, for entries in directory indexes.
+    pub fn file_name(&self) -> Result<sys::FileName, Error> {
+        sys::FileName::load(&self.key[..], None)
+    }
+
+    // The data of an entry in a view index.
+    pub fn data(&self) -> Option<&[u8]> {
+        let offset: usize = u16::from_le_bytes([self.raw[0], self.raw[1]]).into();
+        let length: usize = u16::from_le_bytes([self.raw[2], self.raw[3]]).into();
+        self.raw.get(offset..offset + length)
+    }
+}
+
+// The entries of one B-tree node, from $INDEX_ROOT or an index block.
+#[derive(Debug, Clone)]
+pub struct IndexNode {
+    pub entries: Vec<IndexEntry>,
+    pub has_children: bool,
+    // Where the entries end; anything after this is slack.
+    pub entries_end: usize,
+}
+impl IndexNode {
+    // `buf` starts at the node header; offsets in it are relative to that.
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < INDEX_NODE_HEADER_LENGTH {
+            return Err(Error::BadIndexNode);
+        }
+        let entries_offset: usize = u32::from_le_bytes(buf[0..4].try_into().unwrap())
+            .try_into()
+            .unwrap();
+        let entries_end: usize = u32::from_le_bytes(buf[4..8].try_into().unwrap())
+            .try_into()
+            .unwrap();
+        if entries_offset < INDEX_NODE_HEADER_LENGTH
+            || entries_offset > entries_end
+            || entries_end > buf.len()
+        {
+            return Err(Error::BadIndexNode);
+        }
+
+        let mut entries = Vec::new();
+        let mut offset = entries_offset;
+        while offset < entries_end {
+            let (entry, length) = IndexEntry::load(&buf[offset..entries_end])?;
+            offset += length;
+            let is_last = entry.is_last;
+            entries.push(entry);
+            if is_last {
+                break;
+            }
+        }
+
+        Ok(IndexNode {
+            entries,
+            has_children: buf[12] & index_node_flags::HAS_CHILDREN != 0,
+            entries_end,
+        })
+    }
+}
+
+// A resident $INDEX_ROOT attribute.
+#[derive(Debug, Clone)]
+pub struct IndexRoot {
+    pub name: Option<NtfsName>,
+    // The attribute being indexed: $FILE_NAME for directories, 0 for views
+    pub indexed_attribute_type: u32,
+    pub collation_rule: u32,
+    pub bytes_per_index_block: u32,
+    pub node: IndexNode,
+}
+impl IndexRoot {
+    pub fn load(buf: &[u8], name: Option<NtfsName>) -> Result<Self, Error> {
+        if buf.len() < INDEX_ROOT_HEADER_LENGTH {
+            return Err(Error::BadIndexNode);
+        }
+        Ok(IndexRoot {
+            name,
+            indexed_attribute_type: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            collation_rule: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            bytes_per_index_block: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            node: IndexNode::load(&buf[INDEX_ROOT_HEADER_LENGTH..])?,
+        })
+    }
+}
+
+// One INDX block from $INDEX_ALLOCATION, after fixups.
+#[derive(Debug, Clone)]
+pub struct IndexBlock {
+    pub vcn: u64,
+    pub node: IndexNode,
+    pub data: Vec<u8>,
+}
+impl IndexBlock {
+    pub fn load(mut data: Vec<u8>) -> Result<Self, Error> {
+        if data.len() < INDEX_BLOCK_HEADER_LENGTH {
+            return Err(Error::BadIndexNode);
+        }
+        let header = sys::MultiSectorHeader::load_with_signature(
+            &data[..],
+            sys::MULTI_SECTOR_HEADER_INDEX_SIGNATURE,
+        )?;
+        fix_record_with_update_sequence(&header, &mut data[..])?;
+
+        Ok(IndexBlock {
+            vcn: u64::from_le_bytes(data[16..24].try_into().unwrap()),
+            node: IndexNode::load(&data[INDEX_BLOCK_HEADER_LENGTH..])?,
+            data,
+        })
+    }
+}
+
+impl IndexBlock {
+    // Everything after the block's last valid entry.
+    pub fn slack(&self) -> &[u8] {
+        self.data
+            .get(INDEX_BLOCK_HEADER_LENGTH + self.node.entries_end..)
+            .unwrap_or(&[])
+    }
+
+    // Scans the block's slack space for $FILE_NAME keys left behind by
+    // entries which have since been removed, usually because the file was
+    // deleted or renamed. Only keys which look plausible are returned, but
+    // nothing here is guaranteed: the space may have been partly overwritten.
+    pub fn recover_file_names(&self) -> Vec<RecoveredFileName> {
+        let slack_start = INDEX_BLOCK_HEADER_LENGTH + self.node.entries_end;
+        let mut recovered = Vec::new();
+        // Entries, and so their keys, are always 8-byte aligned.
+        let mut offset = align8(slack_start);
+        while offset + sys::FILE_NAME_LENGTH <= self.data.len() {
+            let key_length = match plausible_file_name_length(&self.data[offset..]) {
+                Some(length) => length,
+                None => {
+                    offset += 8;
+                    continue;
+                }
+            };
+            let file_name = match sys::FileName::load(&self.data[offset..offset + key_length], None)
+            {
+                Ok(file_name) => file_name,
+                Err(_) => {
+                    offset += 8;
+                    continue;
+                }
+            };
+
+            // The entry header in front of the key may or may not survive.
+            let file_reference = offset
+                .checked_sub(INDEX_ENTRY_HEADER_LENGTH)
+                .filter(|&header| header >= slack_start)
+                .map(|header| &self.data[header..offset])
+                .filter(|header| {
+                    usize::from(u16::from_le_bytes([header[10], header[11]])) == key_length
+                })
+                .map(|header| sys::FileReference::load(&header[0..8]));
+
+            recovered.push(RecoveredFileName {
+                vcn: self.vcn,
+                offset,
+                file_reference,
+                file_name,
+            });
+            offset += align8(key_length);
+        }
+        recovered
+    }
+}
+
+// A $FILE_NAME key found in the slack space of an index block. These belong
+// to entries which are no longer part of the index, so they may describe
+// deleted files; the sizes and times are as they were when the entry was
+// last written.
+#[derive(Debug, Clone)]
+pub struct RecoveredFileName {
+    // The block it was found in, and where in the block
+    pub vcn: u64,
+    pub offset: usize,
+    // The record the entry named, if its header wasn't overwritten
+    pub file_reference: Option<sys::FileReference>,
+    pub file_name: sys::FileName,
+}
+
+// Times before this are most likely random bytes rather than a timestamp.
+const EARLIEST_PLAUSIBLE_TIME: u64 = 119_600_064_000_000_000; // 1980-01-01
+const LATEST_PLAUSIBLE_TIME: u64 = 157_469_184_000_000_000; // 2100-01-01
+
+// Checks whether `buf` starts with something that looks like a $FILE_NAME,
+// returning its length if so.
+fn plausible_file_name_length(buf: &[u8]) -> Option<usize> {
+    if buf.len() < sys::FILE_NAME_LENGTH {
+        return None;
+    }
+    let name_length: usize = buf[64].into();
+    let length = sys::FILE_NAME_LENGTH + 2 * name_length;
+    // Namespaces run from POSIX (0) to WIN32_DOS (3)
+    if name_length == 0 || buf[65] > 3 || length > buf.len() {
+        return None;
+    }
+    // The parent can't be $MFT itself, and always has a sequence number.
+    let parent = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+    if parent & 0xFFFF_FFFF_FFFF == 0 || parent >> 48 == 0 {
+        return None;
+    }
+    let times_plausible = buf[8..40].chunks_exact(8).all(|time| {
+        let time = u64::from_le_bytes(time.try_into().unwrap());
+        (EARLIEST_PLAUSIBLE_TIME..LATEST_PLAUSIBLE_TIME).contains(&time)
+    });
+    if !times_plausible {
+        return None;
+    }
+    let physical_size = u64::from_le_bytes(buf[40..48].try_into().unwrap());
+    let logical_size = u64::from_le_bytes(buf[48..56].try_into().unwrap());
+    if logical_size > physical_size {
+        return None;
+    }
+    // NTFS names can't contain NUL or a path separator.
+    let name_is_plausible = buf[sys::FILE_NAME_LENGTH..length]
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .all(|unit| unit != 0 && unit != u16::from(b'/') && unit != u16::from(b'\\'));
+    if !name_is_plausible {
+        return None;
+    }
+    Some(length)
+}
+
+fn align8(offset: usize) -> usize {
+    (offset + 7) & !7
+}
+
+// A whole index: the root node, plus every block marked as in use in the
+// index's $BITMAP.
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub root: IndexRoot,
+    pub blocks: Vec<IndexBlock>,
+}
+impl Index {
+    // Every entry with a key, in B-tree (that is, collation) order.
+    pub fn entries(&self) -> Vec<&IndexEntry> {
+        let blocks_by_vcn = self
+            .blocks
+            .iter()
+            .map(|block| (block.vcn, &block.node))
+            .collect::<HashMap<_, _>>();
+        let mut visited = HashSet::new();
+        let mut entries = Vec::new();
+        collect_entries(&self.root.node, &blocks_by_vcn, &mut visited, &mut entries);
+        entries
+    }
+
+    // The $FILE_NAME keys of a directory index, in order, with the file
+    // reference of the record each one names.
+    pub fn file_names(&self) -> Result<Vec<(sys::FileReference, sys::FileName)>, Error> {
+        self.entries()
+            .into_iter()
+            .map(|entry| Ok((entry.file_reference, entry.file_name()?)))
+            .collect()
+    }
+
+    // $FILE_NAME keys recovered from the slack space of every block, except
+    // those which match an entry still in the index. Entries are shifted
+    // along as others are added and removed, so slack often holds stale
+    // copies of live entries too.
+    pub fn recover_file_names(&self) -> Vec<RecoveredFileName> {
+        let live = self
+            .entries()
+            .into_iter()
+            .filter_map(|entry| entry.file_name().ok())
+            .collect::<Vec<_>>();
+        self.blocks
+            .iter()
+            .flat_map(IndexBlock::recover_file_names)
+            .filter(|recovered| {
+                !live.iter().any(|file_name| {
+                    file_name.parent == recovered.file_name.parent
+                        && file_name.filename == recovered.file_name.filename
+                })
+            })
+            .collect()
+    }
+}
+
+fn collect_entries<'a>(
+    node: &'a IndexNode,
+    blocks_by_vcn: &HashMap<u64, &'a IndexNode>,
+    visited: &mut HashSet<u64>,
+    entries: &mut Vec<&'a IndexEntry>,
+) {
+    for entry in node.entries.iter() {
+        // Everything in an entry's subnode sorts before the entry itself.
+        if let Some(vcn) = entry.subnode_vcn {
+            if let Some(subnode) = blocks_by_vcn.get(&vcn) {
+                // Guard against loops in a corrupt tree.
+                if visited.insert(vcn) {
+                    collect_entries(subnode, blocks_by_vcn, visited, entries);
+                }
+            }
+        }
+        if !entry.is_last {
+            entries.push(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mft::{FileTime, MasterFileTable},
+        test_support::{
+            directory_index_entry, file_name_value, file_reference, index_block, index_entry,
+            index_root_node, last_index_entry, namespaces, Attribute, ImageBuilder, MemoryReader,
+            Record, Run, ROOT_RECORD,
+        },
+    };
+
+    fn names(index: &Index) -> Vec<String> {
+        index
+            .file_names()
+            .unwrap()
+            .into_iter()
+            .map(|(_, file_name)| file_name.filename.to_string())
+            .collect()
+    }
+
+    fn read_directory(image: MemoryReader, dir: u64) -> Index {
+        let mut mft = MasterFileTable::open(image).unwrap();
+        let entry = mft.read_entry(dir).unwrap().unwrap();
+        mft.read_index(&entry, &NtfsName::from("$I30")).unwrap()
+    }
+
+    #[test]
+    fn small_directory() {
+        let mut builder = ImageBuilder::new();
+        let dir = builder.add_directory(ROOT_RECORD, "dir");
+        let a = builder.add_file(dir, "a", b"");
+        let b = builder.add_file(dir, "b", b"");
+        builder.set_record(
+            dir,
+            Record::directory()
+                .with(Attribute::file_name(ROOT_RECORD, "dir", 1))
+                .with(Attribute::index_root(
+                    "$I30",
+                    index_root_node(&[
+                        directory_index_entry(a, dir, "a", None),
+                        directory_index_entry(b, dir, "b", None),
+                        last_index_entry(None),
+                    ]),
+                )),
+        );
+
+        let index = read_directory(builder.build(), dir);
+        assert!(!index.root.node.has_children);
+        assert_eq!(index.root.indexed_attribute_type, 0x30);
+        assert!(index.blocks.is_empty());
+        assert_eq!(names(&index), vec!["a", "b"]);
+
+        // Every entry should point back at the directory.
+        for (file, file_name) in index.file_names().unwrap() {
+            assert_eq!(file_name.parent.segment(), dir);
+            assert!(u64::from(file) == a || u64::from(file) == b);
+        }
+    }
+
+    #[test]
+    fn large_directory() {
+        let mut builder = ImageBuilder::new();
+        let dir = 20;
+        let blocks = [
+            index_block(
+                0,
+                &[
+                    directory_index_entry(21, dir, "a", None),
+                    directory_index_entry(22, dir, "b", None),
+                    last_index_entry(None),
+                ],
+                &[],
+            ),
+            index_block(
+                1,
+                &[
+                    directory_index_entry(24, dir, "x", None),
+                    directory_index_entry(25, dir, "y", None),
+                    last_index_entry(None),
+                ],
+                &[],
+            ),
+            // Free, so it's never read; it doesn't even have a valid header.
+            vec![0; 4096],
+        ]
+        .concat();
+        let lcn = builder.write_clusters(&blocks);
+
+        builder.set_record(
+            dir,
+            Record::directory()
+                .with(Attribute::file_name(ROOT_RECORD, "dir", 1))
+                .with(Attribute::index_root(
+                    "$I30",
+                    index_root_node(&[
+                        directory_index_entry(23, dir, "m", Some(0)),
+                        last_index_entry(Some(1)),
+                    ]),
+                ))
+                .with(Attribute::index_allocation(
+                    "$I30",
+                    vec![Run::at(lcn, 3)],
+                    3 * 4096,
+                ))
+                .with(Attribute::bitmap("$I30", &[0b011])),
+        );
+
+        let index = read_directory(builder.build(), dir);
+        assert!(index.root.node.has_children);
+        assert_eq!(index.blocks.len(), 2);
+        assert_eq!(index.blocks[1].vcn, 1);
+        assert_eq!(names(&index), vec!["a", "b", "m", "x", "y"]);
+    }
+
+    #[test]
+    fn missing_index() {
+        let mut builder = ImageBuilder::new();
+        let file = builder.add_file(ROOT_RECORD, "file", b"");
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+        let entry = mft.read_entry(file).unwrap().unwrap();
+        assert!(matches!(
+            mft.read_index(&entry, &NtfsName::from("$I30")),
+            Err(Error::MissingIndexRoot)
+        ));
+    }
+
+    #[test]
+    fn damaged_index_root() {
+        let mut builder = ImageBuilder::new();
+        let dir = builder.add_directory(ROOT_RECORD, "dir");
+        let mut node = index_root_node(&[last_index_entry(None)]);
+        // Entries starting past the end of the node
+        node[0..4].copy_from_slice(&0x1000u32.to_le_bytes());
+        builder.set_record(
+            dir,
+            Record::directory()
+                .with(Attribute::file_name(ROOT_RECORD, "dir", 1))
+                .with(Attribute::index_root("$I30", node)),
+        );
+        let file = builder.add_file(ROOT_RECORD, "file", b"");
+
+        // The rest of the volume can still be read.
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+        let entries = mft.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        assert!(entries.iter().any(|e| e.base_record_segment_idx == file));
+        let entry = entries
+            .iter()
+            .find(|e| e.base_record_segment_idx == dir)
+            .unwrap();
+        assert!(entry.index_roots.is_empty());
+        assert!(matches!(
+            mft.read_index(entry, &NtfsName::from("$I30")),
+            Err(Error::BadIndexNode)
+        ));
+    }
+
+    #[test]
+    fn recovers_file_names_from_slack() {
+        // 2021-03-04T05:06:07.1234567Z
+        const TIME: u64 = 132_593_079_671_234_567;
+        let dir = 20;
+        let mut deleted = file_name_value(dir, "gone.txt", namespaces::WIN32, [TIME; 4]);
+        deleted[40..48].copy_from_slice(&8192u64.to_le_bytes());
+        deleted[48..56].copy_from_slice(&5000u64.to_le_bytes());
+        // Slack holds a stale copy of a live entry, a deleted file's entry
+        // whose header has been overwritten, and then junk.
+        let stale = file_name_value(dir, "b", namespaces::WIN32, [TIME; 4]);
+        let mut slack = index_entry(file_reference(22, 1), &stale, None, false);
+        let mut entry = index_entry(file_reference(30, 2), &deleted, None, false);
+        entry[..8].copy_from_slice(&[0xAA; 8]);
+        entry[10] = 0xAA;
+        slack.extend_from_slice(&entry);
+        slack.extend_from_slice(&[0xEE; 200]);
+
+        let mut builder = ImageBuilder::new();
+        let lcn = builder.write_clusters(&index_block(
+            0,
+            &[
+                directory_index_entry(21, dir, "a", None),
+                directory_index_entry(22, dir, "b", None),
+                last_index_entry(None),
+            ],
+            &slack,
+        ));
+        builder.set_record(
+            dir,
+            Record::directory()
+                .with(Attribute::file_name(ROOT_RECORD, "dir", 1))
+                .with(Attribute::index_root(
+                    "$I30",
+                    index_root_node(&[last_index_entry(Some(0))]),
+                ))
+                .with(Attribute::index_allocation(
+                    "$I30",
+                    vec![Run::at(lcn, 1)],
+                    4096,
+                ))
+                .with(Attribute::bitmap("$I30", &[0b1])),
+        );
+        let index = read_directory(builder.build(), dir);
+        assert_eq!(names(&index), vec!["a", "b"]);
+
+        let in_block = index.blocks[0].recover_file_names();
+        assert_eq!(in_block.len(), 2);
+        assert_eq!(in_block[0].file_name.filename.to_string(), "b");
+        assert_eq!(u64::from(in_block[0].file_reference.unwrap()), 22);
+
+        let recovered = index.recover_file_names();
+        assert_eq!(recovered.len(), 1);
+        let gone = &recovered[0];
+        assert_eq!(gone.vcn, 0);
+        assert!(gone.file_reference.is_none());
+        assert_eq!(gone.file_name.filename.to_string(), "gone.txt");
+        assert_eq!(gone.file_name.parent, sys::FileReference::new(dir, 1));
+        assert_eq!(gone.file_name.physical_size, 8192);
+        assert_eq!(gone.file_name.logical_size, 5000);
+        assert_eq!(gone.file_name.created, FileTime::from_raw(TIME));
+    }
+}
@@ -0,0 +1,129 @@
+use crate::err::Error;
+
+// LZNT1, the compression NTFS uses for compressed files. The input is a
+// series of chunks, each holding up to 4KB of output.
+const CHUNK_SIZE: usize = 4096;
+
+mod chunk_header_flags {
+    pub const COMPRESSED: u16 = 0x8000;
+    pub const SIZE_MASK: u16 = 0x0FFF;
+}
+
+// Decompresses `input`, appending the result to `output`. Stops at the end
+// of the input, or at a zero chunk header, whichever comes first.
+pub fn decompress(mut input: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+    while input.len() >= 2 {
+        let header = u16::from_le_bytes([input[0], input[1]]);
+        if header == 0 {
+            break;
+        }
+        let chunk_len = usize::from(header & chunk_header_flags::SIZE_MASK) + 1;
+        let chunk = input
+            .get(2..2 + chunk_len)
+            .ok_or(Error::BadCompressedData)?;
+        input = &input[2 + chunk_len..];
+
+        let chunk_start = output.len();
+        if header & chunk_header_flags::COMPRESSED != 0 {
+            decompress_chunk(chunk, output)?;
+        } else {
+            output.extend_from_slice(chunk);
+        }
+
+        // Every chunk but the last stands for a whole 4KB, even if it came out
+        // shorter; the rest is zeros.
+        if input.len() >= 2 && input[..2] != [0, 0] {
+            output.resize(chunk_start + CHUNK_SIZE, 0);
+        }
+    }
+    Ok(())
+}
+
+fn decompress_chunk(mut chunk: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+    let chunk_start = output.len();
+    while let Some((&flags, rest)) = chunk.split_first() {
+        chunk = rest;
+        for bit in 0..8 {
+            if chunk.is_empty() {
+                break;
+            }
+            if flags & (1 << bit) == 0 {
+                output.push(chunk[0]);
+                chunk = &chunk[1..];
+                continue;
+            }
+
+            // A back-reference. The split between offset and length bits
+            // depends on how far into the chunk we are: the further in, the
+            // more bits the offset needs.
+            if chunk.len() < 2 {
+                return Err(Error::BadCompressedData);
+            }
+            let token = u16::from_le_bytes([chunk[0], chunk[1]]);
+            chunk = &chunk[2..];
+
+            let position = output.len() - chunk_start;
+            if position == 0 {
+                return Err(Error::BadCompressedData);
+            }
+            let mut length_bits = 12;
+            let mut scaled = position - 1;
+            while scaled >= 0x10 {
+                length_bits -= 1;
+                scaled >>= 1;
+            }
+            let offset = usize::from(token >> length_bits) + 1;
+            let length = usize::from(token & ((1 << length_bits) - 1)) + 3;
+            if offset > position || position + length > CHUNK_SIZE {
+                return Err(Error::BadCompressedData);
+            }
+
+            // The source and destination may overlap, so copy a byte at a time.
+            let start = output.len() - offset;
+            for i in 0..length {
+                output.push(output[start + i]);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::lznt1_compress;
+
+    #[test]
+    fn decompresses_back_references() {
+        // "abc", then 9 bytes copied from 3 back
+        let input = [0x05, 0xB0, 0x08, b'a', b'b', b'c', 0x06, 0x20];
+        let mut output = Vec::new();
+        decompress(&input, &mut output).unwrap();
+        assert_eq!(output, b"abcabcabcabc");
+    }
+
+    #[test]
+    fn round_trips_several_chunks() {
+        let mut data = Vec::new();
+        for i in 0..3000u32 {
+            data.extend_from_slice(format!("line {}\n", i % 250).as_bytes());
+        }
+        // Something that won't compress, to get an uncompressed chunk too.
+        data.extend((0..5000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8));
+
+        let compressed = lznt1_compress(&data);
+        assert!(compressed.len() < data.len());
+        let mut output = Vec::new();
+        decompress(&compressed, &mut output).unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn rejects_references_before_the_chunk() {
+        let input = [0x02, 0xB0, 0x01, 0x00, 0x10];
+        assert!(matches!(
+            decompress(&input, &mut Vec::new()),
+            Err(Error::BadCompressedData)
+        ));
+    }
+}
@@ -1,108 +1,798 @@
-use crate::{err::Error, SafeHandle};
-
-use winapi::{
-    ctypes::c_void,
-    um::{
-        errhandlingapi as ehapi,
-        fileapi::{CreateFileW, OPEN_EXISTING},
-        handleapi::INVALID_HANDLE_VALUE,
-        ioapiset::DeviceIoControl,
-        winbase::FILE_FLAG_BACKUP_SEMANTICS,
-        winioctl::{
-            FSCTL_GET_NTFS_VOLUME_DATA, NTFS_EXTENDED_VOLUME_DATA, NTFS_VOLUME_DATA_BUFFER,
-        },
-        winnt::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE},
-    },
+use crate::{
+    err::Error,
+    guid::Guid,
+    reader::{ImageReader, VolumeReader},
 };
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     convert::TryInto as _,
-    ffi::{OsStr, OsString},
-    mem,
-    os::windows::ffi::{OsStrExt as _, OsStringExt as _},
+    io::{Read as _, Seek as _, SeekFrom},
     path::Path,
-    ptr,
 };
 
+mod data;
+mod ea;
+mod huffman;
+pub mod index;
+mod lznt1;
+mod lzx;
+mod name;
+mod object_id;
+mod reparse;
+pub mod security;
 mod stream;
 pub mod sys;
-
+mod time;
+mod timestamps;
+mod volume;
+mod wof;
+mod xpress;
+
+pub use data::DataStream;
+pub use ea::{EaInformation, ExtendedAttribute, LxMetadata};
+pub use name::NtfsName;
+pub use object_id::{ObjectId, ObjectIdEntry};
+pub use reparse::{reparse_tags, ReparsePoint, WofAlgorithm, WofProvider};
+pub use stream::Extent;
 use stream::MftStream;
+pub use time::FileTime;
+pub use timestamps::{TimestampAnomaly, TimestampField};
+pub use volume::{volume_flags, VolumeInformation, VolumeMetadata};
 
-const NTFS_VOLUME_DATA_BUFFER_SIZE: usize =
-    mem::size_of::<NTFS_VOLUME_DATA_BUFFER>() + mem::size_of::<NTFS_EXTENDED_VOLUME_DATA>();
+const DEFAULT_BYTES_PER_CLUSTER: u64 = 4096;
+const MIN_RECORD_SIZE: u64 = 256;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct MftEntry {
     pub base_record_segment_idx: u64,
+    pub sequence_number: u16,
+    // Set for records which are no longer in use, as returned when reading
+    // with `with_deleted_records`. Their contents are whatever was left
+    // behind when the file was deleted, and may be partly reused.
+    pub is_deleted: bool,
     pub hard_link_count: u16,
     pub standard_information: Vec<sys::StandardInformation>,
     pub filename: Vec<sys::FileName>,
     pub data: Vec<sys::Data>,
+    pub reparse_point: Option<ReparsePoint>,
+    // A per-file security descriptor, as used before NTFS 3.0 moved them
+    // into $Secure. Newer files only have a security ID.
+    pub security_descriptor: Option<security::SecurityDescriptor>,
+    // The file's distributed link tracking IDs, if it's been given any.
+    pub object_id: Option<ObjectId>,
+    pub ea_information: Option<EaInformation>,
+    pub extended_attributes: Vec<ExtendedAttribute>,
+    // Only $Volume has these.
+    pub volume_name: Option<NtfsName>,
+    pub volume_information: Option<VolumeInformation>,
+    pub index_roots: Vec<index::IndexRoot>,
+    // Where the index blocks of large indexes are; read with `read_index`.
+    pub index_allocations: Vec<sys::Data>,
+    pub bitmaps: Vec<sys::Bitmap>,
+    // Set when the entry has a non-resident attribute list that couldn't be
+    // read, which happens when parsing a standalone $MFT file, or when a
+    // deleted entry's extension records have since been reused. Any
+    // attributes stored in extension records will be missing from the entry.
+    // Also set when pieces of an attribute are missing, leaving only the
+    // start of it.
+    pub has_unresolved_attribute_list: bool,
+    // Optional attributes which were there but couldn't be decoded, and so
+    // were left out. A damaged one doesn't stop the rest of the entry, or
+    // the rest of the volume, being read.
+    pub bad_attributes: Vec<sys::AttributeType>,
 }
 impl MftEntry {
-    pub fn get_best_filename(&self) -> Option<OsString> {
+    pub fn get_standard_information(&self) -> Option<&sys::StandardInformation> {
+        self.standard_information.first()
+    }
+
+    // Compares the $STANDARD_INFORMATION and $FILE_NAME times for signs of
+    // tampering. An empty result doesn't prove the times are genuine.
+    pub fn timestamp_anomalies(&self) -> Vec<TimestampAnomaly> {
+        timestamps::find_anomalies(self)
+    }
+
+    pub fn get_best_filename(&self) -> Option<NtfsName> {
         self.filename.first().map(|e| e.filename.clone())
     }
 
-    pub fn parents(&self) -> Vec<u64> {
+    // This entry's own record, as a reference to it would be made.
+    pub fn reference(&self) -> sys::FileReference {
+        sys::FileReference::new(self.base_record_segment_idx, self.sequence_number)
+    }
+
+    // The $DATA attribute with the given stream name (None for the file's
+    // main contents).
+    pub fn get_data(&self, name: Option<&NtfsName>) -> Option<&sys::Data> {
+        self.data.iter().find(|data| data.name.as_ref() == name)
+    }
+
+    // The file's security descriptor: its own, or the shared one its
+    // security ID points at, from `read_security_descriptors`.
+    pub fn resolve_security_descriptor<'a>(
+        &'a self,
+        shared: &'a HashMap<u32, security::SecurityDescriptor>,
+    ) -> Option<&'a security::SecurityDescriptor> {
+        self.security_descriptor.as_ref().or_else(|| {
+            let security_id = self.get_standard_information()?.security_id?;
+            shared.get(&security_id)
+        })
+    }
+
+    // The Linux owner, mode and device numbers WSL keeps in the file's EAs.
+    pub fn lx_metadata(&self) -> Option<LxMetadata> {
+        LxMetadata::from_extended_attributes(&self.extended_attributes)
+    }
+
+    pub fn parents(&self) -> Vec<sys::FileReference> {
         self.filename.iter().map(|f| f.parent).collect()
     }
 
+    // The algorithm a WOF (compact.exe) compressed file is compressed with.
+    // Such a file's main stream is sparse, with its contents compressed into
+    // a "WofCompressedData" stream.
+    pub fn wof_algorithm(&self) -> Option<WofAlgorithm> {
+        match self.reparse_point {
+            Some(ReparsePoint::Wof {
+                provider: WofProvider::File { algorithm },
+            }) => Some(algorithm),
+            _ => None,
+        }
+    }
+
     pub fn get_allocated_size(&self, bytes_per_cluster: u64) -> u64 {
         self.data
             .iter()
-            .filter(|data| !(data.name.is_none()))
+            .filter(|data| data.name.is_some())
             .map(|data| data.compute_allocated_size(bytes_per_cluster))
             .sum()
     }
 }
 
-pub struct MasterFileTable {
-    mft_stream: MftStream,
-    bytes_per_file_record_segment: u64,
-    bytes_per_sector: u64,
-    bytes_per_cluster: u64,
+// A record whose in-use flag disagrees with $MFT's $BITMAP, as found while
+// iterating. Either can be the one that's wrong; a crash or tampering
+// leaves them out of step until chkdsk puts them right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationMismatch {
+    pub segment: u64,
+    // Whether the bitmap has the record in use; its header says otherwise.
+    pub in_bitmap: bool,
+}
+
+pub struct MasterFileTable<R: VolumeReader> {
+    mft_stream: MftStream<R>,
+    geometry: sys::VolumeGeometry,
+    // False when reading a standalone $MFT file, in which case there's no
+    // volume to read non-resident attributes from.
+    has_volume: bool,
+    include_deleted: bool,
     current_file_record_segment: u64,
+    // Which records are in use, from $MFT's $BITMAP. Iterating skips the
+    // rest, unless deleted records were asked for. Standalone $MFT files
+    // don't have it, as it's stored outside the MFT.
+    record_bitmap: Option<sys::Bitmap>,
+    allocation_mismatches: Vec<AllocationMismatch>,
 }
-impl MasterFileTable {
-    pub fn load(volume_handle: SafeHandle, volume_path: &OsStr) -> Result<(Self, u64), Error> {
-        let (volume_data, extended_data) = get_ntfs_volume_data(&volume_handle)?;
-
-        // We only know how to deal with NTFS 3.0 or 3.1 data. Make sure the volume
-        // is the correct NTFS version.
-        if !(extended_data.MajorVersion == 3
-            && (extended_data.MinorVersion == 0 || extended_data.MinorVersion == 1))
+impl MasterFileTable<ImageReader> {
+    // Opens a standalone copy of the MFT, as extracted from a volume by other
+    // tools. Records are read straight out of the file; since the rest of the
+    // volume isn't available, non-resident attribute lists are reported as
+    // unresolved on the entry instead.
+    //
+    // The cluster size isn't recorded anywhere in the MFT, so allocated sizes
+    // assume 4KB clusters unless told otherwise with `with_bytes_per_cluster`.
+    pub fn from_mft_file<P: AsRef<Path>>(path: P, record_size: u64) -> Result<Self, Error> {
+        let file = ImageReader::open(path)?;
+        // Records are protected by update sequences, one entry per sector.
+        if !record_size.is_power_of_two()
+            || record_size < MIN_RECORD_SIZE
+            || record_size < file.sector_size()
         {
-            return Err(Error::UnknownNtfsVersion);
+            return Err(Error::BadRecordSize(record_size));
         }
+        let record_count = file.len() / record_size;
+        let mft_len = record_count * record_size;
+
+        // The whole file is one extent, with "clusters" the size of one record.
+        let extents = vec![Extent {
+            min_vcn: 0,
+            min_lcn: 0,
+            cluster_count: record_count as i64,
+        }];
+        let geometry = sys::VolumeGeometry {
+            bytes_per_sector: file.sector_size(),
+            bytes_per_cluster: DEFAULT_BYTES_PER_CLUSTER,
+            bytes_per_file_record_segment: record_size,
+            bytes_per_index_record: DEFAULT_BYTES_PER_CLUSTER,
+            total_sectors: 0,
+            mft_start_lcn: 0,
+            mft_mirror_start_lcn: 0,
+            volume_serial_number: 0,
+        };
 
-        let mft_handle = get_mft_handle(volume_path)?;
+        Ok(MasterFileTable {
+            mft_stream: MftStream::new(file, extents, record_size, record_size, mft_len)?,
+            geometry,
+            has_volume: false,
+            include_deleted: false,
+            current_file_record_segment: 0,
+            record_bitmap: None,
+            allocation_mismatches: Vec::new(),
+        })
+    }
 
-        Ok((
-            MasterFileTable {
-                mft_stream: MftStream::new(volume_handle, volume_data, &mft_handle)?,
-                bytes_per_file_record_segment: volume_data.BytesPerFileRecordSegment.into(),
-                bytes_per_sector: volume_data.BytesPerSector.into(),
-                bytes_per_cluster: volume_data.BytesPerCluster.into(),
-                current_file_record_segment: 0,
-            },
-            volume_data.BytesPerCluster.into(),
-        ))
+    pub fn with_bytes_per_cluster(mut self, bytes_per_cluster: u64) -> Self {
+        self.geometry.bytes_per_cluster = bytes_per_cluster;
+        self
+    }
+}
+impl<R: VolumeReader> MasterFileTable<R> {
+    // Builds a table reading the MFT out of `volume`, given the extents
+    // which make up the MFT and the volume's geometry.
+    pub fn new(
+        volume: R,
+        extents: Vec<Extent>,
+        geometry: sys::VolumeGeometry,
+        mft_len: u64,
+    ) -> Result<Self, Error> {
+        Ok(MasterFileTable {
+            mft_stream: MftStream::new(
+                volume,
+                extents,
+                geometry.bytes_per_cluster,
+                geometry.bytes_per_file_record_segment,
+                mft_len,
+            )?,
+            geometry,
+            has_volume: true,
+            include_deleted: false,
+            current_file_record_segment: 0,
+            record_bitmap: None,
+            allocation_mismatches: Vec::new(),
+        })
+    }
+
+    // Opens the MFT of an NTFS volume. The MFT's layout is found by reading
+    // its first record, which describes the $MFT file itself.
+    pub fn open(mut volume: R) -> Result<Self, Error> {
+        let geometry = sys::VolumeGeometry::read(&mut volume)?;
+
+        // To start with, all we know is where the first record lives.
+        let first_record = Extent {
+            min_vcn: 0,
+            min_lcn: geometry.mft_start_lcn as i64,
+            cluster_count: geometry
+                .bytes_per_file_record_segment
+                .div_ceil(geometry.bytes_per_cluster) as i64,
+        };
+        let first_record_len = geometry.bytes_per_file_record_segment;
+        let mut mft = MasterFileTable::new(volume, vec![first_record], geometry, first_record_len)?;
+
+        // The base record maps (at least) the start of the MFT, which is where
+        // any extension records for $MFT itself will be.
+        let (extents, mft_len) = mft.load_mft_extents(false)?;
+        mft.mft_stream.set_extents(extents, mft_len);
+
+        // Now that those are readable, go again and follow the attribute list
+        // to pick up the rest of a fragmented MFT.
+        let (extents, mft_len) = mft.load_mft_extents(true)?;
+        mft.mft_stream.set_extents(extents, mft_len);
+
+        // Without a readable $BITMAP, iterating reads every record.
+        mft.record_bitmap = mft.read_entry(0).ok().flatten().and_then(|entry| {
+            entry
+                .bitmaps
+                .into_iter()
+                .find(|bitmap| bitmap.name.is_none())
+        });
+        Ok(mft)
+    }
+
+    // Also returns records which aren't in use, marked with `is_deleted`.
+    pub fn with_deleted_records(mut self, include_deleted: bool) -> Self {
+        self.include_deleted = include_deleted;
+        self
+    }
+
+    pub fn bytes_per_cluster(&self) -> u64 {
+        self.geometry.bytes_per_cluster
+    }
+
+    pub fn geometry(&self) -> &sys::VolumeGeometry {
+        &self.geometry
     }
 
     pub fn entry_count(&self) -> u64 {
         self.mft_stream.get_file_record_segment_count()
     }
 
+    // The records iterated over so far whose in-use flags disagree with
+    // $MFT's $BITMAP. When deleted records aren't included, only records
+    // the bitmap has in use are read, so only those can be found.
+    pub fn allocation_mismatches(&self) -> &[AllocationMismatch] {
+        &self.allocation_mismatches
+    }
+
+    // Reads a single file by its record number. Returns None if the record
+    // is an extension of another record, or isn't in use (unless deleted
+    // records were asked for).
+    pub fn read_entry(&mut self, segment: u64) -> Result<Option<MftEntry>, Error> {
+        if segment >= self.mft_stream.get_file_record_segment_count() {
+            return Err(Error::SegmentOutsideMftExtents(segment));
+        }
+        self.load_entry(segment, false)
+    }
+
+    // Reads an index of `entry` (such as a directory's "$I30") in full.
+    // Blocks which the index's bitmap marks as free are left out.
+    pub fn read_index(&mut self, entry: &MftEntry, name: &NtfsName) -> Result<index::Index, Error> {
+        let name = Some(name);
+        let root = entry
+            .index_roots
+            .iter()
+            .find(|root| root.name.as_ref() == name);
+        let root = match root {
+            Some(root) => root.clone(),
+            None if entry
+                .bad_attributes
+                .contains(&sys::AttributeType::IndexRoot) =>
+            {
+                return Err(Error::BadIndexNode)
+            }
+            None => return Err(Error::MissingIndexRoot),
+        };
+
+        let allocation = entry
+            .index_allocations
+            .iter()
+            .find(|allocation| allocation.name.as_ref() == name);
+        let allocation = match allocation {
+            Some(allocation) if self.has_volume => allocation,
+            _ => {
+                return Ok(index::Index {
+                    root,
+                    blocks: Vec::new(),
+                })
+            }
+        };
+        let logical_size = allocation.logical_size;
+        let runs = allocation.runs.clone().unwrap_or_default();
+        let total_size =
+            runs.iter().map(|run| run.cluster_count).sum::<u64>() * self.geometry.bytes_per_cluster;
+        let mut data = self.read_non_resident_data(total_size, runs)?;
+        data.truncate(logical_size as usize);
+
+        let bitmap = entry
+            .bitmaps
+            .iter()
+            .find(|bitmap| bitmap.name.as_ref() == name);
+        let block_size = root.bytes_per_index_block as usize;
+        if block_size == 0 {
+            return Err(Error::BadIndexNode);
+        }
+        let mut blocks = Vec::new();
+        for (i, block) in data.chunks_exact(block_size).enumerate() {
+            if bitmap.is_some_and(|bitmap| !bitmap.is_set(i as u64)) {
+                continue;
+            }
+            blocks.push(index::IndexBlock::load(block.to_vec())?);
+        }
+
+        Ok(index::Index { root, blocks })
+    }
+
+    // Reads every security descriptor in $Secure, by security ID. Volumes
+    // from before NTFS 3.0 have no $Secure, and give an empty map. Any that
+    // can't be read are left out.
+    pub fn read_security_descriptors(
+        &mut self,
+    ) -> Result<HashMap<u32, security::SecurityDescriptor>, Error> {
+        let secure = match self.read_entry(security::SECURE_RECORD)? {
+            Some(secure) => secure,
+            None => return Ok(HashMap::new()),
+        };
+        let sii_name = NtfsName::from(security::SII_INDEX_NAME);
+        if !secure
+            .index_roots
+            .iter()
+            .any(|root| root.name.as_ref() == Some(&sii_name))
+        {
+            return Ok(HashMap::new());
+        }
+        // A damaged entry only costs that security ID its descriptor.
+        let locations = self
+            .read_index(&secure, &sii_name)?
+            .entries()
+            .into_iter()
+            .filter_map(|entry| security::SdsLocation::load(entry.data()?).ok())
+            .collect::<Vec<_>>();
+
+        let sds = secure
+            .get_data(Some(&NtfsName::from(security::SDS_STREAM_NAME)))
+            .ok_or(Error::BadSecurityDescriptor)?;
+        let mut sds = self.open_data(sds)?;
+        let sds_len = sds.len();
+        let mut read_descriptor = |location: &security::SdsLocation| {
+            let length = u64::from(location.length)
+                .checked_sub(security::SDS_HEADER_LENGTH as u64)
+                .filter(|_| location.offset.saturating_add(location.length.into()) <= sds_len)
+                .ok_or(Error::BadSecurityDescriptor)?;
+            let mut buf = vec![0; length as usize];
+            sds.seek(SeekFrom::Start(
+                location.offset + security::SDS_HEADER_LENGTH as u64,
+            ))?;
+            sds.read_exact(&mut buf)?;
+            security::SecurityDescriptor::load(&buf)
+        };
+        let mut descriptors = HashMap::new();
+        for location in locations {
+            if let Ok(descriptor) = read_descriptor(&location) {
+                descriptors.insert(location.security_id, descriptor);
+            }
+        }
+        Ok(descriptors)
+    }
+
+    // Reads the volume's label, NTFS version and state from $Volume. Returns
+    // None if $Volume is missing or has no $VOLUME_INFORMATION.
+    pub fn read_volume_metadata(&mut self) -> Result<Option<VolumeMetadata>, Error> {
+        if volume::VOLUME_RECORD >= self.entry_count() {
+            return Ok(None);
+        }
+        Ok(self
+            .read_entry(volume::VOLUME_RECORD)?
+            .as_ref()
+            .and_then(VolumeMetadata::from_entry))
+    }
+
+    // Reads $Extend\$ObjId:$O, which maps object IDs (such as those in
+    // shortcuts' link tracking data) to the files that have them. Volumes
+    // without $ObjId give an empty map.
+    pub fn read_object_ids(&mut self) -> Result<HashMap<Guid, ObjectIdEntry>, Error> {
+        let extend = match self.read_entry(object_id::EXTEND_RECORD)? {
+            Some(extend) => extend,
+            None => return Ok(HashMap::new()),
+        };
+        let i30_name = NtfsName::from("$I30");
+        if !extend
+            .index_roots
+            .iter()
+            .any(|root| root.name.as_ref() == Some(&i30_name))
+        {
+            return Ok(HashMap::new());
+        }
+        let obj_id_name = NtfsName::from(object_id::OBJ_ID_FILE_NAME);
+        let obj_id = self
+            .read_index(&extend, &i30_name)?
+            .file_names()?
+            .into_iter()
+            .find(|(_, file_name)| file_name.filename.eq_ignore_case(&obj_id_name))
+            .map(|(reference, _)| reference.segment());
+        let obj_id = match obj_id.map(|segment| self.read_entry(segment)).transpose()? {
+            Some(Some(obj_id)) => obj_id,
+            _ => return Ok(HashMap::new()),
+        };
+
+        let o_name = NtfsName::from(object_id::O_INDEX_NAME);
+        self.read_index(&obj_id, &o_name)?
+            .entries()
+            .into_iter()
+            .map(|entry| {
+                let entry =
+                    ObjectIdEntry::load(&entry.key, entry.data().ok_or(Error::BadObjectId)?)?;
+                Ok((entry.object_id.object_id, entry))
+            })
+            .collect()
+    }
+
+    // Opens the contents of a $DATA attribute for reading. Resident data can
+    // always be read, but anything else needs the volume.
+    pub fn open_data(&mut self, data: &sys::Data) -> Result<DataStream<'_, R>, Error> {
+        if data.value.is_none() && !self.has_volume {
+            return Err(Error::MissingVolume);
+        }
+        DataStream::new(&mut self.mft_stream, self.geometry.bytes_per_cluster, data)
+    }
+
+    // Opens a file's main contents, decompressing them if the file was
+    // compressed by WOF. Returns None if the entry has no main stream, as
+    // with directories.
+    pub fn open_file(&mut self, entry: &MftEntry) -> Result<Option<DataStream<'_, R>>, Error> {
+        let data = match entry.get_data(None) {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let compressed_name = NtfsName::from(wof::COMPRESSED_STREAM_NAME);
+        let compressed = entry
+            .wof_algorithm()
+            .zip(entry.get_data(Some(&compressed_name)));
+        let (algorithm, compressed) = match compressed {
+            Some(compressed) => compressed,
+            None => return self.open_data(data).map(Some),
+        };
+        if compressed.value.is_none() && !self.has_volume {
+            return Err(Error::MissingVolume);
+        }
+        DataStream::wof(
+            &mut self.mft_stream,
+            self.geometry.bytes_per_cluster,
+            compressed,
+            algorithm,
+            data.logical_size,
+        )
+        .map(Some)
+    }
+
     // private helpers
 
+    fn load_entry(&mut self, segment: u64, use_cache: bool) -> Result<Option<MftEntry>, Error> {
+        match self.read_record(segment, use_cache)? {
+            Some((segment_header, segment_buffer)) => {
+                self.parse_record(segment, segment_header, segment_buffer)
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Reads a record and its header, or returns None if it's blank.
+    fn read_record(
+        &mut self,
+        segment: u64,
+        use_cache: bool,
+    ) -> Result<Option<(sys::FileRecordSegmentHeader, Vec<u8>)>, Error> {
+        let mut segment_buffer = vec![0; self.geometry.bytes_per_file_record_segment as usize];
+        self.mft_stream
+            .read_file_record_segment(segment, &mut segment_buffer[..], use_cache)?;
+
+        // If the buffer's header is 0's instead of "FILE", just skip
+        if segment_buffer.iter().take(4).all(|x| *x == 0) {
+            return Ok(None);
+        }
+
+        let segment_header = sys::FileRecordSegmentHeader::load_any(&segment_buffer[..])?;
+        Ok(Some((segment_header, segment_buffer)))
+    }
+
+    fn parse_record(
+        &mut self,
+        segment: u64,
+        segment_header: sys::FileRecordSegmentHeader,
+        mut segment_buffer: Vec<u8>,
+    ) -> Result<Option<MftEntry>, Error> {
+        if !segment_header.in_use && !self.include_deleted {
+            return Ok(None);
+        }
+
+        let mut entry = MftEntry {
+            base_record_segment_idx: segment,
+            sequence_number: segment_header.sequence_number,
+            is_deleted: !segment_header.in_use,
+            hard_link_count: segment_header.hard_link_count,
+            ..Default::default()
+        };
+
+        // Use the update sequence array to validate and correct the buffer.
+        let parsed = fix_record_with_update_sequence(
+            &segment_header.multi_sector_header,
+            &mut segment_buffer[..],
+        )
+        .and_then(|_| {
+            self.parse_segment(
+                &segment_header,
+                segment,
+                false, // allow extensions
+                &segment_buffer[..],
+                &mut entry,
+            )
+        });
+        let is_base_record = match parsed {
+            Ok(is_base_record) => is_base_record,
+            // Freed records are often half-overwritten; one that no longer
+            // parses is skipped rather than ending the whole scan. Parsing
+            // checks every length and offset, so damage shows up as an error.
+            Err(_) if entry.is_deleted => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if !is_base_record {
+            return Ok(None);
+        }
+
+        // Deleted entries may be missing pieces whose records were reused;
+        // what's left of each attribute is kept. For a live entry that means
+        // a damaged attribute list, which is reported on the entry.
+        let (data, data_complete) = merge_pieces(std::mem::take(&mut entry.data), true)?;
+        let (index_allocations, index_complete) =
+            merge_pieces(std::mem::take(&mut entry.index_allocations), true)?;
+        entry.data = data;
+        entry.index_allocations = index_allocations;
+        if !data_complete || !index_complete {
+            entry.has_unresolved_attribute_list = true;
+        }
+        if !entry.is_deleted {
+            if !data_complete {
+                entry.bad_attributes.push(sys::AttributeType::Data);
+            }
+            if !index_complete {
+                entry
+                    .bad_attributes
+                    .push(sys::AttributeType::IndexAllocation);
+            }
+        }
+        entry.filename.sort_by_key(|f| f.filename_type.clone());
+        Ok(Some(entry))
+    }
+
+    // The first record from `segment` on which $MFT's $BITMAP has in use.
+    // Records past the end of the bitmap are assumed to be in use.
+    fn next_allocated_record(&self, segment: u64) -> u64 {
+        let bits = match self.record_bitmap {
+            Some(ref bitmap) => &bitmap.bits,
+            None => return segment,
+        };
+        let mut index = (segment / 8) as usize;
+        if let Some(byte) = bits.get(index) {
+            // Bits in the first byte before `segment` don't count.
+            let byte = byte & (0xFF << (segment % 8));
+            if byte != 0 {
+                return index as u64 * 8 + u64::from(byte.trailing_zeros());
+            }
+            index += 1;
+        }
+        // Skip whole bytes of free records at a time.
+        match bits.iter().skip(index).position(|byte| *byte != 0) {
+            Some(position) => {
+                let index = index + position;
+                index as u64 * 8 + u64::from(bits[index].trailing_zeros())
+            }
+            None => segment.max(bits.len() as u64 * 8),
+        }
+    }
+
+    // Reads the next record while iterating, noting if its in-use flag
+    // disagrees with the bitmap.
+    fn read_next_record(&mut self, segment: u64) -> Result<Option<MftEntry>, Error> {
+        let in_bitmap = self
+            .record_bitmap
+            .as_ref()
+            .filter(|bitmap| segment < bitmap.bits.len() as u64 * 8)
+            .map(|bitmap| bitmap.is_set(segment));
+        // When skipping free records, the buffer is only filled up to the
+        // last record in use that fits, rather than reading free ones.
+        if self.record_bitmap.is_some()
+            && !self.include_deleted
+            && !self.mft_stream.is_buffered(segment)
+        {
+            let count = self.allocated_span(segment);
+            self.mft_stream.fill_buffer(segment, count)?;
+        }
+
+        let record = self.read_record(segment, true)?;
+        let in_use = record.as_ref().is_some_and(|(header, _)| header.in_use);
+        if let Some(in_bitmap) = in_bitmap {
+            if in_bitmap != in_use {
+                self.allocation_mismatches
+                    .push(AllocationMismatch { segment, in_bitmap });
+            }
+        }
+        match record {
+            Some((segment_header, segment_buffer)) => {
+                self.parse_record(segment, segment_header, segment_buffer)
+            }
+            None => Ok(None),
+        }
+    }
+
+    // How many records from `segment` to read at once: up to the last one
+    // in use which fits in the buffer.
+    fn allocated_span(&self, segment: u64) -> u64 {
+        let end = (segment + self.mft_stream.buffer_capacity())
+            .min(self.mft_stream.get_file_record_segment_count());
+        let mut last = segment;
+        let mut next = segment;
+        while next < end {
+            last = next;
+            next = self.next_allocated_record(next + 1);
+        }
+        last - segment + 1
+    }
+
+    // Reads record 0 ($MFT) and turns its unnamed $DATA attribute into the
+    // list of extents making up the MFT, along with the MFT's length.
+    fn load_mft_extents(
+        &mut self,
+        follow_attribute_list: bool,
+    ) -> Result<(Vec<Extent>, u64), Error> {
+        let mut segment_buf = vec![0; self.geometry.bytes_per_file_record_segment as usize];
+        self.mft_stream
+            .read_file_record_segment(0, &mut segment_buf[..], false)?;
+        let segment_header = sys::FileRecordSegmentHeader::load(&segment_buf[..])?
+            .ok_or(Error::MftRecordNotInUse)?;
+        fix_record_with_update_sequence(&segment_header.multi_sector_header, &mut segment_buf[..])?;
+
+        let pieces = if follow_attribute_list {
+            let mut entry = MftEntry {
+                base_record_segment_idx: 0,
+                sequence_number: segment_header.sequence_number,
+                hard_link_count: segment_header.hard_link_count,
+                ..Default::default()
+            };
+            self.parse_segment(&segment_header, 0, false, &segment_buf[..], &mut entry)?;
+            entry
+                .data
+                .into_iter()
+                .filter(|data| data.name.is_none())
+                .collect()
+        } else {
+            self.find_unnamed_data(&segment_header, &segment_buf[..])?
+        };
+
+        let (pieces, _) = merge_pieces(pieces, false)?;
+        let data = pieces.first().ok_or(Error::MftHasNoExtents)?;
+        let mft_len = data.logical_size;
+
+        let mut extents = Vec::new();
+        let mut next_vcn: i64 = 0;
+        for run in data.runs.iter().flatten() {
+            extents.push(Extent {
+                min_vcn: next_vcn,
+                min_lcn: run.starting_lcn,
+                cluster_count: run.cluster_count as i64,
+            });
+            next_vcn += run.cluster_count as i64;
+        }
+
+        Ok((extents, mft_len))
+    }
+
+    // Finds the unnamed, non-resident $DATA attribute stored directly in a
+    // segment, without following any attribute list.
+    fn find_unnamed_data(
+        &self,
+        segment_header: &sys::FileRecordSegmentHeader,
+        buf: &[u8],
+    ) -> Result<Vec<sys::Data>, Error> {
+        let mut attribute_buffer = buf
+            .get(segment_header.first_attribute_offset as usize..)
+            .ok_or(Error::BadAttribute)?;
+        while attribute_buffer.len() > 4 && attribute_buffer[0..4] != [0xFF, 0xFF, 0xFF, 0xFF] {
+            let (attrib_header, record, rest) = split_attribute(attribute_buffer)?;
+
+            if attrib_header.type_code == sys::AttributeType::Data
+                && attrib_header.name_length == 0
+                && attrib_header.form_code == sys::form_codes::NON_RESIDENT
+            {
+                let (nonresident_header, _) = sys::AttributeRecordHeaderNonResident::load(
+                    &record[sys::ATTRIBUTE_RECORD_HEADER_LENGTH..],
+                )?;
+                let start_offset: usize = nonresident_header.mapping_pairs_offset.into();
+                let (_, runs) = self
+                    .read_data_run_list(record.get(start_offset..).ok_or(Error::BadAttribute)?)?;
+
+                return Ok(vec![sys::Data {
+                    name: None,
+                    lowest_vcn: nonresident_header.lowest_vcn,
+                    logical_size: nonresident_header.file_size,
+                    physical_size: nonresident_header.allocated_length,
+                    valid_data_length: nonresident_header.valid_data_length,
+                    runs: Some(runs),
+                    value: None,
+                    is_sparse: attrib_header.is_sparse,
+                    compression_unit: 0,
+                }]);
+            }
+
+            attribute_buffer = rest;
+        }
+
+        Err(Error::MftHasNoExtents)
+    }
+
     fn parse_resident_attribute(
         &mut self,
         attrib_header: &sys::AttributeRecordHeader,
         resident_header: &sys::AttributeRecordHeaderResident,
-        attribute_name: Option<OsString>,
+        attribute_name: Option<NtfsName>,
         attribute_data: &[u8],
         current_file_record_segment: u64,
         entry: &mut MftEntry,
@@ -130,10 +820,14 @@ impl MasterFileTable {
             AttributeType::Data => {
                 entry.data.push(sys::Data {
                     name: attribute_name,
+                    lowest_vcn: 0,
                     logical_size: resident_header.value_length.into(),
                     physical_size: resident_header.value_length.into(),
+                    valid_data_length: resident_header.value_length.into(),
                     runs: None,
+                    value: Some(attribute_data.to_vec()),
                     is_sparse: false, // resident DATA can't be sparse
+                    compression_unit: 0,
                 });
             }
 
@@ -145,20 +839,76 @@ impl MasterFileTable {
                 self.parse_attribute_list(attribute_data, current_file_record_segment, entry)?;
             }
 
-            type_code @ AttributeType::IndexAllocation => {
-                return Err(Error::UnsupportedResident(type_code));
+            // Only needed when the index is read, so a damaged one is noted
+            // and left for `read_index` to report.
+            AttributeType::IndexRoot => {
+                let root = index::IndexRoot::load(attribute_data, attribute_name);
+                if let Some(root) = decode_optional(entry, AttributeType::IndexRoot, root) {
+                    entry.index_roots.push(root);
+                }
+            }
+
+            AttributeType::Bitmap => entry.bitmaps.push(sys::Bitmap {
+                name: attribute_name,
+                bits: attribute_data.to_vec(),
+            }),
+
+            AttributeType::SecurityDescriptor => {
+                entry.security_descriptor = decode_optional(
+                    entry,
+                    AttributeType::SecurityDescriptor,
+                    security::SecurityDescriptor::load(attribute_data),
+                );
+            }
+
+            AttributeType::Ea => {
+                entry.extended_attributes = decode_optional(
+                    entry,
+                    AttributeType::Ea,
+                    ExtendedAttribute::load_list(attribute_data),
+                )
+                .unwrap_or_default();
             }
 
-            AttributeType::SecurityDescriptor
-            | AttributeType::LoggedUtilityStream
-            | AttributeType::Ea
-            | AttributeType::EaInformation
-            | AttributeType::ObjectId
-            | AttributeType::VolumeInformation
-            | AttributeType::VolumeName
-            | AttributeType::IndexRoot
-            | AttributeType::Bitmap
-            | AttributeType::ReparsePoint => {}
+            AttributeType::EaInformation => {
+                entry.ea_information = decode_optional(
+                    entry,
+                    AttributeType::EaInformation,
+                    EaInformation::load(attribute_data),
+                );
+            }
+
+            AttributeType::ObjectId => {
+                entry.object_id = decode_optional(
+                    entry,
+                    AttributeType::ObjectId,
+                    ObjectId::load(attribute_data),
+                );
+            }
+
+            AttributeType::VolumeName => {
+                entry.volume_name = Some(NtfsName::load(attribute_data));
+            }
+
+            AttributeType::VolumeInformation => {
+                entry.volume_information = decode_optional(
+                    entry,
+                    AttributeType::VolumeInformation,
+                    VolumeInformation::load(attribute_data),
+                );
+            }
+
+            AttributeType::LoggedUtilityStream
+            // Index blocks are never resident; ignore one if we find it.
+            | AttributeType::IndexAllocation => {}
+
+            AttributeType::ReparsePoint => {
+                entry.reparse_point = decode_optional(
+                    entry,
+                    AttributeType::ReparsePoint,
+                    ReparsePoint::load(attribute_data),
+                );
+            }
         };
 
         Ok(())
@@ -168,7 +918,7 @@ impl MasterFileTable {
         &mut self,
         attrib_header: &sys::AttributeRecordHeader,
         non_resident_header: &sys::AttributeRecordHeaderNonResident,
-        attribute_name: Option<OsString>,
+        attribute_name: Option<NtfsName>,
         current_file_record_segment: u64,
         data_runs: &[u8],
         entry: &mut MftEntry,
@@ -182,40 +932,129 @@ impl MasterFileTable {
             | type_code @ AttributeType::VolumeInformation
             | type_code @ AttributeType::EaInformation
             | type_code @ AttributeType::FileName
-            | type_code @ AttributeType::ReparsePoint
             | type_code @ AttributeType::IndexRoot => {
                 return Err(Error::UnsupportedNonResident(type_code))
             }
 
             AttributeType::Data => entry.data.push(sys::Data {
                 name: attribute_name,
+                lowest_vcn: non_resident_header.lowest_vcn,
                 logical_size: non_resident_header.file_size,
                 physical_size: non_resident_header.allocated_length,
+                valid_data_length: non_resident_header.valid_data_length,
                 runs: {
-                    let (_, runs) = self.read_data_run_list(data_runs);
+                    let (_, runs) = self.read_data_run_list(data_runs)?;
                     Some(runs)
                 },
+                value: None,
                 is_sparse: attrib_header.is_sparse,
+                compression_unit: if attrib_header.is_compressed {
+                    non_resident_header.compression_unit
+                } else {
+                    0
+                },
             }),
 
+            // Reparse data can be up to 16KB, so it's occasionally pushed out
+            // of the record. Without a volume it's left unread.
+            AttributeType::ReparsePoint if !self.has_volume => {}
+
+            AttributeType::ReparsePoint => {
+                let reparse_point = self
+                    .read_data_run_list(data_runs)
+                    .and_then(|(total_size, data_runs)| {
+                        self.read_non_resident_data(total_size, data_runs)
+                    })
+                    .and_then(|data| {
+                        let len = (non_resident_header.file_size as usize).min(data.len());
+                        ReparsePoint::load(&data[..len])
+                    });
+                entry.reparse_point =
+                    decode_optional(entry, AttributeType::ReparsePoint, reparse_point);
+            }
+
+            AttributeType::AttributeList if !self.has_volume => {
+                entry.has_unresolved_attribute_list = true;
+            }
+
             AttributeType::AttributeList => {
                 // We actually need to go read this
-                let (total_size, data_runs) = self.read_data_run_list(data_runs);
+                let (total_size, data_runs) = self.read_data_run_list(data_runs)?;
                 let data = self.read_non_resident_data(total_size, data_runs)?;
-                debug_assert_eq!(data.len(), non_resident_header.allocated_length as usize);
+                let list = usize::try_from(non_resident_header.valid_data_length)
+                    .ok()
+                    .and_then(|len| data.get(..len))
+                    .ok_or(Error::BadAttribute)?;
 
-                self.parse_attribute_list(
-                    &data[..non_resident_header.valid_data_length as usize],
-                    current_file_record_segment,
-                    entry,
-                )?;
+                self.parse_attribute_list(list, current_file_record_segment, entry)?;
             }
 
-            AttributeType::SecurityDescriptor
-            | AttributeType::LoggedUtilityStream
-            | AttributeType::Ea
-            | AttributeType::Bitmap
-            | AttributeType::IndexAllocation => {}
+            AttributeType::IndexAllocation => entry.index_allocations.push(sys::Data {
+                name: attribute_name,
+                lowest_vcn: non_resident_header.lowest_vcn,
+                logical_size: non_resident_header.file_size,
+                physical_size: non_resident_header.allocated_length,
+                valid_data_length: non_resident_header.valid_data_length,
+                runs: {
+                    let (_, runs) = self.read_data_run_list(data_runs)?;
+                    Some(runs)
+                },
+                value: None,
+                is_sparse: attrib_header.is_sparse,
+                compression_unit: 0,
+            }),
+
+            AttributeType::Bitmap if !self.has_volume => {}
+
+            AttributeType::Bitmap => {
+                let bits =
+                    self.read_data_run_list(data_runs)
+                        .and_then(|(total_size, data_runs)| {
+                            self.read_non_resident_data(total_size, data_runs)
+                        });
+                if let Some(mut bits) = decode_optional(entry, AttributeType::Bitmap, bits) {
+                    bits.truncate(non_resident_header.file_size as usize);
+                    entry.bitmaps.push(sys::Bitmap {
+                        name: attribute_name,
+                        bits,
+                    });
+                }
+            }
+
+            AttributeType::SecurityDescriptor if !self.has_volume => {}
+
+            AttributeType::SecurityDescriptor => {
+                let descriptor = self
+                    .read_data_run_list(data_runs)
+                    .and_then(|(total_size, data_runs)| {
+                        self.read_non_resident_data(total_size, data_runs)
+                    })
+                    .and_then(|data| {
+                        let len = (non_resident_header.file_size as usize).min(data.len());
+                        security::SecurityDescriptor::load(&data[..len])
+                    });
+                entry.security_descriptor =
+                    decode_optional(entry, AttributeType::SecurityDescriptor, descriptor);
+            }
+
+            // EAs can take up to 64KB, too much to always fit in the record.
+            AttributeType::Ea if !self.has_volume => {}
+
+            AttributeType::Ea => {
+                let attributes = self
+                    .read_data_run_list(data_runs)
+                    .and_then(|(total_size, data_runs)| {
+                        self.read_non_resident_data(total_size, data_runs)
+                    })
+                    .and_then(|data| {
+                        let len = (non_resident_header.file_size as usize).min(data.len());
+                        ExtendedAttribute::load_list(&data[..len])
+                    });
+                entry.extended_attributes =
+                    decode_optional(entry, AttributeType::Ea, attributes).unwrap_or_default();
+            }
+
+            AttributeType::LoggedUtilityStream => {}
         };
 
         Ok(())
@@ -229,9 +1068,12 @@ impl MasterFileTable {
         buf: &[u8],
         entry: &mut MftEntry,
     ) -> Result<bool, Error> {
+        // Base records have an all-zero base reference. Extensions of $MFT
+        // itself point at segment 0, so the sequence number has to be checked too.
         if !allow_extensions
             && ((segment_header.base_file_record_segment.segment_number_low != 0)
-                || (segment_header.base_file_record_segment.segment_number_high != 0))
+                || (segment_header.base_file_record_segment.segment_number_high != 0)
+                || (segment_header.base_file_record_segment.sequence_number != 0))
         {
             // This is an extension of a previous record; skip it.
             // #[cfg(debug_assertions)]
@@ -241,32 +1083,38 @@ impl MasterFileTable {
             return Ok(false);
         }
 
-        let mut attribute_buffer = &buf[segment_header.first_attribute_offset as usize..];
+        let mut attribute_buffer = buf
+            .get(segment_header.first_attribute_offset as usize..)
+            .ok_or(Error::BadAttribute)?;
         loop {
-            let attrib_header = sys::AttributeRecordHeader::load(attribute_buffer)?;
+            let (attrib_header, record, rest) = split_attribute(attribute_buffer)?;
 
             // Attribute names are WTF-16 but the maximum length is 255 *bytes*.
             let attribute_name = match attrib_header.name_length {
                 0 => None,
                 pseudo_code_points => {
-                    let name_start: usize = attrib_header.name_offset.try_into().unwrap();
+                    let name_start: usize = attrib_header.name_offset.into();
                     let name_end: usize = name_start + (2 * pseudo_code_points) as usize;
-                    let name_buffer = &attribute_buffer[name_start..name_end];
-                    Some(parse_string(name_buffer))
+                    let name_buffer = record
+                        .get(name_start..name_end)
+                        .ok_or(Error::BadAttribute)?;
+                    Some(NtfsName::load(name_buffer))
                 }
             };
 
             match attrib_header.form_code {
                 sys::form_codes::RESIDENT => {
                     let (resident_header, _) = sys::AttributeRecordHeaderResident::load(
-                        &attribute_buffer[sys::ATTRIBUTE_RECORD_HEADER_LENGTH..],
-                    );
+                        &record[sys::ATTRIBUTE_RECORD_HEADER_LENGTH..],
+                    )?;
                     // The data is resident, so we don't need additional reads to get it.
                     // value_offset measures from the beginning of the attribute record.
-                    let start_offset: usize = resident_header.value_offset.try_into().unwrap();
+                    let start_offset: usize = resident_header.value_offset.into();
                     let value_length: usize = resident_header.value_length.try_into().unwrap();
-                    let end_offset = start_offset + value_length;
-                    let attribute_data = &attribute_buffer[start_offset..end_offset];
+                    let attribute_data = record
+                        .get(start_offset..)
+                        .and_then(|value| value.get(..value_length))
+                        .ok_or(Error::BadAttribute)?;
                     self.parse_resident_attribute(
                         &attrib_header,
                         &resident_header,
@@ -279,13 +1127,11 @@ impl MasterFileTable {
 
                 sys::form_codes::NON_RESIDENT => {
                     let (nonresident_header, _) = sys::AttributeRecordHeaderNonResident::load(
-                        &attribute_buffer[sys::ATTRIBUTE_RECORD_HEADER_LENGTH..],
-                    );
+                        &record[sys::ATTRIBUTE_RECORD_HEADER_LENGTH..],
+                    )?;
 
-                    let start_offset: usize =
-                        nonresident_header.mapping_pairs_offset.try_into().unwrap();
-                    let end_offset: usize = attrib_header.record_length.try_into().unwrap();
-                    let data_runs = &attribute_buffer[start_offset..end_offset];
+                    let start_offset: usize = nonresident_header.mapping_pairs_offset.into();
+                    let data_runs = record.get(start_offset..).ok_or(Error::BadAttribute)?;
                     self.parse_non_resident_attribute(
                         &attrib_header,
                         &nonresident_header,
@@ -301,7 +1147,7 @@ impl MasterFileTable {
                 }
             }
 
-            attribute_buffer = &attribute_buffer[attrib_header.record_length.try_into().unwrap()..];
+            attribute_buffer = rest;
             if attribute_buffer.len() <= 4 || attribute_buffer[0..4] == [0xFF, 0xFF, 0xFF, 0xFF] {
                 break;
             }
@@ -316,7 +1162,7 @@ impl MasterFileTable {
         current_file_record_segment: u64,
         entry: &mut MftEntry,
     ) -> Result<(), Error> {
-        let mut segment_buf = vec![0; self.bytes_per_file_record_segment as usize];
+        let mut segment_buf = vec![0; self.geometry.bytes_per_file_record_segment as usize];
         let mut record_segments = HashSet::new();
 
         while !buf.is_empty() {
@@ -324,14 +1170,15 @@ impl MasterFileTable {
 
             let record_len = {
                 let mut len = sys::MIN_ATTRIBUTE_LIST_ENTRY_SIZE;
-                let name_len_wtf16: usize = attrib.name_length.try_into().unwrap();
+                let name_len_wtf16: usize = attrib.name_length.into();
                 len += 2 * name_len_wtf16;
 
                 // Round up to the nearest multiple of 8
-                ((len + 7) / 8) * 8
+                len.div_ceil(8) * 8
             };
 
-            buf = &buf[record_len..];
+            // A truncated last entry just ends the list.
+            buf = buf.get(record_len..).unwrap_or_default();
 
             let segment_to_read = attrib.segment_reference.into();
             if segment_to_read != current_file_record_segment {
@@ -346,9 +1193,23 @@ impl MasterFileTable {
                 &mut segment_buf[..],
                 false, // use_cache
             )?;
-            let segment_header = sys::FileRecordSegmentHeader::load(&segment_buf[..])?
-                .ok_or(Error::AttributeListPointedToUnusedFileRecord)?;
-            self.fix_record_with_update_sequence(
+            let segment_header = sys::FileRecordSegmentHeader::load_any(&segment_buf[..])?;
+            if !segment_header.in_use {
+                if !entry.is_deleted {
+                    return Err(Error::AttributeListPointedToUnusedFileRecord);
+                }
+                // A deleted file's extension records are freed along with it,
+                // but may have been reused by another file since.
+                if u64::from(segment_header.base_file_record_segment) != current_file_record_segment
+                {
+                    entry.has_unresolved_attribute_list = true;
+                    continue;
+                }
+            } else if entry.is_deleted {
+                entry.has_unresolved_attribute_list = true;
+                continue;
+            }
+            fix_record_with_update_sequence(
                 &segment_header.multi_sector_header,
                 &mut segment_buf[..],
             )?;
@@ -365,45 +1226,7 @@ impl MasterFileTable {
         Ok(())
     }
 
-    // The data must start on a sector boundary (will be the case for all file record segments).
-    fn fix_record_with_update_sequence(
-        &self,
-        header: &sys::MultiSectorHeader,
-        data: &mut [u8],
-    ) -> Result<(), Error> {
-        // First, find the update sequence array
-        let start_offset: usize = header.update_sequence_array_offset.try_into().unwrap();
-
-        let end_offset = {
-            let size: usize = header.update_sequence_array_size.try_into().unwrap();
-            start_offset + (size * 2)
-        };
-
-        let (before, after) = data.split_at_mut(end_offset);
-
-        let update_sequence_array = &before[start_offset..];
-        let update_sequence_number = &update_sequence_array[0..2];
-
-        for (sector, replacement_sequence) in update_sequence_array[2..].chunks_exact(2).enumerate()
-        {
-            // In each sector, the last two bytes should equal the sequence number
-            // and should be replaced with the bytes from the array.
-            let offset = ((1 + sector) * self.bytes_per_sector as usize) - 2 - before.len();
-
-            if after[offset] != update_sequence_number[0]
-                || after[offset + 1] != update_sequence_number[1]
-            {
-                return Err(Error::UpdateSequenceValidationFailed);
-            }
-
-            after[offset] = replacement_sequence[0];
-            after[offset + 1] = replacement_sequence[1];
-        }
-
-        Ok(())
-    }
-
-    fn read_data_run_list(&self, data_runs: &[u8]) -> (u64, Vec<sys::DataRun>) {
+    fn read_data_run_list(&self, data_runs: &[u8]) -> Result<(u64, Vec<sys::DataRun>), Error> {
         let mut runs = Vec::new();
         let mut remaining_data = data_runs;
         let mut last_offset: i64 = 0;
@@ -416,6 +1239,12 @@ impl MasterFileTable {
 
             // Next, "length_size" bytes point to the length of the run, in clusters.
             // This can be anything from 0 -> 15 bytes. We support 0 - 8.
+            if length_size > 8
+                || offset_size > 8
+                || remaining_data.len() < usize::from(length_size + offset_size)
+            {
+                return Err(Error::BadAttribute);
+            }
             let length = parse_runlist_unsigned_int(remaining_data, length_size);
             remaining_data = &remaining_data[length_size.into()..];
 
@@ -424,210 +1253,213 @@ impl MasterFileTable {
             let offset_rel = parse_runlist_signed_int(remaining_data, offset_size);
             remaining_data = &remaining_data[offset_size.into()..];
 
-            let offset = last_offset.checked_add(offset_rel).unwrap(); // panic on over/underflow
+            let offset = last_offset
+                .checked_add(offset_rel)
+                .ok_or(Error::BadAttribute)?;
 
             runs.push(sys::DataRun {
                 starting_lcn: offset,
                 cluster_count: length,
+                // Sparse runs leave out the offset altogether.
+                is_sparse: offset_size == 0,
             });
 
             last_offset = offset;
-            total_size += length * self.bytes_per_cluster;
+            total_size = length
+                .checked_mul(self.geometry.bytes_per_cluster)
+                .and_then(|size| total_size.checked_add(size))
+                .ok_or(Error::BadAttribute)?;
         }
-        (total_size, runs)
+        Ok((total_size, runs))
     }
 
+    // Reads the whole of a non-resident value. The runs may come from a
+    // damaged or freed record, so they have to fit on the volume before
+    // anything is allocated or read for them.
     fn read_non_resident_data(
         &mut self,
         total_size: u64,
         data_runs: Vec<sys::DataRun>,
     ) -> Result<Vec<u8>, Error> {
-        let mut buffer = vec![0; total_size.try_into().unwrap()];
+        let volume_clusters = self.mft_stream.volume_len() / self.geometry.bytes_per_cluster;
+        if total_size > volume_clusters * self.geometry.bytes_per_cluster {
+            return Err(Error::BadAttribute);
+        }
+        let mut buffer = vec![0; total_size.try_into().map_err(|_| Error::BadAttribute)?];
         let mut cur_buf_offset: usize = 0;
 
         for run in data_runs {
             let end_offset: usize =
-                cur_buf_offset + (run.cluster_count * self.bytes_per_cluster) as usize;
-            self.mft_stream.read_clusters(
-                run.starting_lcn as u64,
-                run.cluster_count,
-                &mut buffer[cur_buf_offset..end_offset],
-                false, // use_cache
-            )?;
+                cur_buf_offset + (run.cluster_count * self.geometry.bytes_per_cluster) as usize;
+            // Sparse runs are left as zeros.
+            if !run.is_sparse {
+                let lcn = u64::try_from(run.starting_lcn)
+                    .ok()
+                    .filter(|lcn| {
+                        lcn.checked_add(run.cluster_count)
+                            .is_some_and(|end| end <= volume_clusters)
+                    })
+                    .ok_or(Error::BadAttribute)?;
+                self.mft_stream.read_clusters(
+                    lcn,
+                    run.cluster_count,
+                    &mut buffer[cur_buf_offset..end_offset],
+                    false, // use_cache
+                )?;
+            }
 
-            cur_buf_offset += (self.bytes_per_cluster * run.cluster_count) as usize;
+            cur_buf_offset += (self.geometry.bytes_per_cluster * run.cluster_count) as usize;
         }
 
         Ok(buffer)
     }
 }
-impl Iterator for MasterFileTable {
+impl<R: VolumeReader> Iterator for MasterFileTable<R> {
     type Item = Result<MftEntry, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut segment_buffer = vec![0; self.bytes_per_file_record_segment as usize];
-
         // We loop until we read a record that's in use and is not an extension of a previous one.
         loop {
-            if self.current_file_record_segment >= self.mft_stream.get_file_record_segment_count() {
+            let mut segment = self.current_file_record_segment;
+            if !self.include_deleted {
+                segment = self.next_allocated_record(segment);
+            }
+            if segment >= self.mft_stream.get_file_record_segment_count() {
+                self.current_file_record_segment = segment;
                 break None;
             }
+            self.current_file_record_segment = segment + 1;
 
-            match self.mft_stream.read_file_record_segment(
-                self.current_file_record_segment,
-                &mut segment_buffer[..],
-                true, // use_cache
-            ) {
-                Ok(_) => {}
+            match self.read_next_record(segment) {
+                Ok(Some(entry)) => break Some(Ok(entry)),
+                Ok(None) => continue,
                 Err(err) => break Some(Err(err)),
             }
+        }
+    }
+}
 
-            // If the buffer's header is 0's instead of "FILE", just skip
-            if segment_buffer.iter().take(4).all(|x| *x == 0) {
-                // #[cfg(debug_assertions)]
-                // {
-                //     println!(
-                //         "Skipping empty record: {}",
-                //         self.current_file_record_segment
-                //     );
-                // }
+// Splits the attribute at the front of `buf` into its header, the whole
+// record, and what follows it. The record's length is checked before
+// anything else in it is trusted.
+fn split_attribute(buf: &[u8]) -> Result<(sys::AttributeRecordHeader, &[u8], &[u8]), Error> {
+    let header = sys::AttributeRecordHeader::load(buf)?;
+    let length = header.record_length as usize;
+    if length < sys::ATTRIBUTE_RECORD_HEADER_LENGTH
+        || !length.is_multiple_of(8)
+        || length > buf.len()
+    {
+        return Err(Error::BadAttribute);
+    }
+    let (record, rest) = buf.split_at(length);
+    Ok((header, record, rest))
+}
 
-                self.current_file_record_segment += 1;
+// Keeps the value of an optional attribute if it could be decoded. If not,
+// the attribute is noted as bad on the entry and left out.
+fn decode_optional<T>(
+    entry: &mut MftEntry,
+    type_code: sys::AttributeType,
+    value: Result<T, Error>,
+) -> Option<T> {
+    match value {
+        Ok(value) => Some(value),
+        Err(_) => {
+            entry.bad_attributes.push(type_code);
+            None
+        }
+    }
+}
+
+// Large non-resident attributes are split across several records when their
+// runs don't fit in one, each piece covering the VCNs after the last. This
+// joins the pieces of each attribute back together; only the first piece has
+// the attribute's sizes. Pieces which don't follow on are an error, unless
+// `allow_gaps`, when they're dropped along with anything after them. Returns
+// the attributes, and whether every piece was used.
+fn merge_pieces(
+    mut pieces: Vec<sys::Data>,
+    allow_gaps: bool,
+) -> Result<(Vec<sys::Data>, bool), Error> {
+    // A stable sort, so attributes stay in the order they were found.
+    pieces.sort_by_key(|piece| piece.lowest_vcn);
+    let mut attributes: Vec<sys::Data> = Vec::new();
+    let mut complete = true;
+    for piece in pieces {
+        let attribute = attributes
+            .iter_mut()
+            .find(|attribute| attribute.name == piece.name);
+        let next_vcn = match attribute {
+            None if piece.lowest_vcn == 0 => {
+                attributes.push(piece);
                 continue;
             }
-
-            let segment_header = match sys::FileRecordSegmentHeader::load(&segment_buffer[..]) {
-                Ok(Some(header)) => header,
-                Ok(None) => {
-                    // #[cfg(debug_assertions)]
-                    // {
-                    //     println!(
-                    //         "Skipping non-used record: {}",
-                    //         self.current_file_record_segment
-                    //     );
-                    // }
-
-                    self.current_file_record_segment += 1;
-                    continue;
+            None => 0,
+            Some(attribute) => {
+                let next_vcn = attribute.lowest_vcn
+                    + attribute
+                        .runs
+                        .iter()
+                        .flatten()
+                        .map(|run| run.cluster_count)
+                        .sum::<u64>();
+                match (&mut attribute.runs, piece.runs) {
+                    (Some(runs), Some(more)) if piece.lowest_vcn == next_vcn => {
+                        runs.extend(more);
+                        continue;
+                    }
+                    _ => next_vcn,
                 }
-                Err(err) => break Some(Err(err)),
-            };
-
-            // Use the update sequence array to validate and correct the buffer.
-            match self.fix_record_with_update_sequence(
-                &segment_header.multi_sector_header,
-                &mut segment_buffer[..],
-            ) {
-                Ok(_) => {}
-                Err(err) => break Some(Err(err)),
             }
-
-            let mut entry = MftEntry {
-                base_record_segment_idx: self.current_file_record_segment,
-                hard_link_count: segment_header.hard_link_count,
-                // children: Default::default(),
-                data: Default::default(),
-                filename: Default::default(),
-                standard_information: Default::default(),
-            };
-
-            match self.parse_segment(
-                &segment_header,
-                self.current_file_record_segment,
-                false, // allow extensions
-                &segment_buffer[..],
-                &mut entry,
-            ) {
-                Ok(true) => {
-                    self.current_file_record_segment += 1;
-                    entry.filename.sort_by_key(|f| f.filename_type.clone());
-                    break Some(Ok(entry));
-                }
-                Ok(false) => {
-                    self.current_file_record_segment += 1;
-                    continue;
-                }
-                Err(err) => break Some(Err(err)),
-            };
+        };
+        if !allow_gaps {
+            return Err(Error::DiscontiguousAttribute(next_vcn));
         }
+        complete = false;
     }
+    Ok((attributes, complete))
 }
 
-fn get_ntfs_volume_data(
-    handle: &SafeHandle,
-) -> Result<(NTFS_VOLUME_DATA_BUFFER, NTFS_EXTENDED_VOLUME_DATA), Error> {
-    let mut result_size = 0;
-    // Build the buffer out of u64s to guarantee 8-byte alignment.
-    let mut buf: Vec<u64> = vec![0; NTFS_VOLUME_DATA_BUFFER_SIZE / 8];
-    let success = unsafe {
-        DeviceIoControl(
-            **handle,
-            FSCTL_GET_NTFS_VOLUME_DATA,
-            ptr::null_mut(),
-            0,
-            buf.as_mut_ptr() as *mut c_void,
-            (buf.len() * 8).try_into().unwrap(),
-            &mut result_size,
-            ptr::null_mut(),
-        )
-    };
-    if success == 0 {
-        let err = unsafe { ehapi::GetLastError() };
-        return Err(Error::GetNtfsVolumeDataFailed(err));
-    }
-
-    if result_size != (buf.len() as u32) * 8 {
-        return Err(Error::GetNtfsVolumeDataBadSize);
+// Validates a multi-sector record (file record segments, index buffers) against its
+// update sequence array and puts back the bytes that the array stands in for.
+// `data` must hold exactly one whole record.
+pub fn fix_record_with_update_sequence(
+    header: &sys::MultiSectorHeader,
+    data: &mut [u8],
+) -> Result<(), Error> {
+    // First, find the update sequence array
+    let start_offset: usize = header.update_sequence_array_offset.into();
+    let size: usize = header.update_sequence_array_size.into();
+    let end_offset = start_offset + (size * 2);
+
+    // The array holds the sequence number followed by one entry per stride of the
+    // record. The stride is 512 bytes in practice, whatever the physical sector size.
+    if size < 2 || end_offset > data.len() || !data.len().is_multiple_of(size - 1) {
+        return Err(Error::UpdateSequenceValidationFailed);
     }
+    let stride = data.len() / (size - 1);
 
-    // Parse the results into the two structs. NTFS_VOLUME_DATA_BUFFER always
-    // comes first.
-    let (volume_data_buffer_bytes, extended_volume_data_bytes) =
-        buf.split_at(mem::size_of::<NTFS_VOLUME_DATA_BUFFER>() / 8);
+    let (before, after) = data.split_at_mut(end_offset);
 
-    let volume_data_buffer = volume_data_buffer_bytes.as_ptr() as *const NTFS_VOLUME_DATA_BUFFER;
-    let extended_volume_data =
-        extended_volume_data_bytes.as_ptr() as *const NTFS_EXTENDED_VOLUME_DATA;
+    let update_sequence_array = &before[start_offset..];
+    let update_sequence_number = &update_sequence_array[0..2];
 
-    // We want to be very explicit about this clone - these pointers don't currently
-    // have lifetimes known to rustc, so we have to copy the data on return.
-    #[allow(clippy::clone_on_copy)]
-    Ok((
-        unsafe { *volume_data_buffer }.clone(),
-        unsafe { *extended_volume_data }.clone(),
-    ))
-}
+    for (sector, replacement_sequence) in update_sequence_array[2..].chunks_exact(2).enumerate() {
+        // In each sector, the last two bytes should equal the sequence number
+        // and should be replaced with the bytes from the array.
+        let offset = ((1 + sector) * stride) - 2 - before.len();
 
-fn get_mft_handle(volume_path: &OsStr) -> Result<SafeHandle, Error> {
-    let filename = {
-        let mut path = Path::new(volume_path).to_path_buf();
-        path.push("$MFT");
-        path
-    };
-    let filename = {
-        let mut filename = filename.as_os_str().encode_wide().collect::<Vec<_>>();
-        // Add a null terminator.
-        filename.push(0);
-        filename
-    };
-    let handle = unsafe {
-        CreateFileW(
-            filename.as_ptr(),
-            0, // no permissions - we won't be reading or writing directly with this handle
-            FILE_SHARE_WRITE | FILE_SHARE_READ | FILE_SHARE_DELETE,
-            ptr::null_mut(), // security attributes
-            OPEN_EXISTING,
-            FILE_FLAG_BACKUP_SEMANTICS,
-            ptr::null_mut(), // template file
-        )
-    };
+        if after[offset] != update_sequence_number[0]
+            || after[offset + 1] != update_sequence_number[1]
+        {
+            return Err(Error::UpdateSequenceValidationFailed);
+        }
 
-    if handle != INVALID_HANDLE_VALUE {
-        Ok(SafeHandle { handle })
-    } else {
-        let err = unsafe { ehapi::GetLastError() };
-        Err(Error::OpenMftFailed(err))
+        after[offset] = replacement_sequence[0];
+        after[offset + 1] = replacement_sequence[1];
     }
+
+    Ok(())
 }
 
 fn parse_runlist_unsigned_int(data: &[u8], width: u8) -> u64 {
@@ -664,12 +1496,582 @@ fn parse_runlist_signed_int(data: &[u8], width: u8) -> i64 {
     extend_sign(parse_runlist_unsigned_int(data, width), width.into())
 }
 
-fn parse_string(utf16data: &[u8]) -> OsString {
-    // Since we have no guarantees about the alignment of the buffer,
-    // we can't safely cast the array of u8's to an array of u16's.
-    let mut filename = vec![0u16; utf16data.len() / 2];
-    for i in 0..filename.len() {
-        filename[i] = u16::from_le_bytes([utf16data[i * 2], utf16data[(i * 2) + 1]]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        self, namespaces, type_codes, Attribute, ImageBuilder, ListEntry, MemoryReader, Record,
+        Run, ROOT_RECORD,
+    };
+
+    fn entries(image: MemoryReader) -> Vec<MftEntry> {
+        MasterFileTable::open(image)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    fn entry(entries: &[MftEntry], record: u64) -> &MftEntry {
+        entries
+            .iter()
+            .find(|e| e.base_record_segment_idx == record)
+            .unwrap()
+    }
+
+    #[test]
+    fn opens_minimal_volume() {
+        let mut builder = ImageBuilder::new();
+        let file = builder.add_file(ROOT_RECORD, "hello.txt", b"hello");
+        let mft = MasterFileTable::open(builder.build()).unwrap();
+        assert_eq!(mft.bytes_per_cluster(), 4096);
+        assert_eq!(mft.entry_count(), 20);
+
+        let entries = mft.collect::<Result<Vec<_>, _>>().unwrap();
+        let records = entries
+            .iter()
+            .map(|e| e.base_record_segment_idx)
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![0, ROOT_RECORD, file]);
+
+        let mft_entry = entry(&entries, 0);
+        assert_eq!(mft_entry.get_best_filename(), Some(NtfsName::from("$MFT")));
+        assert_eq!(mft_entry.data[0].logical_size, 20 * 1024);
+
+        let file = entry(&entries, file);
+        assert_eq!(file.get_best_filename(), Some(NtfsName::from("hello.txt")));
+        assert_eq!(
+            file.parents(),
+            vec![sys::FileReference::new(ROOT_RECORD, 1)]
+        );
+        assert_eq!(file.data[0].logical_size, 5);
+        assert!(file.data[0].runs.is_none());
+    }
+
+    #[test]
+    fn skips_unused_and_extension_records() {
+        let mut builder = ImageBuilder::new();
+        let deleted = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "gone", namespaces::WIN32))
+                .not_in_use(),
+        );
+        let base = builder.add_file(ROOT_RECORD, "base", b"");
+        let extension = builder.add_record(
+            Record::extension_of(base).with(Attribute::resident_data(Some("extra"), b"x")),
+        );
+
+        let records = entries(builder.build())
+            .iter()
+            .map(|e| e.base_record_segment_idx)
+            .collect::<Vec<_>>();
+        assert!(!records.contains(&deleted));
+        assert!(records.contains(&base));
+        assert!(!records.contains(&extension));
+    }
+
+    #[test]
+    fn deleted_records_on_request() {
+        let mut builder = ImageBuilder::new();
+        let deleted = builder.add_record(
+            Record::new()
+                .with_sequence_number(2)
+                .with(Attribute::file_name(ROOT_RECORD, "gone", namespaces::WIN32))
+                .with(Attribute::resident_data(None, b"leftovers"))
+                .not_in_use(),
+        );
+        let base = builder.add_file(ROOT_RECORD, "base", b"");
+        let unused_extension = builder.add_record(
+            Record::extension_of(deleted)
+                .with(Attribute::resident_data(Some("extra"), b"x"))
+                .not_in_use(),
+        );
+
+        let entries = MasterFileTable::open(builder.build())
+            .unwrap()
+            .with_deleted_records(true)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let gone = entry(&entries, deleted);
+        assert!(gone.is_deleted);
+        assert_eq!(gone.sequence_number, 2);
+        assert_eq!(gone.get_best_filename(), Some(NtfsName::from("gone")));
+        assert_eq!(gone.data[0].logical_size, 9);
+        assert!(!entry(&entries, base).is_deleted);
+        assert!(!entries
+            .iter()
+            .any(|e| e.base_record_segment_idx == unused_extension));
+    }
+
+    #[test]
+    fn skips_freed_records_with_bad_attribute_lengths() {
+        let mut builder = ImageBuilder::new();
+        let freed = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "gone", namespaces::WIN32))
+                .not_in_use(),
+        );
+        let file = builder.add_file(ROOT_RECORD, "kept", b"");
+        let image = builder.build();
+        let record_offset = (test_support::MFT_START_LCN * 4096 + freed * 1024) as usize;
+        let first_attribute = u16::from_le_bytes(
+            image.bytes()[record_offset + 20..record_offset + 22]
+                .try_into()
+                .unwrap(),
+        );
+        let length_offset = record_offset + usize::from(first_attribute) + 4;
+
+        // Zero would loop forever; the others are shorter than a header,
+        // unaligned, or run past the record.
+        for length in [0u32, 8, 0x4C, 0x10000] {
+            let mut bytes = image.bytes().to_vec();
+            bytes[length_offset..length_offset + 4].copy_from_slice(&length.to_le_bytes());
+            let entries = MasterFileTable::open(MemoryReader::new(bytes.clone()))
+                .unwrap()
+                .with_deleted_records(true)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert!(!entries.iter().any(|e| e.base_record_segment_idx == freed));
+            assert_eq!(
+                entry(&entries, file).get_best_filename(),
+                Some(NtfsName::from("kept"))
+            );
+
+            // The same damage in a live record is an error.
+            bytes[record_offset + 22] |= 1;
+            let result = MasterFileTable::open(MemoryReader::new(bytes))
+                .unwrap()
+                .with_deleted_records(true)
+                .collect::<Result<Vec<_>, _>>();
+            assert!(matches!(result, Err(Error::BadAttribute)));
+        }
+    }
+
+    #[test]
+    fn skips_freed_records_with_bad_runlists() {
+        let mut builder = ImageBuilder::new();
+        // An attribute list far bigger than the volume, which is read in
+        // full before it's parsed.
+        let oversized = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "huge", namespaces::WIN32))
+                .with(Attribute::non_resident(
+                    type_codes::ATTRIBUTE_LIST,
+                    None,
+                    vec![Run::at(1, 1 << 40)],
+                    64,
+                ))
+                .not_in_use(),
+        );
+        let truncated = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(ROOT_RECORD, "cut", namespaces::WIN32))
+                .with(Attribute::non_resident_data(None, vec![Run::at(1, 1)], 10))
+                .not_in_use(),
+        );
+        let file = builder.add_file(ROOT_RECORD, "kept", b"");
+        let mut bytes = builder.build().bytes().to_vec();
+
+        // Claim 8-byte length and offset fields, running off the attribute.
+        let record_offset = (test_support::MFT_START_LCN * 4096 + truncated * 1024) as usize;
+        let mapping_pairs = bytes[record_offset..record_offset + 1024]
+            .windows(3)
+            .position(|run| run == [0x11, 0x01, 0x01])
+            .unwrap();
+        bytes[record_offset + mapping_pairs] = 0x88;
+
+        let entries = MasterFileTable::open(MemoryReader::new(bytes))
+            .unwrap()
+            .with_deleted_records(true)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(!entries
+            .iter()
+            .any(|e| e.base_record_segment_idx == oversized
+                || e.base_record_segment_idx == truncated));
+        assert!(!entry(&entries, file).is_deleted);
+    }
+
+    #[test]
+    fn skips_records_free_in_bitmap() {
+        let mut builder = ImageBuilder::new();
+        let file = builder.add_file(ROOT_RECORD, "file", b"");
+        builder.set_record(
+            20,
+            Record::new().with(Attribute::file_name(
+                ROOT_RECORD,
+                "hidden",
+                namespaces::WIN32,
+            )),
+        );
+        builder.set_bitmap_bit(20, false);
+        builder.set_record(
+            30,
+            Record::new()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "freed",
+                    namespaces::WIN32,
+                ))
+                .not_in_use(),
+        );
+        builder.set_bitmap_bit(30, true);
+        builder.set_record(
+            50,
+            Record::new().with(Attribute::file_name(ROOT_RECORD, "last", namespaces::WIN32)),
+        );
+        let image = builder.build();
+
+        let mut mft = MasterFileTable::open(MemoryReader::new(image.bytes().to_vec())).unwrap();
+        assert_eq!(mft.next_allocated_record(file + 1), 30);
+        assert_eq!(mft.allocated_span(file), 50 - file + 1);
+        let records = mft
+            .by_ref()
+            .map(|entry| entry.unwrap().base_record_segment_idx)
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![0, ROOT_RECORD, file, 50]);
+        assert_eq!(
+            mft.allocation_mismatches(),
+            [AllocationMismatch {
+                segment: 30,
+                in_bitmap: true
+            }]
+        );
+
+        // Reading every record finds both.
+        let mut mft = MasterFileTable::open(image)
+            .unwrap()
+            .with_deleted_records(true);
+        let records = mft
+            .by_ref()
+            .map(|entry| entry.unwrap().base_record_segment_idx)
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![0, ROOT_RECORD, file, 20, 30, 50]);
+        let mismatches = mft
+            .allocation_mismatches()
+            .iter()
+            .map(|mismatch| (mismatch.segment, mismatch.in_bitmap))
+            .collect::<Vec<_>>();
+        assert_eq!(mismatches, vec![(20, false), (30, true)]);
+    }
+
+    #[test]
+    fn reads_non_resident_record_bitmap() {
+        let mut builder = ImageBuilder::new();
+        let file = builder.add_file(ROOT_RECORD, "file", b"");
+        builder.set_record(
+            20,
+            Record::new().with(Attribute::file_name(
+                ROOT_RECORD,
+                "hidden",
+                namespaces::WIN32,
+            )),
+        );
+        builder.set_bitmap_bit(20, false);
+        let bits = builder.mft_bitmap_bits();
+        let lcn = builder.write_clusters(&bits);
+        builder.set_mft_bitmap(Attribute::non_resident(
+            type_codes::BITMAP,
+            None,
+            vec![Run::at(lcn, 1)],
+            bits.len() as u64,
+        ));
+        let mft = MasterFileTable::open(builder.build()).unwrap();
+        let records = mft
+            .map(|entry| entry.unwrap().base_record_segment_idx)
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![0, ROOT_RECORD, file]);
+
+        // One that can't be read is done without.
+        let mut builder = ImageBuilder::new();
+        let file = builder.add_file(ROOT_RECORD, "file", b"");
+        builder.set_mft_bitmap(Attribute::non_resident(
+            type_codes::BITMAP,
+            None,
+            vec![Run::at(1 << 30, 1)],
+            8,
+        ));
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+        let entry = mft.read_entry(0).unwrap().unwrap();
+        assert_eq!(entry.bad_attributes, [sys::AttributeType::Bitmap]);
+        let records = mft
+            .by_ref()
+            .map(|entry| entry.unwrap().base_record_segment_idx)
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![0, ROOT_RECORD, file]);
+        assert!(mft.allocation_mismatches().is_empty());
+    }
+
+    #[test]
+    fn hard_links_and_short_names() {
+        let mut builder = ImageBuilder::new();
+        let dir = builder.add_directory(ROOT_RECORD, "dir");
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::standard_information(0))
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "LONGFI~1.TXT",
+                    namespaces::DOS,
+                ))
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "long file name.txt",
+                    namespaces::WIN32,
+                ))
+                .with(Attribute::file_name(dir, "link.txt", namespaces::POSIX)),
+        );
+
+        let entries = entries(builder.build());
+        let file = entry(&entries, file);
+        assert_eq!(file.hard_link_count, 3);
+        // The DOS name is never preferred.
+        assert_eq!(file.get_best_filename(), Some(NtfsName::from("link.txt")));
+        assert_eq!(
+            file.filename[1].filename,
+            NtfsName::from("long file name.txt")
+        );
+        assert_eq!(file.filename[2].filename_type, sys::FileNameType::Dos);
+        assert_eq!(
+            file.parents(),
+            [dir, ROOT_RECORD, ROOT_RECORD].map(|parent| sys::FileReference::new(parent, 1))
+        );
+    }
+
+    #[test]
+    fn alternate_data_streams() {
+        let mut builder = ImageBuilder::new();
+        let lcn = builder.write_clusters(&[7; 5000]);
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "streams",
+                    namespaces::WIN32,
+                ))
+                .with(Attribute::resident_data(None, b"main"))
+                .with(Attribute::resident_data(
+                    Some("Zone.Identifier"),
+                    b"[ZoneTransfer]",
+                ))
+                .with(Attribute::non_resident_data(
+                    Some("big"),
+                    vec![Run::at(lcn, 2)],
+                    5000,
+                )),
+        );
+
+        let entries = entries(builder.build());
+        let file = entry(&entries, file);
+        let names = file
+            .data
+            .iter()
+            .map(|data| data.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                None,
+                Some(NtfsName::from("Zone.Identifier")),
+                Some(NtfsName::from("big"))
+            ]
+        );
+        assert_eq!(file.data[2].logical_size, 5000);
+        assert_eq!(file.data[2].physical_size, 8192);
+        let runs = file.data[2].runs.as_ref().unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].starting_lcn, lcn as i64);
+        assert_eq!(runs[0].cluster_count, 2);
+        // Only named streams are counted, and only non-resident ones take clusters.
+        assert_eq!(file.get_allocated_size(4096), 8192);
+    }
+
+    #[test]
+    fn sparse_runs() {
+        let mut builder = ImageBuilder::new();
+        let first = builder.write_clusters(&[1; 4096]);
+        let second = builder.write_clusters(&[2; 4096]);
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "sparse",
+                    namespaces::WIN32,
+                ))
+                .with(Attribute::non_resident_data(
+                    None,
+                    vec![Run::at(second, 1), Run::sparse(14), Run::at(first, 1)],
+                    16 * 4096,
+                )),
+        );
+
+        let entries = entries(builder.build());
+        let data = &entry(&entries, file).data[0];
+        assert!(data.is_sparse);
+        assert_eq!(data.physical_size, 16 * 4096);
+        let runs = data.runs.as_ref().unwrap();
+        let counts = runs.iter().map(|run| run.cluster_count).collect::<Vec<_>>();
+        assert_eq!(counts, vec![1, 14, 1]);
+        assert_eq!(runs[0].starting_lcn, second as i64);
+        // A negative delta takes us back to the first cluster.
+        assert_eq!(runs[2].starting_lcn, first as i64);
+    }
+
+    #[test]
+    fn attribute_list_pulls_in_extension_records() {
+        let mut builder = ImageBuilder::new();
+        let base = builder.add_record(Record::new().with(Attribute::file_name(
+            ROOT_RECORD,
+            "split",
+            namespaces::WIN32,
+        )));
+        let extension = builder.add_record(
+            Record::extension_of(base)
+                .with(Attribute::resident_data(None, b"first"))
+                .with(Attribute::resident_data(Some("ads"), b"second")),
+        );
+        let mut ads = ListEntry::new(type_codes::DATA, extension);
+        ads.name = Some("ads".to_string());
+        builder.set_record(
+            base,
+            Record::new()
+                .with(Attribute::attribute_list(&[
+                    ListEntry::new(type_codes::FILE_NAME, base),
+                    ListEntry::new(type_codes::DATA, extension),
+                    ads,
+                ]))
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "split",
+                    namespaces::WIN32,
+                )),
+        );
+
+        let entries = entries(builder.build());
+        let file = entry(&entries, base);
+        assert_eq!(file.get_best_filename(), Some(NtfsName::from("split")));
+        assert_eq!(file.data.len(), 2);
+        assert_eq!(file.data[0].logical_size, 5);
+        assert_eq!(file.data[1].name, Some(NtfsName::from("ads")));
+    }
+
+    #[test]
+    fn joins_attributes_split_across_records() {
+        // The second piece of the file's $DATA starts at `second_vcn`.
+        fn split_file(second_vcn: u64, deleted: bool) -> (MemoryReader, u64, Vec<u8>) {
+            let mut builder = ImageBuilder::new();
+            let mut content = vec![b'a'; 8192];
+            content.extend_from_slice(&[b'b'; 4000]);
+            let first = builder.write_clusters(&content[..8192]);
+            let second = builder.write_clusters(&content[8192..]);
+            let base = builder.add_record(Record::new());
+            let mut extension = Record::extension_of(base).with(
+                Attribute::non_resident_data(None, vec![Run::at(second, 1)], 0)
+                    .with_lowest_vcn(second_vcn),
+            );
+            let mut second_piece = ListEntry::new(type_codes::DATA, base + 1);
+            second_piece.starting_vcn = second_vcn;
+            let mut record = Record::new()
+                .with(Attribute::attribute_list(&[
+                    ListEntry::new(type_codes::FILE_NAME, base),
+                    ListEntry::new(type_codes::DATA, base),
+                    second_piece,
+                ]))
+                .with(Attribute::file_name(ROOT_RECORD, "big", namespaces::WIN32))
+                .with(Attribute::non_resident_data(
+                    None,
+                    vec![Run::at(first, 2)],
+                    content.len() as u64,
+                ));
+            if deleted {
+                record = record.not_in_use();
+                extension = extension.not_in_use();
+            }
+            builder.set_record(base, record);
+            assert_eq!(builder.add_record(extension), base + 1);
+            (builder.build(), base, content)
+        }
+
+        let (image, file, content) = split_file(2, false);
+        let mut mft = MasterFileTable::open(image).unwrap();
+        let entry = mft.read_entry(file).unwrap().unwrap();
+        assert_eq!(entry.data.len(), 1);
+        let data = entry.get_data(None).unwrap();
+        assert_eq!(data.logical_size, content.len() as u64);
+        assert_eq!(data.runs.as_ref().unwrap().len(), 2);
+        assert_eq!(data.compute_allocated_size(4096), 3 * 4096);
+        let mut read = Vec::new();
+        mft.open_data(data).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == content);
+
+        // A gap leaves the start of the attribute, and is reported rather
+        // than failing the entry.
+        let (image, file, _) = split_file(3, false);
+        let mut mft = MasterFileTable::open(image).unwrap();
+        let entry = mft.read_entry(file).unwrap().unwrap();
+        assert!(entry.has_unresolved_attribute_list);
+        assert_eq!(entry.bad_attributes, [sys::AttributeType::Data]);
+        assert_eq!(
+            entry.get_data(None).unwrap().runs.as_ref().unwrap().len(),
+            1
+        );
+
+        // What's left of a deleted file is still worth having.
+        let (image, file, _) = split_file(3, true);
+        let mut mft = MasterFileTable::open(image)
+            .unwrap()
+            .with_deleted_records(true);
+        let entry = mft.read_entry(file).unwrap().unwrap();
+        assert!(entry.has_unresolved_attribute_list);
+        assert_eq!(
+            entry.get_data(None).unwrap().runs.as_ref().unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn fragmented_mft() {
+        let mut builder = ImageBuilder::new();
+        for i in 0..8 {
+            builder.add_file(ROOT_RECORD, &format!("file{}", i), b"");
+        }
+        builder.fragment_mft();
+
+        let mft = MasterFileTable::open(builder.build()).unwrap();
+        assert_eq!(mft.entry_count(), 24);
+        let entries = mft.collect::<Result<Vec<_>, _>>().unwrap();
+        // Records 16 and up are only reachable through the second extent.
+        assert_eq!(entries.len(), 10);
+        assert_eq!(
+            entry(&entries, 23).get_best_filename(),
+            Some(NtfsName::from("file7"))
+        );
+    }
+
+    #[test]
+    fn fixups_are_applied_and_checked() {
+        let mut builder = ImageBuilder::new();
+        // Long enough that the name crosses the first sector boundary, whose
+        // last two bytes are stored in the update sequence array.
+        let name = "x".repeat(250);
+        let file = builder.add_record(
+            Record::new()
+                .with(Attribute::resident_data(None, &[0xAB; 300]))
+                .with(Attribute::file_name(ROOT_RECORD, &name, namespaces::POSIX)),
+        );
+        let image = builder.build();
+        let entries = entries(MemoryReader::new(image.bytes().to_vec()));
+        assert_eq!(
+            entry(&entries, file).get_best_filename(),
+            Some(NtfsName::from(&name[..]))
+        );
+
+        // A torn write leaves a sector without the sequence number.
+        let mut bytes = image.bytes().to_vec();
+        let record_offset = (test_support::MFT_START_LCN * 4096 + file * 1024) as usize;
+        bytes[record_offset + 510] ^= 0xFF;
+        let result = MasterFileTable::open(MemoryReader::new(bytes))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>();
+        assert!(matches!(result, Err(Error::UpdateSequenceValidationFailed)));
     }
-    OsString::from_wide(&filename[..])
 }
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+
+#[cfg(unix)]
+use std::os::unix::ffi::OsStringExt as _;
+#[cfg(windows)]
+use std::os::windows::ffi::OsStringExt as _;
+use std::{ffi::OsString, fmt};
+
+// A file or attribute name exactly as NTFS stores it: UTF-16 code units with
+// no guarantee of being valid UTF-16 (unpaired surrogates are allowed).
+// Keeping the raw units means names round-trip exactly, however they're
+// converted for display.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub struct NtfsName(Vec<u16>);
+impl NtfsName {
+    pub fn from_units(units: Vec<u16>) -> Self {
+        NtfsName(units)
+    }
+
+    // Parses little-endian UTF-16 straight out of a record.
+    pub fn load(utf16data: &[u8]) -> Self {
+        // Since we have no guarantees about the alignment of the buffer,
+        // we can't safely cast the array of u8's to an array of u16's.
+        NtfsName(
+            utf16data
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect(),
+        )
+    }
+
+    pub fn units(&self) -> &[u16] {
+        &self.0[..]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Windows gets the exact units back. Unix gets WTF-8, which is lossless
+    // too; anywhere else unpaired surrogates become U+FFFD.
+    pub fn to_os_string(&self) -> OsString {
+        #[cfg(windows)]
+        {
+            OsString::from_wide(&self.0[..])
+        }
+        #[cfg(unix)]
+        {
+            OsString::from_vec(self.to_wtf8())
+        }
+        #[cfg(not(any(windows, unix)))]
+        {
+            OsString::from(self.to_string_lossy())
+        }
+    }
+
+    // Encodes the name as WTF-8: UTF-8, except that unpaired surrogates are
+    // encoded as if they were ordinary code points.
+    pub fn to_wtf8(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.0.len());
+        for unit in char::decode_utf16(self.0.iter().copied()) {
+            let code_point = match unit {
+                Ok(c) => {
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    continue;
+                }
+                Err(err) => u32::from(err.unpaired_surrogate()),
+            };
+            out.push(0xE0 | (code_point >> 12) as u8);
+            out.push(0x80 | ((code_point >> 6) & 0x3F) as u8);
+            out.push(0x80 | (code_point & 0x3F) as u8);
+        }
+        out
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf16_lossy(&self.0[..])
+    }
+
+    // Compares names the way Windows does by default, ignoring case. This uses
+    // Unicode's simple upper-case mapping rather than the volume's $UpCase
+    // table, which agree for everything but a handful of historical characters.
+    pub fn eq_ignore_case(&self, other: &NtfsName) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(other.0.iter())
+                .all(|(a, b)| a == b || upcase(*a) == upcase(*b))
+    }
+}
+impl From<&str> for NtfsName {
+    fn from(name: &str) -> Self {
+        NtfsName(name.encode_utf16().collect())
+    }
+}
+// Displays the name as a string, escaping unpaired surrogates as \u{XXXX}.
+// Backslashes aren't escaped, so a name written by something other than
+// Windows could display the same as one with an escaped surrogate.
+impl fmt::Display for NtfsName {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for unit in char::decode_utf16(self.0.iter().copied()) {
+            match unit {
+                Ok(c) => write!(fmt, "{}", c)?,
+                Err(err) => write!(fmt, "\\u{{{:04X}}}", err.unpaired_surrogate())?,
+            }
+        }
+        Ok(())
+    }
+}
+impl fmt::Debug for NtfsName {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "\"{}\"", self)
+    }
+}
+
+fn upcase(unit: u16) -> u16 {
+    match char::from_u32(unit.into()) {
+        Some(c) => {
+            let mut upper = c.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(u), None) if (u as u32) <= 0xFFFF => u as u32 as u16,
+                _ => unit,
+            }
+        }
+        // Surrogates have no case.
+        None => unit,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpaired_surrogates_survive() {
+        let name = NtfsName::from_units(vec![0x61, 0xD800, 0x62]);
+        assert_eq!(name.to_string(), "a\\u{D800}b");
+        assert_eq!(name.to_wtf8(), vec![0x61, 0xED, 0xA0, 0x80, 0x62]);
+        assert_ne!(
+            name.to_string(),
+            NtfsName::from_units(vec![0x61, 0xDC00, 0x62]).to_string()
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt as _;
+            assert_eq!(name.to_os_string().as_bytes(), &name.to_wtf8()[..]);
+        }
+    }
+
+    #[test]
+    fn load_reads_little_endian_units() {
+        let name = NtfsName::load(&[0x41, 0x00, 0x3D, 0xD8, 0x00, 0xDE]);
+        assert_eq!(name.units(), &[0x0041, 0xD83D, 0xDE00]);
+        assert_eq!(name.to_string(), "A\u{1F600}");
+        assert_eq!(name.to_os_string(), OsString::from("A\u{1F600}"));
+    }
+
+    #[test]
+    fn compares_ignoring_case() {
+        assert!(NtfsName::from("Readme.TXT").eq_ignore_case(&NtfsName::from("README.txt")));
+        assert!(NtfsName::from("straße").eq_ignore_case(&NtfsName::from("STRAßE")));
+        assert!(!NtfsName::from("readme").eq_ignore_case(&NtfsName::from("readme2")));
+        assert_ne!(NtfsName::from("a"), NtfsName::from("A"));
+    }
+}
@@ -0,0 +1,194 @@
+use crate::{err::Error, guid::Guid, mft::sys};
+
+use serde::{Deserialize, Serialize};
+
+// $Extend, the directory holding $ObjId and the other NTFS 3.0 metadata
+// files. They don't have fixed record numbers of their own.
+pub const EXTEND_RECORD: u64 = 11;
+pub const OBJ_ID_FILE_NAME: &str = "$ObjId";
+// The index in $ObjId of every object ID on the volume
+pub const O_INDEX_NAME: &str = "$O";
+
+const OBJECT_ID_LENGTH: usize = 16;
+const EXTENDED_INFO_LENGTH: usize = 48;
+
+// A file's $OBJECT_ID attribute: the ID the distributed link tracking
+// service gave it, and where it was first given one. The "birth" IDs stay
+// the same when the file moves to another volume, which gives it a new
+// object ID. Anything not stored is nil.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectId {
+    pub object_id: Guid,
+    pub birth_volume_id: Guid,
+    pub birth_object_id: Guid,
+    // Unused, and always nil in practice
+    pub domain_id: Guid,
+}
+impl ObjectId {
+    // The attribute is either the object ID alone, or followed by the
+    // three others.
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < OBJECT_ID_LENGTH {
+            return Err(Error::BadObjectId);
+        }
+        let mut object_id = ObjectId {
+            object_id: Guid::load(&buf[0..16]),
+            ..Default::default()
+        };
+        if buf.len() >= OBJECT_ID_LENGTH + EXTENDED_INFO_LENGTH {
+            object_id.load_extended_info(&buf[16..64]);
+        }
+        Ok(object_id)
+    }
+
+    fn load_extended_info(&mut self, buf: &[u8]) {
+        self.birth_volume_id = Guid::load(&buf[0..16]);
+        self.birth_object_id = Guid::load(&buf[16..32]);
+        self.domain_id = Guid::load(&buf[32..48]);
+    }
+}
+
+// An entry in $ObjId:$O, which maps an object ID back to the file that
+// has it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectIdEntry {
+    pub file_reference: sys::FileReference,
+    pub object_id: ObjectId,
+}
+impl ObjectIdEntry {
+    // The entry's key is the object ID; its data is the file reference,
+    // followed by the rest of the file's $OBJECT_ID.
+    pub fn load(key: &[u8], data: &[u8]) -> Result<Self, Error> {
+        if key.len() < OBJECT_ID_LENGTH || data.len() < 8 + EXTENDED_INFO_LENGTH {
+            return Err(Error::BadObjectId);
+        }
+        let mut object_id = ObjectId {
+            object_id: Guid::load(&key[0..16]),
+            ..Default::default()
+        };
+        object_id.load_extended_info(&data[8..56]);
+        Ok(ObjectIdEntry {
+            file_reference: sys::FileReference::load(&data[0..8]),
+            object_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mft::MasterFileTable,
+        test_support::{
+            directory_index_entry, file_reference, index_root_node, last_index_entry, namespaces,
+            type_codes, view_index_entry, Attribute, ImageBuilder, Record, ROOT_RECORD,
+        },
+    };
+
+    #[test]
+    fn maps_object_ids_to_files() {
+        let object_id = [0x11; 16];
+        let birth_volume_id = [0x22; 16];
+        let birth_object_id = [0x33; 16];
+        let mut builder = ImageBuilder::new();
+        let tracked = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "a.txt",
+                    namespaces::WIN32,
+                ))
+                .with(Attribute::resident(
+                    type_codes::OBJECT_ID,
+                    None,
+                    [object_id, birth_volume_id, birth_object_id, [0; 16]].concat(),
+                )),
+        );
+        let short = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "b.txt",
+                    namespaces::WIN32,
+                ))
+                .with(Attribute::resident(
+                    type_codes::OBJECT_ID,
+                    None,
+                    vec![0x44; 16],
+                )),
+        );
+
+        let truncated = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "c.txt",
+                    namespaces::WIN32,
+                ))
+                .with(Attribute::resident(
+                    type_codes::OBJECT_ID,
+                    None,
+                    vec![0x55; 10],
+                )),
+        );
+
+        let data = [
+            &file_reference(tracked, 1).to_le_bytes()[..],
+            &birth_volume_id,
+            &birth_object_id,
+            &[0; 16],
+        ]
+        .concat();
+        let obj_id = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(
+                    EXTEND_RECORD,
+                    OBJ_ID_FILE_NAME,
+                    namespaces::WIN32_DOS,
+                ))
+                .with(Attribute::index_root(
+                    O_INDEX_NAME,
+                    index_root_node(&[view_index_entry(&object_id, &data), last_index_entry(None)]),
+                )),
+        );
+        builder.set_record(
+            EXTEND_RECORD,
+            Record::directory()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "$Extend",
+                    namespaces::WIN32_DOS,
+                ))
+                .with(Attribute::index_root(
+                    "$I30",
+                    index_root_node(&[
+                        directory_index_entry(obj_id, EXTEND_RECORD, OBJ_ID_FILE_NAME, None),
+                        last_index_entry(None),
+                    ]),
+                )),
+        );
+
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+        let entry = mft.read_entry(tracked).unwrap().unwrap();
+        let expected = ObjectId {
+            object_id: Guid::load(&object_id),
+            birth_volume_id: Guid::load(&birth_volume_id),
+            birth_object_id: Guid::load(&birth_object_id),
+            domain_id: Guid::default(),
+        };
+        assert_eq!(entry.object_id, Some(expected));
+        // Only the object ID itself is stored.
+        let entry = mft.read_entry(short).unwrap().unwrap();
+        assert!(entry.object_id.unwrap().birth_object_id.is_nil());
+        // Too short for even that is left out, without failing the entry.
+        let entry = mft.read_entry(truncated).unwrap().unwrap();
+        assert_eq!(entry.object_id, None);
+        assert_eq!(entry.bad_attributes, [sys::AttributeType::ObjectId]);
+
+        let object_ids = mft.read_object_ids().unwrap();
+        assert_eq!(object_ids.len(), 1);
+        let found = &object_ids[&Guid::load(&object_id)];
+        assert_eq!(found.file_reference.segment(), tracked);
+        assert_eq!(found.object_id, expected);
+    }
+}
@@ -0,0 +1,402 @@
+use crate::{err::Error, guid::Guid, mft::NtfsName};
+
+use std::convert::TryInto as _;
+
+pub mod reparse_tags {
+    pub const MOUNT_POINT: u32 = 0xA000_0003;
+    pub const SYMLINK: u32 = 0xA000_000C;
+    pub const DEDUP: u32 = 0x8000_0013;
+    pub const WOF: u32 = 0x8000_0017;
+    // Cloud files (OneDrive and friends) use 0x9000X01A, where X is a subtag.
+    pub const CLOUD: u32 = 0x9000_001A;
+    pub const CLOUD_MASK: u32 = 0xFFFF_0FFF;
+    pub const APPEXECLINK: u32 = 0x8000_001B;
+    pub const LX_SYMLINK: u32 = 0xA000_001D;
+    pub const AF_UNIX: u32 = 0x8000_0023;
+    pub const LX_FIFO: u32 = 0x8000_0024;
+    pub const LX_CHR: u32 = 0x8000_0025;
+    pub const LX_BLK: u32 = 0x8000_0026;
+
+    // Set on tags owned by Microsoft, which don't carry a GUID.
+    pub const MICROSOFT_BIT: u32 = 0x8000_0000;
+    // Set on tags that point at another named entity.
+    pub const NAME_SURROGATE_BIT: u32 = 0x2000_0000;
+}
+
+const REPARSE_HEADER_LENGTH: usize = 8;
+const GUID_REPARSE_HEADER_LENGTH: usize = 24;
+const SYMLINK_FLAG_RELATIVE: u32 = 0x1;
+
+mod wof_providers {
+    pub const WIM: u32 = 1;
+    pub const FILE: u32 = 2;
+}
+
+// How a file compressed by Windows Overlay Filter (compact.exe) is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WofAlgorithm {
+    Xpress4K,
+    Lzx,
+    Xpress8K,
+    Xpress16K,
+    Unknown(u32),
+}
+impl From<u32> for WofAlgorithm {
+    fn from(algorithm: u32) -> Self {
+        match algorithm {
+            0 => WofAlgorithm::Xpress4K,
+            1 => WofAlgorithm::Lzx,
+            2 => WofAlgorithm::Xpress8K,
+            3 => WofAlgorithm::Xpress16K,
+            unknown => WofAlgorithm::Unknown(unknown),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WofProvider {
+    // The file's data lives in a WIM image, as with WIMBoot.
+    Wim { data: Vec<u8> },
+    // The file's data is compressed into its "WofCompressedData" stream.
+    File { algorithm: WofAlgorithm },
+    Unknown { provider: u32, data: Vec<u8> },
+}
+
+// The contents of a $REPARSE_POINT attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReparsePoint {
+    SymbolicLink {
+        // The path the filesystem follows, usually an NT path like \??\C:\dir
+        substitute_name: NtfsName,
+        // The path for display, usually a Win32 path like C:\dir
+        print_name: NtfsName,
+        is_relative: bool,
+    },
+    // Junctions, and volume mount points (whose target is \??\Volume{GUID}\)
+    MountPoint {
+        substitute_name: NtfsName,
+        print_name: NtfsName,
+    },
+    Wof {
+        provider: WofProvider,
+    },
+    // Execution aliases for Store apps, as found in %LOCALAPPDATA%\Microsoft\WindowsApps
+    AppExecLink {
+        package_id: NtfsName,
+        app_user_model_id: NtfsName,
+        target: NtfsName,
+    },
+    // Cloud files placeholders; the data is private to the sync provider.
+    Cloud {
+        subtag: u8,
+        data: Vec<u8>,
+    },
+    // Data deduplication; the content lives in the chunk store.
+    Dedup {
+        data: Vec<u8>,
+    },
+    // WSL symlinks store their target as UTF-8.
+    LxSymlink {
+        target: Vec<u8>,
+    },
+    LxUnixSocket,
+    LxFifo,
+    LxCharDevice,
+    LxBlockDevice,
+    Unknown {
+        tag: u32,
+        // Only tags from third parties have a GUID.
+        guid: Option<Guid>,
+        data: Vec<u8>,
+    },
+}
+impl ReparsePoint {
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < REPARSE_HEADER_LENGTH {
+            return Err(Error::BadReparsePoint(0));
+        }
+        let tag = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let data_length: usize = u16::from_le_bytes([buf[4], buf[5]]).into();
+
+        let (guid, data_start) = if tag & reparse_tags::MICROSOFT_BIT == 0 {
+            if buf.len() < GUID_REPARSE_HEADER_LENGTH {
+                return Err(Error::BadReparsePoint(tag));
+            }
+            (Some(Guid::load(&buf[8..24])), GUID_REPARSE_HEADER_LENGTH)
+        } else {
+            (None, REPARSE_HEADER_LENGTH)
+        };
+        let data = buf
+            .get(data_start..data_start + data_length)
+            .ok_or(Error::BadReparsePoint(tag))?;
+
+        use reparse_tags::*;
+        Ok(match tag {
+            SYMLINK => {
+                let flags = read_u32(data, 8).ok_or(Error::BadReparsePoint(tag))?;
+                let (substitute_name, print_name) =
+                    load_names(data, 12).ok_or(Error::BadReparsePoint(tag))?;
+                ReparsePoint::SymbolicLink {
+                    substitute_name,
+                    print_name,
+                    is_relative: flags & SYMLINK_FLAG_RELATIVE != 0,
+                }
+            }
+            MOUNT_POINT => {
+                let (substitute_name, print_name) =
+                    load_names(data, 8).ok_or(Error::BadReparsePoint(tag))?;
+                ReparsePoint::MountPoint {
+                    substitute_name,
+                    print_name,
+                }
+            }
+            WOF => {
+                // WOF_EXTERNAL_INFO, followed by the provider's own header
+                let provider = read_u32(data, 4).ok_or(Error::BadReparsePoint(tag))?;
+                let provider = match provider {
+                    wof_providers::FILE => WofProvider::File {
+                        algorithm: read_u32(data, 12)
+                            .ok_or(Error::BadReparsePoint(tag))?
+                            .into(),
+                    },
+                    wof_providers::WIM => WofProvider::Wim {
+                        data: data[8..].to_vec(),
+                    },
+                    provider => WofProvider::Unknown {
+                        provider,
+                        data: data[8..].to_vec(),
+                    },
+                };
+                ReparsePoint::Wof { provider }
+            }
+            APPEXECLINK => {
+                // A version, then NUL-terminated strings
+                let mut strings = data
+                    .get(4..)
+                    .ok_or(Error::BadReparsePoint(tag))?
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect::<Vec<_>>()
+                    .split(|unit| *unit == 0)
+                    .map(|units| NtfsName::from_units(units.to_vec()))
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                let mut next = || strings.next().ok_or(Error::BadReparsePoint(tag));
+                ReparsePoint::AppExecLink {
+                    package_id: next()?,
+                    app_user_model_id: next()?,
+                    target: next()?,
+                }
+            }
+            tag if tag & CLOUD_MASK == CLOUD => ReparsePoint::Cloud {
+                subtag: ((tag >> 12) & 0xF) as u8,
+                data: data.to_vec(),
+            },
+            DEDUP => ReparsePoint::Dedup {
+                data: data.to_vec(),
+            },
+            LX_SYMLINK => ReparsePoint::LxSymlink {
+                target: data.get(4..).ok_or(Error::BadReparsePoint(tag))?.to_vec(),
+            },
+            AF_UNIX => ReparsePoint::LxUnixSocket,
+            LX_FIFO => ReparsePoint::LxFifo,
+            LX_CHR => ReparsePoint::LxCharDevice,
+            LX_BLK => ReparsePoint::LxBlockDevice,
+            tag => ReparsePoint::Unknown {
+                tag,
+                guid,
+                data: data.to_vec(),
+            },
+        })
+    }
+
+    pub fn tag(&self) -> u32 {
+        use reparse_tags::*;
+        match self {
+            ReparsePoint::SymbolicLink { .. } => SYMLINK,
+            ReparsePoint::MountPoint { .. } => MOUNT_POINT,
+            ReparsePoint::Wof { .. } => WOF,
+            ReparsePoint::AppExecLink { .. } => APPEXECLINK,
+            ReparsePoint::Cloud { subtag, .. } => CLOUD | (u32::from(*subtag) << 12),
+            ReparsePoint::Dedup { .. } => DEDUP,
+            ReparsePoint::LxSymlink { .. } => LX_SYMLINK,
+            ReparsePoint::LxUnixSocket => AF_UNIX,
+            ReparsePoint::LxFifo => LX_FIFO,
+            ReparsePoint::LxCharDevice => LX_CHR,
+            ReparsePoint::LxBlockDevice => LX_BLK,
+            ReparsePoint::Unknown { tag, .. } => *tag,
+        }
+    }
+
+    // Where the link points, for the kinds of reparse point that redirect to
+    // another path. Prefers the display name when there is one.
+    pub fn target(&self) -> Option<String> {
+        match self {
+            ReparsePoint::SymbolicLink {
+                substitute_name,
+                print_name,
+                ..
+            }
+            | ReparsePoint::MountPoint {
+                substitute_name,
+                print_name,
+            } => Some(if print_name.is_empty() {
+                substitute_name.to_string()
+            } else {
+                print_name.to_string()
+            }),
+            ReparsePoint::AppExecLink { target, .. } => Some(target.to_string()),
+            ReparsePoint::LxSymlink { target } => {
+                Some(String::from_utf8_lossy(target).into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    // True for reparse points that redirect to another file or directory
+    // (symlinks, junctions and so on). Anything walking the tree should not
+    // follow these, or it'll count the target's contents twice.
+    pub fn is_name_surrogate(&self) -> bool {
+        self.tag() & reparse_tags::NAME_SURROGATE_BIT != 0
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+// Symlinks and mount points both start with the offsets and lengths of the
+// substitute and print names, relative to a path buffer at `path_buffer`.
+fn load_names(data: &[u8], path_buffer: usize) -> Option<(NtfsName, NtfsName)> {
+    let field = |i: usize| -> Option<usize> {
+        Some(u16::from_le_bytes(data.get(i..i + 2)?.try_into().unwrap()).into())
+    };
+    let name = |offset: usize, length: usize| -> Option<NtfsName> {
+        let start = path_buffer + offset;
+        Some(NtfsName::load(data.get(start..start + length)?))
+    };
+    Some((name(field(0)?, field(2)?)?, name(field(4)?, field(6)?)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{link_reparse_buffer, reparse_buffer, utf16_bytes};
+
+    #[test]
+    fn symbolic_links() {
+        let buf = link_reparse_buffer("..\\target", "..\\target", Some(SYMLINK_FLAG_RELATIVE));
+        let reparse_point = ReparsePoint::load(&buf[..]).unwrap();
+        assert_eq!(
+            reparse_point,
+            ReparsePoint::SymbolicLink {
+                substitute_name: NtfsName::from("..\\target"),
+                print_name: NtfsName::from("..\\target"),
+                is_relative: true,
+            }
+        );
+        assert_eq!(reparse_point.tag(), reparse_tags::SYMLINK);
+        assert_eq!(reparse_point.target(), Some("..\\target".to_string()));
+        assert!(reparse_point.is_name_surrogate());
+    }
+
+    #[test]
+    fn junctions() {
+        let buf = link_reparse_buffer("\\??\\C:\\Users\\Public", "", None);
+        let reparse_point = ReparsePoint::load(&buf[..]).unwrap();
+        assert!(matches!(reparse_point, ReparsePoint::MountPoint { .. }));
+        // Without a print name, fall back to the NT path.
+        assert_eq!(
+            reparse_point.target(),
+            Some("\\??\\C:\\Users\\Public".to_string())
+        );
+        assert!(reparse_point.is_name_surrogate());
+    }
+
+    #[test]
+    fn wof_compressed_files() {
+        let mut data = Vec::new();
+        for field in &[1u32, 2, 1, 1] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        let reparse_point = ReparsePoint::load(&reparse_buffer(reparse_tags::WOF, &data)).unwrap();
+        assert_eq!(
+            reparse_point,
+            ReparsePoint::Wof {
+                provider: WofProvider::File {
+                    algorithm: WofAlgorithm::Lzx
+                }
+            }
+        );
+        assert_eq!(reparse_point.target(), None);
+        assert!(!reparse_point.is_name_surrogate());
+    }
+
+    #[test]
+    fn app_exec_links() {
+        let mut data = 3u32.to_le_bytes().to_vec();
+        for s in &[
+            "Pkg_8wekyb3d8bbwe",
+            "Pkg_8wekyb3d8bbwe!App",
+            "C:\\Program Files\\App.exe",
+            "0",
+        ] {
+            data.extend_from_slice(&utf16_bytes(s));
+            data.extend_from_slice(&[0, 0]);
+        }
+        let reparse_point =
+            ReparsePoint::load(&reparse_buffer(reparse_tags::APPEXECLINK, &data)).unwrap();
+        assert_eq!(
+            reparse_point.target(),
+            Some("C:\\Program Files\\App.exe".to_string())
+        );
+    }
+
+    #[test]
+    fn cloud_placeholders_and_wsl() {
+        let cloud = ReparsePoint::load(&reparse_buffer(0x9000_301A, &[1, 2, 3])).unwrap();
+        assert_eq!(
+            cloud,
+            ReparsePoint::Cloud {
+                subtag: 3,
+                data: vec![1, 2, 3]
+            }
+        );
+        assert_eq!(cloud.tag(), 0x9000_301A);
+
+        let mut data = 2u32.to_le_bytes().to_vec();
+        data.extend_from_slice(b"/usr/bin/python3");
+        let symlink = ReparsePoint::load(&reparse_buffer(reparse_tags::LX_SYMLINK, &data)).unwrap();
+        assert_eq!(symlink.target(), Some("/usr/bin/python3".to_string()));
+
+        let fifo = ReparsePoint::load(&reparse_buffer(reparse_tags::LX_FIFO, &[])).unwrap();
+        assert_eq!(fifo, ReparsePoint::LxFifo);
+    }
+
+    #[test]
+    fn unknown_tags_keep_their_data() {
+        // A third-party tag, with a GUID
+        let mut buf = vec![0; 24];
+        buf[0..4].copy_from_slice(&0x0000_1234u32.to_le_bytes());
+        buf[4..6].copy_from_slice(&2u16.to_le_bytes());
+        buf[8] = 0xAA;
+        buf.extend_from_slice(&[5, 6]);
+        let reparse_point = ReparsePoint::load(&buf[..]).unwrap();
+        match reparse_point {
+            ReparsePoint::Unknown { tag, guid, data } => {
+                assert_eq!(tag, 0x1234);
+                assert_eq!(guid.unwrap().data1, 0xAA);
+                assert_eq!(data, vec![5, 6]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let truncated = reparse_buffer(reparse_tags::SYMLINK, &[0; 6]);
+        assert!(matches!(
+            ReparsePoint::load(&truncated[..]),
+            Err(Error::BadReparsePoint(reparse_tags::SYMLINK))
+        ));
+    }
+}
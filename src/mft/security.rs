@@ -0,0 +1,585 @@
+use crate::{err::Error, guid::Guid};
+
+use std::{convert::TryInto as _, fmt};
+
+// $Secure, which holds the security descriptors shared between files. Its
+// $SDS stream has the descriptors themselves, and its $SII index maps the
+// security IDs in $STANDARD_INFORMATION to where they are in $SDS.
+pub const SECURE_RECORD: u64 = 9;
+pub const SDS_STREAM_NAME: &str = "$SDS";
+pub const SII_INDEX_NAME: &str = "$SII";
+// Each descriptor in $SDS follows a header of hash, security ID, offset and
+// length, where the length includes the header.
+pub const SDS_HEADER_LENGTH: usize = 20;
+
+mod control_flags {
+    pub const DACL_PRESENT: u16 = 0x0004;
+    pub const SACL_PRESENT: u16 = 0x0010;
+}
+
+pub mod ace_types {
+    pub const ACCESS_ALLOWED: u8 = 0x00;
+    pub const ACCESS_DENIED: u8 = 0x01;
+    pub const SYSTEM_AUDIT: u8 = 0x02;
+    pub const SYSTEM_ALARM: u8 = 0x03;
+    pub const ACCESS_ALLOWED_OBJECT: u8 = 0x05;
+    pub const ACCESS_DENIED_OBJECT: u8 = 0x06;
+    pub const SYSTEM_AUDIT_OBJECT: u8 = 0x07;
+    pub const SYSTEM_ALARM_OBJECT: u8 = 0x08;
+    pub const ACCESS_ALLOWED_CALLBACK: u8 = 0x09;
+    pub const ACCESS_DENIED_CALLBACK: u8 = 0x0A;
+    pub const ACCESS_ALLOWED_CALLBACK_OBJECT: u8 = 0x0B;
+    pub const ACCESS_DENIED_CALLBACK_OBJECT: u8 = 0x0C;
+    pub const SYSTEM_AUDIT_CALLBACK: u8 = 0x0D;
+    pub const SYSTEM_ALARM_CALLBACK: u8 = 0x0E;
+    pub const SYSTEM_AUDIT_CALLBACK_OBJECT: u8 = 0x0F;
+    pub const SYSTEM_ALARM_CALLBACK_OBJECT: u8 = 0x10;
+    pub const SYSTEM_MANDATORY_LABEL: u8 = 0x11;
+}
+
+pub mod ace_flags {
+    pub const OBJECT_INHERIT: u8 = 0x01;
+    pub const CONTAINER_INHERIT: u8 = 0x02;
+    pub const NO_PROPAGATE_INHERIT: u8 = 0x04;
+    // The ACE only applies to children, not the object itself.
+    pub const INHERIT_ONLY: u8 = 0x08;
+    pub const INHERITED: u8 = 0x10;
+}
+
+pub mod access_rights {
+    pub const FILE_READ_DATA: u32 = 0x0000_0001;
+    pub const FILE_WRITE_DATA: u32 = 0x0000_0002;
+    pub const FILE_APPEND_DATA: u32 = 0x0000_0004;
+    pub const FILE_READ_EA: u32 = 0x0000_0008;
+    pub const FILE_WRITE_EA: u32 = 0x0000_0010;
+    pub const FILE_EXECUTE: u32 = 0x0000_0020;
+    pub const FILE_DELETE_CHILD: u32 = 0x0000_0040;
+    pub const FILE_READ_ATTRIBUTES: u32 = 0x0000_0080;
+    pub const FILE_WRITE_ATTRIBUTES: u32 = 0x0000_0100;
+    pub const DELETE: u32 = 0x0001_0000;
+    pub const READ_CONTROL: u32 = 0x0002_0000;
+    pub const WRITE_DAC: u32 = 0x0004_0000;
+    pub const WRITE_OWNER: u32 = 0x0008_0000;
+    pub const SYNCHRONIZE: u32 = 0x0010_0000;
+    pub const GENERIC_ALL: u32 = 0x1000_0000;
+    pub const GENERIC_EXECUTE: u32 = 0x2000_0000;
+    pub const GENERIC_WRITE: u32 = 0x4000_0000;
+    pub const GENERIC_READ: u32 = 0x8000_0000;
+
+    pub const FILE_GENERIC_READ: u32 =
+        READ_CONTROL | FILE_READ_DATA | FILE_READ_ATTRIBUTES | FILE_READ_EA | SYNCHRONIZE;
+    pub const FILE_GENERIC_WRITE: u32 = READ_CONTROL
+        | FILE_WRITE_DATA
+        | FILE_WRITE_ATTRIBUTES
+        | FILE_WRITE_EA
+        | FILE_APPEND_DATA
+        | SYNCHRONIZE;
+    pub const FILE_GENERIC_EXECUTE: u32 =
+        READ_CONTROL | FILE_READ_ATTRIBUTES | FILE_EXECUTE | SYNCHRONIZE;
+    pub const FILE_ALL_ACCESS: u32 = 0x001F_01FF;
+}
+
+// A security identifier, such as S-1-5-32-544 (the local Administrators
+// group).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Sid {
+    pub revision: u8,
+    // A 48-bit value
+    pub identifier_authority: u64,
+    pub sub_authorities: Vec<u32>,
+}
+impl Sid {
+    // S-1-1-0
+    pub fn everyone() -> Self {
+        Sid {
+            revision: 1,
+            identifier_authority: 1,
+            sub_authorities: vec![0],
+        }
+    }
+
+    // Parses the SID at the start of `buf`, returning it and its length.
+    pub fn load(buf: &[u8]) -> Result<(Self, usize), Error> {
+        if buf.len() < 8 {
+            return Err(Error::BadSecurityDescriptor);
+        }
+        let count = usize::from(buf[1]);
+        let length = 8 + 4 * count;
+        if buf.len() < length {
+            return Err(Error::BadSecurityDescriptor);
+        }
+        // The authority is big-endian, unlike everything else.
+        let identifier_authority = buf[2..8]
+            .iter()
+            .fold(0, |authority, &byte| (authority << 8) | u64::from(byte));
+        let sub_authorities = buf[8..length]
+            .chunks_exact(4)
+            .map(|sub_authority| u32::from_le_bytes(sub_authority.try_into().unwrap()))
+            .collect();
+        Ok((
+            Sid {
+                revision: buf[0],
+                identifier_authority,
+                sub_authorities,
+            },
+            length,
+        ))
+    }
+}
+impl fmt::Display for Sid {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "S-{}-", self.revision)?;
+        // Large authorities are written in hex.
+        if self.identifier_authority >= 1 << 32 {
+            write!(fmt, "0x{:012X}", self.identifier_authority)?;
+        } else {
+            write!(fmt, "{}", self.identifier_authority)?;
+        }
+        for sub_authority in &self.sub_authorities {
+            write!(fmt, "-{}", sub_authority)?;
+        }
+        Ok(())
+    }
+}
+
+// One access control entry. Only the common parts are decoded; `sid` is
+// None for types whose layout isn't known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ace {
+    pub ace_type: u8,
+    pub flags: u8,
+    pub access_mask: u32,
+    // Only object ACEs can limit themselves to a type of object, or of child
+    pub object_type: Option<Guid>,
+    pub inherited_object_type: Option<Guid>,
+    pub sid: Option<Sid>,
+}
+impl Ace {
+    // Parses the ACE at the start of `buf`, returning it and its length.
+    pub fn load(buf: &[u8]) -> Result<(Self, usize), Error> {
+        if buf.len() < 8 {
+            return Err(Error::BadSecurityDescriptor);
+        }
+        let length = usize::from(u16::from_le_bytes([buf[2], buf[3]]));
+        if length < 8 || length > buf.len() {
+            return Err(Error::BadSecurityDescriptor);
+        }
+        let ace_type = buf[0];
+        let body = &buf[..length];
+
+        use ace_types::*;
+        let (object_type, inherited_object_type, sid) = match ace_type {
+            ACCESS_ALLOWED
+            | ACCESS_DENIED
+            | SYSTEM_AUDIT
+            | SYSTEM_ALARM
+            | ACCESS_ALLOWED_CALLBACK
+            | ACCESS_DENIED_CALLBACK
+            | SYSTEM_AUDIT_CALLBACK
+            | SYSTEM_ALARM_CALLBACK
+            | SYSTEM_MANDATORY_LABEL => (None, None, Some(Sid::load(&body[8..])?.0)),
+            ACCESS_ALLOWED_OBJECT
+            | ACCESS_DENIED_OBJECT
+            | SYSTEM_AUDIT_OBJECT
+            | SYSTEM_ALARM_OBJECT
+            | ACCESS_ALLOWED_CALLBACK_OBJECT
+            | ACCESS_DENIED_CALLBACK_OBJECT
+            | SYSTEM_AUDIT_CALLBACK_OBJECT
+            | SYSTEM_ALARM_CALLBACK_OBJECT => {
+                let object_flags = body.get(8..12).ok_or(Error::BadSecurityDescriptor)?;
+                let object_flags = u32::from_le_bytes(object_flags.try_into().unwrap());
+                let mut offset = 12;
+                let mut guid = |present: bool| -> Result<Option<Guid>, Error> {
+                    if !present {
+                        return Ok(None);
+                    }
+                    let guid = body
+                        .get(offset..offset + 16)
+                        .ok_or(Error::BadSecurityDescriptor)?;
+                    offset += 16;
+                    Ok(Some(Guid::load(guid)))
+                };
+                let object_type = guid(object_flags & 0x1 != 0)?;
+                let inherited_object_type = guid(object_flags & 0x2 != 0)?;
+                (
+                    object_type,
+                    inherited_object_type,
+                    Some(Sid::load(&body[offset..])?.0),
+                )
+            }
+            _ => (None, None, None),
+        };
+
+        Ok((
+            Ace {
+                ace_type,
+                flags: buf[1],
+                access_mask: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+                object_type,
+                inherited_object_type,
+                sid,
+            },
+            length,
+        ))
+    }
+
+    pub fn is_allow(&self) -> bool {
+        use ace_types::*;
+        matches!(
+            self.ace_type,
+            ACCESS_ALLOWED
+                | ACCESS_ALLOWED_OBJECT
+                | ACCESS_ALLOWED_CALLBACK
+                | ACCESS_ALLOWED_CALLBACK_OBJECT
+        )
+    }
+
+    pub fn is_deny(&self) -> bool {
+        use ace_types::*;
+        matches!(
+            self.ace_type,
+            ACCESS_DENIED
+                | ACCESS_DENIED_OBJECT
+                | ACCESS_DENIED_CALLBACK
+                | ACCESS_DENIED_CALLBACK_OBJECT
+        )
+    }
+
+    pub fn is_inherited(&self) -> bool {
+        self.flags & ace_flags::INHERITED != 0
+    }
+
+    // The rights this ACE covers, with the generic rights mapped to what
+    // they mean for files.
+    pub fn file_access_mask(&self) -> u32 {
+        use access_rights::*;
+        let mut mask = self.access_mask & 0x0FFF_FFFF;
+        if self.access_mask & GENERIC_READ != 0 {
+            mask |= FILE_GENERIC_READ;
+        }
+        if self.access_mask & GENERIC_WRITE != 0 {
+            mask |= FILE_GENERIC_WRITE;
+        }
+        if self.access_mask & GENERIC_EXECUTE != 0 {
+            mask |= FILE_GENERIC_EXECUTE;
+        }
+        if self.access_mask & GENERIC_ALL != 0 {
+            mask |= FILE_ALL_ACCESS;
+        }
+        mask
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub revision: u8,
+    pub aces: Vec<Ace>,
+}
+impl Acl {
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < 8 {
+            return Err(Error::BadSecurityDescriptor);
+        }
+        let size = usize::from(u16::from_le_bytes([buf[2], buf[3]]));
+        let count = u16::from_le_bytes([buf[4], buf[5]]);
+        let buf = buf.get(..size).ok_or(Error::BadSecurityDescriptor)?;
+        let mut aces = Vec::new();
+        let mut offset = 8;
+        for _ in 0..count {
+            let (ace, length) = Ace::load(buf.get(offset..).ok_or(Error::BadSecurityDescriptor)?)?;
+            aces.push(ace);
+            offset += length;
+        }
+        Ok(Acl {
+            revision: buf[0],
+            aces,
+        })
+    }
+}
+
+// A self-relative security descriptor, as stored in $SDS or a
+// $SECURITY_DESCRIPTOR attribute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityDescriptor {
+    pub revision: u8,
+    pub control: u16,
+    pub owner: Option<Sid>,
+    pub group: Option<Sid>,
+    // None if there's no DACL, which grants everyone full access. An empty
+    // DACL grants no access at all.
+    pub dacl: Option<Acl>,
+    pub sacl: Option<Acl>,
+}
+impl SecurityDescriptor {
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < 20 {
+            return Err(Error::BadSecurityDescriptor);
+        }
+        let control = u16::from_le_bytes([buf[2], buf[3]]);
+        let offset = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap()) as usize;
+        let part = |offset: usize| match offset {
+            0 => Ok(None),
+            offset => buf
+                .get(offset..)
+                .map(Some)
+                .ok_or(Error::BadSecurityDescriptor),
+        };
+        let sid = |offset| -> Result<Option<Sid>, Error> {
+            part(offset)?
+                .map(|buf| Sid::load(buf).map(|(sid, _)| sid))
+                .transpose()
+        };
+        let acl = |offset, flag| -> Result<Option<Acl>, Error> {
+            if control & flag == 0 {
+                return Ok(None);
+            }
+            part(offset)?.map(Acl::load).transpose()
+        };
+
+        Ok(SecurityDescriptor {
+            revision: buf[0],
+            control,
+            owner: sid(offset(4))?,
+            group: sid(offset(8))?,
+            sacl: acl(offset(12), control_flags::SACL_PRESENT)?,
+            dacl: acl(offset(16), control_flags::DACL_PRESENT)?,
+        })
+    }
+
+    // Whether the DACL gives `sid` all of `access_mask` (rights from
+    // `access_rights`), going by ACEs naming that SID alone. Group
+    // memberships aren't known offline, so a check for Everyone only
+    // considers ACEs for Everyone.
+    pub fn grants(&self, sid: &Sid, access_mask: u32) -> bool {
+        let dacl = match self.dacl {
+            Some(ref dacl) => dacl,
+            None => return true,
+        };
+        let mut remaining = access_mask;
+        for ace in &dacl.aces {
+            if ace.flags & ace_flags::INHERIT_ONLY != 0 || ace.sid.as_ref() != Some(sid) {
+                continue;
+            }
+            let mask = ace.file_access_mask();
+            if ace.is_deny() && mask & remaining != 0 {
+                return false;
+            }
+            if ace.is_allow() {
+                remaining &= !mask;
+                if remaining == 0 {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+// An entry in $SII: where the descriptor with a given security ID is in
+// $SDS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdsLocation {
+    pub security_id: u32,
+    pub offset: u64,
+    // Including the header
+    pub length: u32,
+}
+impl SdsLocation {
+    // Parses the data of a $SII entry (or the header of a descriptor in
+    // $SDS, which is the same).
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < SDS_HEADER_LENGTH {
+            return Err(Error::BadSecurityDescriptor);
+        }
+        Ok(SdsLocation {
+            security_id: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            length: u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mft::{sys, MasterFileTable},
+        test_support::{
+            index_root_node, last_index_entry, namespaces, security_descriptor, sid_bytes,
+            type_codes, view_index_entry, Attribute, ImageBuilder, Record, Run, StandardInfo,
+            ROOT_RECORD,
+        },
+    };
+
+    #[test]
+    fn formats_sids() {
+        let (sid, length) =
+            Sid::load(&sid_bytes("S-1-5-21-1004336348-1177238915-682003330-512")).unwrap();
+        assert_eq!(length, 28);
+        assert_eq!(
+            sid.to_string(),
+            "S-1-5-21-1004336348-1177238915-682003330-512"
+        );
+        assert_eq!(Sid::everyone().to_string(), "S-1-1-0");
+    }
+
+    #[test]
+    fn decodes_descriptors() {
+        use access_rights::*;
+        let descriptor = security_descriptor(
+            "S-1-5-32-544",
+            "S-1-5-18",
+            Some(&[
+                (ace_types::ACCESS_DENIED, 0, FILE_WRITE_DATA, "S-1-5-32-545"),
+                (
+                    ace_types::ACCESS_ALLOWED,
+                    0,
+                    GENERIC_READ | GENERIC_WRITE,
+                    "S-1-1-0",
+                ),
+                (
+                    ace_types::ACCESS_ALLOWED,
+                    ace_flags::INHERIT_ONLY,
+                    GENERIC_ALL,
+                    "S-1-5-32-545",
+                ),
+            ]),
+        );
+        let descriptor = SecurityDescriptor::load(&descriptor).unwrap();
+        assert_eq!(
+            descriptor.owner.as_ref().unwrap().to_string(),
+            "S-1-5-32-544"
+        );
+        assert_eq!(descriptor.group.as_ref().unwrap().to_string(), "S-1-5-18");
+        assert!(descriptor.sacl.is_none());
+        let dacl = descriptor.dacl.as_ref().unwrap();
+        assert_eq!(dacl.aces.len(), 3);
+        assert!(dacl.aces[0].is_deny());
+        assert_eq!(dacl.aces[1].sid, Some(Sid::everyone()));
+
+        let everyone = Sid::everyone();
+        let (users, _) = Sid::load(&sid_bytes("S-1-5-32-545")).unwrap();
+        assert!(descriptor.grants(&everyone, FILE_WRITE_DATA | FILE_READ_DATA));
+        assert!(!descriptor.grants(&everyone, DELETE));
+        assert!(!descriptor.grants(&users, FILE_READ_DATA));
+
+        // No DACL at all lets anyone do anything.
+        let open =
+            SecurityDescriptor::load(&security_descriptor("S-1-5-18", "S-1-5-18", None)).unwrap();
+        assert!(open.grants(&everyone, FILE_ALL_ACCESS));
+    }
+
+    #[test]
+    fn resolves_security_ids() {
+        let mut builder = ImageBuilder::new();
+        let mut sds = Vec::new();
+        let mut sii = Vec::new();
+        for (security_id, owner) in [
+            (0x100u32, Some("S-1-5-32-544")),
+            (0x101, Some("S-1-5-18")),
+            // Too short to be a descriptor
+            (0x102, None),
+        ] {
+            let descriptor = match owner {
+                Some(owner) => security_descriptor(owner, "S-1-5-18", Some(&[])),
+                None => vec![1, 0, 4, 0x80],
+            };
+            let mut header = vec![0; SDS_HEADER_LENGTH];
+            header[4..8].copy_from_slice(&security_id.to_le_bytes());
+            header[8..16].copy_from_slice(&(sds.len() as u64).to_le_bytes());
+            let length = (SDS_HEADER_LENGTH + descriptor.len()) as u32;
+            header[16..20].copy_from_slice(&length.to_le_bytes());
+            sii.push(view_index_entry(&security_id.to_le_bytes(), &header));
+            sds.extend_from_slice(&header);
+            sds.extend_from_slice(&descriptor);
+            sds.resize(sds.len().div_ceil(16) * 16, 0);
+        }
+        // One that points past the end of $SDS
+        let mut header = vec![0; SDS_HEADER_LENGTH];
+        header[4..8].copy_from_slice(&0x103u32.to_le_bytes());
+        header[8..16].copy_from_slice(&0x10000u64.to_le_bytes());
+        header[16..20].copy_from_slice(&0x100u32.to_le_bytes());
+        sii.push(view_index_entry(&0x103u32.to_le_bytes(), &header));
+        sii.push(last_index_entry(None));
+        let sds_lcn = builder.write_clusters(&sds);
+        builder.set_record(
+            SECURE_RECORD,
+            Record::new()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "$Secure",
+                    namespaces::WIN32_DOS,
+                ))
+                .with(Attribute::non_resident_data(
+                    Some(SDS_STREAM_NAME),
+                    vec![Run::at(sds_lcn, 1)],
+                    sds.len() as u64,
+                ))
+                .with(Attribute::index_root(SII_INDEX_NAME, index_root_node(&sii))),
+        );
+        let shared = builder.add_record(
+            Record::new()
+                .with(
+                    StandardInfo {
+                        security_id: 0x101,
+                        ..Default::default()
+                    }
+                    .attribute(),
+                )
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "shared",
+                    namespaces::WIN32,
+                )),
+        );
+        let own = builder.add_record(
+            Record::new()
+                .with(
+                    StandardInfo {
+                        security_id: 0x100,
+                        ..Default::default()
+                    }
+                    .attribute(),
+                )
+                .with(Attribute::file_name(ROOT_RECORD, "own", namespaces::WIN32))
+                .with(Attribute::resident(
+                    type_codes::SECURITY_DESCRIPTOR,
+                    None,
+                    security_descriptor("S-1-1-0", "S-1-5-18", None),
+                )),
+        );
+        let damaged = builder.add_record(
+            Record::new()
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    "damaged",
+                    namespaces::WIN32,
+                ))
+                .with(Attribute::resident(
+                    type_codes::SECURITY_DESCRIPTOR,
+                    None,
+                    vec![1, 0, 4],
+                )),
+        );
+
+        let mut mft = MasterFileTable::open(builder.build()).unwrap();
+        // Bad entries are left out, rather than losing the good ones too.
+        let descriptors = mft.read_security_descriptors().unwrap();
+        assert_eq!(descriptors.len(), 2);
+        assert!(descriptors.contains_key(&0x100) && descriptors.contains_key(&0x101));
+        let entry = mft.read_entry(damaged).unwrap().unwrap();
+        assert!(entry.security_descriptor.is_none());
+        assert_eq!(
+            entry.bad_attributes,
+            [sys::AttributeType::SecurityDescriptor]
+        );
+        let mut owner = |file| {
+            let entry = mft.read_entry(file).unwrap().unwrap();
+            let descriptor = entry.resolve_security_descriptor(&descriptors).unwrap();
+            descriptor.owner.as_ref().unwrap().to_string()
+        };
+        assert_eq!(owner(shared), "S-1-5-18");
+        // The file's own descriptor wins over its security ID.
+        assert_eq!(owner(own), "S-1-1-0");
+    }
+}
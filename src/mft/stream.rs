@@ -1,33 +1,19 @@
-use crate::{err::Error, SafeHandle};
+use crate::{err::Error, reader::VolumeReader};
 
-use winapi::{
-    ctypes::c_void,
-    shared::{minwindef::DWORD, winerror},
-    um::{
-        errhandlingapi as ehapi,
-        fileapi::ReadFile,
-        ioapiset::DeviceIoControl,
-        minwinbase::OVERLAPPED,
-        winioctl::{FSCTL_GET_RETRIEVAL_POINTERS, NTFS_VOLUME_DATA_BUFFER},
-        winnt::LONGLONG,
-    },
-};
-
-use std::{convert::TryInto as _, mem, ptr};
+use std::convert::TryInto as _;
 
 // Represents a continuous list of logical clusters in one file.
 #[derive(Debug, Clone, Copy)]
-struct Extent {
-    min_vcn: i64,
-    min_lcn: i64,
-    cluster_count: i64,
+pub struct Extent {
+    pub min_vcn: i64,
+    pub min_lcn: i64,
+    pub cluster_count: i64,
 }
 
-// Reads a file using a volume handle instead of a readable file handle.
-// Although a file handle is needed to initialize this object, it's not
-// persisted and does not need to be readable.
-pub struct MftStream {
-    volume_handle: SafeHandle,
+// Reads a file (the MFT) out of a volume, given the list of extents that
+// make it up.
+pub struct MftStream<R: VolumeReader> {
+    volume: R,
 
     bytes_per_cluster: u64,
     bytes_per_file_record_segment: u64,
@@ -35,40 +21,43 @@ pub struct MftStream {
     extents: Vec<Extent>,
 
     buffer: Vec<u8>,
+    buffer_len: usize,  // how much of the buffer holds valid data
     buffer_offset: u64, // into the volume, in bytes
 }
-impl MftStream {
+impl<R: VolumeReader> MftStream<R> {
     pub fn new(
-        volume_handle: SafeHandle,
-        volume_info: NTFS_VOLUME_DATA_BUFFER,
-        file_handle: &SafeHandle,
+        volume: R,
+        extents: Vec<Extent>,
+        bytes_per_cluster: u64,
+        bytes_per_file_record_segment: u64,
+        len: u64,
     ) -> Result<Self, Error> {
-        let extents = load_file_extents(&file_handle)?;
-        if extents.first().ok_or(Error::MftHasNoExtents)?.min_lcn
-            != *unsafe { volume_info.MftStartLcn.QuadPart() }
-        {
-            return Err(Error::MftStartLcnNotFirstExtent);
+        if extents.is_empty() {
+            return Err(Error::MftHasNoExtents);
         }
 
-        let bytes_per_cluster = volume_info.BytesPerCluster.try_into().unwrap();
-
         Ok(MftStream {
-            volume_handle,
+            volume,
             bytes_per_cluster,
-            bytes_per_file_record_segment: volume_info
-                .BytesPerFileRecordSegment
-                .try_into()
-                .unwrap(),
-            len: unsafe { *volume_info.MftValidDataLength.QuadPart() }
-                .try_into()
-                .unwrap(),
+            bytes_per_file_record_segment,
+            len,
             extents,
             // 16 MB is a reasonable tradeoff between perf and memory usage
             buffer: vec![0; 16 * 1024 * 1024],
+            buffer_len: 0,
             buffer_offset: 0,
         })
     }
 
+    pub fn set_extents(&mut self, extents: Vec<Extent>, len: u64) {
+        self.extents = extents;
+        self.len = len;
+    }
+
+    pub fn volume_len(&self) -> u64 {
+        self.volume.len()
+    }
+
     pub fn get_file_record_segment_count(&self) -> u64 {
         self.len / self.bytes_per_file_record_segment
     }
@@ -93,36 +82,53 @@ impl MftStream {
         debug_assert_eq!(0, self.len % self.bytes_per_file_record_segment);
         debug_assert_eq!(buf.len() as u64, self.bytes_per_file_record_segment);
 
-        // Convert the segment to an LCN and offset. A file record segment can't be split
-        // across multiple extents (I think/hope).
-        let volume_offset: u64 = {
-            // Convert the segment number to a offset in the file.
-            let mut target_offset = segment * self.bytes_per_file_record_segment;
-            let mut extent_idx = 0;
-            loop {
-                let extent = &self.extents[extent_idx];
-                let extent_len = extent.cluster_count as u64 * self.bytes_per_cluster;
-                if target_offset < extent_len {
-                    // We found the correct extent!
-                    let extent_start_lcn = extent.min_lcn as u64;
-                    break Some((extent_start_lcn * self.bytes_per_cluster) + target_offset);
-                } else {
-                    extent_idx += 1;
-                    target_offset -= extent_len;
-                }
+        let (volume_offset, _) = self.locate_segment(segment)?;
+        self.read_volume(volume_offset, buf, use_cache)
+    }
+
+    // How many records the buffer holds when full.
+    pub fn buffer_capacity(&self) -> u64 {
+        self.buffer.len() as u64 / self.bytes_per_file_record_segment
+    }
 
-                if extent_idx >= self.extents.len() {
-                    println!(
-                        "WHOOPS: {:#?} {} {}",
-                        self.extents, self.bytes_per_file_record_segment, segment,
-                    );
-                    break None;
-                }
+    // Whether reading `segment` with the cache would be served from the
+    // buffer.
+    pub fn is_buffered(&self, segment: u64) -> bool {
+        match self.locate_segment(segment) {
+            Ok((offset, _)) => {
+                offset >= self.buffer_offset
+                    && offset + self.bytes_per_file_record_segment
+                        <= self.buffer_offset + self.buffer_len as u64
             }
+            Err(_) => false,
         }
-        .unwrap(); // TODO: proper error handling here
+    }
 
-        self.read_volume(volume_offset, buf, use_cache)
+    // Fills the buffer with `count` records starting at `segment`, instead
+    // of as much as fits, so runs of free records after them aren't read.
+    // Stops early at the end of the extent `segment` is in.
+    pub fn fill_buffer(&mut self, segment: u64, count: u64) -> Result<(), Error> {
+        let (offset, extent_remaining) = self.locate_segment(segment)?;
+        let len = (count.max(1) * self.bytes_per_file_record_segment).min(extent_remaining);
+        self.fill(offset, len.try_into().unwrap_or(usize::MAX))
+    }
+
+    // Finds where `segment` is on the volume, and how many bytes of its
+    // extent are left from there. A file record segment can't be split
+    // across multiple extents (I think/hope).
+    fn locate_segment(&self, segment: u64) -> Result<(u64, u64), Error> {
+        // Convert the segment number to a offset in the file.
+        let mut target_offset = segment * self.bytes_per_file_record_segment;
+        for extent in &self.extents {
+            let extent_len = extent.cluster_count as u64 * self.bytes_per_cluster;
+            if target_offset < extent_len {
+                // We found the correct extent!
+                let extent_start = extent.min_lcn as u64 * self.bytes_per_cluster;
+                return Ok((extent_start + target_offset, extent_len - target_offset));
+            }
+            target_offset -= extent_len;
+        }
+        Err(Error::SegmentOutsideMftExtents(segment))
     }
 
     fn read_volume(&mut self, offset: u64, buf: &mut [u8], use_cache: bool) -> Result<(), Error> {
@@ -131,168 +137,49 @@ impl MftStream {
         //     println!("read_volume: {:X}", offset);
         // }
 
-        if use_cache {
-            let cur_buffer_end = self.buffer_offset + (self.buffer.len() as u64);
-            let request_end = offset + (buf.len() as u64);
-
-            if cur_buffer_end < request_end || offset < self.buffer_offset {
-                // The read will go out of the buffer, so read a new one.
-                // #[cfg(debug_assertions)]
-                // {
-                //     println!("reading new buffer");
-                // }
-
-                self.buffer_offset = offset;
-
-                let mut overlapped = {
-                    let offset: u64 = offset.try_into().unwrap();
-                    let mut ov = OVERLAPPED::default();
-                    unsafe { ov.u.s_mut() }.Offset =
-                        (offset & 0x0000_0000_FFFF_FFFFu64).try_into().unwrap();
-                    unsafe { ov.u.s_mut() }.OffsetHigh = ((offset & 0xFFFF_FFFF_0000_0000u64)
-                        >> 32)
-                        .try_into()
-                        .unwrap();
-                    ov
-                };
+        if !use_cache {
+            return self.volume.read_at(offset, buf);
+        }
 
-                let mut num_bytes_read = 0;
-                let success = unsafe {
-                    ReadFile(
-                        *self.volume_handle,
-                        self.buffer.as_mut_ptr() as *mut c_void,
-                        self.buffer.len().try_into().unwrap(),
-                        &mut num_bytes_read,
-                        &mut overlapped,
-                    )
-                };
-                if success == 0 {
-                    let err = unsafe { ehapi::GetLastError() };
-                    return Err(Error::ReadVolumeFailed(err));
-                }
-                let num_bytes_read: usize = num_bytes_read.try_into().unwrap();
-                if num_bytes_read != self.buffer.len() {
-                    return Err(Error::ReadVolumeTooShort);
-                }
-            }
+        let cur_buffer_end = self.buffer_offset + (self.buffer_len as u64);
+        let request_end = offset + (buf.len() as u64);
 
-            let buffer_start: usize = (offset - self.buffer_offset).try_into().unwrap();
-            let buffer_end = buffer_start + buf.len();
-            buf.copy_from_slice(&self.buffer[buffer_start..buffer_end]);
-        } else {
-            let mut overlapped = {
-                let offset: u64 = offset.try_into().unwrap();
-                let mut ov = OVERLAPPED::default();
-                unsafe { ov.u.s_mut() }.Offset =
-                    (offset & 0x0000_0000_FFFF_FFFFu64).try_into().unwrap();
-                unsafe { ov.u.s_mut() }.OffsetHigh = ((offset & 0xFFFF_FFFF_0000_0000u64) >> 32)
-                    .try_into()
-                    .unwrap();
-                ov
-            };
+        if cur_buffer_end < request_end || offset < self.buffer_offset {
+            // The read will go out of the buffer, so read a new one.
+            // #[cfg(debug_assertions)]
+            // {
+            //     println!("reading new buffer");
+            // }
 
-            let mut num_bytes_read = 0;
-            let success = unsafe {
-                ReadFile(
-                    *self.volume_handle,
-                    buf.as_mut_ptr() as *mut c_void,
-                    buf.len().try_into().unwrap(),
-                    &mut num_bytes_read,
-                    &mut overlapped,
-                )
-            };
-            if success == 0 {
-                let err = unsafe { ehapi::GetLastError() };
-                return Err(Error::ReadVolumeFailed(err));
-            }
-            let num_bytes_read: usize = num_bytes_read.try_into().unwrap();
-            if num_bytes_read != buf.len() {
+            self.fill(offset, self.buffer.len())?;
+            if self.buffer_len < buf.len() {
                 return Err(Error::ReadVolumeTooShort);
             }
         }
 
+        let buffer_start: usize = (offset - self.buffer_offset).try_into().unwrap();
+        let buffer_end = buffer_start + buf.len();
+        buf.copy_from_slice(&self.buffer[buffer_start..buffer_end]);
+
         Ok(())
     }
-}
-
-fn load_file_extents(handle: &SafeHandle) -> Result<Vec<Extent>, Error> {
-    let mut result = Vec::new();
-    let mut current_starting_vcn = 0;
-
-    // This should be big enough for all but the most fragmented files.
-    let mut retrieval_buffer = vec![0u8; 64 * 1024];
-
-    loop {
-        let mut result_size = 0;
-
-        let success = unsafe {
-            DeviceIoControl(
-                **handle,
-                FSCTL_GET_RETRIEVAL_POINTERS,
-                &mut current_starting_vcn as *mut LONGLONG as *mut c_void,
-                mem::size_of::<LONGLONG>().try_into().unwrap(),
-                retrieval_buffer.as_mut_ptr() as *mut c_void,
-                retrieval_buffer.len().try_into().unwrap(),
-                &mut result_size,
-                ptr::null_mut(),
-            )
-        };
-        let err = unsafe { ehapi::GetLastError() };
-        if success == 0 {
-            return Err(Error::GetRetrievalPointersFailed(err));
-        }
-
-        let mut returned_buffer = &retrieval_buffer[..result_size as usize];
 
-        // The first DWORD in the buffer is the number of Extents returned.
-        let extent_count = DWORD::from_le_bytes(
-            returned_buffer[0..mem::size_of::<DWORD>()]
-                .try_into()
-                .unwrap(),
-        );
-        // Note that we have now consumed sizeof(LONGLONG) bytes due to struct padding.
-        returned_buffer = &returned_buffer[mem::size_of::<LONGLONG>()..];
-
-        // That's followed by a LARGE_INTEGER representing the first VCN mapping returned.
-        let mut min_vcn = LONGLONG::from_le_bytes(
-            returned_buffer[..mem::size_of::<LONGLONG>()]
-                .try_into()
-                .unwrap(),
-        );
-        returned_buffer = &returned_buffer[mem::size_of::<LONGLONG>()..];
-
-        // Then, there's a list of tuples, each giving the starting LCN for the current
-        // extent and the VCN that starts the next extent.
-        let mut next_min_vcn = min_vcn;
-
-        for _ in 0..extent_count {
-            next_min_vcn = LONGLONG::from_le_bytes(
-                returned_buffer[..mem::size_of::<LONGLONG>()]
-                    .try_into()
-                    .unwrap(),
-            );
-            returned_buffer = &returned_buffer[mem::size_of::<LONGLONG>()..];
-
-            let min_lcn = LONGLONG::from_le_bytes(
-                returned_buffer[..mem::size_of::<LONGLONG>()]
-                    .try_into()
-                    .unwrap(),
-            );
-            returned_buffer = &returned_buffer[mem::size_of::<LONGLONG>()..];
-
-            result.push(Extent {
-                min_lcn,
-                min_vcn,
-                cluster_count: next_min_vcn - min_vcn,
-            });
-            min_vcn = next_min_vcn;
-        }
-
-        current_starting_vcn = next_min_vcn;
-        if err != winerror::ERROR_MORE_DATA {
-            break;
-        }
+    // Reads up to `len` bytes at `offset` into the buffer.
+    fn fill(&mut self, offset: u64, len: usize) -> Result<(), Error> {
+        // Don't read past the end of the volume; images in particular tend
+        // to be much smaller than our buffer.
+        let available = self.volume.len().saturating_sub(offset);
+        let buffer_len = self
+            .buffer
+            .len()
+            .min(len)
+            .min(available.try_into().unwrap_or(usize::MAX));
+
+        self.buffer_offset = offset;
+        self.buffer_len = 0;
+        self.volume
+            .read_at(offset, &mut self.buffer[..buffer_len])?;
+        self.buffer_len = buffer_len;
+        Ok(())
     }
-
-    Ok(result)
 }
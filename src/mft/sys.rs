@@ -1,8 +1,100 @@
-use crate::{err::Error, mft::parse_string};
+use crate::{
+    err::Error,
+    mft::{FileTime, NtfsName},
+    reader::VolumeReader,
+};
 
-use std::{convert::TryInto as _, ffi::OsString};
+use std::convert::TryInto as _;
 
 const MULTI_SECTOR_HEADER_FILE_SIGNATURE: [u8; 4] = [b'F', b'I', b'L', b'E'];
+pub const MULTI_SECTOR_HEADER_INDEX_SIGNATURE: [u8; 4] = [b'I', b'N', b'D', b'X'];
+
+pub const BOOT_SECTOR_LENGTH: usize = 512;
+pub const NTFS_OEM_ID: [u8; 8] = *b"NTFS    ";
+const BOOT_SECTOR_END_MARKER: [u8; 2] = [0x55, 0xAA];
+// The largest cluster size NTFS supports
+const MAX_BYTES_PER_CLUSTER: u64 = 2 * 1024 * 1024;
+
+// The parts of the NTFS boot sector ($Boot) that describe the volume's layout.
+// This carries the same information FSCTL_GET_NTFS_VOLUME_DATA gives us, but
+// can be read from any volume, including images.
+#[derive(Debug, Clone)]
+pub struct VolumeGeometry {
+    pub bytes_per_sector: u64,
+    pub bytes_per_cluster: u64,
+    pub bytes_per_file_record_segment: u64,
+    pub bytes_per_index_record: u64,
+    pub total_sectors: u64,
+    pub mft_start_lcn: u64,
+    pub mft_mirror_start_lcn: u64,
+    pub volume_serial_number: u64,
+}
+impl VolumeGeometry {
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < BOOT_SECTOR_LENGTH {
+            return Err(Error::ReadVolumeTooShort);
+        }
+        if buf[3..11] != NTFS_OEM_ID || buf[510..512] != BOOT_SECTOR_END_MARKER {
+            return Err(Error::BadBootSectorSignature);
+        }
+
+        let bytes_per_sector: u64 = u16::from_le_bytes([buf[11], buf[12]]).into();
+        // Volumes with clusters bigger than 64KB store the cluster size as a
+        // negative power of two.
+        let sectors_per_cluster = match buf[13] {
+            0 => None,
+            count @ 1..=0x80 => Some(count.into()),
+            exponent => 1u64.checked_shl(256 - u32::from(exponent)),
+        };
+        if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 256 {
+            return Err(Error::BadBootSectorGeometry);
+        }
+        let bytes_per_cluster = sectors_per_cluster
+            .and_then(|count| bytes_per_sector.checked_mul(count))
+            .filter(|bytes| *bytes <= MAX_BYTES_PER_CLUSTER)
+            .ok_or(Error::BadBootSectorGeometry)?;
+
+        Ok(VolumeGeometry {
+            bytes_per_sector,
+            bytes_per_cluster,
+            bytes_per_file_record_segment: clusters_or_exponent_to_bytes(
+                buf[64] as i8,
+                bytes_per_cluster,
+            )?,
+            bytes_per_index_record: clusters_or_exponent_to_bytes(
+                buf[68] as i8,
+                bytes_per_cluster,
+            )?,
+            total_sectors: u64::from_le_bytes(buf[40..48].try_into().unwrap()),
+            mft_start_lcn: u64::from_le_bytes(buf[48..56].try_into().unwrap()),
+            mft_mirror_start_lcn: u64::from_le_bytes(buf[56..64].try_into().unwrap()),
+            volume_serial_number: u64::from_le_bytes(buf[72..80].try_into().unwrap()),
+        })
+    }
+
+    // Reads and parses the boot sector at the very start of the volume.
+    pub fn read<R: VolumeReader + ?Sized>(volume: &mut R) -> Result<Self, Error> {
+        let sector_size: usize = volume.sector_size().try_into().unwrap();
+        let mut buf = vec![0; sector_size.max(BOOT_SECTOR_LENGTH)];
+        volume.read_at(0, &mut buf[..])?;
+        VolumeGeometry::load(&buf[..])
+    }
+
+    pub fn volume_size(&self) -> u64 {
+        self.total_sectors * self.bytes_per_sector
+    }
+}
+
+// Record sizes in the boot sector are either a positive number of clusters,
+// or (when a record is smaller than a cluster) a negative power of two in bytes.
+fn clusters_or_exponent_to_bytes(value: i8, bytes_per_cluster: u64) -> Result<u64, Error> {
+    match value {
+        0 => Err(Error::BadBootSectorGeometry),
+        clusters @ 1..=i8::MAX => Ok(clusters as u64 * bytes_per_cluster),
+        exponent if exponent > -32 => Ok(1u64 << -exponent),
+        _ => Err(Error::BadBootSectorGeometry),
+    }
+}
 
 #[derive(Debug)]
 pub struct MultiSectorHeader {
@@ -11,7 +103,11 @@ pub struct MultiSectorHeader {
 }
 impl MultiSectorHeader {
     pub fn load(buf: &[u8]) -> Result<Self, Error> {
-        if buf[0..4] != MULTI_SECTOR_HEADER_FILE_SIGNATURE {
+        Self::load_with_signature(buf, MULTI_SECTOR_HEADER_FILE_SIGNATURE)
+    }
+
+    pub fn load_with_signature(buf: &[u8], signature: [u8; 4]) -> Result<Self, Error> {
+        if buf[0..4] != signature {
             return Err(Error::BadMultiSectorHeaderSignature);
         }
 
@@ -23,13 +119,28 @@ impl MultiSectorHeader {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+// A 48-bit record number, plus the sequence number the record had when the
+// reference was made. A record's sequence number changes when it's freed,
+// so a reference whose sequence number doesn't match is stale.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
 pub struct FileReference {
     pub segment_number_low: u32,
     pub segment_number_high: u16,
     pub sequence_number: u16,
 }
 impl FileReference {
+    pub fn new(segment: u64, sequence_number: u16) -> Self {
+        FileReference {
+            segment_number_low: segment as u32,
+            segment_number_high: (segment >> 32) as u16,
+            sequence_number,
+        }
+    }
+
+    pub fn segment(&self) -> u64 {
+        (*self).into()
+    }
+
     pub fn load(buf: &[u8]) -> Self {
         FileReference {
             segment_number_low: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
@@ -50,26 +161,33 @@ mod segment_header_flags {
 
 pub struct FileRecordSegmentHeader {
     pub multi_sector_header: MultiSectorHeader,
+    // Bumped each time the record is freed, so stale references to it can be told apart
+    pub sequence_number: u16,
     pub hard_link_count: u16,
     pub first_attribute_offset: u16, // offset of the first attribute record
+    pub in_use: bool,
     pub base_file_record_segment: FileReference,
 }
 impl FileRecordSegmentHeader {
     // Returns Ok(None) if not in use
     pub fn load(buf: &[u8]) -> Result<Option<Self>, Error> {
+        Ok(Some(Self::load_any(buf)?).filter(|header| header.in_use))
+    }
+
+    // Like `load`, but also returns records which aren't in use. The contents
+    // of those are left over from a deleted file.
+    pub fn load_any(buf: &[u8]) -> Result<Self, Error> {
         let multi_sector_header = MultiSectorHeader::load(&buf[..8])?;
 
         let flags = u16::from_le_bytes(buf[22..24].try_into().unwrap());
-        if is_flag_set16(flags, segment_header_flags::FILE_RECORD_SEGMENT_IN_USE) {
-            Ok(Some(FileRecordSegmentHeader {
-                multi_sector_header,
-                hard_link_count: u16::from_le_bytes(buf[18..20].try_into().unwrap()),
-                first_attribute_offset: u16::from_le_bytes(buf[20..22].try_into().unwrap()),
-                base_file_record_segment: FileReference::load(&buf[32..40]),
-            }))
-        } else {
-            Ok(None)
-        }
+        Ok(FileRecordSegmentHeader {
+            multi_sector_header,
+            sequence_number: u16::from_le_bytes(buf[16..18].try_into().unwrap()),
+            hard_link_count: u16::from_le_bytes(buf[18..20].try_into().unwrap()),
+            first_attribute_offset: u16::from_le_bytes(buf[20..22].try_into().unwrap()),
+            in_use: is_flag_set16(flags, segment_header_flags::FILE_RECORD_SEGMENT_IN_USE),
+            base_file_record_segment: FileReference::load(&buf[32..40]),
+        })
     }
 }
 
@@ -178,6 +296,9 @@ pub struct AttributeRecordHeader {
 }
 impl AttributeRecordHeader {
     pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < ATTRIBUTE_RECORD_HEADER_LENGTH {
+            return Err(Error::BadAttribute);
+        }
         let flags = u16::from_le_bytes([buf[12], buf[13]]);
 
         Ok(AttributeRecordHeader {
@@ -200,13 +321,16 @@ pub struct AttributeRecordHeaderResident {
     pub value_offset: u16,
 }
 impl AttributeRecordHeaderResident {
-    pub fn load(buf: &[u8]) -> (Self, usize) {
+    pub fn load(buf: &[u8]) -> Result<(Self, usize), Error> {
+        if buf.len() < 8 {
+            return Err(Error::BadAttribute);
+        }
         let header = AttributeRecordHeaderResident {
             value_length: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
             value_offset: u16::from_le_bytes([buf[4], buf[5]]),
         };
 
-        (header, 8)
+        Ok((header, 8))
     }
 }
 
@@ -214,22 +338,28 @@ pub struct AttributeRecordHeaderNonResident {
     pub lowest_vcn: u64,
     pub highest_vcn: u64,
     pub mapping_pairs_offset: u16,
+    // log2 of the clusters in each compression unit; 0 if not compressed
+    pub compression_unit: u8,
     pub allocated_length: u64,
     pub file_size: u64,
     pub valid_data_length: u64,
 }
 impl AttributeRecordHeaderNonResident {
-    pub fn load(buf: &[u8]) -> (Self, usize) {
+    pub fn load(buf: &[u8]) -> Result<(Self, usize), Error> {
+        if buf.len() < 48 {
+            return Err(Error::BadAttribute);
+        }
         let header = AttributeRecordHeaderNonResident {
             lowest_vcn: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
             highest_vcn: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
             mapping_pairs_offset: u16::from_le_bytes([buf[16], buf[17]]),
+            compression_unit: buf[18],
             allocated_length: u64::from_le_bytes(buf[24..32].try_into().unwrap()),
             file_size: u64::from_le_bytes(buf[32..40].try_into().unwrap()),
             valid_data_length: u64::from_le_bytes(buf[40..48].try_into().unwrap()),
         };
 
-        (header, 48)
+        Ok((header, 48))
     }
 }
 
@@ -295,18 +425,44 @@ impl From<u32> for StandardFlags {
 #[derive(Debug, Clone)]
 #[non_exhaustive]
 pub struct StandardInformation {
-    pub name: Option<OsString>,
+    pub name: Option<NtfsName>,
+    pub created: FileTime,
+    pub modified: FileTime,
+    pub mft_changed: FileTime,
+    pub accessed: FileTime,
     pub flags: StandardFlags,
+    pub max_versions: u32,
+    pub version_number: u32,
+    pub class_id: u32,
+    // The rest is only present in the 72-byte form written by NTFS 3.0+.
+    pub owner_id: Option<u32>,
+    // Key into $Secure's $SII index
+    pub security_id: Option<u32>,
+    pub quota_charged: Option<u64>,
+    // The file's last entry in the USN change journal, or 0 if none
+    pub usn: Option<u64>,
 }
 impl StandardInformation {
-    pub fn load(buf: &[u8], name: Option<OsString>) -> Result<Self, Error> {
+    pub fn load(buf: &[u8], name: Option<NtfsName>) -> Result<Self, Error> {
         if buf.len() != 72 && buf.len() != 48 {
             return Err(Error::UnknownStandardInformationSize(buf.len()));
         }
+        let is_extended = buf.len() == 72;
 
         Ok(StandardInformation {
             name,
+            created: FileTime::load(&buf[0..8]),
+            modified: FileTime::load(&buf[8..16]),
+            mft_changed: FileTime::load(&buf[16..24]),
+            accessed: FileTime::load(&buf[24..32]),
             flags: u32::from_le_bytes(buf[32..36].try_into().unwrap()).into(),
+            max_versions: u32::from_le_bytes(buf[36..40].try_into().unwrap()),
+            version_number: u32::from_le_bytes(buf[40..44].try_into().unwrap()),
+            class_id: u32::from_le_bytes(buf[44..48].try_into().unwrap()),
+            owner_id: is_extended.then(|| u32::from_le_bytes(buf[48..52].try_into().unwrap())),
+            security_id: is_extended.then(|| u32::from_le_bytes(buf[52..56].try_into().unwrap())),
+            quota_charged: is_extended.then(|| u64::from_le_bytes(buf[56..64].try_into().unwrap())),
+            usn: is_extended.then(|| u64::from_le_bytes(buf[64..72].try_into().unwrap())),
         })
     }
 }
@@ -341,17 +497,21 @@ pub enum FileNameType {
 #[non_exhaustive]
 pub struct FileName {
     // name OF THE ATTRIBUTE, not the file name
-    pub name: Option<OsString>,
-    pub filename: OsString,
+    pub name: Option<NtfsName>,
+    pub filename: NtfsName,
     pub filename_type: FileNameType,
-    pub parent: u64,
+    pub parent: FileReference,
+    pub created: FileTime,
+    pub modified: FileTime,
+    pub mft_changed: FileTime,
+    pub accessed: FileTime,
     pub flags: StandardFlags,
     pub logical_size: u64,
     pub physical_size: u64,
     pub reparse_tag: u32,
 }
 impl FileName {
-    pub fn load(buf: &[u8], name: Option<OsString>) -> Result<Self, Error> {
+    pub fn load(buf: &[u8], name: Option<NtfsName>) -> Result<Self, Error> {
         if buf.len() < (FILE_NAME_LENGTH + 2) {
             return Err(Error::UnknownFilenameSize(buf.len()));
         }
@@ -359,7 +519,7 @@ impl FileName {
         let name_len: usize = buf[64].into();
         let name_len = name_len * 2;
         let filename_bytes = &buf[FILE_NAME_LENGTH..FILE_NAME_LENGTH + name_len];
-        let filename = parse_string(filename_bytes);
+        let filename = NtfsName::load(filename_bytes);
 
         Ok(FileName {
             name,
@@ -371,9 +531,13 @@ impl FileName {
                 filename_types::WIN32_DOS => FileNameType::Win32AndDos,
                 unknown => return Err(Error::UnknownFilenameType(unknown)),
             },
-            parent: FileReference::load(&buf[0..8]).into(),
-            logical_size: u64::from_le_bytes(buf[40..48].try_into().unwrap()),
-            physical_size: u64::from_le_bytes(buf[48..56].try_into().unwrap()),
+            parent: FileReference::load(&buf[0..8]),
+            created: FileTime::load(&buf[8..16]),
+            modified: FileTime::load(&buf[16..24]),
+            mft_changed: FileTime::load(&buf[24..32]),
+            accessed: FileTime::load(&buf[32..40]),
+            physical_size: u64::from_le_bytes(buf[40..48].try_into().unwrap()),
+            logical_size: u64::from_le_bytes(buf[48..56].try_into().unwrap()),
             flags: u32::from_le_bytes(buf[56..60].try_into().unwrap()).into(),
             reparse_tag: u32::from_le_bytes(buf[60..64].try_into().unwrap()),
         })
@@ -384,25 +548,37 @@ impl FileName {
 pub struct DataRun {
     pub starting_lcn: i64,
     pub cluster_count: u64,
+    // Sparse runs have no clusters on disk, and read as zeros.
+    pub is_sparse: bool,
 }
 
 #[derive(Debug, Clone)]
 #[non_exhaustive]
 pub struct Data {
-    pub name: Option<OsString>,
+    pub name: Option<NtfsName>,
+    // First VCN described by this attribute. Large attributes can be split
+    // across several file record segments, but an entry's pieces are joined
+    // together when it's read.
+    pub lowest_vcn: u64,
     pub logical_size: u64,
     pub physical_size: u64,
+    // Anything past this reads as zeros, whatever is on disk.
+    pub valid_data_length: u64,
     pub runs: Option<Vec<DataRun>>,
+    // The contents of a resident attribute.
+    pub value: Option<Vec<u8>>,
     pub is_sparse: bool,
+    // log2 of the clusters in each compression unit, for LZNT1-compressed
+    // attributes; 0 otherwise.
+    pub compression_unit: u8,
 }
 impl Data {
     pub fn compute_allocated_size(&self, bytes_per_cluster: u64) -> u64 {
         let mut total_size = 0;
         if let Some(ref runs) = self.runs {
             for run in runs {
-                // Sparse files (including placeholders) have extents starting at 0
-                // (discovered through experimentation - is that documented anywhere?)
-                if self.is_sparse && run.starting_lcn == 0 {
+                // Sparse files (including placeholders) have runs with no clusters.
+                if run.is_sparse {
                     continue;
                 }
 
@@ -413,6 +589,21 @@ impl Data {
     }
 }
 
+// Allocation bits, one per index block for indexes, or one per record for $MFT.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Bitmap {
+    pub name: Option<NtfsName>,
+    pub bits: Vec<u8>,
+}
+impl Bitmap {
+    pub fn is_set(&self, index: u64) -> bool {
+        self.bits
+            .get((index / 8) as usize)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+}
+
 pub struct AttributeListEntry {
     pub type_code: u32,
     pub record_length: u16,
@@ -448,3 +639,131 @@ fn is_flag_set(data: u32, flag: u32) -> bool {
 fn is_flag_set16(data: u16, flag: u16) -> bool {
     (data & flag) != 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn boot_sector_geometry() {
+        let buf = test_support::boot_sector(2048);
+        let geometry = VolumeGeometry::load(&buf[..]).unwrap();
+        assert_eq!(geometry.bytes_per_sector, 512);
+        assert_eq!(geometry.bytes_per_cluster, 4096);
+        assert_eq!(geometry.bytes_per_file_record_segment, 1024);
+        assert_eq!(geometry.bytes_per_index_record, 4096);
+        assert_eq!(geometry.mft_start_lcn, test_support::MFT_START_LCN);
+        assert_eq!(
+            geometry.mft_mirror_start_lcn,
+            test_support::MFT_MIRROR_START_LCN
+        );
+        assert_eq!(
+            geometry.volume_serial_number,
+            test_support::VOLUME_SERIAL_NUMBER
+        );
+        assert_eq!(geometry.volume_size(), 2048 * 512);
+    }
+
+    #[test]
+    fn boot_sector_large_clusters() {
+        let mut buf = test_support::boot_sector(2048);
+        // 2^9 sectors per cluster, i.e. 256KB clusters
+        buf[13] = (-9i8) as u8;
+        let geometry = VolumeGeometry::load(&buf[..]).unwrap();
+        assert_eq!(geometry.bytes_per_cluster, 256 * 1024);
+        assert_eq!(geometry.bytes_per_file_record_segment, 1024);
+        assert_eq!(geometry.bytes_per_index_record, 256 * 1024);
+    }
+
+    #[test]
+    fn boot_sector_rejects_other_filesystems() {
+        let mut buf = test_support::boot_sector(2048);
+        buf[3..11].copy_from_slice(b"MSDOS5.0");
+        assert!(matches!(
+            VolumeGeometry::load(&buf[..]),
+            Err(Error::BadBootSectorSignature)
+        ));
+
+        // No sectors per cluster, 2^127 of them, and 4MB clusters
+        for sectors_per_cluster in [0, 0x81, (-13i8) as u8] {
+            let mut buf = test_support::boot_sector(2048);
+            buf[13] = sectors_per_cluster;
+            assert!(matches!(
+                VolumeGeometry::load(&buf[..]),
+                Err(Error::BadBootSectorGeometry)
+            ));
+        }
+        let mut buf = test_support::boot_sector(2048);
+        buf[11..13].copy_from_slice(&32768u16.to_le_bytes());
+        buf[13] = (-7i8) as u8;
+        assert!(matches!(
+            VolumeGeometry::load(&buf[..]),
+            Err(Error::BadBootSectorGeometry)
+        ));
+    }
+
+    #[test]
+    fn file_name_attribute() {
+        let mut buf = [0; FILE_NAME_LENGTH + 6];
+        buf[0..8].copy_from_slice(&test_support::file_reference(42, 3).to_le_bytes());
+        buf[8..16].copy_from_slice(&11u64.to_le_bytes());
+        buf[32..40].copy_from_slice(&44u64.to_le_bytes());
+        buf[48..56].copy_from_slice(&100u64.to_le_bytes());
+        buf[64] = 3;
+        buf[65] = 1;
+        buf[66..72].copy_from_slice(&[b'a', 0, b'b', 0, b'c', 0]);
+
+        let file_name = FileName::load(&buf[..], None).unwrap();
+        assert_eq!(file_name.parent, FileReference::new(42, 3));
+        assert_eq!(file_name.filename, NtfsName::from("abc"));
+        assert_eq!(file_name.filename_type, FileNameType::Win32);
+        assert_eq!(file_name.logical_size, 100);
+        assert_eq!(file_name.created, FileTime::from_raw(11));
+        assert_eq!(file_name.accessed, FileTime::from_raw(44));
+
+        buf[65] = 9;
+        assert!(matches!(
+            FileName::load(&buf[..], None),
+            Err(Error::UnknownFilenameType(9))
+        ));
+    }
+
+    #[test]
+    fn standard_information_attribute() {
+        let info = test_support::StandardInfo {
+            created: 1,
+            modified: 2,
+            mft_changed: 3,
+            accessed: 4,
+            file_attributes: test_support::file_attributes::HIDDEN,
+            owner_id: 5,
+            security_id: 0x100,
+            quota_charged: 7,
+            usn: 0x1234,
+        }
+        .encode();
+
+        let extended = StandardInformation::load(&info[..], None).unwrap();
+        assert_eq!(extended.created, FileTime::from_raw(1));
+        assert_eq!(extended.modified, FileTime::from_raw(2));
+        assert_eq!(extended.mft_changed, FileTime::from_raw(3));
+        assert_eq!(extended.accessed, FileTime::from_raw(4));
+        assert!(extended.flags.is_hidden);
+        assert_eq!(extended.owner_id, Some(5));
+        assert_eq!(extended.security_id, Some(0x100));
+        assert_eq!(extended.quota_charged, Some(7));
+        assert_eq!(extended.usn, Some(0x1234));
+
+        // NTFS 1.x volumes only have the first 48 bytes.
+        let legacy = StandardInformation::load(&info[..48], None).unwrap();
+        assert_eq!(legacy.accessed, FileTime::from_raw(4));
+        assert_eq!(legacy.security_id, None);
+        assert_eq!(legacy.usn, None);
+
+        assert!(matches!(
+            StandardInformation::load(&info[..56], None),
+            Err(Error::UnknownStandardInformationSize(56))
+        ));
+    }
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const TICKS_PER_SECOND: u64 = 10_000_000;
+const SECONDS_PER_DAY: u64 = 86_400;
+// 1601-01-01 to 1970-01-01, in 100ns ticks
+const UNIX_EPOCH_TICKS: u64 = 116_444_736_000_000_000;
+const DAYS_FROM_1601_TO_1970: i64 = 134_774;
+
+// A timestamp as NTFS stores it: a count of 100ns ticks since 1601-01-01
+// UTC. The raw value is kept as-is, since the exact ticks matter for
+// forensic comparisons.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub struct FileTime(u64);
+impl FileTime {
+    pub const fn from_raw(ticks: u64) -> Self {
+        FileTime(ticks)
+    }
+
+    pub fn load(buf: &[u8]) -> Self {
+        FileTime(u64::from_le_bytes([
+            buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
+        ]))
+    }
+
+    pub fn raw(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    // The ticks past the whole second (0 to 9,999,999).
+    pub fn subsecond_ticks(self) -> u32 {
+        (self.0 % TICKS_PER_SECOND) as u32
+    }
+
+    // Returns None for times the platform's SystemTime can't represent.
+    pub fn to_system_time(self) -> Option<SystemTime> {
+        if self.0 >= UNIX_EPOCH_TICKS {
+            UNIX_EPOCH.checked_add(ticks_to_duration(self.0 - UNIX_EPOCH_TICKS))
+        } else {
+            UNIX_EPOCH.checked_sub(ticks_to_duration(UNIX_EPOCH_TICKS - self.0))
+        }
+    }
+
+    pub fn from_system_time(time: SystemTime) -> Option<Self> {
+        let ticks = match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => UNIX_EPOCH_TICKS.checked_add(duration_to_ticks(after)?)?,
+            Err(err) => UNIX_EPOCH_TICKS.checked_sub(duration_to_ticks(err.duration())?)?,
+        };
+        Some(FileTime(ticks))
+    }
+}
+impl From<FileTime> for u64 {
+    fn from(time: FileTime) -> u64 {
+        time.0
+    }
+}
+// Displays the time in UTC as ISO 8601, keeping all seven fractional digits:
+// 2021-03-04T05:06:07.1234567Z
+impl fmt::Display for FileTime {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.0 / TICKS_PER_SECOND;
+        let days = (seconds / SECONDS_PER_DAY) as i64;
+        let time_of_day = seconds % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days - DAYS_FROM_1601_TO_1970);
+        write!(
+            fmt,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:07}Z",
+            year,
+            month,
+            day,
+            time_of_day / 3600,
+            time_of_day / 60 % 60,
+            time_of_day % 60,
+            self.subsecond_ticks()
+        )
+    }
+}
+impl fmt::Debug for FileTime {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self)
+    }
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::new(
+        ticks / TICKS_PER_SECOND,
+        (ticks % TICKS_PER_SECOND) as u32 * 100,
+    )
+}
+
+fn duration_to_ticks(duration: Duration) -> Option<u64> {
+    duration
+        .as_secs()
+        .checked_mul(TICKS_PER_SECOND)?
+        .checked_add(u64::from(duration.subsec_nanos() / 100))
+}
+
+// Converts days since 1970-01-01 to a (year, month, day) in the proleptic
+// Gregorian calendar. See http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2021-03-04T05:06:07.1234567Z
+    const SAMPLE: u64 = 132_593_079_671_234_567;
+
+    #[test]
+    fn displays_utc() {
+        assert_eq!(
+            FileTime::from_raw(0).to_string(),
+            "1601-01-01T00:00:00.0000000Z"
+        );
+        assert_eq!(
+            FileTime::from_raw(UNIX_EPOCH_TICKS).to_string(),
+            "1970-01-01T00:00:00.0000000Z"
+        );
+        assert_eq!(
+            FileTime::from_raw(SAMPLE).to_string(),
+            "2021-03-04T05:06:07.1234567Z"
+        );
+        // Leap day in a leap century
+        assert_eq!(
+            FileTime::from_raw(125_962_560_000_000_000).to_string(),
+            "2000-02-29T00:00:00.0000000Z"
+        );
+    }
+
+    #[test]
+    fn converts_to_system_time() {
+        let time = FileTime::from_raw(SAMPLE);
+        let system_time = time.to_system_time().unwrap();
+        let since_epoch = system_time.duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(since_epoch.as_secs(), 1_614_834_367);
+        assert_eq!(since_epoch.subsec_nanos(), 123_456_700);
+        assert_eq!(FileTime::from_system_time(system_time), Some(time));
+        assert_eq!(time.subsecond_ticks(), 1_234_567);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::mft::{FileTime, MftEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampField {
+    Created,
+    Modified,
+    MftChanged,
+    Accessed,
+}
+
+// Signs that a file's $STANDARD_INFORMATION times were set by hand. Tools
+// which backdate files generally only touch $STANDARD_INFORMATION, since
+// $FILE_NAME can't be set through the Win32 API and is only updated by
+// Windows when the file is created, renamed or moved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimestampAnomaly {
+    // The file claims to have been created before its name was.
+    CreatedBeforeFileName {
+        standard_information: FileTime,
+        file_name: FileTime,
+    },
+    // Windows records times to the 100ns tick, so a time on an exact second
+    // usually came from a tool (or an archive format) that truncated it.
+    ZeroSubseconds(TimestampField),
+    // Setting a time changes the record, so the MFT-change time should never
+    // be earlier than the creation or modification times.
+    AfterMftChange {
+        field: TimestampField,
+        time: FileTime,
+        mft_changed: FileTime,
+    },
+}
+
+pub(crate) fn find_anomalies(entry: &MftEntry) -> Vec<TimestampAnomaly> {
+    let mut anomalies = Vec::new();
+    let si = match entry.get_standard_information() {
+        Some(si) => si,
+        None => return anomalies,
+    };
+
+    // All of a file's names are created at least as late as the file itself,
+    // so compare against the earliest.
+    let fn_created = entry
+        .filename
+        .iter()
+        .map(|name| name.created)
+        .filter(|time| !time.is_zero())
+        .min();
+    if let Some(fn_created) = fn_created {
+        if !si.created.is_zero() && si.created < fn_created {
+            anomalies.push(TimestampAnomaly::CreatedBeforeFileName {
+                standard_information: si.created,
+                file_name: fn_created,
+            });
+        }
+    }
+
+    let fields = [
+        (TimestampField::Created, si.created),
+        (TimestampField::Modified, si.modified),
+        (TimestampField::MftChanged, si.mft_changed),
+        (TimestampField::Accessed, si.accessed),
+    ];
+    for (field, time) in fields.iter() {
+        if !time.is_zero() && time.subsecond_ticks() == 0 {
+            anomalies.push(TimestampAnomaly::ZeroSubseconds(*field));
+        }
+    }
+
+    // Access times are left out, since Windows updates them without
+    // touching the MFT-change time.
+    if !si.mft_changed.is_zero() {
+        for (field, time) in fields[..2].iter() {
+            if *time > si.mft_changed {
+                anomalies.push(TimestampAnomaly::AfterMftChange {
+                    field: *field,
+                    time: *time,
+                    mft_changed: si.mft_changed,
+                });
+            }
+        }
+    }
+
+    anomalies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mft::MasterFileTable,
+        test_support::{namespaces, Attribute, ImageBuilder, Record, StandardInfo, ROOT_RECORD},
+    };
+
+    const SECOND: u64 = 10_000_000;
+    // 2021-03-04T05:06:07.1234567Z
+    const BASE: u64 = 132_593_079_671_234_567;
+
+    fn anomalies(si: StandardInfo, fn_times: [u64; 4]) -> Vec<TimestampAnomaly> {
+        let mut builder = ImageBuilder::new();
+        let file = builder.add_record(Record::new().with(si.attribute()).with(
+            Attribute::file_name_with_times(ROOT_RECORD, "file", namespaces::WIN32, fn_times),
+        ));
+        MasterFileTable::open(builder.build())
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.base_record_segment_idx == file)
+            .unwrap()
+            .timestamp_anomalies()
+    }
+
+    #[test]
+    fn consistent_times_are_clean() {
+        let si = StandardInfo {
+            created: BASE,
+            modified: BASE + SECOND + 1,
+            mft_changed: BASE + 2 * SECOND + 2,
+            accessed: BASE + 3 * SECOND + 3,
+            ..Default::default()
+        };
+        assert_eq!(anomalies(si, [BASE; 4]), vec![]);
+    }
+
+    #[test]
+    fn backdated_creation() {
+        // Whole seconds, as set by a typical timestomping tool
+        let backdated = (BASE / SECOND - 1000) * SECOND;
+        let si = StandardInfo {
+            created: backdated,
+            modified: BASE + 1,
+            mft_changed: BASE + 2,
+            accessed: BASE + 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            anomalies(si, [BASE; 4]),
+            vec![
+                TimestampAnomaly::CreatedBeforeFileName {
+                    standard_information: FileTime::from_raw(backdated),
+                    file_name: FileTime::from_raw(BASE),
+                },
+                TimestampAnomaly::ZeroSubseconds(TimestampField::Created),
+            ]
+        );
+    }
+
+    #[test]
+    fn modified_after_mft_change() {
+        let si = StandardInfo {
+            created: BASE,
+            modified: BASE + 10 * SECOND,
+            mft_changed: BASE + 1,
+            accessed: BASE + 20 * SECOND,
+            ..Default::default()
+        };
+        assert_eq!(
+            anomalies(si, [BASE; 4]),
+            vec![TimestampAnomaly::AfterMftChange {
+                field: TimestampField::Modified,
+                time: FileTime::from_raw(BASE + 10 * SECOND),
+                mft_changed: FileTime::from_raw(BASE + 1),
+            }]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{
+    err::Error,
+    mft::{MftEntry, NtfsName},
+};
+
+use serde::{Deserialize, Serialize};
+
+// $Volume, whose $VOLUME_NAME and $VOLUME_INFORMATION describe the volume.
+pub const VOLUME_RECORD: u64 = 3;
+const VOLUME_INFORMATION_LENGTH: usize = 12;
+
+pub mod volume_flags {
+    // Set while mounted, and cleared on a clean dismount; chkdsk runs at the
+    // next mount if it's still set.
+    pub const DIRTY: u16 = 0x0001;
+    pub const RESIZE_LOG_FILE: u16 = 0x0002;
+    // Mount upgrades the volume to the running system's NTFS version.
+    pub const UPGRADE_ON_MOUNT: u16 = 0x0004;
+    pub const MOUNTED_ON_NT4: u16 = 0x0008;
+    pub const DELETE_USN_UNDERWAY: u16 = 0x0010;
+    pub const REPAIR_OBJECT_IDS: u16 = 0x0020;
+    pub const CHKDSK_UNDERWAY: u16 = 0x4000;
+    pub const MODIFIED_BY_CHKDSK: u16 = 0x8000;
+}
+
+// A $VOLUME_INFORMATION attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeInformation {
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub flags: u16,
+}
+impl VolumeInformation {
+    // The first 8 bytes are unused.
+    pub fn load(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < VOLUME_INFORMATION_LENGTH {
+            return Err(Error::BadVolumeInformation);
+        }
+        Ok(VolumeInformation {
+            major_version: buf[8],
+            minor_version: buf[9],
+            flags: u16::from_le_bytes([buf[10], buf[11]]),
+        })
+    }
+}
+
+// What $Volume says about the volume: its label, the NTFS version it was
+// formatted with or upgraded to (3.1 since Windows XP), and its state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeMetadata {
+    // Empty if the volume was never given one
+    pub label: String,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub flags: u16,
+}
+impl VolumeMetadata {
+    // Returns None if `entry` has no $VOLUME_INFORMATION, which only
+    // $Volume has.
+    pub fn from_entry(entry: &MftEntry) -> Option<Self> {
+        let information = entry.volume_information?;
+        Some(VolumeMetadata {
+            label: entry
+                .volume_name
+                .as_ref()
+                .map(NtfsName::to_string_lossy)
+                .unwrap_or_default(),
+            major_version: information.major_version,
+            minor_version: information.minor_version,
+            flags: information.flags,
+        })
+    }
+
+    // Whether the volume wasn't cleanly dismounted (as with an image taken
+    // of a running system), or has had chkdsk scheduled on it.
+    pub fn is_dirty(&self) -> bool {
+        self.flags & volume_flags::DIRTY != 0
+    }
+
+    pub fn upgrade_on_mount(&self) -> bool {
+        self.flags & volume_flags::UPGRADE_ON_MOUNT != 0
+    }
+
+    pub fn chkdsk_underway(&self) -> bool {
+        self.flags & volume_flags::CHKDSK_UNDERWAY != 0
+    }
+}
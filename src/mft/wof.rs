@@ -0,0 +1,147 @@
+use crate::{
+    err::Error,
+    mft::{lzx, xpress, WofAlgorithm},
+};
+
+use std::{convert::TryInto as _, ops::Range};
+
+// The stream WOF keeps a compressed file's contents in. The file's main
+// stream is left sparse and unallocated.
+pub const COMPRESSED_STREAM_NAME: &str = "WofCompressedData";
+
+// Where each chunk of a WOF compressed file lives in its compressed stream.
+// The stream starts with a table of where every chunk but the first starts,
+// relative to the end of the table, followed by the chunks themselves.
+pub struct ChunkTable {
+    algorithm: WofAlgorithm,
+    chunk_size: u64,
+    // The file's uncompressed length
+    len: u64,
+    chunks: Vec<Range<u64>>,
+}
+impl ChunkTable {
+    fn chunk_size_for(algorithm: WofAlgorithm) -> Result<u64, Error> {
+        match algorithm {
+            WofAlgorithm::Xpress4K => Ok(4096),
+            WofAlgorithm::Xpress8K => Ok(8192),
+            WofAlgorithm::Xpress16K => Ok(16384),
+            WofAlgorithm::Lzx => Ok(32768),
+            WofAlgorithm::Unknown(algorithm) => Err(Error::UnsupportedWofAlgorithm(algorithm)),
+        }
+    }
+
+    // How many bytes at the start of the compressed stream the table takes,
+    // for a file of `len` bytes.
+    pub fn table_len(algorithm: WofAlgorithm, len: u64) -> Result<u64, Error> {
+        let chunks = len.div_ceil(Self::chunk_size_for(algorithm)?);
+        Ok(chunks.saturating_sub(1) * Self::entry_size(len))
+    }
+
+    // Files of 4GB or more need 64-bit offsets.
+    fn entry_size(len: u64) -> u64 {
+        if len > u64::from(u32::MAX) {
+            8
+        } else {
+            4
+        }
+    }
+
+    pub fn load(
+        algorithm: WofAlgorithm,
+        len: u64,
+        table: &[u8],
+        compressed_len: u64,
+    ) -> Result<Self, Error> {
+        let chunk_size = Self::chunk_size_for(algorithm)?;
+        let entry_size = Self::entry_size(len) as usize;
+        if table.len() as u64 != Self::table_len(algorithm, len)? {
+            return Err(Error::BadCompressedData);
+        }
+        let data_len = compressed_len
+            .checked_sub(table.len() as u64)
+            .ok_or(Error::BadCompressedData)?;
+
+        let mut starts = vec![0];
+        starts.extend(table.chunks(entry_size).map(|entry| match entry_size {
+            8 => u64::from_le_bytes(entry.try_into().unwrap()),
+            _ => u32::from_le_bytes(entry.try_into().unwrap()).into(),
+        }));
+        let mut ends = starts[1..].to_vec();
+        ends.push(data_len);
+
+        let base = table.len() as u64;
+        let mut chunks = Vec::with_capacity(starts.len());
+        for (start, end) in starts.into_iter().zip(ends) {
+            if start > end || end > data_len {
+                return Err(Error::BadCompressedData);
+            }
+            chunks.push(base + start..base + end);
+        }
+        if len == 0 {
+            chunks.clear();
+        }
+        Ok(ChunkTable {
+            algorithm,
+            chunk_size,
+            len,
+            chunks,
+        })
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    // Where chunk `index` is in the compressed stream.
+    pub fn chunk(&self, index: usize) -> Option<Range<u64>> {
+        self.chunks.get(index).cloned()
+    }
+
+    // The length of chunk `index` once decompressed; only the last chunk
+    // can be short.
+    pub fn chunk_len(&self, index: usize) -> usize {
+        let start = index as u64 * self.chunk_size;
+        (self.len - start).min(self.chunk_size) as usize
+    }
+
+    pub fn decompress(&self, index: usize, input: &[u8]) -> Result<Vec<u8>, Error> {
+        let len = self.chunk_len(index);
+        // Chunks which didn't compress are stored as they are.
+        if input.len() == len {
+            return Ok(input.to_vec());
+        }
+        match self.algorithm {
+            WofAlgorithm::Lzx => lzx::decompress(input, len),
+            _ => xpress::decompress(input, len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_chunk_offsets() {
+        let len = 3 * 4096 + 10;
+        let table = [100u32, 250, 4346]
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect::<Vec<_>>();
+        let chunks = ChunkTable::load(WofAlgorithm::Xpress4K, len, &table, 12 + 4356).unwrap();
+        assert_eq!(chunks.chunk(0), Some(12..112));
+        assert_eq!(chunks.chunk(1), Some(112..262));
+        assert_eq!(chunks.chunk(3), Some(4358..4368));
+        assert_eq!(chunks.chunk(4), None);
+        assert_eq!(chunks.chunk_len(2), 4096);
+        assert_eq!(chunks.chunk_len(3), 10);
+
+        // Offsets past the end, or a table of the wrong size
+        assert!(ChunkTable::load(WofAlgorithm::Xpress4K, len, &table, 12 + 4000).is_err());
+        assert!(ChunkTable::load(WofAlgorithm::Xpress8K, len, &table, 12 + 4356).is_err());
+        assert!(matches!(
+            ChunkTable::load(WofAlgorithm::Unknown(7), len, &table, 12 + 4356),
+            Err(Error::UnsupportedWofAlgorithm(7))
+        ));
+    }
+}
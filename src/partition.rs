@@ -0,0 +1,697 @@
+use crate::{
+    err::Error,
+    guid::Guid,
+    mft::sys::{BOOT_SECTOR_LENGTH, NTFS_OEM_ID},
+    reader::{self, VolumeReader},
+};
+
+use std::{
+    collections::HashSet,
+    convert::TryInto as _,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+const MBR_PARTITION_ENTRY_LENGTH: usize = 16;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+const GPT_MIN_ENTRY_LENGTH: usize = 128;
+const GPT_MAX_ENTRY_LENGTH: usize = 4096;
+// Tables normally hold 128 entries of 128 bytes; anything near this is
+// a corrupt header rather than a real table.
+const GPT_MAX_ENTRY_TABLE_LENGTH: usize = 1024 * 1024;
+
+// Give up on extended partition chains longer than this; it's almost
+// certainly a loop in a corrupted table.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+mod mbr_types {
+    pub const EMPTY: u8 = 0x00;
+    pub const EXTENDED_CHS: u8 = 0x05;
+    // NTFS, exFAT and HPFS all share this one
+    pub const NTFS: u8 = 0x07;
+    pub const EXTENDED_LBA: u8 = 0x0F;
+    pub const HIDDEN_NTFS: u8 = 0x17;
+    pub const WINDOWS_RECOVERY: u8 = 0x27;
+    pub const LINUX_EXTENDED: u8 = 0x85;
+    pub const GPT_PROTECTIVE: u8 = 0xEE;
+}
+
+mod gpt_types {
+    use crate::guid::Guid;
+
+    // Used for NTFS, FAT and exFAT data partitions alike
+    pub const BASIC_DATA: Guid = Guid::from_fields(
+        0xEBD0_A0A2,
+        0xB9E5,
+        0x4433,
+        [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+    );
+    pub const WINDOWS_RECOVERY: Guid = Guid::from_fields(
+        0xDE94_BBA4,
+        0x06D1,
+        0x4D40,
+        [0xA1, 0x6A, 0xBF, 0xD5, 0x01, 0x79, 0xD6, 0xAC],
+    );
+}
+
+#[derive(Debug, Clone)]
+pub enum PartitionKind {
+    // There's no partition table; the image is a single volume.
+    WholeImage,
+    Mbr {
+        type_id: u8,
+    },
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Partition {
+    // 1-based, numbered the way Windows and Linux number them: primary MBR
+    // partitions are 1-4 and logical partitions start at 5. 0 for WholeImage.
+    pub index: usize,
+    pub offset: u64, // in bytes
+    pub len: u64,    // in bytes
+    pub kind: PartitionKind,
+    // Whether the partition table says this could be NTFS.
+    pub has_ntfs_type: bool,
+    // Whether the partition actually starts with an NTFS boot sector.
+    pub has_ntfs_boot_sector: bool,
+}
+impl Partition {
+    pub fn is_ntfs(&self) -> bool {
+        self.has_ntfs_boot_sector
+    }
+
+    // Wraps the whole disk in a reader for just this partition.
+    pub fn reader<R: VolumeReader>(&self, disk: R) -> PartitionReader<R> {
+        PartitionReader {
+            disk,
+            offset: self.offset,
+            len: self.len,
+        }
+    }
+}
+
+// A window onto one partition of a whole disk.
+pub struct PartitionReader<R: VolumeReader> {
+    disk: R,
+    offset: u64,
+    len: u64,
+}
+impl<R: VolumeReader> VolumeReader for PartitionReader<R> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if offset + (buf.len() as u64) > self.len {
+            return Err(Error::ReadVolumeTooShort);
+        }
+        self.disk.read_at(self.offset + offset, buf)
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.disk.sector_size()
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+// Reads the partition table (GPT, or MBR including logical partitions) of a
+// whole disk. An image that starts with an NTFS boot sector is reported as a
+// single WholeImage partition.
+pub fn read_partitions<R: VolumeReader + ?Sized>(disk: &mut R) -> Result<Vec<Partition>, Error> {
+    let sector_size = disk.sector_size();
+    let disk_len = disk.len();
+    let first_sector = read_sector(disk, 0)?;
+
+    if is_ntfs_boot_sector(&first_sector) {
+        return Ok(vec![Partition {
+            index: 0,
+            offset: 0,
+            len: disk.len(),
+            kind: PartitionKind::WholeImage,
+            has_ntfs_type: true,
+            has_ntfs_boot_sector: true,
+        }]);
+    }
+
+    if first_sector[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let entries = parse_mbr_entries(&first_sector);
+    let mut partitions = if entries
+        .iter()
+        .any(|entry| entry.type_id == mbr_types::GPT_PROTECTIVE)
+    {
+        read_gpt(disk)?
+    } else {
+        let mut partitions = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            match entry.type_id {
+                mbr_types::EMPTY => {}
+                mbr_types::EXTENDED_CHS | mbr_types::EXTENDED_LBA | mbr_types::LINUX_EXTENDED => {
+                    read_logical_partitions(disk, entry.start_lba, &mut partitions)?;
+                }
+                type_id => {
+                    let extent = partition_extent(
+                        entry.start_lba,
+                        entry.sector_count,
+                        sector_size,
+                        disk_len,
+                    );
+                    if let Some((offset, len)) = extent {
+                        partitions.push(Partition {
+                            index: i + 1,
+                            offset,
+                            len,
+                            kind: PartitionKind::Mbr { type_id },
+                            has_ntfs_type: is_ntfs_mbr_type(type_id),
+                            has_ntfs_boot_sector: false,
+                        });
+                    }
+                }
+            }
+        }
+        partitions
+    };
+
+    for partition in &mut partitions {
+        partition.has_ntfs_boot_sector = partition.offset + BOOT_SECTOR_LENGTH as u64 <= disk.len()
+            && is_ntfs_boot_sector(&read_sector(disk, partition.offset / sector_size)?);
+    }
+
+    Ok(partitions)
+}
+
+// The NTFS partitions of a whole disk.
+pub fn find_ntfs_partitions<R: VolumeReader + ?Sized>(
+    disk: &mut R,
+) -> Result<Vec<Partition>, Error> {
+    Ok(read_partitions(disk)?
+        .into_iter()
+        .filter(Partition::is_ntfs)
+        .collect())
+}
+
+struct MbrEntry {
+    type_id: u8,
+    start_lba: u64,
+    sector_count: u64,
+}
+
+fn parse_mbr_entries(sector: &[u8]) -> Vec<MbrEntry> {
+    sector[MBR_PARTITION_TABLE_OFFSET..MBR_PARTITION_TABLE_OFFSET + 4 * MBR_PARTITION_ENTRY_LENGTH]
+        .chunks_exact(MBR_PARTITION_ENTRY_LENGTH)
+        .map(|entry| MbrEntry {
+            type_id: entry[4],
+            start_lba: u32::from_le_bytes(entry[8..12].try_into().unwrap()).into(),
+            sector_count: u32::from_le_bytes(entry[12..16].try_into().unwrap()).into(),
+        })
+        .collect()
+}
+
+// Walks the chain of extended boot records. The first entry of each EBR is a
+// logical partition relative to that EBR; the second points at the next EBR,
+// relative to the start of the extended partition.
+fn read_logical_partitions<R: VolumeReader + ?Sized>(
+    disk: &mut R,
+    extended_start_lba: u64,
+    partitions: &mut Vec<Partition>,
+) -> Result<(), Error> {
+    let sector_size = disk.sector_size();
+    let disk_len = disk.len();
+    let mut ebr_lba = extended_start_lba;
+    let mut visited = HashSet::new();
+    let mut index = 5;
+
+    while visited.insert(ebr_lba) && visited.len() <= MAX_LOGICAL_PARTITIONS {
+        let sector = read_sector(disk, ebr_lba)?;
+        if sector[510..512] != MBR_SIGNATURE {
+            break;
+        }
+
+        let entries = parse_mbr_entries(&sector);
+        if entries[0].type_id != mbr_types::EMPTY {
+            let extent = ebr_lba.checked_add(entries[0].start_lba).and_then(|lba| {
+                partition_extent(lba, entries[0].sector_count, sector_size, disk_len)
+            });
+            if let Some((offset, len)) = extent {
+                partitions.push(Partition {
+                    index,
+                    offset,
+                    len,
+                    kind: PartitionKind::Mbr {
+                        type_id: entries[0].type_id,
+                    },
+                    has_ntfs_type: is_ntfs_mbr_type(entries[0].type_id),
+                    has_ntfs_boot_sector: false,
+                });
+            }
+            index += 1;
+        }
+
+        match entries[1].type_id {
+            mbr_types::EXTENDED_CHS | mbr_types::EXTENDED_LBA | mbr_types::LINUX_EXTENDED => {
+                ebr_lba = extended_start_lba + entries[1].start_lba;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(())
+}
+
+fn read_gpt<R: VolumeReader + ?Sized>(disk: &mut R) -> Result<Vec<Partition>, Error> {
+    let sector_size = disk.sector_size();
+    let disk_len = disk.len();
+    let header = read_sector(disk, 1)?;
+    if header[0..8] != GPT_SIGNATURE {
+        return Err(Error::BadGptHeader);
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count: usize = u32::from_le_bytes(header[80..84].try_into().unwrap())
+        .try_into()
+        .unwrap();
+    let entry_len: usize = u32::from_le_bytes(header[84..88].try_into().unwrap())
+        .try_into()
+        .unwrap();
+    if !entry_len.is_power_of_two()
+        || !(GPT_MIN_ENTRY_LENGTH..=GPT_MAX_ENTRY_LENGTH).contains(&entry_len)
+    {
+        return Err(Error::BadGptHeader);
+    }
+    let table_len = entry_count
+        .checked_mul(entry_len)
+        .filter(|len| *len <= GPT_MAX_ENTRY_TABLE_LENGTH)
+        .ok_or(Error::BadGptHeader)?;
+    let table_offset = entries_lba
+        .checked_mul(sector_size)
+        .ok_or(Error::BadGptHeader)?;
+
+    let mut entries = vec![0; table_len];
+    disk.read_at(table_offset, &mut entries[..])?;
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_len).enumerate() {
+        let type_guid = Guid::load(&entry[0..16]);
+        if type_guid.is_nil() {
+            continue;
+        }
+
+        let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        let name = {
+            let name = entry[56..128]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|c| *c != 0)
+                .collect::<Vec<_>>();
+            String::from_utf16_lossy(&name[..])
+        };
+
+        let sector_count = last_lba.saturating_add(1).saturating_sub(first_lba);
+        let (offset, len) = match partition_extent(first_lba, sector_count, sector_size, disk_len) {
+            Some(extent) => extent,
+            None => continue,
+        };
+
+        partitions.push(Partition {
+            index: i + 1,
+            offset,
+            len,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: Guid::load(&entry[16..32]),
+                name,
+            },
+            has_ntfs_type: type_guid == gpt_types::BASIC_DATA
+                || type_guid == gpt_types::WINDOWS_RECOVERY,
+            has_ntfs_boot_sector: false,
+        });
+    }
+
+    Ok(partitions)
+}
+
+// Where a partition is on the disk, as a byte offset and length, or None if
+// the table's values overflow or put it past the end of the disk. One that
+// only runs past the end is kept, as the image may just be truncated.
+fn partition_extent(
+    first_lba: u64,
+    sector_count: u64,
+    sector_size: u64,
+    disk_len: u64,
+) -> Option<(u64, u64)> {
+    let offset = first_lba.checked_mul(sector_size)?;
+    let len = sector_count.checked_mul(sector_size)?;
+    offset.checked_add(len)?;
+    (offset < disk_len).then_some((offset, len))
+}
+
+fn read_sector<R: VolumeReader + ?Sized>(disk: &mut R, lba: u64) -> Result<Vec<u8>, Error> {
+    let sector_size: usize = disk.sector_size().try_into().unwrap();
+    let mut buf = vec![0; sector_size.max(BOOT_SECTOR_LENGTH)];
+    let offset = lba
+        .checked_mul(disk.sector_size())
+        .ok_or(Error::ReadVolumeTooShort)?;
+    disk.read_at(offset, &mut buf[..])?;
+    Ok(buf)
+}
+
+fn is_ntfs_mbr_type(type_id: u8) -> bool {
+    matches!(
+        type_id,
+        mbr_types::NTFS | mbr_types::HIDDEN_NTFS | mbr_types::WINDOWS_RECOVERY
+    )
+}
+
+fn is_ntfs_boot_sector(sector: &[u8]) -> bool {
+    sector[3..11] == NTFS_OEM_ID
+}
+
+// One NTFS volume found inside a disk image. This is the image counterpart of
+// volumes::VolumeInfo.
+#[derive(Debug)]
+pub struct ImageVolumeInfo {
+    pub name: OsString,
+    // What the volume's paths are rooted at: "PartitionN" for a partition of a
+    // disk, or empty when the image is a single volume.
+    pub paths: Vec<OsString>,
+    pub partition: Partition,
+    image_path: PathBuf,
+}
+impl ImageVolumeInfo {
+    pub fn get_reader(&self) -> Result<PartitionReader<Box<dyn VolumeReader>>, Error> {
+        Ok(self.partition.reader(reader::open_image(&self.image_path)?))
+    }
+}
+
+// Iterates over the NTFS volumes inside a disk image, the way
+// volumes::VolumeIterator does for the volumes of the running system.
+pub struct ImageVolumeIterator {
+    image_path: PathBuf,
+    partitions: std::vec::IntoIter<Partition>,
+}
+impl ImageVolumeIterator {
+    pub fn new<P: AsRef<Path>>(image_path: P) -> Result<Self, Error> {
+        let image_path = image_path.as_ref().to_path_buf();
+        let mut disk = reader::open_image(&image_path)?;
+        let partitions = find_ntfs_partitions(&mut disk)?;
+
+        Ok(ImageVolumeIterator {
+            image_path,
+            partitions: partitions.into_iter(),
+        })
+    }
+}
+impl Iterator for ImageVolumeIterator {
+    type Item = Result<ImageVolumeInfo, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let partition = self.partitions.next()?;
+
+        let root = match partition.kind {
+            PartitionKind::WholeImage => OsString::new(),
+            _ => OsString::from(format!("Partition{}", partition.index)),
+        };
+        let mut name = self.image_path.clone().into_os_string();
+        if !root.is_empty() {
+            name.push("\\");
+            name.push(&root);
+        }
+
+        Some(Ok(ImageVolumeInfo {
+            name,
+            paths: vec![root],
+            partition,
+            image_path: self.image_path.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        boot_sector, guid_bytes, mbr_entry, mbr_sector, utf16_bytes, ImageBuilder, MemoryReader,
+        SECTOR_SIZE,
+    };
+
+    const DISK_SECTORS: usize = 64;
+    const LINUX: u8 = 0x83;
+
+    fn write_sector(disk: &mut [u8], lba: usize, sector: &[u8]) {
+        let offset = lba * SECTOR_SIZE as usize;
+        disk[offset..offset + sector.len()].copy_from_slice(sector);
+    }
+
+    fn gpt_entry(type_guid: Guid, first_lba: u64, last_lba: u64, name: &str) -> Vec<u8> {
+        let mut entry = vec![0; GPT_MIN_ENTRY_LENGTH];
+        entry[0..16].copy_from_slice(&guid_bytes(type_guid));
+        entry[16..32].copy_from_slice(&[0x42; 16]);
+        entry[32..40].copy_from_slice(&first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+        let name = utf16_bytes(name);
+        entry[56..56 + name.len()].copy_from_slice(&name);
+        entry
+    }
+
+    fn gpt_header(entries_lba: u64, entry_count: u32, entry_len: u32) -> [u8; 512] {
+        let mut header = [0; 512];
+        header[0..8].copy_from_slice(&GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&entry_count.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_len.to_le_bytes());
+        header
+    }
+
+    fn indices(partitions: &[Partition]) -> Vec<usize> {
+        partitions.iter().map(|p| p.index).collect()
+    }
+
+    #[test]
+    fn reads_primary_and_logical_partitions() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE as usize];
+        write_sector(
+            &mut disk,
+            0,
+            &mbr_sector(&[
+                mbr_entry(mbr_types::NTFS, 2, 8),
+                mbr_entry(mbr_types::EXTENDED_LBA, 16, 40),
+            ]),
+        );
+        write_sector(&mut disk, 2, &boot_sector(8));
+        // Logical partitions are relative to their EBR, and the next EBR to
+        // the start of the extended partition.
+        write_sector(
+            &mut disk,
+            16,
+            &mbr_sector(&[
+                mbr_entry(mbr_types::HIDDEN_NTFS, 1, 8),
+                mbr_entry(mbr_types::EXTENDED_CHS, 16, 16),
+            ]),
+        );
+        write_sector(&mut disk, 17, &boot_sector(8));
+        write_sector(&mut disk, 32, &mbr_sector(&[mbr_entry(LINUX, 1, 8)]));
+
+        let mut disk = MemoryReader::new(disk);
+        let partitions = read_partitions(&mut disk).unwrap();
+        assert_eq!(indices(&partitions), [1, 5, 6]);
+        let offsets: Vec<_> = partitions.iter().map(|p| p.offset).collect();
+        assert_eq!(
+            offsets,
+            [2 * SECTOR_SIZE, 17 * SECTOR_SIZE, 33 * SECTOR_SIZE]
+        );
+        assert!(partitions.iter().all(|p| p.len == 8 * SECTOR_SIZE));
+        assert!(!partitions[2].has_ntfs_type);
+
+        let partitions = find_ntfs_partitions(&mut disk).unwrap();
+        assert_eq!(indices(&partitions), [1, 5]);
+    }
+
+    #[test]
+    fn reads_gpt_behind_protective_mbr() {
+        let linux_data = Guid::from_fields(
+            0x0FC6_3DAF,
+            0x8483,
+            0x4772,
+            [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+        );
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE as usize];
+        write_sector(
+            &mut disk,
+            0,
+            &mbr_sector(&[mbr_entry(mbr_types::GPT_PROTECTIVE, 1, 63)]),
+        );
+        write_sector(&mut disk, 1, &gpt_header(2, 4, GPT_MIN_ENTRY_LENGTH as u32));
+        // The second slot is unused, so the Linux partition is number 3.
+        let entries = [
+            gpt_entry(gpt_types::BASIC_DATA, 34, 41, "Basic data partition"),
+            vec![0; GPT_MIN_ENTRY_LENGTH],
+            gpt_entry(linux_data, 48, 55, "root"),
+            vec![0; GPT_MIN_ENTRY_LENGTH],
+        ]
+        .concat();
+        write_sector(&mut disk, 2, &entries);
+        write_sector(&mut disk, 34, &boot_sector(8));
+
+        let mut disk = MemoryReader::new(disk);
+        let partitions = read_partitions(&mut disk).unwrap();
+        assert_eq!(indices(&partitions), [1, 3]);
+        assert_eq!(partitions[0].offset, 34 * SECTOR_SIZE);
+        assert_eq!(partitions[0].len, 8 * SECTOR_SIZE);
+        match &partitions[0].kind {
+            PartitionKind::Gpt {
+                type_guid, name, ..
+            } => {
+                assert_eq!(*type_guid, gpt_types::BASIC_DATA);
+                assert_eq!(name, "Basic data partition");
+            }
+            kind => panic!("expected a GPT partition, got {:?}", kind),
+        }
+        assert!(partitions[0].has_ntfs_type && partitions[0].is_ntfs());
+        assert!(!partitions[1].has_ntfs_type && !partitions[1].is_ntfs());
+
+        let partitions = find_ntfs_partitions(&mut disk).unwrap();
+        assert_eq!(indices(&partitions), [1]);
+    }
+
+    #[test]
+    fn skips_partitions_without_ntfs() {
+        // The type is shared with exFAT, so only the boot sector tells them
+        // apart.
+        let mut exfat = boot_sector(8);
+        exfat[3..11].copy_from_slice(b"EXFAT   ");
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE as usize];
+        write_sector(
+            &mut disk,
+            0,
+            &mbr_sector(&[
+                mbr_entry(mbr_types::NTFS, 2, 8),
+                mbr_entry(LINUX, 16, 8),
+                mbr_entry(mbr_types::NTFS, 32, 8),
+            ]),
+        );
+        write_sector(&mut disk, 2, &exfat);
+        write_sector(&mut disk, 16, &boot_sector(8));
+        write_sector(&mut disk, 32, &boot_sector(8));
+
+        let mut disk = MemoryReader::new(disk);
+        let partitions = read_partitions(&mut disk).unwrap();
+        assert_eq!(indices(&partitions), [1, 2, 3]);
+        assert!(partitions[0].has_ntfs_type && !partitions[0].is_ntfs());
+        // A boot sector is enough, whatever the table says.
+        assert!(!partitions[1].has_ntfs_type && partitions[1].is_ntfs());
+
+        let partitions = find_ntfs_partitions(&mut disk).unwrap();
+        assert_eq!(indices(&partitions), [2, 3]);
+    }
+
+    #[test]
+    fn rejects_bad_gpt_entry_tables() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE as usize];
+        write_sector(
+            &mut disk,
+            0,
+            &mbr_sector(&[mbr_entry(mbr_types::GPT_PROTECTIVE, 1, 63)]),
+        );
+        for header in [
+            // Entries that aren't a power of two, or are too big
+            gpt_header(2, 4, 129),
+            gpt_header(2, 4, 8192),
+            // Around 4TB of entries
+            gpt_header(2, u32::MAX, 1024),
+            gpt_header(u64::MAX / 2, 4, 128),
+        ] {
+            write_sector(&mut disk, 1, &header);
+            let result = read_partitions(&mut MemoryReader::new(disk.clone()));
+            assert!(matches!(result, Err(Error::BadGptHeader)));
+        }
+    }
+
+    #[test]
+    fn skips_partitions_outside_the_disk() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE as usize];
+        write_sector(
+            &mut disk,
+            0,
+            &mbr_sector(&[
+                mbr_entry(mbr_types::NTFS, 2, 8),
+                mbr_entry(mbr_types::NTFS, DISK_SECTORS as u32, 8),
+                // Runs past the end, as in a truncated image
+                mbr_entry(mbr_types::NTFS, 32, u32::MAX),
+            ]),
+        );
+        let mut reader = MemoryReader::new(disk.clone());
+        let partitions = read_partitions(&mut reader).unwrap();
+        assert_eq!(indices(&partitions), [1, 3]);
+
+        write_sector(
+            &mut disk,
+            0,
+            &mbr_sector(&[mbr_entry(mbr_types::GPT_PROTECTIVE, 1, 63)]),
+        );
+        write_sector(&mut disk, 1, &gpt_header(2, 4, GPT_MIN_ENTRY_LENGTH as u32));
+        let entries = [
+            gpt_entry(gpt_types::BASIC_DATA, 34, 41, "fits"),
+            gpt_entry(gpt_types::BASIC_DATA, u64::MAX / 2, u64::MAX, "overflows"),
+            gpt_entry(gpt_types::BASIC_DATA, 48, u64::MAX, "too long"),
+            gpt_entry(gpt_types::BASIC_DATA, 1 << 40, 1 << 41, "past the end"),
+        ]
+        .concat();
+        write_sector(&mut disk, 2, &entries);
+        let partitions = read_partitions(&mut MemoryReader::new(disk)).unwrap();
+        assert_eq!(indices(&partitions), [1]);
+    }
+
+    #[test]
+    fn reads_unpartitioned_volume() {
+        let mut image = ImageBuilder::new().build();
+        let partitions = read_partitions(&mut image).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert!(matches!(partitions[0].kind, PartitionKind::WholeImage));
+        assert_eq!(partitions[0].offset, 0);
+        assert_eq!(partitions[0].len, image.len());
+    }
+
+    #[test]
+    fn stops_at_looping_ebr_chain() {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR_SIZE as usize];
+        write_sector(
+            &mut disk,
+            0,
+            &mbr_sector(&[mbr_entry(mbr_types::EXTENDED_LBA, 16, 40)]),
+        );
+        write_sector(
+            &mut disk,
+            16,
+            &mbr_sector(&[
+                mbr_entry(mbr_types::NTFS, 1, 8),
+                mbr_entry(mbr_types::EXTENDED_LBA, 16, 16),
+            ]),
+        );
+        write_sector(&mut disk, 17, &boot_sector(8));
+        // Points back at the first EBR.
+        write_sector(
+            &mut disk,
+            32,
+            &mbr_sector(&[
+                mbr_entry(mbr_types::NTFS, 1, 8),
+                mbr_entry(mbr_types::EXTENDED_LBA, 0, 32),
+            ]),
+        );
+        write_sector(&mut disk, 33, &boot_sector(8));
+
+        let mut disk = MemoryReader::new(disk);
+        let partitions = read_partitions(&mut disk).unwrap();
+        assert_eq!(indices(&partitions), [5, 6]);
+        assert!(partitions.iter().all(Partition::is_ntfs));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::{
+    err::Error,
+    vhd::{VhdReader, VhdType},
+    vhdx::VhdxReader,
+};
+
+use std::{
+    fs::File,
+    io::{self, Read as _, Seek as _, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+// Most images don't record their sector size anywhere we can cheaply get at,
+// and 512 is what every NTFS volume we care about uses on disk.
+const DEFAULT_SECTOR_SIZE: u64 = 512;
+
+const MAX_PARENT_CHAIN_DEPTH: usize = 16;
+
+// Something we can read a volume's raw bytes from: a live volume handle,
+// a dd-style image, a partition inside a disk image, and so on.
+pub trait VolumeReader {
+    // Fills all of `buf` with the bytes starting at `offset` into the volume.
+    // Reads which can't be completely satisfied are an error.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error>;
+
+    fn sector_size(&self) -> u64;
+
+    // Total length of the volume in bytes.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+impl<R: VolumeReader + ?Sized> VolumeReader for Box<R> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn sector_size(&self) -> u64 {
+        (**self).sector_size()
+    }
+
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+}
+
+// A raw (dd-style) image of a single volume stored in a regular file.
+pub struct ImageReader {
+    file: File,
+    sector_size: u64,
+    len: u64,
+}
+impl ImageReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::new(File::open(path)?)
+    }
+
+    pub fn new(file: File) -> Result<Self, Error> {
+        let len = file.metadata()?.len();
+        Ok(ImageReader {
+            file,
+            sector_size: DEFAULT_SECTOR_SIZE,
+            len,
+        })
+    }
+
+    pub fn with_sector_size(mut self, sector_size: u64) -> Self {
+        self.sector_size = sector_size;
+        self
+    }
+}
+impl VolumeReader for ImageReader {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        match self.file.read_exact(buf) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                Err(Error::ReadVolumeTooShort)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+// Opens a disk or volume image for reading. Raw images, VHD and VHDX files
+// are supported; the parents of differencing disks are found and opened too.
+pub fn open_image<P: AsRef<Path>>(path: P) -> Result<Box<dyn VolumeReader>, Error> {
+    open_image_in_chain(path.as_ref(), 0)
+}
+
+fn open_image_in_chain(path: &Path, depth: usize) -> Result<Box<dyn VolumeReader>, Error> {
+    if depth > MAX_PARENT_CHAIN_DEPTH {
+        return Err(Error::ParentChainTooDeep);
+    }
+
+    let mut file = ImageReader::open(path)?;
+
+    if VhdxReader::probe(&mut file)? {
+        let disk = VhdxReader::open(file)?;
+        if !disk.has_parent() {
+            return Ok(Box::new(disk));
+        }
+        let parent_path =
+            find_parent_image(path, disk.parent_locations()).ok_or(Error::MissingParentDisk)?;
+        let parent = open_image_in_chain(&parent_path, depth + 1)?;
+        return Ok(Box::new(disk.with_parent(parent)));
+    }
+
+    if VhdReader::probe(&mut file)? {
+        let disk = VhdReader::open(file)?;
+        if disk.disk_type() != VhdType::Differencing {
+            return Ok(Box::new(disk));
+        }
+        let mut locations = disk.parent_locations().to_vec();
+        locations.extend(disk.parent_name().map(String::from));
+        let parent_path =
+            find_parent_image(path, &locations[..]).ok_or(Error::MissingParentDisk)?;
+        let parent = open_image_in_chain(&parent_path, depth + 1)?;
+        return Ok(Box::new(disk.with_parent(parent)));
+    }
+
+    Ok(Box::new(file))
+}
+
+// Differencing disks record their parent's location as Windows paths, often
+// relative to the child. Look for it there first, then next to the child.
+fn find_parent_image(child: &Path, locations: &[String]) -> Option<PathBuf> {
+    let directory = child.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut candidates = Vec::new();
+    for location in locations {
+        let location = location.replace('\\', std::path::MAIN_SEPARATOR_STR);
+        let location = Path::new(&location);
+        candidates.push(directory.join(location));
+        if let Some(file_name) = location.file_name() {
+            candidates.push(directory.join(file_name));
+        }
+    }
+
+    candidates.into_iter().find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{dynamic_vhd, fixed_vhd};
+
+    use std::fs;
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("mft_ntfs_test_{}_{}", std::process::id(), name));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn opens_differencing_disk_with_its_parent() {
+        let directory = test_directory("chain");
+        let parent = directory.join("parent.vhd");
+        let child = directory.join("child.vhd");
+        fs::write(&parent, fixed_vhd(&[0x11; 8192])).unwrap();
+        let blocks = [Some((vec![0x80], vec![0xCC; 512])), None];
+        fs::write(
+            &child,
+            dynamic_vhd(8192, 4096, &blocks, Some(".\\parent.vhd")),
+        )
+        .unwrap();
+
+        let mut buf = vec![0; 8192];
+        let read = open_image(&child).and_then(|mut disk| disk.read_at(0, &mut buf[..]));
+        fs::remove_file(&parent).unwrap();
+        let missing = open_image(&child).err();
+        fs::remove_dir_all(&directory).unwrap();
+
+        read.unwrap();
+        assert_eq!(buf[..512], [0xCC; 512][..]);
+        assert!(buf[512..].iter().all(|b| *b == 0x11));
+        assert!(matches!(missing, Some(Error::MissingParentDisk)));
+    }
+
+    #[test]
+    fn finds_parent_image() {
+        let directory = test_directory("parent");
+        let child = directory.join("child.vhdx");
+        fs::create_dir_all(directory.join("old")).unwrap();
+        fs::write(directory.join("parent.vhdx"), b"").unwrap();
+        fs::write(directory.join("old").join("parent.vhdx"), b"").unwrap();
+
+        // Relative paths are relative to the child.
+        let relative = find_parent_image(&child, &["old\\parent.vhdx".to_string()]);
+        // Anything else is looked for next to the child.
+        let moved = find_parent_image(&child, &["C:\\Disks\\parent.vhdx".to_string()]);
+        let missing = find_parent_image(&child, &["C:\\Disks\\other.vhdx".to_string()]);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(relative, Some(directory.join("old").join("parent.vhdx")));
+        assert_eq!(moved, Some(directory.join("parent.vhdx")));
+        assert_eq!(missing, None);
+    }
+}
@@ -0,0 +1,1867 @@
+// Fabricates small NTFS volumes in memory, so that parsing can be tested
+// without a real disk. Only the structures this crate reads are filled in;
+// the results wouldn't pass chkdsk.
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use crate::{err::Error, guid::Guid, reader::VolumeReader};
+
+use std::collections::{BTreeMap, HashMap};
+
+pub const SECTOR_SIZE: u64 = 512;
+pub const BYTES_PER_CLUSTER: u64 = 4096;
+pub const RECORD_SIZE: u64 = 1024;
+pub const MFT_START_LCN: u64 = 16;
+pub const MFT_MIRROR_START_LCN: u64 = 2;
+pub const VOLUME_SERIAL_NUMBER: u64 = 0x1234_5678_9ABC_DEF0;
+// Room for this many records is set aside for the MFT, ahead of any data.
+pub const MFT_CAPACITY: u64 = 64;
+
+pub const ROOT_RECORD: u64 = 5;
+// Records below this are reserved for metafiles.
+pub const FIRST_USER_RECORD: u64 = 16;
+
+const USA_OFFSET: usize = 0x30;
+const UPDATE_SEQUENCE_NUMBER: u16 = 0x0001;
+
+pub mod type_codes {
+    pub const STANDARD_INFORMATION: u32 = 0x10;
+    pub const ATTRIBUTE_LIST: u32 = 0x20;
+    pub const FILE_NAME: u32 = 0x30;
+    pub const OBJECT_ID: u32 = 0x40;
+    pub const SECURITY_DESCRIPTOR: u32 = 0x50;
+    pub const VOLUME_NAME: u32 = 0x60;
+    pub const VOLUME_INFORMATION: u32 = 0x70;
+    pub const DATA: u32 = 0x80;
+    pub const INDEX_ROOT: u32 = 0x90;
+    pub const INDEX_ALLOCATION: u32 = 0xA0;
+    pub const BITMAP: u32 = 0xB0;
+    pub const REPARSE_POINT: u32 = 0xC0;
+    pub const EA_INFORMATION: u32 = 0xD0;
+    pub const EA: u32 = 0xE0;
+}
+
+pub mod namespaces {
+    pub const POSIX: u8 = 0;
+    pub const WIN32: u8 = 1;
+    pub const DOS: u8 = 2;
+    pub const WIN32_DOS: u8 = 3;
+}
+
+pub mod file_attributes {
+    pub const READ_ONLY: u32 = 0x0001;
+    pub const HIDDEN: u32 = 0x0002;
+    pub const SYSTEM: u32 = 0x0004;
+    pub const ARCHIVE: u32 = 0x0020;
+    pub const SPARSE: u32 = 0x0200;
+    pub const DIRECTORY: u32 = 0x1000_0000;
+}
+
+// A volume held entirely in memory.
+pub struct MemoryReader {
+    data: Vec<u8>,
+    sector_size: u64,
+}
+impl MemoryReader {
+    pub fn new(data: Vec<u8>) -> Self {
+        MemoryReader {
+            data,
+            sector_size: SECTOR_SIZE,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..]
+    }
+}
+impl VolumeReader for MemoryReader {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let start = offset as usize;
+        let end = start
+            .checked_add(buf.len())
+            .filter(|end| *end <= self.data.len())
+            .ok_or(Error::ReadVolumeTooShort)?;
+        buf.copy_from_slice(&self.data[start..end]);
+        Ok(())
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    fn len(&self) -> u64 {
+        self.data.len() as u64
+    }
+}
+
+// A run in an attribute's mapping pairs: `lcn` is None for sparse runs.
+#[derive(Debug, Clone, Copy)]
+pub struct Run {
+    pub lcn: Option<u64>,
+    pub cluster_count: u64,
+}
+impl Run {
+    pub fn at(lcn: u64, cluster_count: u64) -> Self {
+        Run {
+            lcn: Some(lcn),
+            cluster_count,
+        }
+    }
+
+    pub fn sparse(cluster_count: u64) -> Self {
+        Run {
+            lcn: None,
+            cluster_count,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Resident(Vec<u8>),
+    NonResident {
+        lowest_vcn: u64,
+        runs: Vec<Run>,
+        file_size: u64,
+        valid_data_length: u64,
+        compression_unit: u8,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    type_code: u32,
+    name: Option<String>,
+    flags: u16,
+    value: Value,
+}
+impl Attribute {
+    pub fn resident(type_code: u32, name: Option<&str>, value: Vec<u8>) -> Self {
+        Attribute {
+            type_code,
+            name: name.map(String::from),
+            flags: 0,
+            value: Value::Resident(value),
+        }
+    }
+
+    // `file_size` is the attribute's logical size; the allocated size is
+    // taken from the runs.
+    pub fn non_resident(
+        type_code: u32,
+        name: Option<&str>,
+        runs: Vec<Run>,
+        file_size: u64,
+    ) -> Self {
+        Attribute {
+            type_code,
+            name: name.map(String::from),
+            flags: 0,
+            value: Value::NonResident {
+                lowest_vcn: 0,
+                runs,
+                file_size,
+                valid_data_length: file_size,
+                compression_unit: 0,
+            },
+        }
+    }
+
+    pub fn standard_information(file_attributes: u32) -> Self {
+        StandardInfo {
+            file_attributes,
+            ..Default::default()
+        }
+        .attribute()
+    }
+
+    pub fn file_name(parent: u64, name: &str, namespace: u8) -> Self {
+        Attribute::file_name_with_times(parent, name, namespace, [0; 4])
+    }
+
+    // `times` are the raw created, modified, MFT-changed and accessed times.
+    pub fn file_name_with_times(parent: u64, name: &str, namespace: u8, times: [u64; 4]) -> Self {
+        let value = file_name_value(parent, name, namespace, times);
+        Attribute::resident(type_codes::FILE_NAME, None, value)
+    }
+
+    // Like `file_name`, but with a whole file reference (as made by
+    // `file_reference`) for the parent, so its sequence number can be set.
+    pub fn file_name_with_parent_reference(parent: u64, name: &str, namespace: u8) -> Self {
+        let mut value = file_name_value(0, name, namespace, [0; 4]);
+        value[0..8].copy_from_slice(&parent.to_le_bytes());
+        Attribute::resident(type_codes::FILE_NAME, None, value)
+    }
+
+    // `node` is built with `index_node`.
+    pub fn index_root(name: &str, node: Vec<u8>) -> Self {
+        let mut value = vec![0; 16];
+        value[0..4].copy_from_slice(&type_codes::FILE_NAME.to_le_bytes());
+        // COLLATION_FILE_NAME
+        value[4..8].copy_from_slice(&1u32.to_le_bytes());
+        value[8..12].copy_from_slice(&(BYTES_PER_CLUSTER as u32).to_le_bytes());
+        value[12] = 1;
+        value.extend_from_slice(&node);
+        Attribute::resident(type_codes::INDEX_ROOT, Some(name), value)
+    }
+
+    pub fn index_allocation(name: &str, runs: Vec<Run>, size: u64) -> Self {
+        Attribute::non_resident(type_codes::INDEX_ALLOCATION, Some(name), runs, size)
+    }
+
+    pub fn bitmap(name: &str, bits: &[u8]) -> Self {
+        Attribute::resident(type_codes::BITMAP, Some(name), bits.to_vec())
+    }
+
+    pub fn resident_data(name: Option<&str>, value: &[u8]) -> Self {
+        Attribute::resident(type_codes::DATA, name, value.to_vec())
+    }
+
+    pub fn non_resident_data(name: Option<&str>, runs: Vec<Run>, file_size: u64) -> Self {
+        let is_sparse = runs.iter().any(|run| run.lcn.is_none());
+        let attribute = Attribute::non_resident(type_codes::DATA, name, runs, file_size);
+        if is_sparse {
+            attribute.with_flags(0x8000)
+        } else {
+            attribute
+        }
+    }
+
+    // An LZNT1-compressed $DATA attribute, in 16-cluster compression units.
+    // Use `lznt1_compress` to make the contents.
+    pub fn compressed_data(name: Option<&str>, runs: Vec<Run>, file_size: u64) -> Self {
+        Attribute::non_resident(type_codes::DATA, name, runs, file_size)
+            .with_compression_unit(4)
+            .with_flags(0x0001)
+    }
+
+    pub fn reparse_point(buffer: Vec<u8>) -> Self {
+        Attribute::resident(type_codes::REPARSE_POINT, None, buffer)
+    }
+
+    pub fn attribute_list(entries: &[ListEntry]) -> Self {
+        let mut value = Vec::new();
+        for entry in entries {
+            value.extend_from_slice(&entry.encode());
+        }
+        Attribute::resident(type_codes::ATTRIBUTE_LIST, None, value)
+    }
+
+    pub fn with_flags(mut self, flags: u16) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    // Makes this a later piece of an attribute split across records. Like
+    // NTFS, only the first piece gives the attribute's sizes.
+    pub fn with_lowest_vcn(mut self, vcn: u64) -> Self {
+        if let Value::NonResident {
+            lowest_vcn,
+            file_size,
+            valid_data_length,
+            ..
+        } = &mut self.value
+        {
+            *lowest_vcn = vcn;
+            *file_size = 0;
+            *valid_data_length = 0;
+        }
+        self
+    }
+
+    // log2 of the clusters in each compression unit
+    pub fn with_compression_unit(mut self, shift: u8) -> Self {
+        if let Value::NonResident {
+            compression_unit, ..
+        } = &mut self.value
+        {
+            *compression_unit = shift;
+        }
+        self
+    }
+
+    pub fn with_valid_data_length(mut self, length: u64) -> Self {
+        if let Value::NonResident {
+            valid_data_length, ..
+        } = &mut self.value
+        {
+            *valid_data_length = length;
+        }
+        self
+    }
+
+    fn encode(&self, instance: u16) -> Vec<u8> {
+        let name = self
+            .name
+            .as_deref()
+            .unwrap_or("")
+            .encode_utf16()
+            .collect::<Vec<_>>();
+        let header_len = match self.value {
+            Value::Resident(_) => 24,
+            // Compressed attributes also record their total allocation.
+            Value::NonResident {
+                compression_unit: 0,
+                ..
+            } => 64,
+            Value::NonResident { .. } => 72,
+        };
+        let name_end = header_len + 2 * name.len();
+        let value_offset = align8(name_end);
+
+        let mut buf = vec![0; value_offset];
+        buf[0..4].copy_from_slice(&self.type_code.to_le_bytes());
+        buf[9] = name.len() as u8;
+        if !name.is_empty() {
+            buf[10..12].copy_from_slice(&(header_len as u16).to_le_bytes());
+        }
+        buf[12..14].copy_from_slice(&self.flags.to_le_bytes());
+        buf[14..16].copy_from_slice(&instance.to_le_bytes());
+        for (i, unit) in name.iter().enumerate() {
+            buf[header_len + 2 * i..header_len + 2 * i + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        match &self.value {
+            Value::Resident(value) => {
+                buf[8] = 0;
+                buf[16..20].copy_from_slice(&(value.len() as u32).to_le_bytes());
+                buf[20..22].copy_from_slice(&(value_offset as u16).to_le_bytes());
+                buf.extend_from_slice(value);
+            }
+            Value::NonResident {
+                lowest_vcn,
+                runs,
+                file_size,
+                valid_data_length,
+                compression_unit,
+            } => {
+                let cluster_count: u64 = runs.iter().map(|run| run.cluster_count).sum();
+                buf[8] = 1;
+                buf[16..24].copy_from_slice(&lowest_vcn.to_le_bytes());
+                let highest_vcn = (lowest_vcn + cluster_count).saturating_sub(1);
+                buf[24..32].copy_from_slice(&highest_vcn.to_le_bytes());
+                buf[32..34].copy_from_slice(&(value_offset as u16).to_le_bytes());
+                buf[34] = *compression_unit;
+                buf[40..48].copy_from_slice(&(cluster_count * BYTES_PER_CLUSTER).to_le_bytes());
+                buf[48..56].copy_from_slice(&file_size.to_le_bytes());
+                buf[56..64].copy_from_slice(&valid_data_length.to_le_bytes());
+                buf.extend_from_slice(&encode_mapping_pairs(runs));
+            }
+        }
+
+        buf.resize(align8(buf.len()), 0);
+        let len = buf.len() as u32;
+        buf[4..8].copy_from_slice(&len.to_le_bytes());
+        buf
+    }
+}
+
+pub fn file_name_value(parent: u64, name: &str, namespace: u8, times: [u64; 4]) -> Vec<u8> {
+    let name = name.encode_utf16().collect::<Vec<_>>();
+    let mut value = vec![0; 66 + 2 * name.len()];
+    value[0..8].copy_from_slice(&file_reference(parent, 1).to_le_bytes());
+    for (i, time) in times.iter().enumerate() {
+        value[8 + 8 * i..16 + 8 * i].copy_from_slice(&time.to_le_bytes());
+    }
+    value[64] = name.len() as u8;
+    value[65] = namespace;
+    for (i, unit) in name.iter().enumerate() {
+        value[66 + 2 * i..68 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+    }
+    value
+}
+
+// An $I30 index entry naming `name` in `parent`, found in record `file`.
+pub fn directory_index_entry(
+    file: u64,
+    parent: u64,
+    name: &str,
+    subnode_vcn: Option<u64>,
+) -> Vec<u8> {
+    let key = file_name_value(parent, name, namespaces::WIN32, [0; 4]);
+    index_entry(file_reference(file, 1), &key, subnode_vcn, false)
+}
+
+// The entry which ends every index node.
+pub fn last_index_entry(subnode_vcn: Option<u64>) -> Vec<u8> {
+    index_entry(0, &[], subnode_vcn, true)
+}
+
+pub fn index_entry(
+    file_reference: u64,
+    key: &[u8],
+    subnode_vcn: Option<u64>,
+    is_last: bool,
+) -> Vec<u8> {
+    let mut len = align8(16 + key.len());
+    if subnode_vcn.is_some() {
+        len += 8;
+    }
+    let mut buf = vec![0; len];
+    buf[0..8].copy_from_slice(&file_reference.to_le_bytes());
+    buf[8..10].copy_from_slice(&(len as u16).to_le_bytes());
+    buf[10..12].copy_from_slice(&(key.len() as u16).to_le_bytes());
+    let flags = u16::from(subnode_vcn.is_some()) | (u16::from(is_last) << 1);
+    buf[12..14].copy_from_slice(&flags.to_le_bytes());
+    buf[16..16 + key.len()].copy_from_slice(key);
+    if let Some(vcn) = subnode_vcn {
+        buf[len - 8..].copy_from_slice(&vcn.to_le_bytes());
+    }
+    buf
+}
+
+// A node header followed by `entries`, which start `entries_offset` bytes
+// after the header. `allocated` is the space the node may grow into.
+pub fn index_node(entries: &[Vec<u8>], entries_offset: usize, allocated: usize) -> Vec<u8> {
+    let mut buf = vec![0; entries_offset];
+    for entry in entries {
+        buf.extend_from_slice(entry);
+    }
+    let has_children = entries
+        .iter()
+        .any(|entry| u16::from_le_bytes([entry[12], entry[13]]) & 1 != 0);
+    let len = buf.len();
+    buf[0..4].copy_from_slice(&(entries_offset as u32).to_le_bytes());
+    buf[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+    buf[8..12].copy_from_slice(&(allocated.max(len) as u32).to_le_bytes());
+    buf[12] = has_children as u8;
+    buf
+}
+
+// The node header of a root sits straight before its entries.
+pub fn index_root_node(entries: &[Vec<u8>]) -> Vec<u8> {
+    index_node(entries, 16, 0)
+}
+
+// One cluster-sized INDX block, with fixups applied. Anything in `slack` is
+// written straight after the entries, as if left over from earlier contents.
+pub fn index_block(vcn: u64, entries: &[Vec<u8>], slack: &[u8]) -> Vec<u8> {
+    let block_size = BYTES_PER_CLUSTER as usize;
+    let usa_offset = 0x28;
+    let usa_size = block_size / SECTOR_SIZE as usize + 1;
+    let node_offset = 24;
+    let entries_offset = align8(usa_offset + 2 * usa_size) - node_offset;
+
+    let mut buf = vec![0; block_size];
+    buf[0..4].copy_from_slice(b"INDX");
+    buf[4..6].copy_from_slice(&(usa_offset as u16).to_le_bytes());
+    buf[6..8].copy_from_slice(&(usa_size as u16).to_le_bytes());
+    buf[16..24].copy_from_slice(&vcn.to_le_bytes());
+    let node = index_node(entries, entries_offset, block_size - node_offset);
+    // The node's padding overlaps the update sequence array, so skip it.
+    buf[node_offset..node_offset + 16].copy_from_slice(&node[..16]);
+    buf[node_offset + entries_offset..node_offset + node.len()]
+        .copy_from_slice(&node[entries_offset..]);
+    let slack_start = node_offset + node.len();
+    buf[slack_start..slack_start + slack.len()].copy_from_slice(slack);
+    apply_fixups(&mut buf[..], usa_offset, SECTOR_SIZE as usize);
+    buf
+}
+
+// An entry in a view index such as $SII, whose data follows its key.
+pub fn view_index_entry(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let len = align8(16 + key.len() + data.len());
+    let mut buf = vec![0; len];
+    buf[0..2].copy_from_slice(&(16 + key.len() as u16).to_le_bytes());
+    buf[2..4].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    buf[8..10].copy_from_slice(&(len as u16).to_le_bytes());
+    buf[10..12].copy_from_slice(&(key.len() as u16).to_le_bytes());
+    buf[16..16 + key.len()].copy_from_slice(key);
+    buf[16 + key.len()..16 + key.len() + data.len()].copy_from_slice(data);
+    buf
+}
+
+// The value of a $EA attribute: FILE_FULL_EA_INFORMATION structures, each
+// but the last padded to a multiple of 4 bytes.
+pub fn ea_list(attributes: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (i, (name, value)) in attributes.iter().enumerate() {
+        let start = buf.len();
+        buf.extend_from_slice(&[0; 4]);
+        buf.push(0);
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(value);
+        if i + 1 < attributes.len() {
+            buf.resize(start + (buf.len() - start).div_ceil(4) * 4, 0);
+            let next = (buf.len() - start) as u32;
+            buf[start..start + 4].copy_from_slice(&next.to_le_bytes());
+        }
+    }
+    buf
+}
+
+// A SID in its binary form, from a string like "S-1-5-32-544".
+pub fn sid_bytes(sid: &str) -> Vec<u8> {
+    let parts = sid.split('-').skip(1).collect::<Vec<_>>();
+    let authority: u64 = parts[1].parse().unwrap();
+    let mut buf = vec![parts[0].parse().unwrap(), (parts.len() - 2) as u8];
+    buf.extend_from_slice(&authority.to_be_bytes()[2..]);
+    for sub_authority in &parts[2..] {
+        buf.extend_from_slice(&sub_authority.parse::<u32>().unwrap().to_le_bytes());
+    }
+    buf
+}
+
+// A self-relative security descriptor. Each ACE in the DACL is given as
+// (type, flags, access mask, SID).
+pub fn security_descriptor(
+    owner: &str,
+    group: &str,
+    dacl: Option<&[(u8, u8, u32, &str)]>,
+) -> Vec<u8> {
+    let mut buf = vec![0; 20];
+    buf[0] = 1;
+    // SE_SELF_RELATIVE, and SE_DACL_PRESENT if there's a DACL
+    let control: u16 = if dacl.is_some() { 0x8004 } else { 0x8000 };
+    buf[2..4].copy_from_slice(&control.to_le_bytes());
+    let owner_offset = buf.len() as u32;
+    buf.extend_from_slice(&sid_bytes(owner));
+    let group_offset = buf.len() as u32;
+    buf.extend_from_slice(&sid_bytes(group));
+    buf[4..8].copy_from_slice(&owner_offset.to_le_bytes());
+    buf[8..12].copy_from_slice(&group_offset.to_le_bytes());
+    if let Some(aces) = dacl {
+        let dacl_offset = buf.len();
+        buf.extend_from_slice(&[2, 0, 0, 0]);
+        buf.extend_from_slice(&(aces.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        for &(ace_type, flags, access_mask, sid) in aces {
+            let sid = sid_bytes(sid);
+            buf.extend_from_slice(&[ace_type, flags]);
+            buf.extend_from_slice(&(8 + sid.len() as u16).to_le_bytes());
+            buf.extend_from_slice(&access_mask.to_le_bytes());
+            buf.extend_from_slice(&sid);
+        }
+        let dacl_len = (buf.len() - dacl_offset) as u16;
+        buf[dacl_offset + 2..dacl_offset + 4].copy_from_slice(&dacl_len.to_le_bytes());
+        buf[16..20].copy_from_slice(&(dacl_offset as u32).to_le_bytes());
+    }
+    buf
+}
+
+// A simple (greedy, and slow) LZNT1 compressor, for making test data.
+pub fn lznt1_compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    for chunk in data.chunks(4096) {
+        let mut body = Vec::new();
+        let mut position = 0;
+        while position < chunk.len() {
+            let flags_offset = body.len();
+            body.push(0);
+            for bit in 0..8 {
+                if position >= chunk.len() {
+                    break;
+                }
+                let mut length_bits = 12;
+                let mut scaled = position.saturating_sub(1);
+                while scaled >= 0x10 {
+                    length_bits -= 1;
+                    scaled >>= 1;
+                }
+                let max_length = (1 << length_bits) + 2;
+                let (mut best_length, mut best_offset) = (0, 0);
+                for offset in 1..=position.min(1 << (16 - length_bits)) {
+                    let mut length = 0;
+                    while length < max_length
+                        && position + length < chunk.len()
+                        && chunk[position + length - offset] == chunk[position + length]
+                    {
+                        length += 1;
+                    }
+                    if length > best_length {
+                        best_length = length;
+                        best_offset = offset;
+                    }
+                }
+
+                if best_length >= 3 {
+                    let token = ((best_offset - 1) << length_bits) | (best_length - 3);
+                    body[flags_offset] |= 1 << bit;
+                    body.extend_from_slice(&(token as u16).to_le_bytes());
+                    position += best_length;
+                } else {
+                    body.push(chunk[position]);
+                    position += 1;
+                }
+            }
+        }
+
+        if body.len() < chunk.len() {
+            output.extend_from_slice(&(0xB000 | (body.len() as u16 - 1)).to_le_bytes());
+            output.extend_from_slice(&body);
+        } else {
+            output.extend_from_slice(&(0x3000 | (chunk.len() as u16 - 1)).to_le_bytes());
+            output.extend_from_slice(chunk);
+        }
+    }
+    output
+}
+
+// Finds greedy matches for the test compressors below, through chains of
+// earlier positions starting with the same three bytes.
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    chains: HashMap<[u8; 3], Vec<usize>>,
+}
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        MatchFinder {
+            data,
+            chains: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, position: usize) {
+        if let Some(key) = self.data.get(position..position + 3) {
+            self.chains
+                .entry(key.try_into().unwrap())
+                .or_default()
+                .push(position);
+        }
+    }
+
+    // The longest match at `position` (as length and offset) which ends by
+    // `end`. Every position before this one must have been inserted.
+    fn find(
+        &self,
+        position: usize,
+        end: usize,
+        max_length: usize,
+        max_offset: usize,
+    ) -> (usize, usize) {
+        let max_length = max_length.min(end - position);
+        let candidates = match self.data.get(position..position + 3) {
+            Some(key) if max_length >= 3 => match self.chains.get(key) {
+                Some(candidates) => candidates,
+                None => return (0, 0),
+            },
+            _ => return (0, 0),
+        };
+        let mut best = (0, 0);
+        for &candidate in candidates.iter().rev().take(64) {
+            let offset = position - candidate;
+            if offset > max_offset {
+                break;
+            }
+            let mut length = 0;
+            while length < max_length
+                && self.data[candidate + length] == self.data[position + length]
+            {
+                length += 1;
+            }
+            if length > best.0 {
+                best = (length, offset);
+            }
+        }
+        best
+    }
+}
+
+// Code lengths for a usable (if not very good) Huffman code: every symbol
+// that turns up gets the same length. At least two symbols are given codes.
+fn flat_code_lengths(used: &[bool]) -> Vec<u8> {
+    let mut used = used.to_vec();
+    for i in 0..used.len() {
+        if used.iter().filter(|&&u| u).count() >= 2 {
+            break;
+        }
+        used[i] = true;
+    }
+    let count = used.iter().filter(|&&u| u).count();
+    let length = (usize::BITS - (count - 1).leading_zeros()) as u8;
+    used.iter().map(|&u| if u { length } else { 0 }).collect()
+}
+
+// The canonical codes for a set of code lengths.
+fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut codes = vec![0; lengths.len()];
+    let mut code = 0;
+    for length in 1..=16 {
+        for (symbol, _) in lengths.iter().enumerate().filter(|(_, &l)| l == length) {
+            codes[symbol] = code;
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+// A simple XPRESS Huffman compressor, for making test data. Following
+// MS-XCA, two 16-bit words are kept in reserve for the bits, so that extra
+// match length bytes can be written out ahead of them.
+pub fn xpress_compress(data: &[u8]) -> Vec<u8> {
+    assert!(data.len() <= 65536, "only single blocks are supported");
+
+    // (symbol, extra length, offset, offset bits)
+    let mut tokens = Vec::new();
+    let mut matches = MatchFinder::new(data);
+    let mut position = 0;
+    while position < data.len() {
+        let (length, offset) = matches.find(position, data.len(), usize::MAX, 65535);
+        let step = if length >= 3 {
+            let offset_bits = usize::BITS - 1 - offset.leading_zeros();
+            let symbol = 256 + (offset_bits as usize) * 16 + (length - 3).min(15);
+            tokens.push((symbol, length - 3, offset, offset_bits));
+            length
+        } else {
+            tokens.push((usize::from(data[position]), 0, 0, 0));
+            1
+        };
+        for _ in 0..step {
+            matches.insert(position);
+            position += 1;
+        }
+    }
+
+    let mut used = vec![false; 512];
+    for &(symbol, ..) in &tokens {
+        used[symbol] = true;
+    }
+    let lengths = flat_code_lengths(&used);
+    let codes = canonical_codes(&lengths);
+
+    let mut output = lengths
+        .chunks(2)
+        .map(|pair| pair[0] | (pair[1] << 4))
+        .collect::<Vec<_>>();
+    output.resize(260, 0);
+    let mut slots = (256, 258);
+    let mut accumulator: u32 = 0;
+    let mut free_bits = 16;
+    let mut write_bits = |output: &mut Vec<u8>, mut count: u32, value: u32| {
+        if count == 0 {
+            return;
+        }
+        if free_bits >= count {
+            free_bits -= count;
+            accumulator = (accumulator << count) | value;
+            return;
+        }
+        accumulator = (accumulator << free_bits) | (value >> (count - free_bits));
+        count -= free_bits;
+        output[slots.0..slots.0 + 2].copy_from_slice(&(accumulator as u16).to_le_bytes());
+        slots = (slots.1, output.len());
+        output.extend_from_slice(&[0, 0]);
+        free_bits = 16 - count;
+        accumulator = value & ((1 << count) - 1);
+    };
+
+    for (symbol, extra_length, offset, offset_bits) in tokens {
+        write_bits(&mut output, lengths[symbol].into(), codes[symbol]);
+        if symbol < 256 {
+            continue;
+        }
+        if extra_length >= 15 {
+            if extra_length - 15 < 255 {
+                output.push((extra_length - 15) as u8);
+            } else {
+                output.push(255);
+                if extra_length < 65536 {
+                    output.extend_from_slice(&(extra_length as u16).to_le_bytes());
+                } else {
+                    output.extend_from_slice(&[0, 0]);
+                    output.extend_from_slice(&(extra_length as u32).to_le_bytes());
+                }
+            }
+        }
+        write_bits(
+            &mut output,
+            offset_bits,
+            (offset as u32) & ((1 << offset_bits) - 1),
+        );
+    }
+    let (first, _) = slots;
+    output[first..first + 2].copy_from_slice(&((accumulator << free_bits) as u16).to_le_bytes());
+    output
+}
+
+// Writes 16-bit little-endian words, most significant bit first, as LZX
+// reads them.
+struct BitWriter {
+    output: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+impl BitWriter {
+    fn write(&mut self, count: u32, value: u32) {
+        self.bits = (self.bits << count) | (u64::from(value) & ((1 << count) - 1));
+        self.count += count;
+        while self.count >= 16 {
+            let word = (self.bits >> (self.count - 16)) as u16;
+            self.output.extend_from_slice(&word.to_le_bytes());
+            self.count -= 16;
+        }
+        self.bits &= (1 << self.count) - 1;
+    }
+
+    // Pads with 1 to 16 zero bits, to reach a 16-bit boundary.
+    fn align(&mut self) {
+        self.write(16 - self.count, 0);
+    }
+}
+
+// A simple LZX compressor for one WOF chunk, for making test data. The
+// chunk is split evenly into blocks of the given types.
+pub fn lzx_compress(data: &[u8], block_types: &[u32]) -> Vec<u8> {
+    const FILE_SIZE: i32 = 12_000_000;
+    let mut data = data.to_vec();
+    if data.len() > 10 {
+        let mut i = 0;
+        while i < data.len() - 10 {
+            if data[i] == 0xE8 {
+                let position = i as i32;
+                let relative = i32::from_le_bytes(data[i + 1..i + 5].try_into().unwrap());
+                if relative >= -position && relative < FILE_SIZE {
+                    let absolute = if relative < FILE_SIZE - position {
+                        relative + position
+                    } else {
+                        relative - FILE_SIZE
+                    };
+                    data[i + 1..i + 5].copy_from_slice(&absolute.to_le_bytes());
+                }
+                i += 5;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    let footer_bits = |slot: usize| {
+        if slot < 4 {
+            0
+        } else {
+            ((slot as u32 - 2) / 2).min(17)
+        }
+    };
+    let slot_base = |slot: usize| (0..slot).map(|s| 1usize << footer_bits(s)).sum::<usize>();
+
+    let mut writer = BitWriter {
+        output: Vec::new(),
+        bits: 0,
+        count: 0,
+    };
+    let mut matches = MatchFinder::new(&data);
+    let mut main_lengths = vec![0; 496];
+    let mut length_lengths = vec![0; 249];
+    let mut recent_offsets = [1, 1, 1];
+    let block_size = data.len().div_ceil(block_types.len());
+    let mut position = 0;
+    for (i, &block_type) in block_types.iter().enumerate() {
+        let end = if i + 1 == block_types.len() {
+            data.len()
+        } else {
+            position + block_size
+        };
+        writer.write(3, block_type);
+        if end - position == 32768 {
+            writer.write(1, 1);
+        } else {
+            writer.write(1, 0);
+            writer.write(16, (end - position) as u32);
+        }
+
+        if block_type == 3 {
+            writer.align();
+            for offset in recent_offsets {
+                writer
+                    .output
+                    .extend_from_slice(&(offset as u32).to_le_bytes());
+            }
+            writer.output.extend_from_slice(&data[position..end]);
+            if (end - position) % 2 == 1 {
+                writer.output.push(0);
+            }
+            while position < end {
+                matches.insert(position);
+                position += 1;
+            }
+            continue;
+        }
+
+        // (main symbol, length symbol, footer bits, footer)
+        let mut tokens = Vec::new();
+        while position < end {
+            let (length, offset) = matches.find(position, end, 257, 32767);
+            let step = if length >= 3 {
+                let slot = match recent_offsets.iter().position(|&r| r == offset) {
+                    Some(slot) => {
+                        recent_offsets.swap(0, slot);
+                        slot
+                    }
+                    None => {
+                        recent_offsets = [offset, recent_offsets[0], recent_offsets[1]];
+                        (3..)
+                            .find(|&slot| slot_base(slot + 1) > offset + 2)
+                            .unwrap()
+                    }
+                };
+                let length_header = (length - 2).min(7);
+                let length_symbol = (length_header == 7).then(|| length - 2 - 7);
+                let footer = (offset + 2 - slot_base(slot)) as u32;
+                let footer_bits = if slot < 3 { 0 } else { footer_bits(slot) };
+                tokens.push((
+                    256 + slot * 8 + length_header,
+                    length_symbol,
+                    footer_bits,
+                    footer,
+                ));
+                length
+            } else {
+                tokens.push((usize::from(data[position]), None, 0, 0));
+                1
+            };
+            for _ in 0..step {
+                matches.insert(position);
+                position += 1;
+            }
+        }
+
+        let mut used_main = vec![false; 496];
+        let mut used_lengths = vec![false; 249];
+        for &(main_symbol, length_symbol, ..) in &tokens {
+            used_main[main_symbol] = true;
+            if let Some(length_symbol) = length_symbol {
+                used_lengths[length_symbol] = true;
+            }
+        }
+        let new_main_lengths = flat_code_lengths(&used_main);
+        let new_length_lengths = if used_lengths.contains(&true) {
+            flat_code_lengths(&used_lengths)
+        } else {
+            vec![0; 249]
+        };
+
+        if block_type == 2 {
+            for _ in 0..8 {
+                writer.write(3, 3);
+            }
+        }
+        write_lzx_lengths(&mut writer, &main_lengths[..256], &new_main_lengths[..256]);
+        write_lzx_lengths(&mut writer, &main_lengths[256..], &new_main_lengths[256..]);
+        write_lzx_lengths(&mut writer, &length_lengths, &new_length_lengths);
+        main_lengths = new_main_lengths;
+        length_lengths = new_length_lengths;
+
+        let main_codes = canonical_codes(&main_lengths);
+        let length_codes = canonical_codes(&length_lengths);
+        for (main_symbol, length_symbol, footer_bits, footer) in tokens {
+            writer.write(main_lengths[main_symbol].into(), main_codes[main_symbol]);
+            if let Some(length_symbol) = length_symbol {
+                writer.write(
+                    length_lengths[length_symbol].into(),
+                    length_codes[length_symbol],
+                );
+            }
+            if block_type == 2 && footer_bits >= 3 {
+                // Every aligned symbol has a 3-bit code, which is the symbol.
+                writer.write(footer_bits - 3, footer >> 3);
+                writer.write(3, footer & 7);
+            } else {
+                writer.write(footer_bits, footer);
+            }
+        }
+    }
+    if writer.count > 0 {
+        writer.align();
+    }
+    writer.output
+}
+
+// Sends code lengths as differences from `previous`, through a pretree.
+fn write_lzx_lengths(writer: &mut BitWriter, previous: &[u8], lengths: &[u8]) {
+    let delta = |i: usize| u32::from((previous[i] + 17 - lengths[i]) % 17);
+    // (pretree symbol, extra bits, extra value, delta for symbol 19)
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let run = lengths[i..]
+            .iter()
+            .take_while(|&&l| l == lengths[i])
+            .count();
+        if lengths[i] == 0 && run >= 20 {
+            let run = run.min(51);
+            tokens.push((18, 5, run as u32 - 20, None));
+            i += run;
+        } else if lengths[i] == 0 && run >= 4 {
+            let run = run.min(19);
+            tokens.push((17, 4, run as u32 - 4, None));
+            i += run;
+        } else if run >= 4 {
+            let run = run.min(5);
+            tokens.push((19, 1, run as u32 - 4, Some(delta(i))));
+            i += run;
+        } else {
+            tokens.push((delta(i), 0, 0, None));
+            i += 1;
+        }
+    }
+
+    let mut used = vec![false; 20];
+    for &(symbol, _, _, same) in &tokens {
+        used[symbol as usize] = true;
+        if let Some(same) = same {
+            used[same as usize] = true;
+        }
+    }
+    let pretree_lengths = flat_code_lengths(&used);
+    let pretree_codes = canonical_codes(&pretree_lengths);
+    for &length in &pretree_lengths {
+        writer.write(4, length.into());
+    }
+    for (symbol, extra_bits, extra, same) in tokens {
+        let symbol = symbol as usize;
+        writer.write(pretree_lengths[symbol].into(), pretree_codes[symbol]);
+        writer.write(extra_bits, extra);
+        if let Some(same) = same {
+            let same = same as usize;
+            writer.write(pretree_lengths[same].into(), pretree_codes[same]);
+        }
+    }
+}
+
+// The reparse buffer WOF puts on the files it compresses.
+pub fn wof_reparse_buffer(algorithm: u32) -> Vec<u8> {
+    // WOF_EXTERNAL_INFO (version 1, the file provider), then
+    // FILE_PROVIDER_EXTERNAL_INFO_V1 (version 1)
+    let data = [1u32, 2, 1, algorithm]
+        .iter()
+        .flat_map(|field| field.to_le_bytes())
+        .collect::<Vec<_>>();
+    reparse_buffer(0x8000_0017, &data)
+}
+
+// The "WofCompressedData" stream for `data`: the chunk table, then each
+// chunk compressed on its own, or stored as-is if that's no bigger.
+pub fn wof_compressed_stream(algorithm: u32, data: &[u8]) -> Vec<u8> {
+    let chunk_size = match algorithm {
+        0 => 4096,
+        1 => 32768,
+        2 => 8192,
+        _ => 16384,
+    };
+    let mut table = Vec::new();
+    let mut chunks = Vec::new();
+    for chunk in data.chunks(chunk_size) {
+        if !chunks.is_empty() {
+            table.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        }
+        let compressed = match algorithm {
+            1 => lzx_compress(chunk, &[1]),
+            _ => xpress_compress(chunk),
+        };
+        if compressed.len() < chunk.len() {
+            chunks.extend_from_slice(&compressed);
+        } else {
+            chunks.extend_from_slice(chunk);
+        }
+    }
+    table.extend_from_slice(&chunks);
+    table
+}
+
+// A REPARSE_DATA_BUFFER for a Microsoft tag (so without a GUID).
+pub fn reparse_buffer(tag: u32, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 8];
+    buf[0..4].copy_from_slice(&tag.to_le_bytes());
+    buf[4..6].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+// The reparse buffer for a symlink (when `symlink_flags` is given) or a
+// mount point.
+pub fn link_reparse_buffer(
+    substitute_name: &str,
+    print_name: &str,
+    symlink_flags: Option<u32>,
+) -> Vec<u8> {
+    let substitute_name = utf16_bytes(substitute_name);
+    let print_name = utf16_bytes(print_name);
+    let mut data = Vec::new();
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(&(substitute_name.len() as u16).to_le_bytes());
+    data.extend_from_slice(&(substitute_name.len() as u16).to_le_bytes());
+    data.extend_from_slice(&(print_name.len() as u16).to_le_bytes());
+    let tag = match symlink_flags {
+        Some(flags) => {
+            data.extend_from_slice(&flags.to_le_bytes());
+            0xA000_000C
+        }
+        None => 0xA000_0003,
+    };
+    data.extend_from_slice(&substitute_name);
+    data.extend_from_slice(&print_name);
+    reparse_buffer(tag, &data)
+}
+
+pub fn utf16_bytes(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+// The contents of a $STANDARD_INFORMATION attribute; times are raw FILETIMEs.
+#[derive(Debug, Clone, Default)]
+pub struct StandardInfo {
+    pub created: u64,
+    pub modified: u64,
+    pub mft_changed: u64,
+    pub accessed: u64,
+    pub file_attributes: u32,
+    pub owner_id: u32,
+    pub security_id: u32,
+    pub quota_charged: u64,
+    pub usn: u64,
+}
+impl StandardInfo {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = vec![0; 72];
+        value[0..8].copy_from_slice(&self.created.to_le_bytes());
+        value[8..16].copy_from_slice(&self.modified.to_le_bytes());
+        value[16..24].copy_from_slice(&self.mft_changed.to_le_bytes());
+        value[24..32].copy_from_slice(&self.accessed.to_le_bytes());
+        value[32..36].copy_from_slice(&self.file_attributes.to_le_bytes());
+        value[48..52].copy_from_slice(&self.owner_id.to_le_bytes());
+        value[52..56].copy_from_slice(&self.security_id.to_le_bytes());
+        value[56..64].copy_from_slice(&self.quota_charged.to_le_bytes());
+        value[64..72].copy_from_slice(&self.usn.to_le_bytes());
+        value
+    }
+
+    pub fn attribute(&self) -> Attribute {
+        Attribute::resident(type_codes::STANDARD_INFORMATION, None, self.encode())
+    }
+}
+
+// One entry in an $ATTRIBUTE_LIST, pointing at the record an attribute lives in.
+#[derive(Debug, Clone)]
+pub struct ListEntry {
+    pub type_code: u32,
+    pub name: Option<String>,
+    pub starting_vcn: u64,
+    pub segment: u64,
+}
+impl ListEntry {
+    pub fn new(type_code: u32, segment: u64) -> Self {
+        ListEntry {
+            type_code,
+            name: None,
+            starting_vcn: 0,
+            segment,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let name = self
+            .name
+            .as_deref()
+            .unwrap_or("")
+            .encode_utf16()
+            .collect::<Vec<_>>();
+        let mut buf = vec![0; align8(26 + 2 * name.len())];
+        let len = buf.len() as u16;
+        buf[0..4].copy_from_slice(&self.type_code.to_le_bytes());
+        buf[4..6].copy_from_slice(&len.to_le_bytes());
+        buf[6] = name.len() as u8;
+        buf[7] = 26;
+        buf[8..16].copy_from_slice(&self.starting_vcn.to_le_bytes());
+        buf[16..24].copy_from_slice(&file_reference(self.segment, 1).to_le_bytes());
+        for (i, unit) in name.iter().enumerate() {
+            buf[26 + 2 * i..28 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+        }
+        buf
+    }
+}
+
+// A single file record segment.
+#[derive(Debug, Clone)]
+pub struct Record {
+    in_use: bool,
+    is_directory: bool,
+    sequence_number: u16,
+    base: Option<u64>,
+    attributes: Vec<Attribute>,
+}
+impl Record {
+    pub fn new() -> Self {
+        Record {
+            in_use: true,
+            is_directory: false,
+            sequence_number: 1,
+            base: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn directory() -> Self {
+        Record {
+            is_directory: true,
+            ..Record::new()
+        }
+    }
+
+    // A record holding overflow attributes for the base record `base`.
+    pub fn extension_of(base: u64) -> Self {
+        Record {
+            base: Some(base),
+            ..Record::new()
+        }
+    }
+
+    pub fn not_in_use(mut self) -> Self {
+        self.in_use = false;
+        self
+    }
+
+    pub fn with_sequence_number(mut self, sequence_number: u16) -> Self {
+        self.sequence_number = sequence_number;
+        self
+    }
+
+    pub fn with(mut self, attribute: Attribute) -> Self {
+        self.attributes.push(attribute);
+        self
+    }
+
+    fn encode(&self, number: u64) -> Vec<u8> {
+        let record_size = RECORD_SIZE as usize;
+        let usa_size = record_size / SECTOR_SIZE as usize + 1;
+        let first_attribute_offset = align8(USA_OFFSET + 2 * usa_size);
+
+        let mut buf = vec![0; record_size];
+        buf[0..4].copy_from_slice(b"FILE");
+        buf[4..6].copy_from_slice(&(USA_OFFSET as u16).to_le_bytes());
+        buf[6..8].copy_from_slice(&(usa_size as u16).to_le_bytes());
+        buf[16..18].copy_from_slice(&self.sequence_number.to_le_bytes());
+        let hard_link_count = self
+            .attributes
+            .iter()
+            .filter(|a| a.type_code == type_codes::FILE_NAME)
+            .count() as u16;
+        buf[18..20].copy_from_slice(&hard_link_count.to_le_bytes());
+        buf[20..22].copy_from_slice(&(first_attribute_offset as u16).to_le_bytes());
+        let flags = (self.in_use as u16) | ((self.is_directory as u16) << 1);
+        buf[22..24].copy_from_slice(&flags.to_le_bytes());
+        buf[28..32].copy_from_slice(&(record_size as u32).to_le_bytes());
+        if let Some(base) = self.base {
+            buf[32..40].copy_from_slice(&file_reference(base, 1).to_le_bytes());
+        }
+        buf[44..48].copy_from_slice(&(number as u32).to_le_bytes());
+
+        // Attributes must be sorted by type.
+        let mut attributes = self.attributes.iter().collect::<Vec<_>>();
+        attributes.sort_by_key(|a| a.type_code);
+
+        let mut offset = first_attribute_offset;
+        for (instance, attribute) in attributes.into_iter().enumerate() {
+            let encoded = attribute.encode(instance as u16);
+            buf[offset..offset + encoded.len()].copy_from_slice(&encoded);
+            offset += encoded.len();
+        }
+        buf[offset..offset + 4].copy_from_slice(&[0xFF; 4]);
+        offset += 8;
+        assert!(offset <= record_size, "record {} is too full", number);
+        buf[24..28].copy_from_slice(&(offset as u32).to_le_bytes());
+        buf[40..42].copy_from_slice(&(self.attributes.len() as u16).to_le_bytes());
+
+        apply_fixups(&mut buf[..], USA_OFFSET, SECTOR_SIZE as usize);
+        buf
+    }
+}
+impl Default for Record {
+    fn default() -> Self {
+        Record::new()
+    }
+}
+
+// Builds a volume with 512-byte sectors, 4KB clusters and 1KB file records.
+// $MFT (record 0) and the root directory (record 5) are always present; the
+// other metafiles are left out. $MFT's $BITMAP marks the records in use.
+pub struct ImageBuilder {
+    records: BTreeMap<u64, Record>,
+    // Everything after the space reserved for the MFT.
+    data: Vec<u8>,
+    fragment_mft: bool,
+    // Bits of $MFT's $BITMAP which disagree with the records
+    bitmap_overrides: BTreeMap<u64, bool>,
+    // Replaces the resident $BITMAP normally given to $MFT
+    mft_bitmap: Option<Attribute>,
+}
+impl ImageBuilder {
+    pub fn new() -> Self {
+        let mut builder = ImageBuilder {
+            records: BTreeMap::new(),
+            data: Vec::new(),
+            fragment_mft: false,
+            bitmap_overrides: BTreeMap::new(),
+            mft_bitmap: None,
+        };
+        builder.set_record(
+            ROOT_RECORD,
+            Record::directory()
+                .with(Attribute::standard_information(
+                    file_attributes::HIDDEN | file_attributes::SYSTEM,
+                ))
+                .with(Attribute::file_name(
+                    ROOT_RECORD,
+                    ".",
+                    namespaces::WIN32_DOS,
+                )),
+        );
+        builder
+    }
+
+    pub fn set_record(&mut self, number: u64, record: Record) {
+        assert!(number > 0 && number < MFT_CAPACITY);
+        self.records.insert(number, record);
+    }
+
+    // Adds a record in the first free user slot, returning its number.
+    pub fn add_record(&mut self, record: Record) -> u64 {
+        let number = (FIRST_USER_RECORD..)
+            .find(|n| !self.records.contains_key(n))
+            .unwrap();
+        self.set_record(number, record);
+        number
+    }
+
+    // Adds a plain file with a resident, unnamed $DATA attribute.
+    pub fn add_file(&mut self, parent: u64, name: &str, content: &[u8]) -> u64 {
+        self.add_record(
+            Record::new()
+                .with(Attribute::standard_information(file_attributes::ARCHIVE))
+                .with(Attribute::file_name(parent, name, namespaces::WIN32))
+                .with(Attribute::resident_data(None, content)),
+        )
+    }
+
+    pub fn add_directory(&mut self, parent: u64, name: &str) -> u64 {
+        self.add_record(
+            Record::directory()
+                .with(Attribute::standard_information(file_attributes::DIRECTORY))
+                .with(Attribute::file_name(parent, name, namespaces::WIN32)),
+        )
+    }
+
+    // Stores `content` in newly allocated clusters, returning the first LCN.
+    pub fn write_clusters(&mut self, content: &[u8]) -> u64 {
+        let lcn = self.first_data_lcn() + self.data.len() as u64 / BYTES_PER_CLUSTER;
+        let cluster_count = (content.len() as u64).div_ceil(BYTES_PER_CLUSTER).max(1);
+        let start = self.data.len();
+        self.data
+            .resize(start + (cluster_count * BYTES_PER_CLUSTER) as usize, 0);
+        self.data[start..start + content.len()].copy_from_slice(content);
+        lcn
+    }
+
+    // Stores everything but the first cluster of the MFT after the data, with
+    // the second extent described by an extension record (record 1) that's
+    // reached through an attribute list on record 0.
+    pub fn fragment_mft(&mut self) {
+        assert!(!self.records.contains_key(&1));
+        self.fragment_mft = true;
+    }
+
+    // Sets the bit for record `number` in $MFT's $BITMAP, whatever the
+    // record itself says.
+    pub fn set_bitmap_bit(&mut self, number: u64, in_use: bool) {
+        self.bitmap_overrides.insert(number, in_use);
+    }
+
+    // Gives $MFT this $BITMAP attribute instead, such as a non-resident
+    // copy of `mft_bitmap_bits`.
+    pub fn set_mft_bitmap(&mut self, bitmap: Attribute) {
+        self.mft_bitmap = Some(bitmap);
+    }
+
+    // The contents of $MFT's $BITMAP, given the records added so far.
+    pub fn mft_bitmap_bits(&self) -> Vec<u8> {
+        let record_count = self.mft_record_count();
+        let mut in_use = self
+            .records
+            .iter()
+            .map(|(number, record)| (*number, record.in_use))
+            .collect::<BTreeMap<_, _>>();
+        in_use.insert(0, true);
+        if self.fragment_mft {
+            in_use.insert(1, true);
+        }
+        in_use.extend(&self.bitmap_overrides);
+        let mut bitmap = vec![0; align8(record_count.div_ceil(8) as usize)];
+        for (number, _) in in_use.into_iter().filter(|(_, in_use)| *in_use) {
+            bitmap[(number / 8) as usize] |= 1 << (number % 8);
+        }
+        bitmap
+    }
+
+    // The records making up the MFT, exactly as they'd be in an extracted $MFT.
+    pub fn mft_bytes(&self) -> Vec<u8> {
+        let record_count = self.mft_record_count();
+        let mut mft = vec![0; (record_count * RECORD_SIZE) as usize];
+        let mut records = self.mft_records(record_count);
+        records.extend(self.records.iter().map(|(n, r)| (*n, r.clone())));
+        for (number, record) in records {
+            let offset = (number * RECORD_SIZE) as usize;
+            mft[offset..offset + RECORD_SIZE as usize].copy_from_slice(&record.encode(number));
+        }
+        mft
+    }
+
+    pub fn build(self) -> MemoryReader {
+        let mft = self.mft_bytes();
+        let data_offset = (self.first_data_lcn() * BYTES_PER_CLUSTER) as usize;
+        let data_end = data_offset + self.data.len();
+
+        let mut image = vec![0; data_end + mft.len() + BYTES_PER_CLUSTER as usize];
+        let total_sectors = image.len() as u64 / SECTOR_SIZE;
+        image[..512].copy_from_slice(&boot_sector(total_sectors));
+        let mft_offset = (MFT_START_LCN * BYTES_PER_CLUSTER) as usize;
+        image[data_offset..data_end].copy_from_slice(&self.data);
+        if self.fragment_mft {
+            let cluster = BYTES_PER_CLUSTER as usize;
+            image[mft_offset..mft_offset + cluster].copy_from_slice(&mft[..cluster]);
+            image[data_end..data_end + mft.len() - cluster].copy_from_slice(&mft[cluster..]);
+        } else {
+            image[mft_offset..mft_offset + mft.len()].copy_from_slice(&mft);
+        }
+
+        MemoryReader::new(image)
+    }
+
+    fn first_data_lcn(&self) -> u64 {
+        MFT_START_LCN + MFT_CAPACITY * RECORD_SIZE / BYTES_PER_CLUSTER
+    }
+
+    // Enough records to cover everything added, in whole clusters.
+    fn mft_record_count(&self) -> u64 {
+        let records_per_cluster = BYTES_PER_CLUSTER / RECORD_SIZE;
+        let highest = self.records.keys().next_back().copied().unwrap_or(0);
+        (highest + 1).div_ceil(records_per_cluster) * records_per_cluster
+    }
+
+    fn mft_records(&self, record_count: u64) -> Vec<(u64, Record)> {
+        let cluster_count = record_count * RECORD_SIZE / BYTES_PER_CLUSTER;
+        let mft_len = record_count * RECORD_SIZE;
+        let bitmap = self.mft_bitmap.clone().unwrap_or_else(|| {
+            Attribute::resident(type_codes::BITMAP, None, self.mft_bitmap_bits())
+        });
+
+        let record = Record::new()
+            .with(Attribute::standard_information(
+                file_attributes::HIDDEN | file_attributes::SYSTEM,
+            ))
+            .with(Attribute::file_name(
+                ROOT_RECORD,
+                "$MFT",
+                namespaces::WIN32_DOS,
+            ))
+            .with(bitmap);
+        if !self.fragment_mft {
+            let data = vec![Run::at(MFT_START_LCN, cluster_count)];
+            return vec![(
+                0,
+                record.with(Attribute::non_resident_data(None, data, mft_len)),
+            )];
+        }
+
+        let relocated_lcn = self.first_data_lcn() + self.data.len() as u64 / BYTES_PER_CLUSTER;
+        let mut second_piece = ListEntry::new(type_codes::DATA, 1);
+        second_piece.starting_vcn = 1;
+        let base = record
+            .with(Attribute::attribute_list(&[
+                ListEntry::new(type_codes::STANDARD_INFORMATION, 0),
+                ListEntry::new(type_codes::FILE_NAME, 0),
+                ListEntry::new(type_codes::DATA, 0),
+                second_piece,
+                ListEntry::new(type_codes::BITMAP, 0),
+            ]))
+            .with(Attribute::non_resident_data(
+                None,
+                vec![Run::at(MFT_START_LCN, 1)],
+                mft_len,
+            ));
+        let extension = Record::extension_of(0).with(
+            Attribute::non_resident_data(
+                None,
+                vec![Run::at(relocated_lcn, cluster_count - 1)],
+                mft_len,
+            )
+            .with_lowest_vcn(1),
+        );
+        vec![(0, base), (1, extension)]
+    }
+}
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        ImageBuilder::new()
+    }
+}
+
+pub fn boot_sector(total_sectors: u64) -> [u8; 512] {
+    let mut buf = [0; 512];
+    buf[0..3].copy_from_slice(&[0xEB, 0x52, 0x90]);
+    buf[3..11].copy_from_slice(b"NTFS    ");
+    buf[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    buf[13] = (BYTES_PER_CLUSTER / SECTOR_SIZE) as u8;
+    buf[21] = 0xF8;
+    buf[40..48].copy_from_slice(&total_sectors.to_le_bytes());
+    buf[48..56].copy_from_slice(&MFT_START_LCN.to_le_bytes());
+    buf[56..64].copy_from_slice(&MFT_MIRROR_START_LCN.to_le_bytes());
+    // 1KB records are smaller than a cluster, so they're stored as -log2(1024).
+    buf[64] = (-10i8) as u8;
+    buf[68] = 1;
+    buf[72..80].copy_from_slice(&VOLUME_SERIAL_NUMBER.to_le_bytes());
+    buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+    buf
+}
+
+// One entry of an MBR or EBR partition table.
+pub fn mbr_entry(type_id: u8, start_lba: u32, sector_count: u32) -> [u8; 16] {
+    let mut entry = [0; 16];
+    entry[4] = type_id;
+    entry[8..12].copy_from_slice(&start_lba.to_le_bytes());
+    entry[12..16].copy_from_slice(&sector_count.to_le_bytes());
+    entry
+}
+
+// An MBR or EBR holding up to four partition table entries.
+pub fn mbr_sector(entries: &[[u8; 16]]) -> [u8; 512] {
+    let mut buf = [0; 512];
+    for (i, entry) in entries.iter().enumerate() {
+        buf[446 + 16 * i..446 + 16 * (i + 1)].copy_from_slice(entry);
+    }
+    buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+    buf
+}
+
+pub fn guid_bytes(guid: Guid) -> Vec<u8> {
+    [
+        &guid.data1.to_le_bytes()[..],
+        &guid.data2.to_le_bytes(),
+        &guid.data3.to_le_bytes(),
+        &guid.data4,
+    ]
+    .concat()
+}
+
+// A fixed VHD: the disk as-is, followed by the footer.
+pub fn fixed_vhd(disk: &[u8]) -> Vec<u8> {
+    [
+        disk,
+        &vhd_footer(vhd_disk_types::FIXED, u64::MAX, disk.len() as u64),
+    ]
+    .concat()
+}
+
+// A dynamic VHD, or a differencing one if `parent` gives the relative path
+// of its parent. Each block is either unallocated, or its sector bitmap
+// (most significant bit first) and data.
+pub fn dynamic_vhd(
+    len: u64,
+    block_size: u64,
+    blocks: &[Option<(Vec<u8>, Vec<u8>)>],
+    parent: Option<&str>,
+) -> Vec<u8> {
+    let sector = SECTOR_SIZE as usize;
+    let bitmap_size = ((block_size / SECTOR_SIZE).div_ceil(8) as usize).div_ceil(sector) * sector;
+    let header_offset = sector;
+    let table_offset = header_offset + 1024;
+    let locator_offset = table_offset + (blocks.len() * 4).div_ceil(sector).max(1) * sector;
+    let mut file = vec![0; locator_offset + sector];
+
+    let mut table = Vec::new();
+    for block in blocks {
+        match block {
+            Some((bitmap, data)) => {
+                table.extend_from_slice(&((file.len() / sector) as u32).to_be_bytes());
+                let start = file.len();
+                file.resize(start + bitmap_size + block_size as usize, 0);
+                file[start..start + bitmap.len()].copy_from_slice(bitmap);
+                let data_start = start + bitmap_size;
+                file[data_start..data_start + data.len()].copy_from_slice(data);
+            }
+            None => table.extend_from_slice(&u32::MAX.to_be_bytes()),
+        }
+    }
+    file[table_offset..table_offset + table.len()].copy_from_slice(&table);
+
+    let mut header = [0; 1024];
+    header[0..8].copy_from_slice(b"cxsparse");
+    header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+    header[16..24].copy_from_slice(&(table_offset as u64).to_be_bytes());
+    header[28..32].copy_from_slice(&(blocks.len() as u32).to_be_bytes());
+    header[32..36].copy_from_slice(&(block_size as u32).to_be_bytes());
+    let disk_type = match parent {
+        Some(parent) => {
+            let name = parent.rsplit('\\').next().unwrap();
+            let name: Vec<u8> = name.encode_utf16().flat_map(u16::to_be_bytes).collect();
+            header[64..64 + name.len()].copy_from_slice(&name);
+            let path = utf16_bytes(parent);
+            header[576..580].copy_from_slice(b"W2ru");
+            header[580..584].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+            header[584..588].copy_from_slice(&(path.len() as u32).to_be_bytes());
+            header[592..600].copy_from_slice(&(locator_offset as u64).to_be_bytes());
+            file[locator_offset..locator_offset + path.len()].copy_from_slice(&path);
+            vhd_disk_types::DIFFERENCING
+        }
+        None => vhd_disk_types::DYNAMIC,
+    };
+    file[header_offset..header_offset + 1024].copy_from_slice(&header);
+
+    let footer = vhd_footer(disk_type, header_offset as u64, len);
+    file[..sector].copy_from_slice(&footer);
+    file.extend_from_slice(&footer);
+    file
+}
+
+mod vhd_disk_types {
+    pub const FIXED: u32 = 2;
+    pub const DYNAMIC: u32 = 3;
+    pub const DIFFERENCING: u32 = 4;
+}
+
+fn vhd_footer(disk_type: u32, data_offset: u64, len: u64) -> [u8; 512] {
+    let mut footer = [0; 512];
+    footer[0..8].copy_from_slice(b"conectix");
+    footer[16..24].copy_from_slice(&data_offset.to_be_bytes());
+    footer[40..48].copy_from_slice(&len.to_be_bytes());
+    footer[48..56].copy_from_slice(&len.to_be_bytes());
+    footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+    footer
+}
+
+pub mod vhdx_block_states {
+    pub const NOT_PRESENT: u64 = 0;
+    pub const ZERO: u64 = 2;
+    pub const FULLY_PRESENT: u64 = 6;
+    pub const PARTIALLY_PRESENT: u64 = 7;
+}
+
+mod vhdx_ids {
+    use crate::guid::Guid;
+
+    pub const BAT: Guid = Guid::from_fields(
+        0x2DC2_7766,
+        0xF623,
+        0x4200,
+        [0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08],
+    );
+    pub const METADATA: Guid = Guid::from_fields(
+        0x8B7C_A206,
+        0x4790,
+        0x4B9A,
+        [0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E],
+    );
+    pub const FILE_PARAMETERS: Guid = Guid::from_fields(
+        0xCAA1_6737,
+        0xFA36,
+        0x4D43,
+        [0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B],
+    );
+    pub const VIRTUAL_DISK_SIZE: Guid = Guid::from_fields(
+        0x2FA5_4224,
+        0xCD1B,
+        0x4876,
+        [0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8],
+    );
+    pub const LOGICAL_SECTOR_SIZE: Guid = Guid::from_fields(
+        0x8141_BF1D,
+        0xA96F,
+        0x4709,
+        [0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F],
+    );
+    pub const PARENT_LOCATOR: Guid = Guid::from_fields(
+        0xA8D3_5F2D,
+        0xB30B,
+        0x454D,
+        [0xAB, 0xF7, 0xD3, 0xD8, 0x48, 0x34, 0xAB, 0x0C],
+    );
+}
+
+// The layout of a VHDX file. BAT entries are raw, a block state in the low
+// bits and the file offset in MB above bit 20; `vhdx_bat_entry` makes them.
+// Whatever's at those offsets goes in `file_blocks`, keyed by offset in MB.
+pub struct Vhdx {
+    pub len: u64,
+    pub block_size: u32,
+    pub logical_sector_size: u32,
+    pub bat: Vec<u64>,
+    pub file_blocks: BTreeMap<u64, Vec<u8>>,
+    // The parent's relative path, for a differencing disk
+    pub parent: Option<String>,
+}
+impl Vhdx {
+    pub fn new(len: u64, block_size: u32, logical_sector_size: u32) -> Self {
+        Vhdx {
+            len,
+            block_size,
+            logical_sector_size,
+            bat: Vec::new(),
+            file_blocks: BTreeMap::new(),
+            parent: None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        const KB: usize = 1024;
+        const MB: usize = 1024 * KB;
+        const METADATA_OFFSET: usize = 256 * KB;
+        const BAT_OFFSET: usize = MB;
+
+        let end = self
+            .file_blocks
+            .iter()
+            .map(|(offset, data)| *offset as usize * MB + data.len())
+            .max()
+            .unwrap_or(0)
+            .max(2 * MB);
+        let mut file = vec![0; end];
+        file[0..8].copy_from_slice(b"vhdxfile");
+        // Only the newer of the two headers matters.
+        for (offset, sequence_number) in [(64 * KB, 1u64), (128 * KB, 0)] {
+            file[offset..offset + 4].copy_from_slice(b"head");
+            file[offset + 8..offset + 16].copy_from_slice(&sequence_number.to_le_bytes());
+        }
+
+        let regions = [
+            (vhdx_ids::BAT, BAT_OFFSET, MB),
+            (vhdx_ids::METADATA, METADATA_OFFSET, 256 * KB),
+        ];
+        let table = 192 * KB;
+        file[table..table + 4].copy_from_slice(b"regi");
+        file[table + 8..table + 12].copy_from_slice(&(regions.len() as u32).to_le_bytes());
+        for (i, (id, offset, length)) in regions.iter().enumerate() {
+            let entry = table + 16 + 32 * i;
+            file[entry..entry + 16].copy_from_slice(&guid_bytes(*id));
+            file[entry + 16..entry + 24].copy_from_slice(&(*offset as u64).to_le_bytes());
+            file[entry + 24..entry + 28].copy_from_slice(&(*length as u32).to_le_bytes());
+        }
+
+        // HAS_PARENT
+        let flags: u32 = if self.parent.is_some() { 2 } else { 0 };
+        let mut items = vec![
+            (
+                vhdx_ids::FILE_PARAMETERS,
+                [self.block_size.to_le_bytes(), flags.to_le_bytes()].concat(),
+            ),
+            (vhdx_ids::VIRTUAL_DISK_SIZE, self.len.to_le_bytes().to_vec()),
+            (
+                vhdx_ids::LOGICAL_SECTOR_SIZE,
+                self.logical_sector_size.to_le_bytes().to_vec(),
+            ),
+        ];
+        if let Some(parent) = &self.parent {
+            items.push((vhdx_ids::PARENT_LOCATOR, vhdx_parent_locator(parent)));
+        }
+        let metadata = METADATA_OFFSET;
+        file[metadata..metadata + 8].copy_from_slice(b"metadata");
+        file[metadata + 10..metadata + 12].copy_from_slice(&(items.len() as u16).to_le_bytes());
+        let mut item_offset = 64 * KB;
+        for (i, (id, item)) in items.iter().enumerate() {
+            let entry = metadata + 32 + 32 * i;
+            file[entry..entry + 16].copy_from_slice(&guid_bytes(*id));
+            file[entry + 16..entry + 20].copy_from_slice(&(item_offset as u32).to_le_bytes());
+            file[entry + 20..entry + 24].copy_from_slice(&(item.len() as u32).to_le_bytes());
+            file[metadata + item_offset..metadata + item_offset + item.len()].copy_from_slice(item);
+            item_offset += item.len();
+        }
+
+        for (i, entry) in self.bat.iter().enumerate() {
+            let offset = BAT_OFFSET + 8 * i;
+            file[offset..offset + 8].copy_from_slice(&entry.to_le_bytes());
+        }
+        for (offset, data) in &self.file_blocks {
+            let offset = *offset as usize * MB;
+            file[offset..offset + data.len()].copy_from_slice(data);
+        }
+        file
+    }
+}
+
+pub fn vhdx_bat_entry(state: u64, offset_mb: u64) -> u64 {
+    state | (offset_mb << 20)
+}
+
+fn vhdx_parent_locator(parent: &str) -> Vec<u8> {
+    let key = utf16_bytes("relative_path");
+    let value = utf16_bytes(parent);
+    let key_offset = 20 + 12;
+    let value_offset = key_offset + key.len();
+    let mut item = vec![0; 20];
+    item[18..20].copy_from_slice(&1u16.to_le_bytes());
+    item.extend_from_slice(&(key_offset as u32).to_le_bytes());
+    item.extend_from_slice(&(value_offset as u32).to_le_bytes());
+    item.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    item.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    item.extend_from_slice(&key);
+    item.extend_from_slice(&value);
+    item
+}
+
+// Moves the last two bytes of each stride into the update sequence array,
+// replacing them with the update sequence number.
+pub fn apply_fixups(buf: &mut [u8], usa_offset: usize, stride: usize) {
+    let usn = UPDATE_SEQUENCE_NUMBER.to_le_bytes();
+    buf[usa_offset..usa_offset + 2].copy_from_slice(&usn);
+    for i in 0..buf.len() / stride {
+        let end = (i + 1) * stride;
+        let entry = usa_offset + 2 * (i + 1);
+        buf[entry] = buf[end - 2];
+        buf[entry + 1] = buf[end - 1];
+        buf[end - 2..end].copy_from_slice(&usn);
+    }
+}
+
+pub fn file_reference(segment: u64, sequence_number: u16) -> u64 {
+    segment | (u64::from(sequence_number) << 48)
+}
+
+fn encode_mapping_pairs(runs: &[Run]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut last_lcn: i64 = 0;
+    for run in runs {
+        let length = minimal_bytes(run.cluster_count as i64, false);
+        let offset = match run.lcn {
+            Some(lcn) => {
+                let delta = lcn as i64 - last_lcn;
+                last_lcn = lcn as i64;
+                minimal_bytes(delta, true)
+            }
+            None => Vec::new(),
+        };
+        out.push(((offset.len() as u8) << 4) | length.len() as u8);
+        out.extend_from_slice(&length);
+        out.extend_from_slice(&offset);
+    }
+    out.push(0);
+    out
+}
+
+// The shortest little-endian encoding of `value`. Signed values keep enough
+// bytes for the top bit to carry the sign.
+fn minimal_bytes(value: i64, signed: bool) -> Vec<u8> {
+    let bytes = value.to_le_bytes();
+    let mut len = 8;
+    while len > 1 {
+        let top = bytes[len - 1];
+        let next_top = bytes[len - 2];
+        let redundant = if signed {
+            (top == 0 && next_top & 0x80 == 0) || (top == 0xFF && next_top & 0x80 != 0)
+        } else {
+            top == 0
+        };
+        if !redundant {
+            break;
+        }
+        len -= 1;
+    }
+    bytes[..len].to_vec()
+}
+
+fn align8(len: usize) -> usize {
+    len.div_ceil(8) * 8
+}
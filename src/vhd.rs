@@ -0,0 +1,412 @@
+use crate::{err::Error, reader::VolumeReader};
+
+use std::convert::TryInto as _;
+
+const VHD_SECTOR_SIZE: u64 = 512;
+const FOOTER_LENGTH: usize = 512;
+const DYNAMIC_HEADER_LENGTH: usize = 1024;
+const FOOTER_COOKIE: [u8; 8] = *b"conectix";
+const DYNAMIC_HEADER_COOKIE: [u8; 8] = *b"cxsparse";
+const UNALLOCATED_BLOCK: u32 = 0xFFFF_FFFF;
+const PARENT_LOCATOR_COUNT: usize = 8;
+const PARENT_LOCATOR_LENGTH: usize = 24;
+// Locators only hold a path, so anything bigger is a corrupt header.
+const MAX_PARENT_LOCATOR_DATA_LENGTH: usize = 64 * 1024;
+
+mod disk_types {
+    pub const FIXED: u32 = 2;
+    pub const DYNAMIC: u32 = 3;
+    pub const DIFFERENCING: u32 = 4;
+}
+
+mod platform_codes {
+    // Windows relative and absolute paths, stored as UTF-16LE
+    pub const W2RU: [u8; 4] = *b"W2ru";
+    pub const W2KU: [u8; 4] = *b"W2ku";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhdType {
+    Fixed,
+    Dynamic,
+    Differencing,
+}
+
+// Everything we need from a dynamic or differencing disk's header.
+struct DynamicLayout {
+    block_size: u64,
+    // Size of the sector bitmap which precedes each block's data, in bytes
+    bitmap_size: u64,
+    // Byte offset of each block in the file, if allocated
+    block_table: Vec<Option<u64>>,
+}
+
+// Reads the virtual disk stored in a Virtual PC/Hyper-V VHD file. All three
+// disk types are supported; differencing disks need their parent supplied
+// with `with_parent` (which `reader::open_image` does automatically).
+pub struct VhdReader<R: VolumeReader> {
+    file: R,
+    disk_type: VhdType,
+    len: u64,
+    dynamic: Option<DynamicLayout>,
+    parent_name: Option<String>,
+    parent_locations: Vec<String>,
+    parent: Option<Box<dyn VolumeReader>>,
+}
+impl<R: VolumeReader> VhdReader<R> {
+    // Checks for the VHD footer at the end of the file.
+    pub fn probe(file: &mut R) -> Result<bool, Error> {
+        if file.len() < FOOTER_LENGTH as u64 {
+            return Ok(false);
+        }
+        let mut footer = [0; FOOTER_LENGTH];
+        file.read_at(file.len() - FOOTER_LENGTH as u64, &mut footer[..])?;
+        Ok(footer[0..8] == FOOTER_COOKIE)
+    }
+
+    pub fn open(mut file: R) -> Result<Self, Error> {
+        if file.len() < FOOTER_LENGTH as u64 {
+            return Err(Error::BadVhdFooter);
+        }
+        let mut footer = [0; FOOTER_LENGTH];
+        file.read_at(file.len() - FOOTER_LENGTH as u64, &mut footer[..])?;
+        if footer[0..8] != FOOTER_COOKIE {
+            return Err(Error::BadVhdFooter);
+        }
+
+        let data_offset = read_u64_be(&footer[16..24]);
+        let len = read_u64_be(&footer[48..56]);
+        let disk_type = match read_u32_be(&footer[60..64]) {
+            disk_types::FIXED => VhdType::Fixed,
+            disk_types::DYNAMIC => VhdType::Dynamic,
+            disk_types::DIFFERENCING => VhdType::Differencing,
+            unknown => return Err(Error::UnsupportedVhdType(unknown)),
+        };
+
+        let mut reader = VhdReader {
+            file,
+            disk_type,
+            len,
+            dynamic: None,
+            parent_name: None,
+            parent_locations: Vec::new(),
+            parent: None,
+        };
+
+        if disk_type == VhdType::Fixed {
+            if reader.file.len() < len + FOOTER_LENGTH as u64 {
+                return Err(Error::BadVhdFooter);
+            }
+        } else {
+            reader.load_dynamic_header(data_offset)?;
+        }
+
+        Ok(reader)
+    }
+
+    pub fn disk_type(&self) -> VhdType {
+        self.disk_type
+    }
+
+    // The file name of a differencing disk's parent, as recorded in the header.
+    pub fn parent_name(&self) -> Option<&str> {
+        self.parent_name.as_deref()
+    }
+
+    // The paths the parent disk was last known at (relative ones first), as
+    // recorded by Windows in the parent locators.
+    pub fn parent_locations(&self) -> &[String] {
+        &self.parent_locations[..]
+    }
+
+    pub fn with_parent(mut self, parent: Box<dyn VolumeReader>) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    fn load_dynamic_header(&mut self, header_offset: u64) -> Result<(), Error> {
+        let mut header = [0; DYNAMIC_HEADER_LENGTH];
+        self.file.read_at(header_offset, &mut header[..])?;
+        if header[0..8] != DYNAMIC_HEADER_COOKIE {
+            return Err(Error::BadVhdDynamicHeader);
+        }
+
+        let table_offset = read_u64_be(&header[16..24]);
+        let table_entries: usize = read_u32_be(&header[28..32]).try_into().unwrap();
+        let block_size: u64 = read_u32_be(&header[32..36]).into();
+        if block_size == 0 || !block_size.is_multiple_of(VHD_SECTOR_SIZE) {
+            return Err(Error::BadVhdDynamicHeader);
+        }
+        // The table has one entry per block of the disk.
+        if table_entries as u64 > self.len.div_ceil(block_size) {
+            return Err(Error::BadVhdDynamicHeader);
+        }
+
+        let mut table = vec![0; table_entries * 4];
+        self.file.read_at(table_offset, &mut table[..])?;
+        let block_table = table
+            .chunks_exact(4)
+            .map(|entry| match read_u32_be(entry) {
+                UNALLOCATED_BLOCK => None,
+                sector => Some(u64::from(sector) * VHD_SECTOR_SIZE),
+            })
+            .collect();
+
+        // One bit per sector, padded out to a whole sector.
+        let bitmap_size = {
+            let bytes = (block_size / VHD_SECTOR_SIZE).div_ceil(8);
+            bytes.div_ceil(VHD_SECTOR_SIZE) * VHD_SECTOR_SIZE
+        };
+
+        if self.disk_type == VhdType::Differencing {
+            let name = header[64..576]
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .take_while(|c| *c != 0)
+                .collect::<Vec<_>>();
+            self.parent_name = Some(String::from_utf16_lossy(&name[..]));
+
+            let mut relative = Vec::new();
+            let mut absolute = Vec::new();
+            for locator in header[576..576 + PARENT_LOCATOR_COUNT * PARENT_LOCATOR_LENGTH]
+                .chunks_exact(PARENT_LOCATOR_LENGTH)
+            {
+                let platform_code: [u8; 4] = locator[0..4].try_into().unwrap();
+                let data_length: usize = read_u32_be(&locator[8..12]).try_into().unwrap();
+                let data_offset = read_u64_be(&locator[16..24]);
+
+                let list = match platform_code {
+                    platform_codes::W2RU => &mut relative,
+                    platform_codes::W2KU => &mut absolute,
+                    _ => continue,
+                };
+                if data_length > MAX_PARENT_LOCATOR_DATA_LENGTH {
+                    return Err(Error::BadVhdDynamicHeader);
+                }
+                let mut data = vec![0; data_length];
+                self.file.read_at(data_offset, &mut data[..])?;
+                let path = data
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect::<Vec<_>>();
+                list.push(String::from_utf16_lossy(&path[..]));
+            }
+            self.parent_locations = relative;
+            self.parent_locations.append(&mut absolute);
+        }
+
+        self.dynamic = Some(DynamicLayout {
+            block_size,
+            bitmap_size,
+            block_table,
+        });
+        Ok(())
+    }
+
+    // Reads part of a single block.
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let layout = self.dynamic.as_ref().unwrap();
+        let block = (offset / layout.block_size) as usize;
+        let offset_in_block = offset % layout.block_size;
+        let bitmap_size = layout.bitmap_size;
+
+        let block_offset = match layout.block_table.get(block).copied().flatten() {
+            Some(block_offset) => block_offset,
+            None => return self.read_unallocated(offset, buf),
+        };
+        let data_offset = block_offset + bitmap_size;
+
+        if self.disk_type != VhdType::Differencing {
+            return self.file.read_at(data_offset + offset_in_block, buf);
+        }
+
+        // Sectors whose bit is clear in the block's bitmap haven't been
+        // written to in this disk, and come from the parent instead.
+        let first_sector = offset_in_block / VHD_SECTOR_SIZE;
+        let last_sector = (offset_in_block + buf.len() as u64 - 1) / VHD_SECTOR_SIZE;
+        let mut bitmap = vec![0; (last_sector / 8 - first_sector / 8 + 1) as usize];
+        self.file
+            .read_at(block_offset + first_sector / 8, &mut bitmap[..])?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset_in_block + done as u64;
+            let sector = position / VHD_SECTOR_SIZE;
+            let chunk_len =
+                ((sector + 1) * VHD_SECTOR_SIZE - position).min((buf.len() - done) as u64) as usize;
+            let bit_index = (sector / 8 - first_sector / 8) as usize;
+            let is_present = bitmap[bit_index] & (0x80 >> (sector % 8)) != 0;
+
+            let chunk = &mut buf[done..done + chunk_len];
+            if is_present {
+                self.file.read_at(data_offset + position, chunk)?;
+            } else {
+                self.read_unallocated(offset + done as u64, chunk)?;
+            }
+            done += chunk_len;
+        }
+
+        Ok(())
+    }
+
+    fn read_unallocated(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        match self.disk_type {
+            VhdType::Differencing => self
+                .parent
+                .as_mut()
+                .ok_or(Error::MissingParentDisk)?
+                .read_at(offset, buf),
+            _ => {
+                buf.iter_mut().for_each(|b| *b = 0);
+                Ok(())
+            }
+        }
+    }
+}
+impl<R: VolumeReader> VolumeReader for VhdReader<R> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if offset + (buf.len() as u64) > self.len {
+            return Err(Error::ReadVolumeTooShort);
+        }
+
+        if self.disk_type == VhdType::Fixed {
+            return self.file.read_at(offset, buf);
+        }
+
+        // Split the read up along block boundaries.
+        let block_size = self.dynamic.as_ref().unwrap().block_size;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let chunk_len = (block_size - position % block_size).min((buf.len() - done) as u64);
+            let chunk_len = chunk_len as usize;
+            self.read_block(position, &mut buf[done..done + chunk_len])?;
+            done += chunk_len;
+        }
+
+        Ok(())
+    }
+
+    fn sector_size(&self) -> u64 {
+        VHD_SECTOR_SIZE
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+fn read_u32_be(buf: &[u8]) -> u32 {
+    u32::from_be_bytes(buf[0..4].try_into().unwrap())
+}
+
+fn read_u64_be(buf: &[u8]) -> u64 {
+    u64::from_be_bytes(buf[0..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{dynamic_vhd, fixed_vhd, MemoryReader};
+
+    const BLOCK_SIZE: u64 = 4096;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn reads_fixed_vhd() {
+        let disk = pattern(4096);
+        let mut file = MemoryReader::new(fixed_vhd(&disk));
+        assert!(VhdReader::probe(&mut file).unwrap());
+        assert!(!VhdReader::probe(&mut MemoryReader::new(disk.clone())).unwrap());
+
+        let mut vhd = VhdReader::open(file).unwrap();
+        assert_eq!(vhd.disk_type(), VhdType::Fixed);
+        assert_eq!(vhd.len(), 4096);
+        let mut buf = vec![0; 100];
+        vhd.read_at(1000, &mut buf[..]).unwrap();
+        assert_eq!(buf, disk[1000..1100]);
+        // The footer isn't part of the disk.
+        assert!(matches!(
+            vhd.read_at(4000, &mut buf[..]),
+            Err(Error::ReadVolumeTooShort)
+        ));
+    }
+
+    #[test]
+    fn reads_dynamic_vhd_through_block_table() {
+        let first = pattern(BLOCK_SIZE as usize);
+        let third = vec![0xCC; BLOCK_SIZE as usize];
+        let blocks = [
+            Some((vec![0xFF], first.clone())),
+            None,
+            Some((vec![0xFF], third.clone())),
+        ];
+        let file = dynamic_vhd(3 * BLOCK_SIZE, BLOCK_SIZE, &blocks, None);
+        let mut vhd = VhdReader::open(MemoryReader::new(file)).unwrap();
+        assert_eq!(vhd.disk_type(), VhdType::Dynamic);
+
+        // Unallocated blocks read as zeros.
+        let mut buf = vec![0xAA; 3 * BLOCK_SIZE as usize];
+        vhd.read_at(0, &mut buf[..]).unwrap();
+        assert_eq!(buf, [first, vec![0; BLOCK_SIZE as usize], third].concat());
+    }
+
+    #[test]
+    fn rejects_oversized_block_tables_and_locators() {
+        // The dynamic header follows the footer copy at the start of the file.
+        let header = VHD_SECTOR_SIZE as usize;
+        let blocks = [None, None, None];
+        let file = dynamic_vhd(3 * BLOCK_SIZE, BLOCK_SIZE, &blocks, Some(".\\parent.vhd"));
+        assert!(VhdReader::open(MemoryReader::new(file.clone())).is_ok());
+
+        // More entries than the disk has blocks
+        for entries in [4u32, u32::MAX] {
+            let mut file = file.clone();
+            file[header + 28..header + 32].copy_from_slice(&entries.to_be_bytes());
+            assert!(matches!(
+                VhdReader::open(MemoryReader::new(file)),
+                Err(Error::BadVhdDynamicHeader)
+            ));
+        }
+
+        let mut file = file.clone();
+        file[header + 584..header + 588].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            VhdReader::open(MemoryReader::new(file)),
+            Err(Error::BadVhdDynamicHeader)
+        ));
+    }
+
+    #[test]
+    fn differencing_vhd_reads_unwritten_sectors_from_parent() {
+        let sector = VHD_SECTOR_SIZE as usize;
+        // Sectors 0 and 2 of the first block have been written; the bitmap
+        // is most significant bit first.
+        let blocks = [Some((vec![0b1010_0000], vec![0xCC; 3 * sector])), None];
+        let file = dynamic_vhd(2 * BLOCK_SIZE, BLOCK_SIZE, &blocks, Some(".\\parent.vhd"));
+
+        let mut vhd = VhdReader::open(MemoryReader::new(file.clone())).unwrap();
+        assert_eq!(vhd.disk_type(), VhdType::Differencing);
+        assert_eq!(vhd.parent_name(), Some("parent.vhd"));
+        assert_eq!(vhd.parent_locations(), [".\\parent.vhd"]);
+        let mut buf = vec![0; 2 * BLOCK_SIZE as usize];
+        assert!(matches!(
+            vhd.read_at(0, &mut buf[..]),
+            Err(Error::MissingParentDisk)
+        ));
+
+        let parent = MemoryReader::new(vec![0x11; 2 * BLOCK_SIZE as usize]);
+        let mut vhd = VhdReader::open(MemoryReader::new(file))
+            .unwrap()
+            .with_parent(Box::new(parent));
+        vhd.read_at(0, &mut buf[..]).unwrap();
+        assert_eq!(buf[..sector], vec![0xCC; sector][..]);
+        assert_eq!(buf[sector..2 * sector], vec![0x11; sector][..]);
+        assert_eq!(buf[2 * sector..3 * sector], vec![0xCC; sector][..]);
+        assert!(buf[3 * sector..].iter().all(|b| *b == 0x11));
+    }
+}
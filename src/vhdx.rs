@@ -0,0 +1,585 @@
+use crate::{err::Error, guid::Guid, reader::VolumeReader};
+
+use std::convert::TryInto as _;
+
+const FILE_IDENTIFIER: [u8; 8] = *b"vhdxfile";
+const HEADER_SIGNATURE: [u8; 4] = *b"head";
+const REGION_TABLE_SIGNATURE: [u8; 4] = *b"regi";
+const METADATA_TABLE_SIGNATURE: [u8; 8] = *b"metadata";
+
+const HEADER_OFFSETS: [u64; 2] = [64 * 1024, 128 * 1024];
+const HEADER_LENGTH: usize = 4 * 1024;
+const REGION_TABLE_OFFSET: u64 = 192 * 1024;
+const REGION_TABLE_LENGTH: usize = 64 * 1024;
+const METADATA_TABLE_LENGTH: usize = 64 * 1024;
+const MAX_REGION_ENTRIES: usize = 2047;
+const MAX_METADATA_ENTRIES: usize = 2047;
+const MAX_METADATA_ITEM_LENGTH: u32 = 1024 * 1024;
+
+// Payload blocks in the BAT are interleaved with sector bitmap blocks, one
+// per "chunk" of 2^23 sectors.
+const SECTORS_PER_CHUNK: u64 = 1 << 23;
+const MB: u64 = 1024 * 1024;
+// The only block and sector sizes the format allows
+const MIN_BLOCK_SIZE: u64 = MB;
+const MAX_BLOCK_SIZE: u64 = 256 * MB;
+const LOGICAL_SECTOR_SIZES: [u64; 2] = [512, 4096];
+const MAX_DISK_SIZE: u64 = 64 * 1024 * 1024 * MB;
+
+mod region_ids {
+    use crate::guid::Guid;
+
+    pub const BAT: Guid = Guid::from_fields(
+        0x2DC2_7766,
+        0xF623,
+        0x4200,
+        [0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08],
+    );
+    pub const METADATA: Guid = Guid::from_fields(
+        0x8B7C_A206,
+        0x4790,
+        0x4B9A,
+        [0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E],
+    );
+}
+
+mod metadata_ids {
+    use crate::guid::Guid;
+
+    pub const FILE_PARAMETERS: Guid = Guid::from_fields(
+        0xCAA1_6737,
+        0xFA36,
+        0x4D43,
+        [0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B],
+    );
+    pub const VIRTUAL_DISK_SIZE: Guid = Guid::from_fields(
+        0x2FA5_4224,
+        0xCD1B,
+        0x4876,
+        [0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8],
+    );
+    pub const LOGICAL_SECTOR_SIZE: Guid = Guid::from_fields(
+        0x8141_BF1D,
+        0xA96F,
+        0x4709,
+        [0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F],
+    );
+    pub const PARENT_LOCATOR: Guid = Guid::from_fields(
+        0xA8D3_5F2D,
+        0xB30B,
+        0x454D,
+        [0xAB, 0xF7, 0xD3, 0xD8, 0x48, 0x34, 0xAB, 0x0C],
+    );
+}
+
+mod file_parameter_flags {
+    pub const HAS_PARENT: u32 = 0x0000_0002;
+}
+
+mod block_states {
+    pub const NOT_PRESENT: u64 = 0;
+    pub const UNDEFINED: u64 = 1;
+    pub const ZERO: u64 = 2;
+    pub const UNMAPPED: u64 = 3;
+    pub const FULLY_PRESENT: u64 = 6;
+    pub const PARTIALLY_PRESENT: u64 = 7;
+}
+
+// Reads the virtual disk stored in a Hyper-V VHDX file. Unallocated blocks
+// read as zeros, or from the parent for differencing disks (which need their
+// parent supplied with `with_parent`, as `reader::open_image` does).
+//
+// The log isn't replayed, so a disk which wasn't closed cleanly, and still
+// has log entries to apply, is refused rather than read stale.
+pub struct VhdxReader<R: VolumeReader> {
+    file: R,
+    len: u64,
+    block_size: u64,
+    logical_sector_size: u64,
+    chunk_ratio: u64,
+    bat: Vec<u64>,
+    has_parent: bool,
+    parent_locations: Vec<String>,
+    parent: Option<Box<dyn VolumeReader>>,
+}
+impl<R: VolumeReader> VhdxReader<R> {
+    pub fn probe(file: &mut R) -> Result<bool, Error> {
+        if file.len() < FILE_IDENTIFIER.len() as u64 {
+            return Ok(false);
+        }
+        let mut identifier = [0; 8];
+        file.read_at(0, &mut identifier[..])?;
+        Ok(identifier == FILE_IDENTIFIER)
+    }
+
+    pub fn open(mut file: R) -> Result<Self, Error> {
+        if !Self::probe(&mut file)? {
+            return Err(Error::BadVhdxHeader);
+        }
+
+        // There are two copies of the header; the one with the higher
+        // sequence number is current.
+        let mut current_header: Option<(u64, Vec<u8>)> = None;
+        for header_offset in HEADER_OFFSETS.iter() {
+            let mut header = vec![0; HEADER_LENGTH];
+            file.read_at(*header_offset, &mut header[..])?;
+            if header[0..4] == HEADER_SIGNATURE {
+                let sequence_number = u64::from_le_bytes(header[8..16].try_into().unwrap());
+                if current_header
+                    .as_ref()
+                    .is_none_or(|(current, _)| sequence_number > *current)
+                {
+                    current_header = Some((sequence_number, header));
+                }
+            }
+        }
+        let (_, header) = current_header.ok_or(Error::BadVhdxHeader)?;
+        // A log GUID is only set while there are log entries to replay.
+        if !Guid::load(&header[48..64]).is_nil() {
+            return Err(Error::VhdxNeedsLogReplay);
+        }
+
+        let mut region_table = vec![0; REGION_TABLE_LENGTH];
+        file.read_at(REGION_TABLE_OFFSET, &mut region_table[..])?;
+        if region_table[0..4] != REGION_TABLE_SIGNATURE {
+            return Err(Error::BadVhdxRegionTable);
+        }
+        let region_count: usize = u32::from_le_bytes(region_table[8..12].try_into().unwrap())
+            .try_into()
+            .unwrap();
+        if region_count > MAX_REGION_ENTRIES {
+            return Err(Error::BadVhdxRegionTable);
+        }
+
+        let mut bat_region = None;
+        let mut metadata_region = None;
+        for entry in region_table[16..16 + region_count * 32].chunks_exact(32) {
+            let region = (
+                u64::from_le_bytes(entry[16..24].try_into().unwrap()),
+                u32::from_le_bytes(entry[24..28].try_into().unwrap()),
+            );
+            match Guid::load(&entry[0..16]) {
+                region_ids::BAT => bat_region = Some(region),
+                region_ids::METADATA => metadata_region = Some(region),
+                _ => {}
+            }
+        }
+        let (bat_offset, bat_length) = bat_region.ok_or(Error::BadVhdxRegionTable)?;
+        let (metadata_offset, _) = metadata_region.ok_or(Error::BadVhdxRegionTable)?;
+
+        let mut reader = VhdxReader {
+            file,
+            len: 0,
+            block_size: 0,
+            logical_sector_size: 0,
+            chunk_ratio: 0,
+            bat: Vec::new(),
+            has_parent: false,
+            parent_locations: Vec::new(),
+            parent: None,
+        };
+        reader.load_metadata(metadata_offset)?;
+
+        // Only the entries the disk's size calls for are read, and they have
+        // to be in both the region and the file.
+        let bat_len = reader.bat_entry_count() * 8;
+        if bat_len > u64::from(bat_length)
+            || bat_offset
+                .checked_add(bat_len)
+                .is_none_or(|end| end > reader.file.len())
+        {
+            return Err(Error::BadVhdxRegionTable);
+        }
+        let mut bat = vec![0; bat_len.try_into().unwrap()];
+        reader.file.read_at(bat_offset, &mut bat[..])?;
+        reader.bat = bat
+            .chunks_exact(8)
+            .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+
+        Ok(reader)
+    }
+
+    pub fn has_parent(&self) -> bool {
+        self.has_parent
+    }
+
+    // The paths the parent disk was last known at (relative ones first), as
+    // recorded in the parent locator.
+    pub fn parent_locations(&self) -> &[String] {
+        &self.parent_locations[..]
+    }
+
+    pub fn with_parent(mut self, parent: Box<dyn VolumeReader>) -> Self {
+        self.parent = Some(parent);
+        self
+    }
+
+    fn load_metadata(&mut self, metadata_offset: u64) -> Result<(), Error> {
+        let mut table = vec![0; METADATA_TABLE_LENGTH];
+        self.file.read_at(metadata_offset, &mut table[..])?;
+        if table[0..8] != METADATA_TABLE_SIGNATURE {
+            return Err(Error::BadVhdxMetadata);
+        }
+        let entry_count: usize = u16::from_le_bytes([table[10], table[11]]).into();
+        if entry_count > MAX_METADATA_ENTRIES {
+            return Err(Error::BadVhdxMetadata);
+        }
+
+        let mut block_size = None;
+        let mut len = None;
+        let mut logical_sector_size = None;
+        for entry in table[32..32 + entry_count * 32].chunks_exact(32) {
+            let item_offset = u32::from_le_bytes(entry[16..20].try_into().unwrap());
+            let item_length = u32::from_le_bytes(entry[20..24].try_into().unwrap());
+            if item_length > MAX_METADATA_ITEM_LENGTH {
+                return Err(Error::BadVhdxMetadata);
+            }
+            let mut item = vec![0; item_length.try_into().unwrap()];
+            self.file
+                .read_at(metadata_offset + u64::from(item_offset), &mut item[..])?;
+
+            match Guid::load(&entry[0..16]) {
+                metadata_ids::FILE_PARAMETERS if item.len() >= 8 => {
+                    block_size = Some(u32::from_le_bytes(item[0..4].try_into().unwrap()).into());
+                    let flags = u32::from_le_bytes(item[4..8].try_into().unwrap());
+                    self.has_parent = flags & file_parameter_flags::HAS_PARENT != 0;
+                }
+                metadata_ids::VIRTUAL_DISK_SIZE if item.len() >= 8 => {
+                    len = Some(u64::from_le_bytes(item[0..8].try_into().unwrap()));
+                }
+                metadata_ids::LOGICAL_SECTOR_SIZE if item.len() >= 4 => {
+                    logical_sector_size =
+                        Some(u32::from_le_bytes(item[0..4].try_into().unwrap()).into());
+                }
+                metadata_ids::PARENT_LOCATOR => {
+                    self.parent_locations = parse_parent_locator(&item[..]);
+                }
+                _ => {}
+            }
+        }
+
+        self.block_size = block_size.ok_or(Error::BadVhdxMetadata)?;
+        self.len = len.ok_or(Error::BadVhdxMetadata)?;
+        self.logical_sector_size = logical_sector_size.ok_or(Error::BadVhdxMetadata)?;
+        if !self.block_size.is_power_of_two()
+            || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size)
+            || !LOGICAL_SECTOR_SIZES.contains(&self.logical_sector_size)
+            || self.len > MAX_DISK_SIZE
+        {
+            return Err(Error::BadVhdxMetadata);
+        }
+        self.chunk_ratio = (SECTORS_PER_CHUNK * self.logical_sector_size) / self.block_size;
+
+        Ok(())
+    }
+
+    // One entry per payload block, with a sector bitmap entry after every
+    // `chunk_ratio` of them. Differencing disks always have the bitmap entry
+    // for their last chunk; other disks only have the ones between blocks.
+    fn bat_entry_count(&self) -> u64 {
+        let payload_blocks = self.len.div_ceil(self.block_size);
+        if self.has_parent {
+            payload_blocks.div_ceil(self.chunk_ratio) * (self.chunk_ratio + 1)
+        } else {
+            payload_blocks + payload_blocks.saturating_sub(1) / self.chunk_ratio
+        }
+    }
+
+    // Reads part of a single payload block.
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let block = offset / self.block_size;
+        let offset_in_block = offset % self.block_size;
+
+        let bat_index = (block + block / self.chunk_ratio) as usize;
+        let entry = self.bat.get(bat_index).copied().unwrap_or(0);
+        let block_offset = (entry >> 20) * MB;
+
+        match entry & 0x7 {
+            block_states::FULLY_PRESENT => self.file.read_at(block_offset + offset_in_block, buf),
+            block_states::PARTIALLY_PRESENT => {
+                self.read_partial_block(block, block_offset, offset, buf)
+            }
+            block_states::NOT_PRESENT if self.has_parent => self.read_parent(offset, buf),
+            block_states::NOT_PRESENT
+            | block_states::UNDEFINED
+            | block_states::ZERO
+            | block_states::UNMAPPED => {
+                buf.iter_mut().for_each(|b| *b = 0);
+                Ok(())
+            }
+            _ => Err(Error::BadVhdxBlockState(entry)),
+        }
+    }
+
+    // Blocks of a differencing disk can be partially present, in which case
+    // the chunk's sector bitmap says which sectors come from this file.
+    fn read_partial_block(
+        &mut self,
+        block: u64,
+        block_offset: u64,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let chunk = block / self.chunk_ratio;
+        let bitmap_index = ((chunk + 1) * (self.chunk_ratio + 1) - 1) as usize;
+        let bitmap_entry = self.bat.get(bitmap_index).copied().unwrap_or(0);
+        if bitmap_entry & 0x7 != block_states::FULLY_PRESENT {
+            return Err(Error::BadVhdxBlockState(bitmap_entry));
+        }
+        let bitmap_offset = (bitmap_entry >> 20) * MB;
+
+        let first_sector_in_chunk =
+            (chunk * self.chunk_ratio * self.block_size) / self.logical_sector_size;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let sector = position / self.logical_sector_size;
+            let chunk_len = ((sector + 1) * self.logical_sector_size - position)
+                .min((buf.len() - done) as u64) as usize;
+
+            let bit = sector - first_sector_in_chunk;
+            let mut bitmap_byte = [0];
+            self.file
+                .read_at(bitmap_offset + bit / 8, &mut bitmap_byte[..])?;
+            let is_present = bitmap_byte[0] & (1 << (bit % 8)) != 0;
+
+            let chunk_buf = &mut buf[done..done + chunk_len];
+            if is_present {
+                self.file
+                    .read_at(block_offset + position % self.block_size, chunk_buf)?;
+            } else {
+                self.read_parent(position, chunk_buf)?;
+            }
+            done += chunk_len;
+        }
+
+        Ok(())
+    }
+
+    fn read_parent(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.parent
+            .as_mut()
+            .ok_or(Error::MissingParentDisk)?
+            .read_at(offset, buf)
+    }
+}
+impl<R: VolumeReader> VolumeReader for VhdxReader<R> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        if offset + (buf.len() as u64) > self.len {
+            return Err(Error::ReadVolumeTooShort);
+        }
+
+        // Split the read up along block boundaries.
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let chunk_len = (self.block_size - position % self.block_size)
+                .min((buf.len() - done) as u64) as usize;
+            self.read_block(position, &mut buf[done..done + chunk_len])?;
+            done += chunk_len;
+        }
+
+        Ok(())
+    }
+
+    fn sector_size(&self) -> u64 {
+        self.logical_sector_size
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+// The parent locator is a list of UTF-16 key/value pairs; we only care about
+// where the parent can be found.
+fn parse_parent_locator(item: &[u8]) -> Vec<String> {
+    if item.len() < 20 {
+        return Vec::new();
+    }
+    let entry_count: usize = u16::from_le_bytes([item[18], item[19]]).into();
+
+    let read_string = |offset: u32, length: u16| -> Option<String> {
+        let start: usize = offset.try_into().unwrap();
+        let end = start + usize::from(length);
+        let units = item
+            .get(start..end)?
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+        Some(String::from_utf16_lossy(&units[..]))
+    };
+
+    let mut relative = Vec::new();
+    let mut absolute = Vec::new();
+    for entry in item[20..].chunks_exact(12).take(entry_count) {
+        let key_offset = u32::from_le_bytes(entry[0..4].try_into().unwrap());
+        let value_offset = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+        let key_length = u16::from_le_bytes([entry[8], entry[9]]);
+        let value_length = u16::from_le_bytes([entry[10], entry[11]]);
+
+        let (key, value) = match (
+            read_string(key_offset, key_length),
+            read_string(value_offset, value_length),
+        ) {
+            (Some(key), Some(value)) => (key, value),
+            _ => continue,
+        };
+        match key.as_str() {
+            "relative_path" => relative.push(value),
+            "absolute_win32_path" | "volume_path" => absolute.push(value),
+            _ => {}
+        }
+    }
+
+    relative.append(&mut absolute);
+    relative
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{vhdx_bat_entry, vhdx_block_states, MemoryReader, Vhdx};
+
+    #[test]
+    fn skips_sector_bitmap_entries_in_bat() {
+        // With 256 MiB blocks and 512 byte sectors, every 16 payload blocks
+        // are followed by a sector bitmap entry.
+        let block_size = 256 * MB;
+        let mut image = Vhdx::new(18 * block_size, block_size as u32, 512);
+        image.bat = vec![vhdx_bat_entry(vhdx_block_states::NOT_PRESENT, 0); 19];
+        image.bat[0] = vhdx_bat_entry(vhdx_block_states::FULLY_PRESENT, 2);
+        image.bat[1] = vhdx_bat_entry(vhdx_block_states::ZERO, 0);
+        image.bat[17] = vhdx_bat_entry(vhdx_block_states::FULLY_PRESENT, 3);
+        image.file_blocks.insert(2, vec![0xAA; 512]);
+        image.file_blocks.insert(3, vec![0xBB; 512]);
+
+        let mut file = MemoryReader::new(image.encode());
+        assert!(VhdxReader::probe(&mut file).unwrap());
+        let mut vhdx = VhdxReader::open(file).unwrap();
+        assert!(!vhdx.has_parent());
+        assert_eq!(vhdx.len(), 18 * block_size);
+        assert_eq!(vhdx.sector_size(), 512);
+
+        let mut read_sector = |block: u64| {
+            let mut buf = vec![0x55; 512];
+            vhdx.read_at(block * block_size, &mut buf[..]).unwrap();
+            buf
+        };
+        assert_eq!(read_sector(0), vec![0xAA; 512]);
+        assert_eq!(read_sector(1), vec![0; 512]);
+        assert_eq!(read_sector(16), vec![0xBB; 512]);
+        assert_eq!(read_sector(17), vec![0; 512]);
+    }
+
+    #[test]
+    fn partially_present_blocks_read_from_parent() {
+        let sector = 4096;
+        let mut image = Vhdx::new(4 * MB, MB as u32, sector as u32);
+        image.parent = Some("..\\parent.vhdx".to_string());
+        // 1 MiB blocks and 4 KiB sectors put the first sector bitmap entry
+        // after 32768 payload blocks.
+        let bitmap_index = 32768;
+        image.bat = vec![vhdx_bat_entry(vhdx_block_states::NOT_PRESENT, 0); bitmap_index + 1];
+        image.bat[0] = vhdx_bat_entry(vhdx_block_states::PARTIALLY_PRESENT, 2);
+        image.bat[2] = vhdx_bat_entry(vhdx_block_states::ZERO, 0);
+        image.bat[bitmap_index] = vhdx_bat_entry(vhdx_block_states::FULLY_PRESENT, 3);
+        image.file_blocks.insert(2, vec![0xCC; 3 * sector]);
+        // Sectors 0 and 2 are present, least significant bit first.
+        image.file_blocks.insert(3, vec![0b101]);
+        let file = image.encode();
+
+        let vhdx = VhdxReader::open(MemoryReader::new(file.clone())).unwrap();
+        assert!(vhdx.has_parent());
+        assert_eq!(vhdx.parent_locations(), ["..\\parent.vhdx"]);
+        assert_eq!(vhdx.sector_size(), 4096);
+        let mut vhdx = vhdx.with_parent(Box::new(MemoryReader::new(vec![0x11; 4 * MB as usize])));
+
+        let mut buf = vec![0; 3 * sector];
+        vhdx.read_at(0, &mut buf[..]).unwrap();
+        assert_eq!(buf[..sector], vec![0xCC; sector][..]);
+        assert_eq!(buf[sector..2 * sector], vec![0x11; sector][..]);
+        assert_eq!(buf[2 * sector..], vec![0xCC; sector][..]);
+        // Blocks not present at all come from the parent, but zeroed ones
+        // don't.
+        vhdx.read_at(MB, &mut buf[..]).unwrap();
+        assert!(buf.iter().all(|b| *b == 0x11));
+        vhdx.read_at(2 * MB, &mut buf[..]).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        let mut vhdx = VhdxReader::open(MemoryReader::new(file)).unwrap();
+        assert!(matches!(
+            vhdx.read_at(MB, &mut buf[..]),
+            Err(Error::MissingParentDisk)
+        ));
+    }
+
+    #[test]
+    fn refuses_disks_with_a_log_to_replay() {
+        let mut file = Vhdx::new(4 * MB, MB as u32, 512).encode();
+        let [current, older] = HEADER_OFFSETS.map(|offset| offset as usize + 48);
+        // Only the current header's log counts.
+        file[older..older + 16].fill(0x42);
+        assert!(VhdxReader::open(MemoryReader::new(file.clone())).is_ok());
+        file[current..current + 16].fill(0x42);
+        assert!(matches!(
+            VhdxReader::open(MemoryReader::new(file)),
+            Err(Error::VhdxNeedsLogReplay)
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_bat_and_metadata() {
+        // The BAT's region table entry comes first, and the file parameters
+        // are the first metadata item.
+        let bat_length = REGION_TABLE_OFFSET as usize + 16 + 24;
+        let item_length = 256 * 1024 + 32 + 20;
+        let file = Vhdx::new(4 * MB, MB as u32, 512).encode();
+
+        // Four blocks need a 32 byte BAT.
+        let mut short = file.clone();
+        short[bat_length..bat_length + 4].copy_from_slice(&16u32.to_le_bytes());
+        assert!(matches!(
+            VhdxReader::open(MemoryReader::new(short)),
+            Err(Error::BadVhdxRegionTable)
+        ));
+
+        // The largest disk needs a 512 MiB BAT, which isn't in the file.
+        let mut huge = Vhdx::new(MAX_DISK_SIZE, MB as u32, 512).encode();
+        huge[bat_length..bat_length + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            VhdxReader::open(MemoryReader::new(huge)),
+            Err(Error::BadVhdxRegionTable)
+        ));
+
+        let too_big = Vhdx::new(MAX_DISK_SIZE + MB, MB as u32, 512).encode();
+        let mut long_item = file;
+        long_item[item_length..item_length + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        for file in [too_big, long_item] {
+            assert!(matches!(
+                VhdxReader::open(MemoryReader::new(file)),
+                Err(Error::BadVhdxMetadata)
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_block_and_sector_sizes() {
+        let sizes = [
+            (0, 512),
+            (MB / 2, 512),
+            (3 * MB, 512),
+            (512 * MB, 512),
+            (MB, 0),
+            (MB, 1024),
+        ];
+        for (block_size, logical_sector_size) in sizes {
+            let image = Vhdx::new(4 * MB, block_size as u32, logical_sector_size);
+            assert!(matches!(
+                VhdxReader::open(MemoryReader::new(image.encode())),
+                Err(Error::BadVhdxMetadata)
+            ));
+        }
+    }
+}